@@ -9,8 +9,15 @@ fn main() {
     let dest = env::var("OUT_DIR").unwrap();
     let mut file = File::create(&Path::new(&dest).join("gl_bindings.rs")).unwrap();
 
-    // We want OpenGL 3.3 Core
-    let registry = Registry::new(Api::Gl, (3, 3), Profile::Core, Fallbacks::All, []);
+    // We want OpenGL 3.3 Core, plus program binaries for the shader cache
+    // (core since 4.1 but widely available as an extension)
+    let registry = Registry::new(
+        Api::Gl,
+        (3, 3),
+        Profile::Core,
+        Fallbacks::All,
+        ["GL_ARB_get_program_binary"],
+    );
 
     if env::var("CARGO_FEATURE_DEBUG").is_ok() {
         registry
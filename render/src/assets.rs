@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, Weak};
+
+// Ids of assets whose last handle has been dropped. Handles can be dropped
+// on any thread (they live in ECS components), but GPU resources have to be
+// destroyed on the thread that owns the GL context, so they're queued here
+// until `Assets::maintain` is called.
+type ReleaseQueue = Arc<Mutex<Vec<u32>>>;
+
+struct HandleInner {
+    id: u32,
+    released: ReleaseQueue,
+}
+
+impl Drop for HandleInner {
+    fn drop(&mut self) {
+        if let Ok(mut released) = self.released.lock() {
+            released.push(self.id);
+        }
+    }
+}
+
+// A reference counted id for an asset stored in an `Assets` registry. The
+// asset stays alive as long as at least one handle to it exists. Handles
+// are cheap to clone and can be sent between threads even though the asset
+// they refer to can't.
+pub struct Handle<T> {
+    inner: Arc<HandleInner>,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    fn new(inner: Arc<HandleInner>) -> Self {
+        Self {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.inner.id
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
+}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.inner.id.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handle({})", self.inner.id)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct AssetStats {
+    // Assets currently stored
+    pub live: usize,
+    // Assets without handles waiting for the next `maintain`
+    pub pending_destroy: usize,
+    pub added_total: usize,
+    pub destroyed_total: usize,
+}
+
+// A slot's asset is empty while it's still being loaded
+struct Slot<T> {
+    asset: Option<T>,
+    path: Option<String>,
+}
+
+pub struct Assets<T> {
+    slots: Vec<Option<Slot<T>>>,
+    free: Vec<u32>,
+    placeholder: Option<T>,
+    paths: HashMap<String, Weak<HandleInner>>,
+    released: ReleaseQueue,
+    added_total: usize,
+    destroyed_total: usize,
+}
+
+impl<T> Assets<T> {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            placeholder: None,
+            paths: HashMap::new(),
+            released: Arc::new(Mutex::new(Vec::new())),
+            added_total: 0,
+            destroyed_total: 0,
+        }
+    }
+
+    // The asset returned by `get` for handles whose asset hasn't finished
+    // loading yet
+    pub fn set_placeholder(&mut self, placeholder: T) {
+        self.placeholder = Some(placeholder);
+    }
+
+    pub fn add(&mut self, asset: T) -> Handle<T> {
+        let inner = self.insert(Some(asset), None);
+        Handle::new(inner)
+    }
+
+    // Create a handle for an asset that will be loaded from the given path
+    // later on. Until `fill` is called, `get` returns the placeholder.
+    pub fn reserve(&mut self, path: &str) -> Handle<T> {
+        if let Some(handle) = self.get_loaded(path) {
+            return handle;
+        }
+
+        let inner = self.insert(None, Some(path.to_owned()));
+        self.paths.insert(path.to_owned(), Arc::downgrade(&inner));
+
+        Handle::new(inner)
+    }
+
+    // Provide the asset for a reserved handle
+    pub fn fill(&mut self, handle: &Handle<T>, asset: T) {
+        if let Some(Some(slot)) = self.slots.get_mut(handle.id() as usize) {
+            slot.asset = Some(asset);
+        }
+    }
+
+    // Get the asset loaded from the given path, only calling `load` if it
+    // isn't already in the registry
+    pub fn load<F>(&mut self, path: &str, load: F) -> Result<Handle<T>, String>
+    where
+        F: FnOnce(&str) -> Result<T, String>,
+    {
+        if let Some(handle) = self.get_loaded(path) {
+            return Ok(handle);
+        }
+
+        let asset = load(path)?;
+        let inner = self.insert(Some(asset), Some(path.to_owned()));
+        self.paths.insert(path.to_owned(), Arc::downgrade(&inner));
+
+        Ok(Handle::new(inner))
+    }
+
+    // Get a new handle to an already loaded asset
+    pub fn get_loaded(&self, path: &str) -> Option<Handle<T>> {
+        // An asset whose handles were all dropped can't be revived because
+        // it's already queued for destruction, so it's loaded again instead
+        self.paths
+            .get(path)
+            .and_then(Weak::upgrade)
+            .map(Handle::new)
+    }
+
+    // Get the asset for a handle, or the placeholder if it's still loading
+    pub fn get(&self, handle: &Handle<T>) -> Option<&T> {
+        self.slots
+            .get(handle.id() as usize)
+            .and_then(Option::as_ref)
+            .and_then(|slot| slot.asset.as_ref())
+            .or(self.placeholder.as_ref())
+    }
+
+    pub fn get_mut(&mut self, handle: &Handle<T>) -> Option<&mut T> {
+        self.slots
+            .get_mut(handle.id() as usize)
+            .and_then(Option::as_mut)
+            .and_then(|slot| slot.asset.as_mut())
+    }
+
+    pub fn is_loaded(&self, handle: &Handle<T>) -> bool {
+        match self.slots.get(handle.id() as usize) {
+            Some(Some(slot)) => slot.asset.is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn path(&self, handle: &Handle<T>) -> Option<&str> {
+        self.slots
+            .get(handle.id() as usize)
+            .and_then(Option::as_ref)
+            .and_then(|slot| slot.path.as_deref())
+    }
+
+    // Destroy every asset that no longer has any handles. This has to be
+    // called regularly (once per frame) on the thread that owns the assets.
+    // Returns the number of destroyed assets.
+    pub fn maintain(&mut self) -> usize {
+        let released: Vec<u32> = match self.released.lock() {
+            Ok(mut released) => released.drain(..).collect(),
+            Err(_) => return 0,
+        };
+
+        for id in released.iter() {
+            if let Some(slot) = self.slots[*id as usize].take() {
+                if let Some(path) = slot.path {
+                    // Only forget the path if it hasn't been loaded again
+                    // into a different slot in the meantime
+                    let stale = match self.paths.get(&path) {
+                        Some(weak) => weak.upgrade().is_none(),
+                        None => false,
+                    };
+                    if stale {
+                        self.paths.remove(&path);
+                    }
+                }
+                self.free.push(*id);
+                self.destroyed_total += 1;
+            }
+        }
+
+        released.len()
+    }
+
+    pub fn stats(&self) -> AssetStats {
+        let pending_destroy = self.released.lock().map_or(0, |released| released.len());
+
+        AssetStats {
+            live: self.slots.len() - self.free.len(),
+            pending_destroy,
+            added_total: self.added_total,
+            destroyed_total: self.destroyed_total,
+        }
+    }
+
+    fn insert(&mut self, asset: Option<T>, path: Option<String>) -> Arc<HandleInner> {
+        let slot = Some(Slot { asset, path });
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.slots[id as usize] = slot;
+                id
+            }
+            None => {
+                self.slots.push(slot);
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.added_total += 1;
+
+        Arc::new(HandleInner {
+            id,
+            released: self.released.clone(),
+        })
+    }
+}
+
+impl<T> Default for Assets<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Loads the path as the asset, counting how often it's called
+    fn load(assets: &mut Assets<String>, path: &str, loads: &mut usize) -> Handle<String> {
+        assets
+            .load(path, |path| {
+                *loads += 1;
+                Ok(path.to_owned())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn paths_are_only_loaded_once() {
+        let mut assets = Assets::new();
+        let mut loads = 0;
+        let a = load(&mut assets, "a", &mut loads);
+        assert_eq!(load(&mut assets, "a", &mut loads), a);
+        assert_eq!(assets.reserve("a"), a);
+        assert_ne!(load(&mut assets, "b", &mut loads), a);
+        assert_eq!(loads, 2);
+        assert_eq!(assets.get(&a).map(String::as_str), Some("a"));
+        assert_eq!(assets.path(&a), Some("a"));
+        let c = assets.add("c".to_owned());
+        assert!(assets.path(&c).is_none());
+
+        let err = assets.load("d", |_| Err("broken".to_owned()));
+        assert_eq!(err, Err("broken".to_owned()));
+        assert!(assets.get_loaded("d").is_none());
+    }
+
+    #[test]
+    fn assets_are_destroyed_once_their_last_handle_is_dropped() {
+        let mut assets = Assets::new();
+        let mut loads = 0;
+        let a = load(&mut assets, "a", &mut loads);
+        let copy = a.clone();
+        let id = a.id();
+
+        drop(a);
+        assert_eq!(assets.maintain(), 0);
+        assert_eq!(assets.get(&copy).map(String::as_str), Some("a"));
+
+        drop(copy);
+        assert_eq!(assets.stats().pending_destroy, 1);
+        assert_eq!(assets.maintain(), 1);
+        assert_eq!(
+            assets.stats(),
+            AssetStats {
+                live: 0,
+                pending_destroy: 0,
+                added_total: 1,
+                destroyed_total: 1,
+            }
+        );
+
+        // The path is forgotten and the slot is reused
+        assert!(assets.get_loaded("a").is_none());
+        let again = load(&mut assets, "a", &mut loads);
+        assert_eq!(again.id(), id);
+        assert_eq!(loads, 2);
+    }
+
+    #[test]
+    fn paths_loaded_again_before_maintaining_are_kept() {
+        let mut assets = Assets::new();
+        let mut loads = 0;
+        drop(load(&mut assets, "a", &mut loads));
+        let again = load(&mut assets, "a", &mut loads);
+        assert_eq!(loads, 2);
+
+        assert_eq!(assets.maintain(), 1);
+        assert_eq!(assets.get_loaded("a"), Some(again.clone()));
+        assert_eq!(assets.get(&again).map(String::as_str), Some("a"));
+    }
+
+    #[test]
+    fn reserved_assets_use_the_placeholder_until_filled() {
+        let mut assets = Assets::new();
+        let handle = assets.reserve("a");
+        assert!(!assets.is_loaded(&handle));
+        assert!(assets.get(&handle).is_none());
+
+        assets.set_placeholder("loading".to_owned());
+        assert_eq!(assets.get(&handle).map(String::as_str), Some("loading"));
+        assert!(assets.get_mut(&handle).is_none());
+
+        assets.fill(&handle, "a".to_owned());
+        assert!(assets.is_loaded(&handle));
+        assert_eq!(assets.get(&handle).map(String::as_str), Some("a"));
+    }
+}
@@ -1,4 +1,4 @@
-use gl::types::{GLchar, GLenum, GLint, GLsizeiptr, GLuint, GLushort, GLvoid};
+use gl::types::{GLchar, GLenum, GLint, GLsizei, GLsizeiptr, GLuint, GLushort, GLvoid};
 use gl_bindings::{gl, Gl};
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
@@ -7,6 +7,10 @@ use std::mem::size_of;
 
 #[macro_use]
 pub mod macros;
+pub mod assets;
+pub mod loader;
+pub mod program_cache;
+pub mod shader_source;
 
 pub trait VertComponent {
     fn attrib_pointer(gl: &Gl, location: u32, stride: usize, offset: i32);
@@ -37,6 +41,13 @@ impl Uniform for f32 {
     }
 }
 
+// Also used to tell samplers which texture unit to read from
+impl Uniform for i32 {
+    fn set_uniform(&self, gl: &Gl, location: GLint) {
+        unsafe { gl.Uniform1i(location, *self) };
+    }
+}
+
 fn create_empty_vec_cstr(len: usize) -> Vec<u8> {
     // Create a vec with enough capacity for the string
     let mut info_log_raw: Vec<u8> = Vec::with_capacity(len + 1);
@@ -59,21 +70,48 @@ fn create_str_from_raw_cstr(mut str: Vec<u8>) -> String {
     unsafe { String::from_utf8_unchecked(str) }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ShaderStage {
+    Vertex,
+    Geometry,
+    Fragment,
+}
+
+impl ShaderStage {
+    pub fn gl_type(self) -> GLenum {
+        match self {
+            ShaderStage::Vertex => gl::VERTEX_SHADER,
+            ShaderStage::Geometry => gl::GEOMETRY_SHADER,
+            ShaderStage::Fragment => gl::FRAGMENT_SHADER,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ShaderStage::Vertex => "vertex",
+            ShaderStage::Geometry => "geometry",
+            ShaderStage::Fragment => "fragment",
+        }
+    }
+}
+
 pub struct Shader {
     id: GLuint,
+    stage: ShaderStage,
     gl: Gl,
 }
 
 impl Shader {
-    fn new(gl: &Gl, shader_type: GLenum) -> Self {
+    fn new(gl: &Gl, stage: ShaderStage) -> Self {
         Self {
-            id: unsafe { gl.CreateShader(shader_type) },
+            id: unsafe { gl.CreateShader(stage.gl_type()) },
+            stage,
             gl: gl.clone(),
         }
     }
 
-    pub fn new_from_source(gl: &Gl, shader_type: GLenum, source: &CStr) -> Result<Self, String> {
-        let shader = Self::new(gl, shader_type);
+    pub fn new_from_source(gl: &Gl, stage: ShaderStage, source: &CStr) -> Result<Self, String> {
+        let shader = Self::new(gl, stage);
 
         // Load the source into the shader and attempt to compile it
         unsafe {
@@ -90,6 +128,10 @@ impl Shader {
         Ok(shader)
     }
 
+    pub fn stage(&self) -> ShaderStage {
+        self.stage
+    }
+
     fn check_compile_error(&self) -> Result<(), String> {
         // Get the length of the error log
         let mut info_log_length: GLint = 0;
@@ -144,6 +186,10 @@ impl ShaderProgram {
         shaders: Vec<Shader>,
         uniforms: Vec<String>,
     ) -> Result<Self, String> {
+        // Make sure the stages make up a complete pipeline before asking the
+        // driver to link them, its errors for this are rarely helpful
+        Self::validate_stages(shaders.iter().map(Shader::stage))?;
+
         let mut program = Self::new(gl, HashMap::with_capacity(uniforms.len()));
 
         // Attach the shaders
@@ -151,6 +197,18 @@ impl ShaderProgram {
             unsafe { gl.AttachShader(program.id, shader.id) };
         }
 
+        // Let the driver know we may want to retrieve the linked binary so
+        // it can be cached (only available with program binary support)
+        if gl.ProgramParameteri.is_loaded() {
+            unsafe {
+                gl.ProgramParameteri(
+                    program.id,
+                    crate::gl::PROGRAM_BINARY_RETRIEVABLE_HINT,
+                    crate::gl::TRUE as GLint,
+                )
+            };
+        }
+
         // Link the program and check for any linking errors
         unsafe { gl.LinkProgram(program.id) };
         if let Err(err) = program.check_link_error() {
@@ -164,6 +222,121 @@ impl ShaderProgram {
             unsafe { gl.DetachShader(program.id, shader.id) };
         }
 
+        program.locate_uniforms(uniforms);
+
+        // Return the program
+        Ok(program)
+    }
+
+    pub fn new_from_binary(
+        gl: &Gl,
+        format: GLenum,
+        binary: &[u8],
+        uniforms: Vec<String>,
+    ) -> Result<Self, String> {
+        if !gl.ProgramBinary.is_loaded() {
+            return Err("program binaries are not supported".to_owned());
+        }
+
+        let mut program = Self::new(gl, HashMap::with_capacity(uniforms.len()));
+
+        // Load the binary into the program. The driver is allowed to reject
+        // it for any reason (like an update), in which case the program won't
+        // be linked and the caller has to compile it from source instead.
+        unsafe {
+            gl.ProgramBinary(
+                program.id,
+                format,
+                binary.as_ptr() as *const GLvoid,
+                binary.len() as GLsizei,
+            )
+        };
+        let mut link_status: GLint = 0;
+        unsafe { gl.GetProgramiv(program.id, crate::gl::LINK_STATUS, &mut link_status) };
+        if link_status != crate::gl::TRUE as GLint {
+            return Err("program binary was rejected by the driver".to_owned());
+        }
+
+        program.locate_uniforms(uniforms);
+
+        // Return the program
+        Ok(program)
+    }
+
+    // Retrieve the linked binary and its format so it can be loaded later
+    // with `new_from_binary`
+    pub fn binary(&self) -> Option<(GLenum, Vec<u8>)> {
+        if !self.gl.GetProgramBinary.is_loaded() {
+            return None;
+        }
+
+        let mut length: GLint = 0;
+        unsafe {
+            self.gl
+                .GetProgramiv(self.id, crate::gl::PROGRAM_BINARY_LENGTH, &mut length)
+        };
+        if length <= 0 {
+            return None;
+        }
+
+        let mut binary: Vec<u8> = vec![0; length as usize];
+        let mut written: GLsizei = 0;
+        let mut format: GLenum = 0;
+        unsafe {
+            self.gl.GetProgramBinary(
+                self.id,
+                length,
+                &mut written,
+                &mut format,
+                binary.as_mut_ptr() as *mut GLvoid,
+            )
+        };
+        binary.truncate(written.max(0) as usize);
+
+        if binary.is_empty() {
+            None
+        } else {
+            Some((format, binary))
+        }
+    }
+
+    // A program needs exactly one vertex and one fragment shader and can
+    // optionally have a single geometry shader between them
+    pub fn validate_stages<Stages: IntoIterator<Item = ShaderStage>>(
+        stages: Stages,
+    ) -> Result<(), String> {
+        let mut counts: HashMap<ShaderStage, usize> = HashMap::new();
+        for stage in stages {
+            *counts.entry(stage).or_insert(0) += 1;
+        }
+
+        for stage in [
+            ShaderStage::Vertex,
+            ShaderStage::Geometry,
+            ShaderStage::Fragment,
+        ]
+        .iter()
+        {
+            let count = counts.get(stage).copied().unwrap_or(0);
+            if count > 1 {
+                return Err(format!(
+                    "shader program has {} {} shaders, at most one is allowed",
+                    count,
+                    stage.name()
+                ));
+            }
+            if count == 0 && *stage != ShaderStage::Geometry {
+                return Err(format!(
+                    "shader program is missing a {} shader",
+                    stage.name()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn locate_uniforms(&mut self, uniforms: Vec<String>) {
         for uniform_name in uniforms.into_iter() {
             // Convert the uniform name to a CString
             let cstr = &CString::new(uniform_name.clone()).expect(&format!(
@@ -172,8 +345,10 @@ impl ShaderProgram {
             ));
 
             // Get the location of the uniform within the program
-            let location =
-                unsafe { gl.GetUniformLocation(program.id, cstr.as_ptr() as *const GLchar) };
+            let location = unsafe {
+                self.gl
+                    .GetUniformLocation(self.id, cstr.as_ptr() as *const GLchar)
+            };
 
             // location will be -1 if the uniform was not found or not used in the program
             if location < 0 {
@@ -183,12 +358,9 @@ impl ShaderProgram {
                 );
             } else {
                 // Add the uniform to the hashmap for later retrieval
-                program.uniforms.insert(uniform_name, location);
+                self.uniforms.insert(uniform_name, location);
             }
         }
-
-        // Return the program
-        Ok(program)
     }
 
     pub fn set_uniform<UniformValue: Uniform>(&self, name: &str, value: &UniformValue) {
@@ -244,6 +416,53 @@ impl Drop for ShaderProgram {
     }
 }
 
+pub struct ShaderProgramBuilder {
+    gl: Gl,
+    shaders: Vec<Shader>,
+    uniforms: Vec<String>,
+}
+
+impl ShaderProgramBuilder {
+    pub fn new(gl: &Gl) -> Self {
+        Self {
+            gl: gl.clone(),
+            shaders: Vec::new(),
+            uniforms: Vec::new(),
+        }
+    }
+
+    pub fn with_shader(mut self, shader: Shader) -> Self {
+        self.shaders.push(shader);
+        self
+    }
+
+    pub fn with_source(self, stage: ShaderStage, source: &CStr) -> Result<Self, String> {
+        let shader = Shader::new_from_source(&self.gl, stage, source)
+            .map_err(|err| format!("failed to compile {} shader:\n{}", stage.name(), err))?;
+        Ok(self.with_shader(shader))
+    }
+
+    pub fn with_uniform(mut self, name: &str) -> Self {
+        self.uniforms.push(name.to_owned());
+        self
+    }
+
+    pub fn with_uniforms(mut self, names: &[&str]) -> Self {
+        self.uniforms
+            .extend(names.iter().map(|name| (*name).to_owned()));
+        self
+    }
+
+    // Check the stage combination without linking
+    pub fn validate(&self) -> Result<(), String> {
+        ShaderProgram::validate_stages(self.shaders.iter().map(Shader::stage))
+    }
+
+    pub fn build(self) -> Result<ShaderProgram, String> {
+        ShaderProgram::new_from_shaders(&self.gl, self.shaders, self.uniforms)
+    }
+}
+
 pub struct Buffer<BufferType> {
     id: GLuint,
     gl: Gl,
@@ -353,12 +572,104 @@ impl Drop for VertexArray {
     }
 }
 
+pub struct Texture {
+    id: GLuint,
+    width: u32,
+    height: u32,
+    gl: Gl,
+}
+
+impl Texture {
+    // Create a 2D texture from tightly packed 8-bit RGBA pixels. Nearest
+    // filtering is used because the game's art is pixel based.
+    pub fn new_rgba(gl: &Gl, width: u32, height: u32, pixels: &[u8]) -> Self {
+        assert_eq!(
+            pixels.len(),
+            width as usize * height as usize * 4,
+            "texture data doesn't match its size"
+        );
+
+        let texture = Self {
+            id: {
+                let mut tex: GLuint = 0;
+                unsafe { gl.GenTextures(1, &mut tex) };
+                tex
+            },
+            width,
+            height,
+            gl: gl.clone(),
+        };
+
+        unsafe {
+            gl.BindTexture(gl::TEXTURE_2D, texture.id);
+            gl.PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl.TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA8 as GLint,
+                width as GLsizei,
+                height as GLsizei,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                pixels.as_ptr() as *const GLvoid,
+            );
+            gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+            gl.TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+            gl.TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_WRAP_S,
+                gl::CLAMP_TO_EDGE as GLint,
+            );
+            gl.TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_WRAP_T,
+                gl::CLAMP_TO_EDGE as GLint,
+            );
+            gl.BindTexture(gl::TEXTURE_2D, 0);
+        }
+
+        texture
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Bind the texture to the given texture unit
+    pub fn bind(&self, unit: u32) {
+        unsafe {
+            self.gl.ActiveTexture(gl::TEXTURE0 + unit);
+            self.gl.BindTexture(gl::TEXTURE_2D, self.id);
+        }
+    }
+
+    pub fn unbind_all(gl: &Gl, unit: u32) {
+        unsafe {
+            gl.ActiveTexture(gl::TEXTURE0 + unit);
+            gl.BindTexture(gl::TEXTURE_2D, 0);
+        }
+    }
+
+    pub fn unbind(&self, unit: u32) {
+        Self::unbind_all(&self.gl, unit);
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe { self.gl.DeleteTextures(1, &self.id) };
+        println!("Dropping texture {}", self.id);
+    }
+}
+
 pub struct Mesh<VertexType: VertexAttrib, IndexType: Index> {
     vao: VertexArray,
-    // We need ownership of the VBO so it's dropped when the mesh is, but it's
-    // never actually *used* in the code after it's added to this struct, so
-    // the compiler will display a warning
-    _vbo: Buffer<VertexType>,
+    vbo: Buffer<VertexType>,
     ebo: Buffer<IndexType>,
     indices: usize,
     gl: Gl,
@@ -374,7 +685,7 @@ impl<VertexType: VertexAttrib, IndexType: Index> Mesh<VertexType, IndexType> {
     ) -> Self {
         Self {
             vao,
-            _vbo: vbo,
+            vbo,
             ebo,
             indices,
             gl: gl.clone(),
@@ -417,6 +728,29 @@ impl<VertexType: VertexAttrib, IndexType: Index> Mesh<VertexType, IndexType> {
         Self::new(vao, vbo, ebo, index_count, gl)
     }
 
+    // Replace the mesh's vertices and indices, keeping its buffers. Meant for
+    // meshes that change every frame, which would otherwise create and delete
+    // their buffers each time.
+    pub fn update(&mut self, vertex_data: Vec<VertexType>, index_data: Vec<IndexType>) {
+        self.vbo.buffer(
+            crate::gl::ARRAY_BUFFER,
+            crate::gl::DYNAMIC_DRAW,
+            vertex_data,
+            true,
+        );
+        self.indices = index_data.len();
+        self.ebo.buffer(
+            crate::gl::ELEMENT_ARRAY_BUFFER,
+            crate::gl::DYNAMIC_DRAW,
+            index_data,
+            true,
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices == 0
+    }
+
     pub fn render(&self) {
         // Binds the vertex array
         self.vao.bind();
@@ -432,7 +766,7 @@ impl<VertexType: VertexAttrib, IndexType: Index> Mesh<VertexType, IndexType> {
             self.gl.DrawElements(
                 crate::gl::TRIANGLES,
                 self.indices as i32,
-                crate::gl::UNSIGNED_SHORT,
+                IndexType::get_type(),
                 std::ptr::null(),
             )
         };
@@ -446,6 +780,78 @@ impl<VertexType: VertexAttrib, IndexType: Index> Mesh<VertexType, IndexType> {
         // Unbind the vertex array
         self.vao.unbind();
     }
+
+    // Draw the mesh once for each instance in the buffer. The instance type's
+    // attributes should have divisors so they advance once per instance.
+    pub fn render_instanced<InstanceType: VertexAttrib>(
+        &self,
+        instances: &InstanceBuffer<InstanceType>,
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+
+        self.vao.bind();
+        self.ebo.bind(crate::gl::ELEMENT_ARRAY_BUFFER);
+
+        // Point the instance attributes at the instance buffer
+        instances.buffer.bind(crate::gl::ARRAY_BUFFER);
+        InstanceType::setup_attrib_pointer(&self.gl);
+        instances.buffer.unbind(crate::gl::ARRAY_BUFFER);
+
+        VertexType::enable_attribs(&self.gl);
+        InstanceType::enable_attribs(&self.gl);
+
+        unsafe {
+            self.gl.DrawElementsInstanced(
+                crate::gl::TRIANGLES,
+                self.indices as i32,
+                IndexType::get_type(),
+                std::ptr::null(),
+                instances.len() as GLsizei,
+            )
+        };
+
+        InstanceType::disable_attribs(&self.gl);
+        VertexType::disable_attribs(&self.gl);
+
+        self.ebo.unbind(crate::gl::ELEMENT_ARRAY_BUFFER);
+        self.vao.unbind();
+    }
+}
+
+// Per-instance data for instanced draws, replaced whenever the instances
+// change (usually every frame)
+pub struct InstanceBuffer<InstanceType: VertexAttrib> {
+    buffer: Buffer<InstanceType>,
+    count: usize,
+}
+
+impl<InstanceType: VertexAttrib> InstanceBuffer<InstanceType> {
+    pub fn new(gl: &Gl) -> Self {
+        Self {
+            buffer: Buffer::new(gl),
+            count: 0,
+        }
+    }
+
+    pub fn update(&mut self, instances: Vec<InstanceType>) {
+        self.count = instances.len();
+        self.buffer.buffer(
+            crate::gl::ARRAY_BUFFER,
+            crate::gl::STREAM_DRAW,
+            instances,
+            true,
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
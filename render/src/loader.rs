@@ -0,0 +1,611 @@
+use crate::assets::{Assets, Handle};
+use crate::{Index, Mesh, Texture, VertexAttrib};
+use gl_bindings::gl::types::GLushort;
+use gl_bindings::Gl;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+// CPU-side data produced by decoding an asset file on a worker thread,
+// ready to be uploaded to the GPU
+pub trait AssetData: Send + 'static {
+    // Roughly how many bytes uploading this data will transfer, used to
+    // limit how much is uploaded each frame
+    fn byte_size(&self) -> usize;
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub requested: usize,
+    pub decoded: usize,
+    pub uploaded: usize,
+    // Assets that failed to decode never count as decoded, but assets that
+    // failed to upload were decoded first
+    pub failed_decoding: usize,
+    pub failed_uploading: usize,
+}
+
+// The progress of several loaders together
+impl std::ops::Add for LoadProgress {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            requested: self.requested + other.requested,
+            decoded: self.decoded + other.decoded,
+            uploaded: self.uploaded + other.uploaded,
+            failed_decoding: self.failed_decoding + other.failed_decoding,
+            failed_uploading: self.failed_uploading + other.failed_uploading,
+        }
+    }
+}
+
+impl LoadProgress {
+    pub fn is_done(&self) -> bool {
+        self.uploaded + self.failed_decoding + self.failed_uploading >= self.requested
+    }
+
+    // How far along loading is, from 0 to 1. Decoding and uploading are
+    // weighted equally.
+    pub fn fraction(&self) -> f32 {
+        if self.requested == 0 {
+            return 1.0;
+        }
+
+        let steps =
+            (self.decoded + self.uploaded + self.failed_decoding * 2 + self.failed_uploading)
+                as f32;
+        steps / (self.requested * 2) as f32
+    }
+}
+
+struct Job {
+    id: u32,
+    path: String,
+}
+
+struct Decoded<Data> {
+    id: u32,
+    path: String,
+    data: Result<Data, String>,
+}
+
+type DecodeFn<Data> = dyn Fn(&str) -> Result<Data, String> + Send + Sync;
+type UploadFn<T, Data> = dyn Fn(&Gl, Data) -> Result<T, String>;
+
+// Loads assets in the background. Files are read and decoded on a pool of
+// worker threads, then uploaded on the thread that owns the GL context (it
+// isn't `Send`) a bounded amount each frame so loading doesn't cause
+// hitches. Handles are returned immediately and resolve to the registry's
+// placeholder until their asset is uploaded.
+pub struct AsyncLoader<T, Data: AssetData> {
+    jobs: Option<Sender<Job>>,
+    results: Receiver<Decoded<Data>>,
+    workers: Vec<JoinHandle<()>>,
+    upload: Box<UploadFn<T, Data>>,
+
+    // Handles are kept until their asset is uploaded so it can't be
+    // destroyed mid-load
+    pending: HashMap<u32, Handle<T>>,
+    ready: VecDeque<(Handle<T>, String, Data)>,
+    progress: LoadProgress,
+}
+
+impl<T, Data: AssetData> AsyncLoader<T, Data> {
+    pub fn new<Decode, Upload>(worker_count: usize, decode: Decode, upload: Upload) -> Self
+    where
+        Decode: Fn(&str) -> Result<Data, String> + Send + Sync + 'static,
+        Upload: Fn(&Gl, Data) -> Result<T, String> + 'static,
+    {
+        let (job_sender, job_receiver) = channel::<Job>();
+        let (result_sender, results) = channel();
+
+        // The workers share a single queue of jobs
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let decode: Arc<DecodeFn<Data>> = Arc::new(decode);
+
+        let workers = (0..worker_count.max(1))
+            .map(|i| {
+                let jobs = job_receiver.clone();
+                let results = result_sender.clone();
+                let decode = decode.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("asset-loader-{}", i))
+                    .spawn(move || run_worker(&jobs, &results, &*decode))
+                    .expect("failed to spawn asset loader thread")
+            })
+            .collect();
+
+        Self {
+            jobs: Some(job_sender),
+            results,
+            workers,
+            upload: Box::new(upload),
+            pending: HashMap::new(),
+            ready: VecDeque::new(),
+            progress: LoadProgress::default(),
+        }
+    }
+
+    // Start loading an asset. Assets that are already loaded or loading are
+    // only loaded once.
+    pub fn load(&mut self, assets: &mut Assets<T>, path: &str) -> Handle<T> {
+        let handle = assets.reserve(path);
+        if assets.is_loaded(&handle) || self.pending.contains_key(&handle.id()) {
+            return handle;
+        }
+
+        self.pending.insert(handle.id(), handle.clone());
+        self.progress.requested += 1;
+
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(Job {
+                id: handle.id(),
+                path: path.to_owned(),
+            });
+        }
+
+        handle
+    }
+
+    // Upload decoded assets, stopping once `byte_budget` bytes have been
+    // uploaded this call. At least one asset is always uploaded so large
+    // assets can't stall loading forever.
+    // Returns the number of uploaded assets.
+    pub fn update(&mut self, gl: &Gl, assets: &mut Assets<T>, byte_budget: usize) -> usize {
+        // Collect everything the workers have finished
+        while let Ok(decoded) = self.results.try_recv() {
+            let handle = match self.pending.get(&decoded.id) {
+                Some(handle) => handle.clone(),
+                None => continue,
+            };
+
+            match decoded.data {
+                Ok(data) => {
+                    self.progress.decoded += 1;
+                    self.ready.push_back((handle, decoded.path, data));
+                }
+                Err(err) => {
+                    println!("Failed to load \"{}\": {}", decoded.path, err);
+                    self.progress.failed_decoding += 1;
+                    self.pending.remove(&decoded.id);
+                }
+            }
+        }
+
+        let mut uploaded_bytes = 0;
+        let mut uploaded = 0;
+        while uploaded == 0 || uploaded_bytes < byte_budget {
+            let (handle, path, data) = match self.ready.pop_front() {
+                Some(ready) => ready,
+                None => break,
+            };
+            uploaded_bytes += data.byte_size();
+            uploaded += 1;
+
+            match (self.upload)(gl, data) {
+                Ok(asset) => {
+                    assets.fill(&handle, asset);
+                    self.progress.uploaded += 1;
+                }
+                Err(err) => {
+                    println!("Failed to upload \"{}\": {}", path, err);
+                    self.progress.failed_uploading += 1;
+                }
+            }
+            self.pending.remove(&handle.id());
+        }
+
+        uploaded
+    }
+
+    pub fn progress(&self) -> LoadProgress {
+        self.progress
+    }
+}
+
+impl<T, Data: AssetData> Drop for AsyncLoader<T, Data> {
+    fn drop(&mut self) {
+        // Closing the job queue stops the workers once they finish their
+        // current job
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_worker<Data>(
+    jobs: &Mutex<Receiver<Job>>,
+    results: &Sender<Decoded<Data>>,
+    decode: &DecodeFn<Data>,
+) {
+    loop {
+        // The lock is only held while waiting for the next job so the
+        // other workers can decode at the same time
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+
+        // The loader was dropped
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let data = decode(&job.path);
+        let decoded = Decoded {
+            id: job.id,
+            path: job.path,
+            data,
+        };
+        if results.send(decoded).is_err() {
+            return;
+        }
+    }
+}
+
+// Decoded 8-bit RGBA pixels
+pub struct TextureData {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl TextureData {
+    pub fn decode_png(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| format!("failed to open: {}", err))?;
+        Self::read_png(BufReader::new(file))
+    }
+
+    pub fn read_png<R: Read>(reader: R) -> Result<Self, String> {
+        // Expand palettes and low bit depths and strip 16 bit channels so
+        // only the 8 bit color types have to be handled
+        let mut decoder = png::Decoder::new(reader);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder
+            .read_info()
+            .map_err(|err| format!("invalid png: {}", err))?;
+
+        let mut buffer = vec![0; info.buffer_size()];
+        reader
+            .next_frame(&mut buffer)
+            .map_err(|err| format!("invalid png: {}", err))?;
+
+        let pixels = match info.color_type {
+            png::ColorType::RGBA => buffer,
+            png::ColorType::RGB => buffer
+                .chunks(3)
+                .flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buffer
+                .chunks(2)
+                .flat_map(|ga| vec![ga[0], ga[0], ga[0], ga[1]])
+                .collect(),
+            png::ColorType::Grayscale => {
+                buffer.iter().flat_map(|g| vec![*g, *g, *g, 255]).collect()
+            }
+            png::ColorType::Indexed => return Err("unexpanded palette".to_owned()),
+        };
+
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, self.width, self.height);
+            encoder.set_color(png::ColorType::RGBA);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder
+                .write_header()
+                .map_err(|err| format!("failed to encode png: {}", err))?;
+            writer
+                .write_image_data(&self.pixels)
+                .map_err(|err| format!("failed to encode png: {}", err))?;
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn upload(self, gl: &Gl) -> Result<Texture, String> {
+        Ok(Texture::new_rgba(gl, self.width, self.height, &self.pixels))
+    }
+}
+
+impl AssetData for TextureData {
+    fn byte_size(&self) -> usize {
+        self.pixels.len()
+    }
+}
+
+// Vertices and indices parsed from a model file
+pub struct MeshData<VertexType, IndexType> {
+    pub vertices: Vec<VertexType>,
+    pub indices: Vec<IndexType>,
+}
+
+impl<VertexType, IndexType> MeshData<VertexType, IndexType>
+where
+    VertexType: VertexAttrib,
+    IndexType: Index,
+{
+    pub fn upload(self, gl: &Gl) -> Result<Mesh<VertexType, IndexType>, String> {
+        Ok(Mesh::create(gl, self.vertices, self.indices))
+    }
+}
+
+impl<VertexType> MeshData<VertexType, GLushort> {
+    pub fn decode_obj<F>(path: &str, vertex: F) -> Result<Self, String>
+    where
+        F: Fn([f32; 3], [f32; 3]) -> VertexType,
+    {
+        let file = File::open(path).map_err(|err| format!("failed to open: {}", err))?;
+        Self::read_obj(BufReader::new(file), vertex)
+    }
+
+    // Read the positions and faces of a Wavefront OBJ model, making each
+    // vertex from its position and color with `vertex`. Colors can follow
+    // the position (`v x y z r g b`), which many tools write, and are white
+    // otherwise. Faces with more than three corners are split into fans of
+    // triangles, and everything else in the file is ignored.
+    pub fn read_obj<R, F>(mut reader: R, vertex: F) -> Result<Self, String>
+    where
+        R: Read,
+        F: Fn([f32; 3], [f32; 3]) -> VertexType,
+    {
+        let mut text = String::new();
+        reader
+            .read_to_string(&mut text)
+            .map_err(|err| format!("invalid obj: {}", err))?;
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: &str| format!("invalid obj: line {}: {}", number + 1, message);
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("v") => {
+                    let values = parts
+                        .map(str::parse)
+                        .collect::<Result<Vec<f32>, _>>()
+                        .map_err(|_| error("invalid number"))?;
+                    let position = match values.get(0..3) {
+                        Some(position) => [position[0], position[1], position[2]],
+                        None => return Err(error("vertices need 3 coordinates")),
+                    };
+                    let color = match values.get(3..6) {
+                        Some(color) => [color[0], color[1], color[2]],
+                        None => [1.0, 1.0, 1.0],
+                    };
+                    vertices.push(vertex(position, color));
+                }
+                Some("f") => {
+                    let corners = parts
+                        .map(|corner| {
+                            // Only the position of `v/vt/vn` is used. Negative
+                            // indices count back from the latest vertex.
+                            let index: i64 = corner
+                                .split('/')
+                                .next()
+                                .and_then(|index| index.parse().ok())
+                                .ok_or_else(|| error("invalid index"))?;
+                            let index = if index < 0 {
+                                vertices.len() as i64 + index
+                            } else {
+                                index - 1
+                            };
+                            if index < 0 || index >= vertices.len() as i64 {
+                                return Err(error("index out of range"));
+                            }
+                            if index > i64::from(GLushort::MAX) {
+                                return Err(error("too many vertices"));
+                            }
+                            Ok(index as GLushort)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if corners.len() < 3 {
+                        return Err(error("faces need at least 3 corners"));
+                    }
+                    for pair in corners[1..].windows(2) {
+                        indices.extend_from_slice(&[corners[0], pair[0], pair[1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self { vertices, indices })
+    }
+}
+
+impl<VertexType, IndexType> AssetData for MeshData<VertexType, IndexType>
+where
+    VertexType: Send + 'static,
+    IndexType: Send + 'static,
+{
+    fn byte_size(&self) -> usize {
+        self.vertices.len() * std::mem::size_of::<VertexType>()
+            + self.indices.len() * std::mem::size_of::<IndexType>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_counts_decoding_and_uploading() {
+        assert_eq!(LoadProgress::default().fraction(), 1.0);
+        assert!(LoadProgress::default().is_done());
+
+        let mut progress = LoadProgress {
+            requested: 4,
+            ..LoadProgress::default()
+        };
+        assert_eq!(progress.fraction(), 0.0);
+        progress.decoded = 2;
+        assert_eq!(progress.fraction(), 0.25);
+        progress.uploaded = 1;
+        progress.failed_decoding = 1;
+        // An asset that failed to decode counts for both steps
+        assert_eq!(progress.fraction(), 0.625);
+        assert!(!progress.is_done());
+
+        progress.decoded = 3;
+        progress.uploaded = 3;
+        assert_eq!(progress.fraction(), 1.0);
+        assert!(progress.is_done());
+
+        let total = progress
+            + LoadProgress {
+                requested: 4,
+                ..LoadProgress::default()
+            };
+        assert_eq!(total.requested, 8);
+        assert_eq!(total.fraction(), 0.5);
+    }
+
+    #[test]
+    fn failed_uploads_are_counted_once() {
+        let mut progress = LoadProgress {
+            requested: 2,
+            ..LoadProgress::default()
+        };
+        progress.decoded = 2;
+        progress.uploaded = 1;
+        assert_eq!(progress.fraction(), 0.75);
+        assert!(!progress.is_done());
+
+        // The asset was already counted as decoded
+        progress.failed_uploading = 1;
+        assert_eq!(progress.fraction(), 1.0);
+        assert!(progress.is_done());
+    }
+
+    // Encode a 2x1 image with the given color type and depth
+    fn png(
+        color: png::ColorType,
+        depth: png::BitDepth,
+        data: &[u8],
+        palette: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 2, 1);
+            encoder.set_color(color);
+            encoder.set_depth(depth);
+            let mut writer = encoder.write_header().unwrap();
+            if let Some(palette) = palette {
+                writer.write_chunk(*b"PLTE", palette).unwrap();
+            }
+            writer.write_image_data(data).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn every_color_type_is_read_as_rgba() {
+        use png::{BitDepth::*, ColorType::*};
+
+        let check = |color, depth, data: &[u8], palette, expected: &[u8]| {
+            let bytes = png(color, depth, data, palette);
+            let texture = TextureData::read_png(&bytes[..]).unwrap();
+            assert_eq!((texture.width, texture.height), (2, 1), "{:?}", color);
+            assert_eq!(texture.pixels, expected, "{:?} {:?}", color, depth);
+        };
+        check(
+            RGBA,
+            Eight,
+            &[1, 2, 3, 4, 5, 6, 7, 8],
+            None,
+            &[1, 2, 3, 4, 5, 6, 7, 8],
+        );
+        check(
+            RGB,
+            Eight,
+            &[1, 2, 3, 5, 6, 7],
+            None,
+            &[1, 2, 3, 255, 5, 6, 7, 255],
+        );
+        check(
+            GrayscaleAlpha,
+            Eight,
+            &[9, 4, 10, 8],
+            None,
+            &[9, 9, 9, 4, 10, 10, 10, 8],
+        );
+        check(
+            Grayscale,
+            Eight,
+            &[9, 10],
+            None,
+            &[9, 9, 9, 255, 10, 10, 10, 255],
+        );
+        // 16 bit channels keep their high byte
+        check(
+            Grayscale,
+            Sixteen,
+            &[9, 0xff, 10, 0],
+            None,
+            &[9, 9, 9, 255, 10, 10, 10, 255],
+        );
+        let palette: &[u8] = &[5, 6, 7, 1, 2, 3];
+        check(
+            Indexed,
+            Eight,
+            &[1, 0],
+            Some(palette),
+            &[1, 2, 3, 255, 5, 6, 7, 255],
+        );
+
+        assert!(TextureData::read_png(&b"not a png"[..]).is_err());
+    }
+
+    #[test]
+    fn encoded_pngs_read_back_the_same() {
+        let texture = TextureData {
+            width: 3,
+            height: 2,
+            pixels: (0..24).map(|i| i * 10).collect(),
+        };
+        let read = TextureData::read_png(&texture.encode_png().unwrap()[..]).unwrap();
+        assert_eq!((read.width, read.height), (3, 2));
+        assert_eq!(read.pixels, texture.pixels);
+    }
+
+    #[test]
+    fn obj_faces_become_triangles() {
+        let obj = "\
+# A colored quad and a white triangle
+v -1 -1 0 1 0 0
+v -1 1 0 0 1 0
+v 1 1 0 0 0 1
+v 1 -1 0
+vt 0 0
+f 1/1 2/1 3/1 4/1
+f -1 -3 -2
+";
+        let mesh = MeshData::read_obj(obj.as_bytes(), |position, color| (position, color)).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.vertices[0], ([-1.0, -1.0, 0.0], [1.0, 0.0, 0.0]));
+        assert_eq!(mesh.vertices[3].1, [1.0, 1.0, 1.0]);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3, 3, 1, 2]);
+
+        let read = |obj: &str| MeshData::read_obj(obj.as_bytes(), |position, _| position);
+        assert!(read("v 1 2\n").is_err());
+        assert!(read("v 1 2 x\n").is_err());
+        assert!(read("v 0 0 0\nv 1 0 0\nf 1 2\n").is_err());
+        assert!(read("v 0 0 0\nv 1 0 0\nf 1 2 3\n").is_err());
+        assert!(read("v 0 0 0\nf 1 1 -2\n").is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use crate::{ShaderProgram, ShaderStage};
+use gl::types::{GLenum, GLint, GLuint};
+use gl_bindings::{gl, Gl};
+use std::ffi::CStr;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+const MAGIC: &[u8; 4] = b"CPB1";
+const HEADER_LEN: usize = 16;
+
+// Stores linked shader programs on disk so they don't have to be compiled
+// and linked again on the next startup.
+// Binaries are only valid for the exact driver that produced them, so the
+// vendor, renderer and version strings are part of every key. Drivers may
+// still reject a binary (after an update, for example) and the caller is
+// expected to compile from source when that happens.
+pub struct ProgramBinaryCache {
+    dir: PathBuf,
+    driver: String,
+}
+
+impl ProgramBinaryCache {
+    // Returns `None` when the driver doesn't support program binaries
+    pub fn new<P: Into<PathBuf>>(gl: &Gl, dir: P) -> Option<Self> {
+        if !Self::is_supported(gl) {
+            return None;
+        }
+
+        let driver = [gl::VENDOR, gl::RENDERER, gl::VERSION]
+            .iter()
+            .map(|name| get_gl_string(gl, *name))
+            .collect::<Vec<_>>()
+            .join(" | ");
+
+        Some(Self {
+            dir: dir.into(),
+            driver,
+        })
+    }
+
+    pub fn is_supported(gl: &Gl) -> bool {
+        if !gl.GetProgramBinary.is_loaded()
+            || !gl.ProgramBinary.is_loaded()
+            || !gl.ProgramParameteri.is_loaded()
+        {
+            return false;
+        }
+
+        // The functions may be loadable even when the driver doesn't
+        // advertise the extension, so check for it explicitly (it's core as
+        // of 4.1)
+        let (mut major, mut minor): (GLint, GLint) = (0, 0);
+        unsafe {
+            gl.GetIntegerv(gl::MAJOR_VERSION, &mut major);
+            gl.GetIntegerv(gl::MINOR_VERSION, &mut minor);
+        }
+        let core = (major, minor) >= (4, 1);
+        if !core && !has_extension(gl, "GL_ARB_get_program_binary") {
+            return false;
+        }
+
+        // Some drivers support the extension but no binary formats at all
+        let mut formats: GLint = 0;
+        unsafe { gl.GetIntegerv(gl::NUM_PROGRAM_BINARY_FORMATS, &mut formats) };
+        formats > 0
+    }
+
+    // Builds the cache key for a program from the sources of each stage
+    // (after preprocessing, so defines are included)
+    pub fn key(&self, sources: &[(ShaderStage, &str)]) -> u64 {
+        let mut hash = Fnv1a::new();
+        hash.write(self.driver.as_bytes());
+        for (stage, source) in sources {
+            hash.write(&stage.gl_type().to_le_bytes());
+            hash.write(source.as_bytes());
+            // Separate the sources so moving text between stages changes
+            // the key
+            hash.write(&[0]);
+        }
+        hash.finish()
+    }
+
+    // Try to create the program for the given key from the cache. A missing,
+    // corrupt or rejected binary returns `None`; broken entries are removed
+    // so they're replaced the next time the program is stored.
+    pub fn load(&self, gl: &Gl, key: u64, uniforms: Vec<String>) -> Option<ShaderProgram> {
+        let path = self.path(key);
+        let data = fs::read(&path).ok()?;
+
+        let program = parse_entry(&data).ok_or_else(|| "corrupt cache entry".to_owned());
+        let program = program.and_then(|(format, binary)| {
+            ShaderProgram::new_from_binary(gl, format, binary, uniforms)
+        });
+
+        match program {
+            Ok(program) => Some(program),
+            Err(err) => {
+                println!("Discarding cached program {}: {}", path.display(), err);
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, key: u64, program: &ShaderProgram) -> Result<(), String> {
+        let (format, binary) = program
+            .binary()
+            .ok_or_else(|| "failed to retrieve program binary".to_owned())?;
+
+        fs::create_dir_all(&self.dir).map_err(|err| {
+            format!(
+                "failed to create program cache directory {}: {}",
+                self.dir.display(),
+                err
+            )
+        })?;
+
+        // Write to a temporary file first so a crash can't leave a partial
+        // entry behind under the real name
+        let path = self.path(key);
+        let temp_path = path.with_extension("tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(&encode_entry(format, &binary))?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)
+        };
+
+        write().map_err(|err| format!("failed to write {}: {}", path.display(), err))
+    }
+
+    // Remove every cached program
+    pub fn clear(&self) -> Result<(), String> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(format!(
+                "failed to clear program cache {}: {}",
+                self.dir.display(),
+                err
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.bin", key))
+    }
+}
+
+// A cache entry is the magic, the binary format, the length of the binary
+// and then the binary itself
+fn encode_entry(format: GLenum, binary: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + binary.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&(format as GLuint).to_le_bytes());
+    data.extend_from_slice(&(binary.len() as u64).to_le_bytes());
+    data.extend_from_slice(binary);
+    data
+}
+
+fn parse_entry(data: &[u8]) -> Option<(GLenum, &[u8])> {
+    if data.len() < HEADER_LEN || &data[0..4] != MAGIC {
+        return None;
+    }
+
+    let mut format = [0; 4];
+    format.copy_from_slice(&data[4..8]);
+    let mut length = [0; 8];
+    length.copy_from_slice(&data[8..16]);
+
+    let binary = &data[HEADER_LEN..];
+    if u64::from_le_bytes(length) != binary.len() as u64 {
+        return None;
+    }
+
+    Some((GLuint::from_le_bytes(format), binary))
+}
+
+fn get_gl_string(gl: &Gl, name: GLenum) -> String {
+    let ptr = unsafe { gl.GetString(name) };
+    if ptr.is_null() {
+        return String::new();
+    }
+
+    unsafe { CStr::from_ptr(ptr as *const _) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn has_extension(gl: &Gl, extension: &str) -> bool {
+    let mut count: GLint = 0;
+    unsafe { gl.GetIntegerv(gl::NUM_EXTENSIONS, &mut count) };
+
+    (0..count.max(0) as GLuint).any(|i| {
+        let ptr = unsafe { gl.GetStringi(gl::EXTENSIONS, i) };
+        !ptr.is_null()
+            && unsafe { CStr::from_ptr(ptr as *const _) }.to_bytes() == extension.as_bytes()
+    })
+}
+
+// The standard library's hasher isn't guaranteed to be stable between
+// releases, and cache keys have to be, so use FNV-1a instead
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(driver: &str) -> ProgramBinaryCache {
+        ProgramBinaryCache {
+            dir: PathBuf::new(),
+            driver: driver.to_owned(),
+        }
+    }
+
+    #[test]
+    fn entries_read_back_what_was_written() {
+        let binary = [1, 2, 3, 4, 5];
+        let data = encode_entry(0x8741, &binary);
+        assert_eq!(&data[0..4], MAGIC);
+        assert_eq!(parse_entry(&data), Some((0x8741, &binary[..])));
+
+        let empty = encode_entry(7, &[]);
+        assert_eq!(empty.len(), HEADER_LEN);
+        assert_eq!(parse_entry(&empty), Some((7, &[][..])));
+    }
+
+    #[test]
+    fn broken_entries_are_rejected() {
+        let data = encode_entry(0x8741, &[1, 2, 3, 4, 5]);
+
+        // Cut off in the binary or in the header
+        assert_eq!(parse_entry(&data[..data.len() - 1]), None);
+        assert_eq!(parse_entry(&data[..HEADER_LEN - 1]), None);
+        assert_eq!(parse_entry(&[]), None);
+
+        // Longer than the header says
+        let mut long = data.clone();
+        long.push(6);
+        assert_eq!(parse_entry(&long), None);
+
+        let mut wrong_magic = data;
+        wrong_magic[3] = b'2';
+        assert_eq!(parse_entry(&wrong_magic), None);
+    }
+
+    #[test]
+    fn keys_change_with_the_driver_and_every_source() {
+        let sources = [
+            (ShaderStage::Vertex, "void main() {}"),
+            (ShaderStage::Fragment, "out vec4 color;"),
+        ];
+        let key = cache("Vendor | Renderer | 4.6").key(&sources);
+        assert_eq!(key, cache("Vendor | Renderer | 4.6").key(&sources));
+
+        assert_ne!(key, cache("Vendor | Renderer | 4.5").key(&sources));
+        let mut changed = sources;
+        changed[0].1 = "void main() { }";
+        assert_ne!(key, cache("Vendor | Renderer | 4.6").key(&changed));
+        let mut changed = sources;
+        changed[1].1 = "out vec4 colour;";
+        assert_ne!(key, cache("Vendor | Renderer | 4.6").key(&changed));
+        let mut changed = sources;
+        changed[1].0 = ShaderStage::Geometry;
+        assert_ne!(key, cache("Vendor | Renderer | 4.6").key(&changed));
+
+        // Text moved from one stage to the next
+        let moved = [
+            (ShaderStage::Vertex, "void main() {}out"),
+            (ShaderStage::Fragment, " vec4 color;"),
+        ];
+        assert_ne!(key, cache("Vendor | Renderer | 4.6").key(&moved));
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        let hash = |bytes: &[u8]| {
+            let mut hash = Fnv1a::new();
+            hash.write(bytes);
+            hash.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
@@ -0,0 +1,589 @@
+use crate::assets::{Assets, Handle};
+use crate::program_cache::ProgramBinaryCache;
+use crate::{Shader, ShaderProgram, ShaderStage};
+use gl_bindings::Gl;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
+use std::path::PathBuf;
+
+// Somewhere shader files can be read from. Include paths are always
+// relative to the root of the file system and use forward slashes.
+pub trait ShaderFileSystem {
+    fn read(&self, path: &str) -> Result<String, String>;
+}
+
+pub struct DiskFileSystem {
+    root: PathBuf,
+}
+
+impl DiskFileSystem {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ShaderFileSystem for DiskFileSystem {
+    fn read(&self, path: &str) -> Result<String, String> {
+        std::fs::read_to_string(self.root.join(path))
+            .map_err(|err| format!("failed to read shader file \"{}\": {}", path, err))
+    }
+}
+
+// A file system made of sources compiled into the executable (usually with
+// `include_str!`) so the game doesn't depend on the working directory
+#[derive(Default)]
+pub struct EmbeddedFileSystem {
+    files: HashMap<String, &'static str>,
+}
+
+impl EmbeddedFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(mut self, path: &str, source: &'static str) -> Self {
+        self.add_file(path, source);
+        self
+    }
+
+    pub fn add_file(&mut self, path: &str, source: &'static str) {
+        self.files.insert(path.to_owned(), source);
+    }
+}
+
+impl ShaderFileSystem for EmbeddedFileSystem {
+    fn read(&self, path: &str) -> Result<String, String> {
+        self.files
+            .get(path)
+            .map(|source| (*source).to_owned())
+            .ok_or_else(|| format!("no embedded shader file \"{}\"", path))
+    }
+}
+
+// The set of `#define`s used to build a shader permutation. It's kept
+// sorted so two equal sets always produce the same source and cache key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ShaderDefines {
+    defines: BTreeMap<String, String>,
+}
+
+impl ShaderDefines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(self, name: &str) -> Self {
+        self.with_value(name, "")
+    }
+
+    pub fn with_value(mut self, name: &str, value: &str) -> Self {
+        self.define(name, value);
+        self
+    }
+
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.defines.insert(name.to_owned(), value.to_owned());
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.defines.contains_key(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defines.is_empty()
+    }
+
+    fn to_glsl(&self) -> Vec<String> {
+        self.defines
+            .iter()
+            .map(|(name, value)| {
+                if value.is_empty() {
+                    format!("#define {}", name)
+                } else {
+                    format!("#define {} {}", name, value)
+                }
+            })
+            .collect()
+    }
+}
+
+// Where a line of the final source came from. Lines injected by the
+// preprocessor (like defines) don't have an origin.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct LineOrigin {
+    file: usize,
+    line: usize,
+}
+
+pub struct PreprocessedSource {
+    source: String,
+    files: Vec<String>,
+    lines: Vec<Option<LineOrigin>>,
+}
+
+impl PreprocessedSource {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // All of the files that make up this source, the root file first
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    // Converts a line number (starting at 1) of the final source into the
+    // file and line number (also starting at 1) it was written at
+    pub fn original_location(&self, line: usize) -> Option<(&str, usize)> {
+        let origin = (*self.lines.get(line.checked_sub(1)?)?)?;
+        Some((&self.files[origin.file], origin.line))
+    }
+
+    // Rewrites the line references in a driver's info log so they point at
+    // the original files rather than the combined source. Drivers report
+    // locations as either `0:LINE` (Mesa, AMD, Intel) or `0(LINE)` (Nvidia).
+    pub fn map_info_log(&self, log: &str) -> String {
+        log.lines()
+            .map(|line| self.map_info_log_line(line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn map_info_log_line(&self, line: &str) -> String {
+        let bytes = line.as_bytes();
+        for i in 0..bytes.len() {
+            // Only look at a `0` that isn't part of a bigger number
+            if bytes[i] != b'0' || (i > 0 && bytes[i - 1].is_ascii_digit()) {
+                continue;
+            }
+
+            let (open, close) = match bytes.get(i + 1) {
+                Some(b':') => (":", ""),
+                Some(b'(') => ("(", ")"),
+                _ => continue,
+            };
+
+            // Read the line number following the separator
+            let start = i + 2;
+            let end = start
+                + bytes[start..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+            if end == start || !line[end..].starts_with(close) {
+                continue;
+            }
+
+            let location = line[start..end]
+                .parse()
+                .ok()
+                .and_then(|number| self.original_location(number));
+            if let Some((file, number)) = location {
+                return format!(
+                    "{}{}{}{}{}{}",
+                    &line[..i],
+                    file,
+                    open,
+                    number,
+                    close,
+                    &line[end + close.len()..]
+                );
+            }
+        }
+
+        line.to_owned()
+    }
+}
+
+pub struct ShaderPreprocessor {
+    fs: Box<dyn ShaderFileSystem>,
+}
+
+impl ShaderPreprocessor {
+    pub fn new<FileSystem: ShaderFileSystem + 'static>(fs: FileSystem) -> Self {
+        Self { fs: Box::new(fs) }
+    }
+
+    // Resolves all of the includes in the given file and injects the defines
+    // directly after its `#version` directive (or at the top if it doesn't
+    // have one).
+    // Each file is only included once, so shared files don't need guards
+    // and include cycles are harmless.
+    pub fn preprocess(
+        &self,
+        path: &str,
+        defines: &ShaderDefines,
+    ) -> Result<PreprocessedSource, String> {
+        let mut state = PreprocessState {
+            output: Vec::new(),
+            files: Vec::new(),
+            included: HashSet::new(),
+            version_index: None,
+        };
+        self.process_file(path, &mut state)?;
+
+        // Insert the defines after the version, where GLSL allows them
+        let insert_at = state.version_index.map_or(0, |index| index + 1);
+        let define_lines = defines.to_glsl().into_iter().map(|line| (line, None));
+        state.output.splice(insert_at..insert_at, define_lines);
+
+        let (mut source, lines): (Vec<String>, Vec<Option<LineOrigin>>) =
+            state.output.into_iter().unzip();
+        source.push(String::new());
+
+        Ok(PreprocessedSource {
+            source: source.join("\n"),
+            files: state.files,
+            lines,
+        })
+    }
+
+    fn process_file(&self, path: &str, state: &mut PreprocessState) -> Result<(), String> {
+        if !state.included.insert(path.to_owned()) {
+            return Ok(());
+        }
+
+        let source = self.fs.read(path)?;
+        let file = state.files.len();
+        state.files.push(path.to_owned());
+
+        for (index, line) in source.lines().enumerate() {
+            let origin = LineOrigin {
+                file,
+                line: index + 1,
+            };
+            let trimmed = line.trim_start();
+
+            if trimmed.starts_with("#include") {
+                let include = parse_include(trimmed).ok_or_else(|| {
+                    format!("{}:{}: malformed include \"{}\"", path, index + 1, trimmed)
+                })?;
+                self.process_file(&resolve_include_path(path, include), state)
+                    .map_err(|err| format!("{}\n  included from {}:{}", err, path, index + 1))?;
+            } else if trimmed.starts_with("#version") {
+                // Only the first version directive is kept; included files
+                // may declare one so they can be checked on their own
+                if state.version_index.is_none() {
+                    state.version_index = Some(state.output.len());
+                    state.output.push((line.to_owned(), Some(origin)));
+                } else {
+                    state.output.push((String::new(), Some(origin)));
+                }
+            } else {
+                state.output.push((line.to_owned(), Some(origin)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct PreprocessState {
+    output: Vec<(String, Option<LineOrigin>)>,
+    files: Vec<String>,
+    included: HashSet<String>,
+    version_index: Option<usize>,
+}
+
+fn parse_include(line: &str) -> Option<&str> {
+    let rest = line["#include".len()..].trim();
+    let (open, close) = match rest.chars().next()? {
+        '"' => ('"', '"'),
+        '<' => ('<', '>'),
+        _ => return None,
+    };
+    let end = rest[open.len_utf8()..].find(close)? + open.len_utf8();
+
+    // Anything after the closing quote other than a comment is an error
+    let after = rest[end + close.len_utf8()..].trim();
+    if !after.is_empty() && !after.starts_with("//") {
+        return None;
+    }
+
+    Some(&rest[open.len_utf8()..end])
+}
+
+// Includes starting with a slash are relative to the root of the file
+// system, all others are relative to the including file's directory
+fn resolve_include_path(current: &str, include: &str) -> String {
+    let mut parts: Vec<&str> = if include.starts_with('/') {
+        Vec::new()
+    } else {
+        let mut dir: Vec<&str> = current.split('/').collect();
+        dir.pop();
+        dir
+    };
+
+    for part in include.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    parts.join("/")
+}
+
+// Loads shader programs through the preprocessor into an asset registry.
+// Every variant is registered under its files and defines, so requesting
+// the same combination again while it's alive is free. With a binary cache,
+// linked programs are also kept on disk between runs.
+pub struct ShaderLoader {
+    preprocessor: ShaderPreprocessor,
+    binary_cache: Option<ProgramBinaryCache>,
+}
+
+impl ShaderLoader {
+    pub fn new<FileSystem: ShaderFileSystem + 'static>(fs: FileSystem) -> Self {
+        Self {
+            preprocessor: ShaderPreprocessor::new(fs),
+            binary_cache: None,
+        }
+    }
+
+    pub fn with_binary_cache(mut self, binary_cache: Option<ProgramBinaryCache>) -> Self {
+        self.binary_cache = binary_cache;
+        self
+    }
+
+    pub fn preprocessor(&self) -> &ShaderPreprocessor {
+        &self.preprocessor
+    }
+
+    pub fn compile_shader(
+        &self,
+        gl: &Gl,
+        stage: ShaderStage,
+        path: &str,
+        defines: &ShaderDefines,
+    ) -> Result<Shader, String> {
+        let source = self.preprocessor.preprocess(path, defines)?;
+        Self::compile_preprocessed(gl, stage, path, &source)
+    }
+
+    fn compile_preprocessed(
+        gl: &Gl,
+        stage: ShaderStage,
+        path: &str,
+        source: &PreprocessedSource,
+    ) -> Result<Shader, String> {
+        let cstr = CString::new(source.source())
+            .map_err(|_| format!("shader \"{}\" contains a null character", path))?;
+
+        Shader::new_from_source(gl, stage, &cstr).map_err(|log| {
+            format!(
+                "failed to compile {} shader \"{}\":\n{}",
+                stage.name(),
+                path,
+                source.map_info_log(&log)
+            )
+        })
+    }
+
+    // Load a program made of the given stages and their files. The stage
+    // combination is checked before anything is compiled.
+    pub fn load_program(
+        &self,
+        gl: &Gl,
+        programs: &mut Assets<ShaderProgram>,
+        stages: &[(ShaderStage, &str)],
+        defines: &ShaderDefines,
+        uniforms: &[&str],
+    ) -> Result<Handle<ShaderProgram>, String> {
+        let mut stages: Vec<(ShaderStage, &str)> = stages.to_vec();
+        stages.sort();
+
+        // The registry key names every file and define in the variant
+        let mut key = stages
+            .iter()
+            .map(|(stage, path)| format!("{}:{}", stage.name(), path))
+            .collect::<Vec<_>>()
+            .join(";");
+        if !defines.is_empty() {
+            key.push('#');
+            key.push_str(&defines.to_glsl().join(";"));
+        }
+
+        programs.load(&key, |_| self.build_program(gl, &stages, defines, uniforms))
+    }
+
+    fn build_program(
+        &self,
+        gl: &Gl,
+        stages: &[(ShaderStage, &str)],
+        defines: &ShaderDefines,
+        uniforms: &[&str],
+    ) -> Result<ShaderProgram, String> {
+        let files = stages
+            .iter()
+            .map(|(_, path)| format!("\"{}\"", path))
+            .collect::<Vec<_>>()
+            .join(", ");
+        ShaderProgram::validate_stages(stages.iter().map(|(stage, _)| *stage))
+            .map_err(|err| format!("invalid shader program {}: {}", files, err))?;
+
+        // Preprocessing is cheap compared to compiling, and the final sources
+        // are needed to look the program up in the binary cache
+        let sources = stages
+            .iter()
+            .map(|(stage, path)| {
+                let source = self.preprocessor.preprocess(path, defines)?;
+                Ok((*stage, *path, source))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let uniforms: Vec<String> = uniforms.iter().map(|name| (*name).to_owned()).collect();
+
+        let binary_key = self.binary_cache.as_ref().map(|cache| {
+            let stage_sources: Vec<(ShaderStage, &str)> = sources
+                .iter()
+                .map(|(stage, _, source)| (*stage, source.source()))
+                .collect();
+            cache.key(&stage_sources)
+        });
+        let cached = match (&self.binary_cache, binary_key) {
+            (Some(cache), Some(binary_key)) => cache.load(gl, binary_key, uniforms.clone()),
+            _ => None,
+        };
+        if let Some(program) = cached {
+            return Ok(program);
+        }
+
+        let shaders = sources
+            .iter()
+            .map(|(stage, path, source)| Self::compile_preprocessed(gl, *stage, path, source))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let program = ShaderProgram::new_from_shaders(gl, shaders, uniforms)
+            .map_err(|log| format!("failed to link {}:\n{}", files, log))?;
+
+        // Failing to cache the program isn't fatal, it'll just be compiled
+        // again next time
+        if let (Some(cache), Some(binary_key)) = (&self.binary_cache, binary_key) {
+            if let Err(err) = cache.store(binary_key, &program) {
+                println!("Failed to cache shader program: {}", err);
+            }
+        }
+
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A shader including a file that includes another, and one that
+    // includes the shader back
+    fn preprocessor() -> ShaderPreprocessor {
+        ShaderPreprocessor::new(
+            EmbeddedFileSystem::new()
+                .with_file(
+                    "shaders/main.glsl",
+                    "#version 330 core\n#include \"lib/common.glsl\"\nvoid main() {}\n",
+                )
+                .with_file(
+                    "shaders/lib/common.glsl",
+                    "#include \"math.glsl\" // helpers\n#include </shaders/main.glsl>\nfloat common;\n",
+                )
+                .with_file("shaders/lib/math.glsl", "#version 330 core\nfloat math;\n")
+                .with_file("shaders/plain.glsl", "void main() {}\n")
+                .with_file("shaders/missing.glsl", "\n#include \"gone.glsl\"\n")
+                .with_file("shaders/malformed.glsl", "#include gone.glsl\n"),
+        )
+    }
+
+    #[test]
+    fn includes_are_resolved_once_with_defines_after_the_version() {
+        let defines = ShaderDefines::new().with("FOO").with_value("BAR", "2");
+        let processed = preprocessor()
+            .preprocess("shaders/main.glsl", &defines)
+            .unwrap();
+        assert_eq!(
+            processed.source(),
+            "#version 330 core\n#define BAR 2\n#define FOO\n\nfloat math;\nfloat common;\nvoid main() {}\n"
+        );
+        assert_eq!(
+            processed.files(),
+            [
+                "shaders/main.glsl",
+                "shaders/lib/common.glsl",
+                "shaders/lib/math.glsl"
+            ]
+        );
+
+        // Without a version the defines go at the top
+        let processed = preprocessor()
+            .preprocess("shaders/plain.glsl", &defines)
+            .unwrap();
+        assert_eq!(
+            processed.source(),
+            "#define BAR 2\n#define FOO\nvoid main() {}\n"
+        );
+    }
+
+    #[test]
+    fn lines_map_back_to_the_files_they_came_from() {
+        let processed = preprocessor()
+            .preprocess("shaders/main.glsl", &ShaderDefines::new().with("FOO"))
+            .unwrap();
+        assert_eq!(
+            processed.original_location(1),
+            Some(("shaders/main.glsl", 1))
+        );
+        assert_eq!(processed.original_location(2), None);
+        assert_eq!(
+            processed.original_location(4),
+            Some(("shaders/lib/math.glsl", 2))
+        );
+        assert_eq!(
+            processed.original_location(5),
+            Some(("shaders/lib/common.glsl", 3))
+        );
+        assert_eq!(processed.original_location(0), None);
+        assert_eq!(processed.original_location(99), None);
+
+        let log = "0:4(3): error: x\n0(5) : error C1: y\nERROR: 10:4: z\nERROR: 0:99: w";
+        assert_eq!(
+            processed.map_info_log(log),
+            "shaders/lib/math.glsl:2(3): error: x\n\
+             shaders/lib/common.glsl(3) : error C1: y\n\
+             ERROR: 10:4: z\n\
+             ERROR: 0:99: w"
+        );
+    }
+
+    #[test]
+    fn bad_includes_say_where_they_are() {
+        let preprocessor = preprocessor();
+        let err = preprocessor
+            .preprocess("shaders/missing.glsl", &ShaderDefines::new())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            "no embedded shader file \"shaders/gone.glsl\"\n  included from shaders/missing.glsl:2"
+        );
+
+        let err = preprocessor
+            .preprocess("shaders/malformed.glsl", &ShaderDefines::new())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            "shaders/malformed.glsl:1: malformed include \"#include gone.glsl\""
+        );
+    }
+
+    #[test]
+    fn include_paths_are_relative_to_the_including_file() {
+        assert_eq!(resolve_include_path("a/b/c.glsl", "d.glsl"), "a/b/d.glsl");
+        assert_eq!(resolve_include_path("a/b/c.glsl", "../d.glsl"), "a/d.glsl");
+        assert_eq!(
+            resolve_include_path("a/b/c.glsl", "./e/d.glsl"),
+            "a/b/e/d.glsl"
+        );
+        assert_eq!(resolve_include_path("a/b/c.glsl", "/d.glsl"), "d.glsl");
+    }
+}
@@ -8,7 +8,7 @@ use proc_macro::TokenStream;
 use syn::export::TokenStream2;
 use syn::DeriveInput;
 
-#[proc_macro_derive(VertexAttribPointers, attributes(location))]
+#[proc_macro_derive(VertexAttribPointers, attributes(location, divisor))]
 pub fn vertex_attrib_pointers_derive(input: TokenStream) -> TokenStream {
     generate_impl(syn::parse_macro_input!(input as DeriveInput)).into()
 }
@@ -131,6 +131,25 @@ fn generate_field_vertex_call(field: &syn::Field) -> (TokenStream2, TokenStream2
         _ => panic!("Invalid location attribute value"),
     };
 
+    // Fields with a divisor advance once every that many instances instead
+    // of once per vertex, which is used for per-instance data
+    let divisor_call = field
+        .attrs
+        .iter()
+        .find(|a| get_path_string(&a.path) == "divisor")
+        .map(|divisor_attr| {
+            let divisor_value = match divisor_attr.parse_meta().unwrap() {
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    lit: syn::Lit::Int(value),
+                    ..
+                }) => value.base10_parse::<u32>().unwrap(),
+                _ => panic!("Invalid divisor attribute value"),
+            };
+            quote! {
+                unsafe { gl.VertexAttribDivisor(#location_value, #divisor_value) };
+            }
+        });
+
     // Cache the field location so it can be used within the quote macro
     let field_type = &field.ty;
 
@@ -147,6 +166,7 @@ fn generate_field_vertex_call(field: &syn::Field) -> (TokenStream2, TokenStream2
         // Create the vertex attrib pointer
         quote! {
             <#field_type as ::render::VertComponent>::attrib_pointer(gl, #location_value, stride, offset as i32);
+            #divisor_call
 
             // Increment the offset
             offset += ::std::mem::size_of::<#field_type>();
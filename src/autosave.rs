@@ -0,0 +1,344 @@
+use crate::plugin::GameWorld;
+use crate::save::{self, SaveFormat, SaveHeader};
+use crate::world::SimulationTime;
+use specs::World;
+use std::cmp::Reverse;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Autosave slots start with these bytes, followed by the slot's sequence
+// number, the checksum of the save and then the save itself
+const SLOT_MAGIC: &[u8; 4] = b"CSUM";
+const SLOT_HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct AutosaveConfig {
+    pub directory: PathBuf,
+    // Number of autosaves to keep. The oldest one is replaced each time.
+    pub slots: u32,
+    // Save every this many in-game months
+    pub every_months: Option<u64>,
+    // Save every this much real time
+    pub every_real: Option<Duration>,
+    pub format: SaveFormat,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("saves"),
+            slots: 3,
+            every_months: Some(1),
+            every_real: Some(Duration::from_secs(5 * 60)),
+            format: SaveFormat::Binary,
+        }
+    }
+}
+
+impl AutosaveConfig {
+    pub fn slot_path(&self, slot: u32) -> PathBuf {
+        self.directory.join(format!("autosave_{}.city", slot))
+    }
+}
+
+// Saves the world in the background every so often. The world is copied on
+// the main thread, then encoded and written on another one so the game
+// doesn't stop while saving.
+pub struct Autosave {
+    config: AutosaveConfig,
+    next_slot: u32,
+    // Increases with every autosave so the newest slot can be found
+    next_sequence: u64,
+    last_month: Option<u64>,
+    last_save: Instant,
+    in_flight: Option<JoinHandle<Result<PathBuf, String>>>,
+    // Whether the save that's due was skipped, so it's only reported once
+    skipped: bool,
+}
+
+impl Autosave {
+    // Autosaves continue after the newest existing slot
+    pub fn new(config: AutosaveConfig) -> Self {
+        let newest = (0..config.slots)
+            .filter_map(|slot| {
+                read_slot_sequence(&config.slot_path(slot))
+                    .ok()
+                    .map(|sequence| (sequence, slot))
+            })
+            .max();
+        let (next_sequence, next_slot) = match newest {
+            Some((sequence, slot)) => (sequence + 1, (slot + 1) % config.slots.max(1)),
+            None => (0, 0),
+        };
+
+        Self {
+            config,
+            next_slot,
+            next_sequence,
+            last_month: None,
+            last_save: Instant::now(),
+            in_flight: None,
+            skipped: false,
+        }
+    }
+
+    pub fn config(&self) -> &AutosaveConfig {
+        &self.config
+    }
+
+    // Start an autosave if one is due. Call once per frame.
+    pub fn update(&mut self, world: &World) {
+        self.poll();
+
+        let month = world.fetch::<SimulationTime>().month();
+        let last_month = *self.last_month.get_or_insert(month);
+        let months_due =
+            matches!(self.config.every_months, Some(months) if month >= last_month + months);
+        let real_due =
+            matches!(self.config.every_real, Some(every) if self.last_save.elapsed() >= every);
+
+        if (months_due || real_due) && !self.save_now(world) && !self.skipped {
+            println!("Skipping autosave, the last one is still being written");
+            self.skipped = true;
+        }
+    }
+
+    // Start saving the world into the next slot. Returns false if the last
+    // autosave is still being written.
+    pub fn save_now(&mut self, world: &World) -> bool {
+        self.poll();
+        if self.in_flight.is_some() {
+            return false;
+        }
+
+        let snapshot = save::snapshot_world(world);
+        let path = self.config.slot_path(self.next_slot);
+        let sequence = self.next_sequence;
+        let format = self.config.format;
+        let directory = self.config.directory.clone();
+        self.in_flight = Some(thread::spawn(move || {
+            let bytes = snapshot.encode(format)?;
+            fs::create_dir_all(&directory)
+                .map_err(|err| format!("failed to create {}: {}", directory.display(), err))?;
+            save::write_atomically(&path, &slot_bytes(sequence, &bytes))?;
+            Ok(path)
+        }));
+
+        self.next_slot = (self.next_slot + 1) % self.config.slots.max(1);
+        self.next_sequence += 1;
+        self.last_month = Some(world.fetch::<SimulationTime>().month());
+        self.last_save = Instant::now();
+        self.skipped = false;
+        true
+    }
+
+    // Wait for the autosave being written to finish, like before exiting
+    pub fn finish(&mut self) {
+        if let Some(handle) = self.in_flight.take() {
+            report(handle);
+        }
+    }
+
+    fn poll(&mut self) {
+        if self
+            .in_flight
+            .as_ref()
+            .is_some_and(|handle| handle.is_finished())
+        {
+            self.finish();
+        }
+    }
+}
+
+impl Drop for Autosave {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn report(handle: JoinHandle<Result<PathBuf, String>>) {
+    match handle.join() {
+        Ok(Ok(path)) => println!("Autosaved to {}", path.display()),
+        Ok(Err(err)) => println!("Autosave failed: {}", err),
+        Err(_) => println!("Autosave failed: the save thread panicked"),
+    }
+}
+
+fn slot_bytes(sequence: u64, save: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(SLOT_HEADER_LEN + save.len());
+    bytes.extend_from_slice(SLOT_MAGIC);
+    bytes.extend_from_slice(&sequence.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(save).to_le_bytes());
+    bytes.extend_from_slice(save);
+    bytes
+}
+
+fn parse_slot_header(bytes: &[u8]) -> Result<(u64, u32), String> {
+    if bytes.len() < SLOT_HEADER_LEN || !bytes.starts_with(SLOT_MAGIC) {
+        return Err("not an autosave".to_owned());
+    }
+
+    let sequence = u64::from_le_bytes(bytes[4..12].try_into().unwrap());
+    let checksum = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+    Ok((sequence, checksum))
+}
+
+fn read_slot_sequence(path: &Path) -> Result<u64, String> {
+    let mut header = [0; SLOT_HEADER_LEN];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .map_err(|err| err.to_string())?;
+    parse_slot_header(&header).map(|(sequence, _)| sequence)
+}
+
+// Read an autosave, checking that it wasn't damaged. Returns its sequence
+// number and the save inside it.
+fn read_slot(path: &Path) -> Result<(u64, Vec<u8>), String> {
+    let mut bytes = fs::read(path).map_err(|err| err.to_string())?;
+    let (sequence, checksum) = parse_slot_header(&bytes)?;
+    if crc32fast::hash(&bytes[SLOT_HEADER_LEN..]) != checksum {
+        return Err("checksum doesn't match, the file is damaged".to_owned());
+    }
+
+    bytes.drain(..SLOT_HEADER_LEN);
+    Ok((sequence, bytes))
+}
+
+// Load the newest autosave into a world from `build_world`. Damaged or
+// unreadable autosaves are skipped in favor of the next newest one, each
+// loaded into a new world so a failed attempt doesn't leave anything behind.
+pub fn load_latest<F>(
+    config: &AutosaveConfig,
+    mut build_world: F,
+) -> Result<(GameWorld, SaveHeader), String>
+where
+    F: FnMut() -> Result<GameWorld, String>,
+{
+    let mut slots: Vec<(u64, PathBuf)> = (0..config.slots)
+        .map(|slot| config.slot_path(slot))
+        .filter(|path| path.exists())
+        .filter_map(|path| match read_slot_sequence(&path) {
+            Ok(sequence) => Some((sequence, path)),
+            Err(err) => {
+                println!("Skipping autosave {}: {}", path.display(), err);
+                None
+            }
+        })
+        .collect();
+    slots.sort_by_key(|(sequence, _)| Reverse(*sequence));
+
+    for (_, path) in slots.iter() {
+        let loaded = read_slot(path).and_then(|(_, bytes)| {
+            let mut game = build_world()?;
+            save::load_world(&mut game.world, &bytes).map(|header| (game, header))
+        });
+        match loaded {
+            Ok(loaded) => return Ok(loaded),
+            Err(err) => println!("Skipping autosave {}: {}", path.display(), err),
+        }
+    }
+
+    Err("no usable autosave".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::WorldBuilder;
+
+    fn config(directory: &Path) -> AutosaveConfig {
+        AutosaveConfig {
+            directory: directory.join("saves"),
+            slots: 2,
+            every_months: Some(1),
+            every_real: None,
+            format: SaveFormat::Binary,
+        }
+    }
+
+    fn build_world() -> Result<GameWorld, String> {
+        WorldBuilder::new().build()
+    }
+
+    fn save_at_tick(autosave: &mut Autosave, tick: u64) {
+        let mut game = build_world().unwrap();
+        game.world.insert(SimulationTime { tick, step: 0.0 });
+        assert!(autosave.save_now(&game.world));
+        autosave.finish();
+    }
+
+    fn loaded_tick(config: &AutosaveConfig) -> u64 {
+        let (game, _) = load_latest(config, build_world).unwrap();
+        let tick = game.world.fetch::<SimulationTime>().tick;
+        tick
+    }
+
+    #[test]
+    fn slots_rotate_and_the_newest_is_loaded() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        let mut autosave = Autosave::new(config.clone());
+        for tick in 1..=3 {
+            save_at_tick(&mut autosave, tick);
+        }
+
+        // The third save replaced the first slot
+        assert_eq!(read_slot(&config.slot_path(0)).unwrap().0, 2);
+        assert_eq!(read_slot(&config.slot_path(1)).unwrap().0, 1);
+        assert!(!config.directory.join("autosave_2.city").exists());
+        assert_eq!(loaded_tick(&config), 3);
+
+        // Saving after a restart continues after the newest slot
+        let mut autosave = Autosave::new(config.clone());
+        save_at_tick(&mut autosave, 4);
+        assert_eq!(read_slot(&config.slot_path(1)).unwrap().0, 3);
+        assert_eq!(loaded_tick(&config), 4);
+    }
+
+    #[test]
+    fn damaged_saves_fall_back_to_the_previous_slot() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        let mut autosave = Autosave::new(config.clone());
+        save_at_tick(&mut autosave, 10);
+        save_at_tick(&mut autosave, 20);
+
+        let newest = config.slot_path(1);
+        let mut bytes = fs::read(&newest).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&newest, bytes).unwrap();
+
+        assert!(read_slot(&newest).unwrap_err().contains("checksum"));
+        assert_eq!(loaded_tick(&config), 10);
+    }
+
+    #[test]
+    fn saves_every_month() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut autosave = Autosave::new(config(directory.path()));
+        let mut game = build_world().unwrap();
+
+        let month = crate::world::TICKS_PER_DAY * crate::world::DAYS_PER_MONTH;
+        for tick in [0, month - 1, month, month + 1, 2 * month].iter() {
+            game.world.insert(SimulationTime {
+                tick: *tick,
+                step: 0.0,
+            });
+            autosave.update(&game.world);
+            autosave.finish();
+        }
+
+        assert_eq!(autosave.next_sequence, 2);
+    }
+
+    #[test]
+    fn nothing_to_load_is_an_error() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(load_latest(&config(directory.path()), build_world).is_err());
+    }
+}
@@ -0,0 +1,1095 @@
+use crate::demand::{Census, Demand};
+use crate::economy::Treasury;
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::tilemap::{Terrain, TileFlags, TileMap, Zone, ZoneDensity};
+use crate::world::{Building, Pickable, SimulationTime, Transform, DAYS_PER_MONTH, TICKS_PER_DAY};
+use nalgebra::{UnitQuaternion, Vector3};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use serde::{Deserialize, Serialize};
+use specs::{Entities, Entity, Join, Read, System, Write, WriteStorage};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// At most this many buildings of each zone go up each day, when the demand
+// for the zone is as high as it gets
+const NEW_BUILDINGS_PER_DAY: f32 = 4.0;
+
+// Zones don't grow at all with less demand than this
+const GROWTH_THRESHOLD: f32 = 0.05;
+
+// New buildings go on the best of this many random lots
+const LOT_SAMPLES: usize = 16;
+
+// How far a building's condition moves each day when its zone's demand is
+// at one of its extremes
+const CONDITION_CHANGE: f32 = 0.05;
+const STARTING_CONDITION: f32 = 0.5;
+
+// Buildings in good condition go up a level when their zone is in demand and
+// the land is worth it
+const UPGRADE_CONDITION: f32 = 0.9;
+const UPGRADE_DEMAND: f32 = 0.3;
+const MAX_UPGRADES_PER_DAY: usize = 4;
+
+// How long abandoned buildings stand before they're torn down
+const ABANDONED_DAYS: u64 = DAYS_PER_MONTH;
+
+// How many tiles from a lot a road can be and still serve the building on it
+const ROAD_REACH: i32 = 3;
+
+// How far water, trees and industry are felt in land values
+const LAND_VALUE_RANGE: i32 = 4;
+
+// The largest level and lot side buildings can have
+pub const MAX_LEVEL: u8 = 5;
+pub const MAX_BUILDING_SIZE: u8 = 4;
+
+// How far services can reach from a building
+pub const MAX_EFFECT_RADIUS: u8 = 16;
+
+// How often changed building types are read again while developing
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+// The building types the game comes with, for when the assets folder can't
+// be read. They're in the same order as the files in the folder.
+const BUILT_IN_TYPES: [(&str, &str); 3] = [
+    (
+        "commercial.ron",
+        include_str!("../assets/buildings/commercial.ron"),
+    ),
+    (
+        "industrial.ron",
+        include_str!("../assets/buildings/industrial.ron"),
+    ),
+    (
+        "residential.ron",
+        include_str!("../assets/buildings/residential.ron"),
+    ),
+];
+
+// Power and water a building uses each day
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Utilities {
+    pub power: f32,
+    pub water: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Service {
+    LandValue,
+    Pollution,
+}
+
+// What a building does for the tiles around it
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceEffect {
+    pub service: Service,
+    // In tiles
+    pub radius: u8,
+    pub strength: f32,
+}
+
+// A kind of building that can grow in a zone, read from the RON files in
+// the assets folder
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildingType {
+    // Saved buildings refer to their type by name
+    pub name: String,
+    pub zone: Zone,
+    pub density: ZoneDensity,
+    pub level: u8,
+    // In tiles
+    pub size: (u8, u8),
+    #[serde(default)]
+    pub residents: u32,
+    #[serde(default)]
+    pub jobs: u32,
+    // Goods made or sold each day
+    #[serde(default)]
+    pub goods_produced: f32,
+    #[serde(default)]
+    pub goods_consumed: f32,
+    #[serde(default)]
+    pub utilities: Utilities,
+    // What the building costs to put up, and to keep up each month
+    #[serde(default)]
+    pub cost: i64,
+    #[serde(default)]
+    pub upkeep: i64,
+    // The sprite or mesh the building is drawn with
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub effects: Vec<ServiceEffect>,
+}
+
+impl BuildingType {
+    pub fn area(&self) -> u32 {
+        u32::from(self.size.0) * u32::from(self.size.1)
+    }
+
+    // Everything wrong with the type
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.name.trim().is_empty() {
+            problems.push("the name is empty".to_owned());
+        }
+        if self.zone == Zone::Unzoned {
+            problems.push("buildings can't grow on unzoned land".to_owned());
+        }
+        if !(1..=MAX_LEVEL).contains(&self.level) {
+            problems.push(format!("the level must be from 1 to {}", MAX_LEVEL));
+        }
+        let sizes = 1..=MAX_BUILDING_SIZE;
+        if !sizes.contains(&self.size.0) || !sizes.contains(&self.size.1) {
+            problems.push(format!(
+                "the size must be from 1 to {} tiles each way",
+                MAX_BUILDING_SIZE
+            ));
+        }
+        match self.zone {
+            Zone::Residential if self.residents == 0 || self.jobs > 0 => {
+                problems.push("residential buildings need residents and no jobs".to_owned())
+            }
+            Zone::Commercial | Zone::Industrial if self.jobs == 0 || self.residents > 0 => {
+                problems.push("workplaces need jobs and no residents".to_owned())
+            }
+            _ => {}
+        }
+
+        let amounts = [
+            ("goods_produced", self.goods_produced),
+            ("goods_consumed", self.goods_consumed),
+            ("utilities.power", self.utilities.power),
+            ("utilities.water", self.utilities.water),
+        ];
+        for (name, amount) in amounts.iter() {
+            if !amount.is_finite() || *amount < 0.0 {
+                problems.push(format!("{} must be a number that isn't negative", name));
+            }
+        }
+        if self.cost < 0 || self.upkeep < 0 {
+            problems.push("costs can't be negative".to_owned());
+        }
+        if self
+            .model
+            .as_ref()
+            .is_some_and(|model| model.trim().is_empty())
+        {
+            problems.push("the model is empty".to_owned());
+        }
+        for effect in self.effects.iter() {
+            if effect.radius > MAX_EFFECT_RADIUS {
+                problems.push(format!(
+                    "{:?} reaches further than {} tiles",
+                    effect.service, MAX_EFFECT_RADIUS
+                ));
+            }
+            if !effect.strength.is_finite() {
+                problems.push(format!("{:?} has no strength", effect.service));
+            }
+        }
+
+        problems
+    }
+}
+
+// Every kind of building that can grow
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildingTypes(pub Vec<BuildingType>);
+
+impl BuildingTypes {
+    pub fn get(&self, name: &str) -> Option<&BuildingType> {
+        self.0.iter().find(|kind| kind.name == name)
+    }
+
+    // Read the types from RON files, each holding a list of types. The error
+    // lists every problem found, with the file and type it was found in.
+    pub fn parse<'f, I>(files: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = (&'f str, &'f str)>,
+    {
+        let mut types: Vec<BuildingType> = Vec::new();
+        let mut problems = Vec::new();
+        let mut unreadable = false;
+        for (file, text) in files {
+            let parsed: Vec<BuildingType> = match ron::from_str(text) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    problems.push(format!("{}: {}", file, err));
+                    unreadable = true;
+                    continue;
+                }
+            };
+
+            for kind in parsed {
+                let mut found = kind.problems();
+                if types.iter().any(|other| other.name == kind.name) {
+                    found.push("another building type has the same name".to_owned());
+                }
+                for problem in found {
+                    problems.push(format!("{}: \"{}\": {}", file, kind.name, problem));
+                }
+                types.push(kind);
+            }
+        }
+
+        // Nothing could grow in a zone without a type to start with. Files
+        // that couldn't be read would make this look worse than it is.
+        for &zone in [Zone::Residential, Zone::Commercial, Zone::Industrial].iter() {
+            for &density in [ZoneDensity::Low, ZoneDensity::Medium, ZoneDensity::High].iter() {
+                let starts = types
+                    .iter()
+                    .any(|kind| (kind.zone, kind.density, kind.level) == (zone, density, 1));
+                if !starts && !unreadable {
+                    problems.push(format!(
+                        "there's no level 1 building for {:?} density {:?} zones",
+                        density, zone
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(Self(types))
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+
+    // Read every `.ron` file in the folder
+    pub fn load_dir(directory: &Path) -> Result<Self, String> {
+        let mut files = Vec::new();
+        for path in ron_files(directory)? {
+            let text = fs::read_to_string(&path)
+                .map_err(|err| format!("couldn't read {}: {}", path.display(), err))?;
+            files.push((path.display().to_string(), text));
+        }
+        if files.is_empty() {
+            return Err(format!("{} has no building types", directory.display()));
+        }
+
+        Self::parse(
+            files
+                .iter()
+                .map(|(file, text)| (file.as_str(), text.as_str())),
+        )
+    }
+}
+
+impl Default for BuildingTypes {
+    fn default() -> Self {
+        Self::parse(BUILT_IN_TYPES.iter().cloned()).expect("invalid built-in building types")
+    }
+}
+
+// The `.ron` files in the folder, sorted so types load in the same order
+// everywhere
+fn ron_files(directory: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(directory)
+        .map_err(|err| format!("couldn't read {}: {}", directory.display(), err))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "ron"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// When the files in the folder were last changed, and how many there are so
+// removing one counts as a change
+fn last_change(directory: &Path) -> Option<(SystemTime, usize)> {
+    let files = ron_files(directory).ok()?;
+    let newest = files
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .max()?;
+    Some((newest, files.len()))
+}
+
+// Reads the building types again when their files change, so they can be
+// tweaked while the game runs. Types with problems are reported and the old
+// ones kept. Buildings whose type is gone are torn down.
+pub struct BuildingReloadSystem {
+    directory: PathBuf,
+    changed: Option<(SystemTime, usize)>,
+    checked: Instant,
+}
+
+impl BuildingReloadSystem {
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            changed: last_change(&directory),
+            directory,
+            checked: Instant::now(),
+        }
+    }
+
+    // Returns whether the types were replaced
+    pub fn reload_if_changed(&mut self, types: &mut BuildingTypes) -> bool {
+        let changed = last_change(&self.directory);
+        if changed == self.changed {
+            return false;
+        }
+
+        self.changed = changed;
+        match BuildingTypes::load_dir(&self.directory) {
+            Ok(loaded) => {
+                println!("Reloaded {} building types", loaded.0.len());
+                *types = loaded;
+                true
+            }
+            Err(err) => {
+                println!("Couldn't reload building types:\n{}", err);
+                false
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for BuildingReloadSystem {
+    type SystemData = Write<'a, BuildingTypes>;
+
+    fn run(&mut self, mut types: Self::SystemData) {
+        if self.checked.elapsed() >= RELOAD_INTERVAL {
+            self.checked = Instant::now();
+            self.reload_if_changed(&mut types);
+        }
+    }
+}
+
+// How strongly each service is felt on each tile, from the effects of the
+// buildings around it. Worked out again each day by the building growth.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceLevels {
+    width: u32,
+    levels: HashMap<Service, Vec<f32>>,
+}
+
+impl ServiceLevels {
+    // Add up the effects of the buildings still in use. Each is felt with
+    // its full strength as far as it reaches from the lot.
+    pub fn new<'b, I>(map: &TileMap, types: &BuildingTypes, buildings: I) -> Self
+    where
+        I: IntoIterator<Item = &'b Building>,
+    {
+        let (width, height) = (map.width(), map.height());
+        let mut levels: HashMap<Service, Vec<f32>> = HashMap::new();
+        for building in buildings {
+            let kind = match types.get(&building.kind) {
+                Some(kind) if building.abandoned.is_none() => kind,
+                _ => continue,
+            };
+
+            let (x, y) = building.lot;
+            let (lot_width, lot_height) = (i32::from(building.size.0), i32::from(building.size.1));
+            for effect in kind.effects.iter() {
+                let radius = i32::from(effect.radius);
+                let level = levels
+                    .entry(effect.service)
+                    .or_insert_with(|| vec![0.0; (width * height) as usize]);
+                for (tile_x, tile_y) in map.rect(
+                    (x - radius, y - radius),
+                    (x + lot_width - 1 + radius, y + lot_height - 1 + radius),
+                ) {
+                    level[(tile_y as u32 * width + tile_x as u32) as usize] += effect.strength;
+                }
+            }
+        }
+
+        Self { width, levels }
+    }
+
+    pub fn get(&self, service: Service, (x, y): (i32, i32)) -> f32 {
+        if x < 0 || y < 0 || x as u32 >= self.width {
+            return 0.0;
+        }
+        self.levels
+            .get(&service)
+            .and_then(|level| level.get((y as u32 * self.width + x as u32) as usize))
+            .cloned()
+            .unwrap_or(0.0)
+    }
+}
+
+// How much the land at the tile is worth, from 0 to 1. Water, trees and
+// buildings raising land values nearby raise it, and industry and pollution
+// lower it.
+pub fn land_value(map: &TileMap, services: &ServiceLevels, (x, y): (i32, i32)) -> f32 {
+    let mut water = false;
+    let mut trees = 0;
+    let mut industry = false;
+    let range = LAND_VALUE_RANGE;
+    for pos in map.rect((x - range, y - range), (x + range, y + range)) {
+        let tile = map.get(pos).unwrap();
+        water |= tile.terrain == Terrain::Water;
+        trees += u32::from(tile.forest);
+        industry |= tile.zone == Zone::Industrial;
+    }
+
+    let mut value = 0.3;
+    if water {
+        value += 0.4;
+    }
+    value += (trees as f32 / 255.0 / 20.0).min(0.3);
+    if industry {
+        value -= 0.3;
+    }
+    value += services.get(Service::LandValue, (x, y));
+    value -= services.get(Service::Pollution, (x, y));
+    value.clamp(0.0, 1.0)
+}
+
+// The land value a building needs to reach the level
+pub fn required_land_value(level: u8) -> f32 {
+    f32::from(level.saturating_sub(1)) * 0.3
+}
+
+// Whether a road is close enough to the lot to serve it
+pub fn has_road_access(map: &TileMap, (x, y): (i32, i32), (width, height): (u8, u8)) -> bool {
+    let (width, height) = (i32::from(width), i32::from(height));
+    map.rect(
+        (x - ROAD_REACH, y - ROAD_REACH),
+        (x + width - 1 + ROAD_REACH, y + height - 1 + ROAD_REACH),
+    )
+    .any(|pos| map.get(pos).unwrap().flags.contains(TileFlags::ROAD))
+}
+
+// Where the building stands, at the middle of its lot, and the bounds it's
+// picked with, which cover the lot
+fn placement(building: &Building) -> (Transform, Pickable) {
+    let size = Vector3::new(f32::from(building.size.0), f32::from(building.size.1), 1.0);
+    let corner = Vector3::new(building.lot.0 as f32, building.lot.1 as f32, 0.0);
+    let transform = Transform::new(
+        corner + Vector3::new(size.x / 2.0, size.y / 2.0, 0.0),
+        UnitQuaternion::identity(),
+        Vector3::new(1.0, 1.0, 1.0),
+    );
+    let half = Vector3::new(size.x / 2.0, size.y / 2.0, 0.0);
+    (
+        transform,
+        Pickable::new(-half, half + Vector3::new(0.0, 0.0, size.z)),
+    )
+}
+
+// Whether the building can go up on the lot, which has to be empty and zoned
+// for it
+fn lot_fits(map: &TileMap, (x, y): (i32, i32), kind: &BuildingType) -> bool {
+    let (width, height) = (i32::from(kind.size.0), i32::from(kind.size.1));
+    (y..y + height).all(|y| {
+        (x..x + width).all(|x| {
+            map.get((x, y)).is_some_and(|tile| {
+                tile.zone == kind.zone && tile.density == kind.density && tile.owner.is_none()
+            })
+        })
+    })
+}
+
+// Grows buildings on zoned lots that roads reach while their zone is in
+// demand, moves them up levels, and abandons and eventually tears down the
+// ones without a road or whose zone isn't wanted anymore. Counts the city's
+// people, jobs, goods and utilities once it's done, and charges the upkeep
+// of the buildings in use at the start of each month.
+pub struct BuildingGrowthSystem {
+    rng: Pcg32,
+}
+
+impl Default for BuildingGrowthSystem {
+    fn default() -> Self {
+        Self {
+            rng: Pcg32::seed_from_u64(0),
+        }
+    }
+}
+
+impl BuildingGrowthSystem {
+    // Pick the best of a few random lots for a new building in the zone, and
+    // the largest kind of building that fits on it
+    fn choose_lot<'t>(
+        &mut self,
+        map: &TileMap,
+        services: &ServiceLevels,
+        types: &'t BuildingTypes,
+        lots: &[(i32, i32)],
+    ) -> Option<((i32, i32), &'t BuildingType)> {
+        if lots.is_empty() {
+            return None;
+        }
+
+        let mut best: Option<(f32, (i32, i32), &BuildingType)> = None;
+        for _ in 0..LOT_SAMPLES {
+            let pos = lots[self.rng.gen_range(0, lots.len())];
+            let tile = map.get(pos).unwrap();
+            let fitting: Vec<&BuildingType> = types
+                .0
+                .iter()
+                .filter(|kind| {
+                    kind.level == 1 && (kind.zone, kind.density) == (tile.zone, tile.density)
+                })
+                .filter(|kind| lot_fits(map, pos, kind) && has_road_access(map, pos, kind.size))
+                .collect();
+            let largest = match fitting.iter().map(|kind| kind.area()).max() {
+                Some(area) => area,
+                None => continue,
+            };
+            let fitting: Vec<&BuildingType> = fitting
+                .into_iter()
+                .filter(|kind| kind.area() == largest)
+                .collect();
+            let kind = fitting[self.rng.gen_range(0, fitting.len())];
+
+            let value = land_value(map, services, pos);
+            if best.is_none_or(|(best, _, _)| value > best) {
+                best = Some((value, pos, kind));
+            }
+        }
+
+        best.map(|(_, pos, kind)| (pos, kind))
+    }
+}
+
+impl<'a> System<'a> for BuildingGrowthSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, SimulationTime>,
+        Read<'a, BuildingTypes>,
+        Read<'a, Demand>,
+        Write<'a, Census>,
+        Write<'a, ServiceLevels>,
+        Write<'a, Treasury>,
+        Option<Write<'a, TileMap>>,
+        WriteStorage<'a, Building>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, Pickable>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            types,
+            demand,
+            mut census,
+            mut services,
+            mut treasury,
+            map,
+            mut buildings,
+            mut transforms,
+            mut pickables,
+        ): Self::SystemData,
+    ) {
+        let mut map = match map {
+            Some(map) if time.tick % TICKS_PER_DAY == 0 => map,
+            _ => return,
+        };
+        let day = time.day();
+        let demand = demand.current;
+        *services = ServiceLevels::new(&map, &types, buildings.join());
+
+        let mut demolished: Vec<Entity> = Vec::new();
+        let mut upgrades: Vec<Entity> = Vec::new();
+        for (entity, building) in (&entities, &mut buildings).join() {
+            // Buildings go when their lot is rezoned or built over
+            let kind = match types.get(&building.kind) {
+                Some(kind)
+                    if building.tiles().all(|pos| {
+                        map.get(pos).is_some_and(|tile| {
+                            (tile.zone, tile.density) == (kind.zone, kind.density)
+                        })
+                    }) =>
+                {
+                    kind
+                }
+                _ => {
+                    demolished.push(entity);
+                    continue;
+                }
+            };
+
+            if let Some(since) = building.abandoned {
+                if day >= since + ABANDONED_DAYS {
+                    demolished.push(entity);
+                }
+                continue;
+            }
+
+            // Without a road there are no services, customers or way to get
+            // to work. Otherwise buildings do as well as their zone.
+            let doing = if has_road_access(&map, building.lot, building.size) {
+                demand.get(kind.zone)
+            } else {
+                -1.0
+            };
+            building.condition = (building.condition + doing * CONDITION_CHANGE).clamp(0.0, 1.0);
+            if building.condition <= 0.0 {
+                building.abandoned = Some(day);
+            } else if building.condition >= UPGRADE_CONDITION
+                && doing >= UPGRADE_DEMAND
+                && land_value(&map, &services, building.lot)
+                    >= required_land_value(building.level + 1)
+            {
+                upgrades.push(entity);
+            }
+        }
+
+        for entity in upgrades.into_iter().take(MAX_UPGRADES_PER_DAY) {
+            let building = buildings.get_mut(entity).unwrap();
+            let kind = types.get(&building.kind).unwrap();
+            let better: Vec<&BuildingType> = types
+                .0
+                .iter()
+                .filter(|other| {
+                    (other.zone, other.density, other.size) == (kind.zone, kind.density, kind.size)
+                        && other.level == building.level + 1
+                })
+                .collect();
+            if better.is_empty() {
+                continue;
+            }
+
+            let better = better[self.rng.gen_range(0, better.len())];
+            building.kind = better.name.clone();
+            building.level = better.level;
+            building.condition = STARTING_CONDITION;
+        }
+
+        // Deleted entities stay alive until the world is maintained
+        let demolished: HashSet<Entity> = demolished.into_iter().collect();
+        for &entity in demolished.iter() {
+            for pos in buildings.get(entity).unwrap().tiles() {
+                map.update(pos, |tile| {
+                    if tile.owner == Some(entity) {
+                        tile.owner = None;
+                    }
+                });
+            }
+            entities.delete(entity).unwrap();
+        }
+
+        // The empty zoned tiles, by zone
+        let mut lots: HashMap<Zone, Vec<(i32, i32)>> = HashMap::new();
+        for pos in map.rect((0, 0), (map.width() as i32 - 1, map.height() as i32 - 1)) {
+            let tile = map.get(pos).unwrap();
+            if tile.zone != Zone::Unzoned && tile.owner.is_none() {
+                lots.entry(tile.zone).or_default().push(pos);
+            }
+        }
+
+        for &zone in [Zone::Residential, Zone::Commercial, Zone::Industrial].iter() {
+            let demand = demand.get(zone);
+            if demand < GROWTH_THRESHOLD {
+                continue;
+            }
+
+            let lots = lots.remove(&zone).unwrap_or_default();
+            for _ in 0..(demand * NEW_BUILDINGS_PER_DAY).ceil() as usize {
+                let (lot, kind) = match self.choose_lot(&map, &services, &types, &lots) {
+                    Some(chosen) => chosen,
+                    None => break,
+                };
+
+                let building = Building {
+                    kind: kind.name.clone(),
+                    lot,
+                    size: kind.size,
+                    level: kind.level,
+                    condition: STARTING_CONDITION,
+                    abandoned: None,
+                };
+                let tiles: Vec<(i32, i32)> = building.tiles().collect();
+                let (transform, pickable) = placement(&building);
+                let entity = entities
+                    .build_entity()
+                    .with(building, &mut buildings)
+                    .with(transform, &mut transforms)
+                    .with(pickable, &mut pickables)
+                    .build();
+                for pos in tiles {
+                    map.update(pos, |tile| tile.owner = Some(entity));
+                }
+            }
+        }
+
+        // Count what the buildings that are still lived and worked in hold
+        let mut counted = Census::default();
+        for (entity, building) in (&entities, &buildings).join() {
+            let kind = match types.get(&building.kind) {
+                Some(kind) if building.abandoned.is_none() && !demolished.contains(&entity) => kind,
+                _ => continue,
+            };
+            counted.population += kind.residents;
+            match kind.zone {
+                Zone::Commercial => counted.commercial_jobs += kind.jobs,
+                Zone::Industrial => counted.industrial_jobs += kind.jobs,
+                _ => {}
+            }
+            counted.goods_produced += kind.goods_produced;
+            counted.goods_consumed += kind.goods_consumed;
+            counted.power_used += kind.utilities.power;
+            counted.water_used += kind.utilities.water;
+            counted.upkeep += kind.upkeep;
+        }
+        *census = counted;
+
+        // The city keeps paying for its buildings even when it's in debt
+        if day > 0 && day % DAYS_PER_MONTH == 0 {
+            treasury.pay(census.upkeep);
+        }
+    }
+}
+
+// Adds the buildings. Building types are read from a folder if one is given,
+// and read again whenever they change in debug builds.
+#[derive(Default)]
+pub struct BuildingPlugin {
+    directory: Option<PathBuf>,
+}
+
+impl BuildingPlugin {
+    pub fn from_directory<P: Into<PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: Some(directory.into()),
+        }
+    }
+}
+
+impl Plugin for BuildingPlugin {
+    fn name(&self) -> &'static str {
+        "buildings"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["tile_map", "demand", "economy"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        let types = match &self.directory {
+            Some(directory) => BuildingTypes::load_dir(directory).unwrap_or_else(|err| {
+                println!(
+                    "Couldn't load building types, using the built-in ones:\n{}",
+                    err
+                );
+                BuildingTypes::default()
+            }),
+            None => BuildingTypes::default(),
+        };
+
+        builder
+            .save_component::<Building>("building")
+            .insert_resource(types)
+            .insert_resource(ServiceLevels::default())
+            .add_simulation_system(
+                BuildingGrowthSystem::default(),
+                "building_growth",
+                &["demand"],
+            );
+        if let (Some(directory), true) = (&self.directory, cfg!(debug_assertions)) {
+            builder.add_frame_system(
+                BuildingReloadSystem::new(directory.clone()),
+                "building_reload",
+                &[],
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demand::{DemandPlugin, TaxRates};
+    use crate::economy::{EconomyPlugin, STARTING_FUNDS};
+    use crate::picking::Ray;
+    use crate::plugin::GameWorld;
+    use crate::tilemap::TileMapPlugin;
+    use nalgebra::Point3;
+    use specs::WorldExt;
+
+    // A road along the bottom of the map with a residential zone next to it,
+    // and another zone too far from the road to be reached
+    fn build_world() -> GameWorld {
+        let game = WorldBuilder::new()
+            .with_plugin(TileMapPlugin::new(24, 24))
+            .with_plugin(EconomyPlugin)
+            .with_plugin(DemandPlugin)
+            .with_plugin(BuildingPlugin::default())
+            .build()
+            .unwrap();
+
+        {
+            let mut map = game.world.write_resource::<TileMap>();
+            for x in 0..24 {
+                map.update((x, 0), |tile| tile.flags.insert(TileFlags::ROAD));
+            }
+            for pos in map.rect((0, 1), (7, 2)).chain(map.rect((0, 20), (7, 21))) {
+                map.update(pos, |tile| tile.zone = Zone::Residential);
+            }
+        }
+        game
+    }
+
+    fn run_days(game: &mut GameWorld, days: u64) {
+        for _ in 0..days * TICKS_PER_DAY {
+            game.world.write_resource::<SimulationTime>().tick += 1;
+            game.simulation.dispatch(&game.world);
+            game.world.maintain();
+        }
+    }
+
+    fn buildings(game: &GameWorld) -> Vec<Building> {
+        game.world
+            .read_storage::<Building>()
+            .join()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn buildings_grow_on_zoned_lots_roads_reach() {
+        let mut game = build_world();
+        run_days(&mut game, 10);
+
+        let buildings = buildings(&game);
+        assert!(!buildings.is_empty());
+        let map = game.world.read_resource::<TileMap>();
+        for building in buildings.iter() {
+            assert!(building.lot.1 <= 2);
+            assert!(building
+                .tiles()
+                .all(|pos| map.get(pos).unwrap().owner.is_some()));
+        }
+        assert!(map
+            .rect((0, 20), (7, 21))
+            .all(|pos| map.get(pos).unwrap().owner.is_none()));
+
+        // Buildings can be clicked anywhere on their lots
+        let transforms = game.world.read_storage::<Transform>();
+        let pickables = game.world.read_storage::<Pickable>();
+        for (building, transform, pickable) in (
+            &game.world.read_storage::<Building>(),
+            &transforms,
+            &pickables,
+        )
+            .join()
+        {
+            let (x, y) = building.lot;
+            let (width, height) = (f32::from(building.size.0), f32::from(building.size.1));
+            let ray = Ray {
+                origin: Point3::new(x as f32 + width - 0.1, y as f32 + height - 0.1, 10.0),
+                direction: Vector3::new(0.0, 0.0, -1.0),
+            };
+            let hit = ray.intersect_bounds(&transform.get_object_transform(), pickable);
+            assert_eq!(hit, Some(9.0));
+        }
+        assert_eq!(transforms.count(), buildings.len());
+
+        // Low density houses have room for four people on each tile
+        let tiles: usize = buildings
+            .iter()
+            .map(|building| building.tiles().count())
+            .sum();
+        let census = game.world.read_resource::<Census>();
+        assert_eq!(census.population, tiles as u32 * 4);
+    }
+
+    #[test]
+    fn buildings_level_up_near_water_while_in_demand() {
+        let mut game = build_world();
+        {
+            let mut map = game.world.write_resource::<TileMap>();
+            for pos in map.rect((0, 4), (7, 5)) {
+                map.update(pos, |tile| tile.terrain = Terrain::Water);
+            }
+        }
+        // Without taxes people keep coming however many live there
+        game.world.insert(TaxRates {
+            residential: 0.0,
+            ..TaxRates::default()
+        });
+        run_days(&mut game, 30);
+
+        assert!(buildings(&game).iter().any(|building| building.level > 1));
+    }
+
+    #[test]
+    fn unwanted_buildings_are_abandoned_then_torn_down() {
+        let mut game = build_world();
+        run_days(&mut game, 5);
+        let built = buildings(&game).len();
+        assert!(built > 0);
+
+        // Nobody wants to live in a city with these taxes
+        game.world.insert(TaxRates {
+            residential: 0.5,
+            ..TaxRates::default()
+        });
+        run_days(&mut game, 20);
+        let buildings_now = buildings(&game);
+        assert_eq!(buildings_now.len(), built);
+        assert!(buildings_now
+            .iter()
+            .all(|building| building.abandoned.is_some()));
+        assert_eq!(game.world.read_resource::<Census>().population, 0);
+
+        run_days(&mut game, DAYS_PER_MONTH);
+        assert!(buildings(&game).is_empty());
+        let map = game.world.read_resource::<TileMap>();
+        assert!(map
+            .rect((0, 1), (7, 2))
+            .all(|pos| map.get(pos).unwrap().owner.is_none()));
+    }
+
+    #[test]
+    fn upkeep_is_charged_each_month() {
+        let mut game = build_world();
+        run_days(&mut game, DAYS_PER_MONTH - 1);
+        assert_eq!(game.world.read_resource::<Treasury>().funds, STARTING_FUNDS);
+
+        run_days(&mut game, 1);
+        let upkeep = game.world.read_resource::<Census>().upkeep;
+        assert!(upkeep > 0);
+        assert_eq!(
+            game.world.read_resource::<Treasury>().funds,
+            STARTING_FUNDS - upkeep
+        );
+    }
+
+    #[test]
+    fn buildings_torn_down_are_not_counted_or_charged() {
+        let mut game = build_world();
+        run_days(&mut game, DAYS_PER_MONTH - 1);
+        assert!(!buildings(&game).is_empty());
+
+        // Every building goes on the day upkeep is due
+        {
+            let mut map = game.world.write_resource::<TileMap>();
+            for pos in map.rect((0, 1), (7, 2)) {
+                map.update(pos, |tile| tile.zone = Zone::Unzoned);
+            }
+        }
+        run_days(&mut game, 1);
+        assert!(buildings(&game).is_empty());
+        assert_eq!(*game.world.read_resource::<Census>(), Census::default());
+        assert_eq!(game.world.read_resource::<Treasury>().funds, STARTING_FUNDS);
+    }
+
+    #[test]
+    fn building_effects_change_land_values_around_them() {
+        let map = TileMap::new(16, 16);
+        let types = BuildingTypes::default();
+        let kind = types.get("high commercial 2x2 level 2").unwrap();
+        let mut building = Building {
+            kind: kind.name.clone(),
+            lot: (5, 5),
+            size: kind.size,
+            level: kind.level,
+            condition: STARTING_CONDITION,
+            abandoned: None,
+        };
+        let empty = ServiceLevels::default();
+        let services = ServiceLevels::new(&map, &types, vec![&building]);
+
+        // It reaches three tiles from the lot
+        let raise = land_value(&map, &services, (2, 9)) - land_value(&map, &empty, (2, 9));
+        assert!((raise - 0.1).abs() < 1e-5, "{}", raise);
+        assert_eq!(
+            land_value(&map, &services, (1, 5)),
+            land_value(&map, &empty, (1, 5))
+        );
+        assert_eq!(services.get(Service::Pollution, (5, 5)), 0.0);
+        assert_eq!(services.get(Service::LandValue, (-1, 5)), 0.0);
+
+        // Empty buildings don't do anything for their neighbors
+        building.abandoned = Some(0);
+        let services = ServiceLevels::new(&map, &types, vec![&building]);
+        assert_eq!(services.get(Service::LandValue, (5, 5)), 0.0);
+    }
+
+    const HOUSE: &str = r#"[(
+        name: "house",
+        zone: Residential,
+        density: Low,
+        level: 1,
+        size: (1, 1),
+        residents: 4,
+    )]"#;
+
+    // Starter types for every zone other than low density residential, so
+    // a file with a house in it is complete
+    fn other_starters() -> String {
+        let starters: Vec<BuildingType> = BuildingTypes::default()
+            .0
+            .into_iter()
+            .filter(|kind| kind.level == 1)
+            .filter(|kind| (kind.zone, kind.density) != (Zone::Residential, ZoneDensity::Low))
+            .collect();
+        ron::to_string(&starters).unwrap()
+    }
+
+    #[test]
+    fn the_assets_folder_has_the_built_in_types() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/buildings");
+        let loaded = BuildingTypes::load_dir(&directory).unwrap();
+        assert_eq!(loaded, BuildingTypes::default());
+        assert!(loaded.get("high industrial 3x3 level 3").is_some());
+    }
+
+    #[test]
+    fn problems_are_reported_with_where_they_are() {
+        let others = other_starters();
+        assert!(BuildingTypes::parse(vec![("a.ron", HOUSE), ("b.ron", others.as_str())]).is_ok());
+
+        let err = BuildingTypes::parse(vec![("a.ron", "[(name: 1)]")]).unwrap_err();
+        assert!(err.starts_with("a.ron: 1:"), "{}", err);
+        assert_eq!(err.lines().count(), 1);
+
+        let broken = HOUSE
+            .replace("level: 1", "level: 9")
+            .replace("residents: 4", "jobs: 4");
+        let err =
+            BuildingTypes::parse(vec![("a.ron", broken.as_str()), ("b.ron", &others)]).unwrap_err();
+        assert!(err.contains("a.ron: \"house\": the level must be from 1 to 5"));
+        assert!(err.contains("a.ron: \"house\": residential buildings need residents"));
+        assert!(err.contains("no level 1 building for Low density Residential zones"));
+
+        let err =
+            BuildingTypes::parse(vec![("a.ron", HOUSE), ("b.ron", HOUSE), ("c.ron", &others)])
+                .unwrap_err();
+        assert_eq!(
+            err,
+            "b.ron: \"house\": another building type has the same name"
+        );
+    }
+
+    #[test]
+    fn changed_types_are_reloaded() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("houses.ron");
+        let others = directory.path().join("others.ron");
+        fs::write(&path, HOUSE).unwrap();
+        fs::write(&others, other_starters()).unwrap();
+
+        let mut types = BuildingTypes::load_dir(directory.path()).unwrap();
+        let mut reload = BuildingReloadSystem::new(directory.path().to_owned());
+        assert!(!reload.reload_if_changed(&mut types));
+
+        // File times aren't always precise enough to tell quick writes apart
+        let touch = |text: &str, seconds: u64| {
+            fs::write(&path, text).unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(seconds))
+                .unwrap();
+        };
+        touch(&HOUSE.replace("residents: 4", "residents: 6"), 10);
+        assert!(reload.reload_if_changed(&mut types));
+        assert_eq!(types.get("house").unwrap().residents, 6);
+
+        // Broken files leave the types as they were
+        touch(&HOUSE.replace("residents: 4", "residents: 0"), 20);
+        assert!(!reload.reload_if_changed(&mut types));
+        assert_eq!(types.get("house").unwrap().residents, 6);
+    }
+}
@@ -0,0 +1,365 @@
+use crate::input::InputState;
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::world::{Camera, DeltaTime, MapBounds};
+use glfw::{Key, MouseButton};
+use nalgebra::Vector2;
+use specs::{Join, Read, System, WriteStorage};
+
+// Moves cameras around with the keyboard and mouse:
+// - WASD or the arrow keys pan
+// - moving the cursor to the edge of the window pans
+// - dragging with the right or middle mouse button pans
+// - the scroll wheel zooms towards the cursor
+// - Q and E rotate the view and V toggles the isometric view
+pub struct CameraController {
+    // Panning speed in view heights per second
+    pub pan_speed: f32,
+    // Distance in pixels from the edge of the window that starts scrolling
+    pub edge_scroll_margin: f64,
+    // How much one step of the scroll wheel multiplies the zoom by
+    pub zoom_step: f32,
+    pub min_zoom: f32,
+    pub max_zoom: f32,
+    // How quickly the camera catches up to its targets, higher is faster
+    pub smoothing: f32,
+}
+
+impl Default for CameraController {
+    fn default() -> Self {
+        Self {
+            pan_speed: 1.0,
+            edge_scroll_margin: 8.0,
+            zoom_step: 1.15,
+            min_zoom: 0.1,
+            max_zoom: 8.0,
+            smoothing: 12.0,
+        }
+    }
+}
+
+impl CameraController {
+    fn pan_direction(&self, input: &InputState) -> Vector2<f32> {
+        let mut pan = Vector2::new(0.0, 0.0);
+
+        if input.is_key_down(Key::W) || input.is_key_down(Key::Up) {
+            pan.y += 1.0;
+        }
+        if input.is_key_down(Key::S) || input.is_key_down(Key::Down) {
+            pan.y -= 1.0;
+        }
+        if input.is_key_down(Key::D) || input.is_key_down(Key::Right) {
+            pan.x += 1.0;
+        }
+        if input.is_key_down(Key::A) || input.is_key_down(Key::Left) {
+            pan.x -= 1.0;
+        }
+
+        // Scroll when the cursor is at the edge of the window, but not while
+        // it's dragging the view around
+        let dragging = input.is_button_down(MouseButton::Button2)
+            || input.is_button_down(MouseButton::Button3);
+        if let (Some((x, y)), true) = (
+            input.cursor(),
+            input.is_focused() && input.is_cursor_in_window() && !dragging,
+        ) {
+            let (width, height) = input.window_size();
+            let margin = self.edge_scroll_margin;
+            if x < margin {
+                pan.x -= 1.0;
+            } else if x > f64::from(width) - margin {
+                pan.x += 1.0;
+            }
+            if y < margin {
+                pan.y += 1.0;
+            } else if y > f64::from(height) - margin {
+                pan.y -= 1.0;
+            }
+        }
+
+        if pan.x != 0.0 || pan.y != 0.0 {
+            pan.normalize()
+        } else {
+            pan
+        }
+    }
+}
+
+impl<'a> System<'a> for CameraController {
+    type SystemData = (
+        Read<'a, InputState>,
+        Read<'a, DeltaTime>,
+        Option<Read<'a, MapBounds>>,
+        WriteStorage<'a, Camera>,
+    );
+
+    fn run(&mut self, (input, delta, bounds, mut cameras): Self::SystemData) {
+        let delta = delta.0;
+        let window_size = input.window_size();
+        let aspect = input.aspect_ratio();
+
+        for camera in (&mut cameras).join() {
+            // Rotation
+            if input.was_key_pressed(Key::Q) {
+                camera.rotation_steps = (camera.rotation_steps + 3) % 4;
+            }
+            if input.was_key_pressed(Key::E) {
+                camera.rotation_steps = (camera.rotation_steps + 1) % 4;
+            }
+            if input.was_key_pressed(Key::V) {
+                camera.isometric = !camera.isometric;
+            }
+
+            // Keyboard and edge panning, scaled by the size of the view so it
+            // feels the same at every zoom level
+            let pan = self.pan_direction(&input);
+            if pan.x != 0.0 || pan.y != 0.0 {
+                let speed = camera.half_extents(aspect).y * 2.0 * self.pan_speed;
+                camera.target_position += camera.screen_to_ground_direction(pan) * speed * delta;
+            }
+
+            // Dragging keeps the point that was grabbed under the cursor, so
+            // it skips the smoothing
+            let (dx, dy) = input.cursor_delta();
+            let dragging = input.is_button_down(MouseButton::Button2)
+                || input.is_button_down(MouseButton::Button3);
+            if let (Some((x, y)), true) = (input.cursor(), dragging && (dx != 0.0 || dy != 0.0)) {
+                let grabbed = camera.screen_to_ground((x - dx, y - dy), window_size);
+                let current = camera.screen_to_ground((x, y), window_size);
+                if let (Some(grabbed), Some(current)) = (grabbed, current) {
+                    let offset = grabbed - current;
+                    camera.position += offset;
+                    camera.target_position += offset;
+                }
+            }
+
+            // Zoom towards the cursor by keeping the point under it in place
+            // relative to where the camera is heading
+            let scroll = input.scroll() as f32;
+            if scroll != 0.0 {
+                let old_zoom = camera.target_zoom;
+                let new_zoom = (old_zoom * self.zoom_step.powf(scroll))
+                    .max(self.min_zoom)
+                    .min(self.max_zoom);
+
+                let mut target = camera.clone();
+                target.position = camera.target_position;
+                target.zoom = camera.target_zoom;
+                if let Some(anchor) = input
+                    .cursor()
+                    .and_then(|cursor| target.screen_to_ground(cursor, window_size))
+                {
+                    camera.target_position =
+                        anchor + (camera.target_position - anchor) * (old_zoom / new_zoom);
+                }
+                camera.target_zoom = new_zoom;
+            }
+
+            // Keep the camera over the map
+            if let Some(bounds) = &bounds {
+                camera.target_position = bounds.clamp(camera.target_position);
+                camera.position = bounds.clamp(camera.position);
+            }
+
+            // Move towards the targets, framerate independently
+            let t = 1.0 - (-self.smoothing * delta).exp();
+            camera.position += (camera.target_position - camera.position) * t;
+            camera.zoom += (camera.target_zoom - camera.zoom) * t;
+        }
+    }
+}
+
+pub struct CameraPlugin;
+
+impl Plugin for CameraPlugin {
+    fn name(&self) -> &'static str {
+        "camera"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["input"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .save_component::<Camera>("camera")
+            .save_resource::<MapBounds>("map_bounds")
+            .add_frame_system(CameraController::default(), "camera_controller", &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glfw::{Action, Modifiers, WindowEvent};
+    use specs::{Builder, RunNow, World, WorldExt};
+
+    const WINDOW_SIZE: (i32, i32) = (800, 600);
+
+    fn key(key: Key) -> WindowEvent {
+        WindowEvent::Key(key, 0, Action::Press, Modifiers::empty())
+    }
+
+    fn button(button: MouseButton, action: Action) -> WindowEvent {
+        WindowEvent::MouseButton(button, action, Modifiers::empty())
+    }
+
+    fn build_world(camera: Camera) -> World {
+        let mut world = World::new();
+        world.register::<Camera>();
+        world.insert(InputState::new(WINDOW_SIZE));
+        world.insert(DeltaTime(0.0));
+        world.create_entity().with(camera).build();
+        world
+    }
+
+    // Run the controller for a frame in which the events happened
+    fn frame(world: &mut World, events: &[WindowEvent], delta: f32) -> Camera {
+        {
+            let mut input = world.write_resource::<InputState>();
+            input.end_frame();
+            for event in events {
+                input.handle_event(event);
+            }
+        }
+        world.insert(DeltaTime(delta));
+        CameraController::default().run_now(world);
+        world
+            .read_storage::<Camera>()
+            .join()
+            .next()
+            .unwrap()
+            .clone()
+    }
+
+    // Where the camera will end up once it catches up to its targets
+    fn settled(camera: &Camera) -> Camera {
+        let mut settled = camera.clone();
+        settled.position = camera.target_position;
+        settled.zoom = camera.target_zoom;
+        settled
+    }
+
+    #[test]
+    fn zooming_keeps_the_point_under_the_cursor() {
+        let camera = Camera::new(Vector2::new(10.0, 10.0), 1.0);
+        let cursor = (600.0, 200.0);
+        let anchor = camera.screen_to_ground(cursor, WINDOW_SIZE).unwrap();
+        let mut world = build_world(camera);
+
+        let zoomed = frame(
+            &mut world,
+            &[
+                WindowEvent::CursorPos(cursor.0, cursor.1),
+                WindowEvent::Scroll(0.0, 2.0),
+            ],
+            0.0,
+        );
+        assert!((zoomed.target_zoom - 1.15 * 1.15).abs() < 1e-5);
+        let point = settled(&zoomed)
+            .screen_to_ground(cursor, WINDOW_SIZE)
+            .unwrap();
+        assert!((point - anchor).norm() < 1e-3, "{}", point);
+
+        // The zoom stops at its limits
+        let zoomed = frame(&mut world, &[WindowEvent::Scroll(0.0, 100.0)], 0.0);
+        assert_eq!(zoomed.target_zoom, CameraController::default().max_zoom);
+        let zoomed = frame(&mut world, &[WindowEvent::Scroll(0.0, -100.0)], 0.0);
+        assert_eq!(zoomed.target_zoom, CameraController::default().min_zoom);
+    }
+
+    #[test]
+    fn the_camera_stays_over_the_map() {
+        let mut camera = Camera::new(Vector2::new(20.0, 20.0), 1.0);
+        camera.target_position = Vector2::new(50.0, -3.0);
+        let mut world = build_world(camera);
+        world.insert(MapBounds::new(
+            Vector2::new(0.0, 0.0),
+            Vector2::new(10.0, 10.0),
+        ));
+
+        let camera = frame(&mut world, &[], 0.0);
+        assert_eq!(camera.target_position, Vector2::new(10.0, 0.0));
+        assert_eq!(camera.position, Vector2::new(10.0, 10.0));
+    }
+
+    #[test]
+    fn dragging_keeps_the_grabbed_point_under_the_cursor() {
+        let camera = Camera::new(Vector2::new(10.0, 10.0), 1.0);
+        let grabbed = camera
+            .screen_to_ground((400.0, 300.0), WINDOW_SIZE)
+            .unwrap();
+        let mut world = build_world(camera);
+
+        frame(
+            &mut world,
+            &[
+                WindowEvent::CursorPos(400.0, 300.0),
+                button(MouseButton::Button2, Action::Press),
+            ],
+            0.0,
+        );
+        let camera = frame(&mut world, &[WindowEvent::CursorPos(550.0, 380.0)], 0.0);
+        let point = camera
+            .screen_to_ground((550.0, 380.0), WINDOW_SIZE)
+            .unwrap();
+        assert!((point - grabbed).norm() < 1e-3, "{}", point);
+        assert_eq!(camera.position, camera.target_position);
+
+        // Moving the cursor after letting go doesn't drag any more
+        frame(
+            &mut world,
+            &[button(MouseButton::Button2, Action::Release)],
+            0.0,
+        );
+        let after = frame(&mut world, &[WindowEvent::CursorPos(300.0, 300.0)], 0.0);
+        assert_eq!(after.position, camera.position);
+    }
+
+    #[test]
+    fn keys_rotate_and_tilt_the_view() {
+        let mut world = build_world(Camera::new(Vector2::new(0.0, 0.0), 1.0));
+
+        assert_eq!(frame(&mut world, &[key(Key::Q)], 0.0).rotation_steps, 3);
+        frame(&mut world, &[key(Key::E)], 0.0);
+        assert_eq!(frame(&mut world, &[key(Key::E)], 0.0).rotation_steps, 1);
+        // Keys held down don't keep rotating
+        assert_eq!(frame(&mut world, &[], 0.0).rotation_steps, 1);
+
+        assert!(frame(&mut world, &[key(Key::V)], 0.0).isometric);
+        assert!(!frame(&mut world, &[key(Key::V)], 0.0).isometric);
+    }
+
+    #[test]
+    fn the_camera_catches_up_to_its_targets() {
+        let mut camera = Camera::new(Vector2::new(0.0, 0.0), 1.0);
+        camera.target_position = Vector2::new(4.0, 0.0);
+        camera.target_zoom = 2.0;
+        let mut world = build_world(camera);
+
+        let camera = frame(&mut world, &[], 1.0 / 60.0);
+        let t = 1.0 - (-CameraController::default().smoothing / 60.0).exp();
+        assert!((camera.position.x - 4.0 * t).abs() < 1e-5);
+        assert!((camera.zoom - (1.0 + t)).abs() < 1e-5);
+
+        let mut camera = camera;
+        for _ in 0..59 {
+            camera = frame(&mut world, &[], 1.0 / 60.0);
+        }
+        assert!((camera.position - Vector2::new(4.0, 0.0)).norm() < 1e-3);
+        assert!((camera.zoom - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn the_cursor_at_the_edge_of_the_window_pans() {
+        // The window doesn't say the cursor entered it when it starts out
+        // inside
+        let mut world = build_world(Camera::new(Vector2::new(10.0, 10.0), 1.0));
+        let camera = frame(&mut world, &[WindowEvent::CursorPos(2.0, 300.0)], 0.1);
+        assert!(camera.target_position.x < 10.0);
+        assert_eq!(camera.target_position.y, 10.0);
+
+        // It stops once the cursor leaves the window
+        let left = frame(&mut world, &[WindowEvent::CursorEnter(false)], 0.1);
+        assert_eq!(left.target_position, camera.target_position);
+    }
+}
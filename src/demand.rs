@@ -0,0 +1,326 @@
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::tilemap::{TileFlags, TileMap, Zone};
+use crate::world::{SimulationTime, TICKS_PER_DAY};
+use serde::{Deserialize, Serialize};
+use specs::{Read, System, Write};
+use std::collections::VecDeque;
+
+// The share of residents who work
+pub const WORKING_SHARE: f32 = 0.6;
+
+// How many shop jobs each resident keeps busy
+pub const COMMERCIAL_JOBS_PER_RESIDENT: f32 = 0.15;
+
+// People who move into a new city before there are any jobs for them. The
+// pull fades as the city grows past it.
+const STARTING_POPULATION: f32 = 200.0;
+
+// Differences smaller than this many people, jobs or goods don't swing the
+// demand all the way, so tiny cities don't jump between extremes
+const MIN_SCALE: f32 = 50.0;
+
+// Taxes at this rate don't change demand. Each point above or below lowers
+// or raises it.
+pub const NEUTRAL_TAX_RATE: f32 = 0.09;
+const TAX_SENSITIVITY: f32 = 6.0;
+
+// What the outside world buys from industry and sells to shops each day,
+// once a road leads off the map
+const EXPORT_GOODS: f32 = 100.0;
+const IMPORT_GOODS: f32 = 100.0;
+
+// How far the demand moves towards its target each day
+const DEMAND_SMOOTHING: f32 = 0.2;
+
+// Days of demand kept for the demand bars
+pub const DEMAND_HISTORY_DAYS: usize = 60;
+
+// The people, jobs and goods in the city, counted by the buildings
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Census {
+    pub population: u32,
+    pub commercial_jobs: u32,
+    pub industrial_jobs: u32,
+    // Goods made by industry and sold by shops each day
+    pub goods_produced: f32,
+    pub goods_consumed: f32,
+    // Power and water used each day
+    pub power_used: f32,
+    pub water_used: f32,
+    // What the buildings cost to keep up each month
+    pub upkeep: i64,
+}
+
+impl Census {
+    pub fn workers(&self) -> f32 {
+        self.population as f32 * WORKING_SHARE
+    }
+
+    pub fn jobs(&self) -> u32 {
+        self.commercial_jobs + self.industrial_jobs
+    }
+}
+
+// The share of income each zone pays in taxes
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaxRates {
+    pub residential: f32,
+    pub commercial: f32,
+    pub industrial: f32,
+}
+
+impl Default for TaxRates {
+    fn default() -> Self {
+        Self {
+            residential: NEUTRAL_TAX_RATE,
+            commercial: NEUTRAL_TAX_RATE,
+            industrial: NEUTRAL_TAX_RATE,
+        }
+    }
+}
+
+// How much each zone wants to grow, from -1 where buildings are leaving to 1
+// where they're needed as fast as they can be built
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ZoneDemand {
+    pub residential: f32,
+    pub commercial: f32,
+    pub industrial: f32,
+}
+
+impl ZoneDemand {
+    pub fn get(&self, zone: Zone) -> f32 {
+        match zone {
+            Zone::Unzoned => 0.0,
+            Zone::Residential => self.residential,
+            Zone::Commercial => self.commercial,
+            Zone::Industrial => self.industrial,
+        }
+    }
+
+    // Move part of the way to the other demand
+    fn approach(&mut self, target: &ZoneDemand, fraction: f32) {
+        self.residential += (target.residential - self.residential) * fraction;
+        self.commercial += (target.commercial - self.commercial) * fraction;
+        self.industrial += (target.industrial - self.industrial) * fraction;
+    }
+}
+
+// How far short of what's wanted there is, from -1 when there's far too
+// much to 1 when there's none at all
+fn shortage(wanted: f32, have: f32) -> f32 {
+    ((wanted - have) / wanted.max(have).max(MIN_SCALE)).clamp(-1.0, 1.0)
+}
+
+// The demand the city is heading towards. Residents come for jobs, shops
+// come for customers and goods to sell, and industry comes for workers and
+// buyers for its goods. Outside connections let industry export and shops
+// import, and taxes above the neutral rate keep everyone away.
+pub fn target_demand(census: &Census, taxes: &TaxRates, connected: bool) -> ZoneDemand {
+    let population = census.population as f32;
+    let workers = census.workers();
+    let commercial_jobs = census.commercial_jobs as f32;
+    let industrial_jobs = census.industrial_jobs as f32;
+    let (exports, imports) = if connected {
+        (EXPORT_GOODS, IMPORT_GOODS)
+    } else {
+        (0.0, 0.0)
+    };
+
+    let residential = shortage(
+        census.jobs() as f32 / WORKING_SHARE + (STARTING_POPULATION - population).max(0.0),
+        population,
+    );
+
+    // Shops can't sell goods nobody makes or imports
+    let goods_supplied = census.goods_produced + imports;
+    let commercial = shortage(population * COMMERCIAL_JOBS_PER_RESIDENT, commercial_jobs)
+        .min(shortage(goods_supplied, census.goods_consumed) + 1.0);
+
+    // Industry takes the workers shops don't, and makes what shops and the
+    // outside world will buy
+    let industrial = (shortage((workers - commercial_jobs).max(0.0), industrial_jobs)
+        + shortage(census.goods_consumed + exports, census.goods_produced))
+        / 2.0;
+
+    let tax_effect = |rate: f32| (NEUTRAL_TAX_RATE - rate) * TAX_SENSITIVITY;
+    ZoneDemand {
+        residential: (residential + tax_effect(taxes.residential)).clamp(-1.0, 1.0),
+        commercial: (commercial + tax_effect(taxes.commercial)).clamp(-1.0, 1.0),
+        industrial: (industrial + tax_effect(taxes.industrial)).clamp(-1.0, 1.0),
+    }
+}
+
+// Whether a road leads off the edge of the map to the outside world
+pub fn has_outside_connection(map: &TileMap) -> bool {
+    let (right, top) = (map.width() as i32 - 1, map.height() as i32 - 1);
+    let is_road = |pos| {
+        map.get(pos)
+            .is_some_and(|tile| tile.flags.contains(TileFlags::ROAD))
+    };
+    (0..=right).any(|x| is_road((x, 0)) || is_road((x, top)))
+        || (0..=top).any(|y| is_road((0, y)) || is_road((right, y)))
+}
+
+// The demand for each zone, which moves slowly towards the target so the
+// city doesn't lurch between booms and busts, and what it was on each of the
+// last few days
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Demand {
+    pub current: ZoneDemand,
+    pub history: VecDeque<ZoneDemand>,
+}
+
+impl Demand {
+    pub fn update(&mut self, target: &ZoneDemand) {
+        self.current.approach(target, DEMAND_SMOOTHING);
+        self.history.push_back(self.current);
+        while self.history.len() > DEMAND_HISTORY_DAYS {
+            self.history.pop_front();
+        }
+    }
+}
+
+// Updates the demand once a day
+#[derive(Default)]
+pub struct DemandSystem;
+
+impl<'a> System<'a> for DemandSystem {
+    type SystemData = (
+        Read<'a, SimulationTime>,
+        Read<'a, Census>,
+        Read<'a, TaxRates>,
+        Option<Read<'a, TileMap>>,
+        Write<'a, Demand>,
+    );
+
+    fn run(&mut self, (time, census, taxes, map, mut demand): Self::SystemData) {
+        if time.tick % TICKS_PER_DAY != 0 {
+            return;
+        }
+
+        let connected = map.is_some_and(|map| has_outside_connection(&map));
+        demand.update(&target_demand(&census, &taxes, connected));
+    }
+}
+
+pub struct DemandPlugin;
+
+impl Plugin for DemandPlugin {
+    fn name(&self) -> &'static str {
+        "demand"
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .insert_resource(Census::default())
+            .insert_resource(TaxRates::default())
+            .insert_resource(Demand::default())
+            .save_resource::<TaxRates>("tax_rates")
+            .save_resource::<Demand>("demand")
+            .add_simulation_system(DemandSystem, "demand", &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A city where everyone who wants a job has one and shops sell exactly
+    // what industry makes
+    fn balanced() -> Census {
+        Census {
+            population: 1000,
+            commercial_jobs: 150,
+            industrial_jobs: 450,
+            goods_produced: 300.0,
+            goods_consumed: 300.0,
+            ..Census::default()
+        }
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() < 0.01,
+            "expected {}, found {}",
+            expected,
+            value
+        );
+    }
+
+    #[test]
+    fn new_cities_want_residents_first() {
+        let demand = target_demand(&Census::default(), &TaxRates::default(), false);
+        assert_near(demand.residential, 1.0);
+        assert_near(demand.commercial, 0.0);
+        assert_near(demand.industrial, 0.0);
+
+        // Exporting gives industry a reason to come too
+        let demand = target_demand(&Census::default(), &TaxRates::default(), true);
+        assert!(demand.industrial > 0.4);
+    }
+
+    #[test]
+    fn balanced_cities_have_no_demand() {
+        let demand = target_demand(&balanced(), &TaxRates::default(), false);
+        assert_near(demand.residential, 0.0);
+        assert_near(demand.commercial, 0.0);
+        assert_near(demand.industrial, 0.0);
+    }
+
+    #[test]
+    fn imbalances_create_demand_for_what_is_missing() {
+        // Jobs nobody fills bring residents, and idle workers want industry
+        let mut census = balanced();
+        census.industrial_jobs = 900;
+        assert!(target_demand(&census, &TaxRates::default(), false).residential > 0.3);
+
+        let mut census = balanced();
+        census.population = 2000;
+        let demand = target_demand(&census, &TaxRates::default(), false);
+        assert!(demand.residential < -0.3);
+        assert!(demand.commercial > 0.3);
+        assert!(demand.industrial > 0.2);
+
+        // Shops without goods to sell don't want to grow until they're
+        // imported
+        let mut census = balanced();
+        census.population = 2000;
+        census.goods_produced = 0.0;
+        assert!(target_demand(&census, &TaxRates::default(), false).commercial <= 0.0);
+        assert!(target_demand(&census, &TaxRates::default(), true).commercial > 0.0);
+    }
+
+    #[test]
+    fn high_taxes_lower_demand() {
+        let taxes = TaxRates {
+            residential: 0.2,
+            commercial: NEUTRAL_TAX_RATE,
+            industrial: 0.0,
+        };
+        let demand = target_demand(&balanced(), &taxes, false);
+        assert!(demand.residential < -0.5);
+        assert_near(demand.commercial, 0.0);
+        assert!(demand.industrial > 0.5);
+    }
+
+    #[test]
+    fn demand_moves_slowly_and_keeps_history() {
+        let mut demand = Demand::default();
+        let target = ZoneDemand {
+            residential: 1.0,
+            commercial: -1.0,
+            industrial: 0.5,
+        };
+        demand.update(&target);
+        assert_near(demand.current.residential, DEMAND_SMOOTHING);
+        assert_near(demand.current.commercial, -DEMAND_SMOOTHING);
+
+        for _ in 0..DEMAND_HISTORY_DAYS * 2 {
+            demand.update(&target);
+        }
+        assert_eq!(demand.history.len(), DEMAND_HISTORY_DAYS);
+        assert_near(demand.current.industrial, 0.5);
+        assert_eq!(*demand.history.back().unwrap(), demand.current);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::plugin::{Plugin, WorldBuilder};
+use serde::{Deserialize, Serialize};
+
+// Money the city starts with
+pub const STARTING_FUNDS: i64 = 50_000;
+
+// The city's money
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Treasury {
+    pub funds: i64,
+}
+
+impl Treasury {
+    pub fn new(funds: i64) -> Self {
+        Self { funds }
+    }
+
+    pub fn can_afford(&self, cost: i64) -> bool {
+        self.funds >= cost
+    }
+
+    // Take the money if there's enough of it
+    pub fn spend(&mut self, cost: i64) -> bool {
+        if self.can_afford(cost) {
+            self.funds -= cost;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn earn(&mut self, amount: i64) {
+        self.funds += amount;
+    }
+
+    // Take the money even if there isn't enough, leaving the city in debt
+    pub fn pay(&mut self, cost: i64) {
+        self.funds -= cost;
+    }
+}
+
+impl Default for Treasury {
+    fn default() -> Self {
+        Self::new(STARTING_FUNDS)
+    }
+}
+
+pub struct EconomyPlugin;
+
+impl Plugin for EconomyPlugin {
+    fn name(&self) -> &'static str {
+        "economy"
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .insert_resource(Treasury::default())
+            .save_resource::<Treasury>("treasury");
+    }
+}
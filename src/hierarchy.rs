@@ -0,0 +1,302 @@
+use crate::world::{Children, GlobalTransform, Parent, Transform};
+use nalgebra::Matrix4;
+use specs::storage::ComponentEvent;
+use specs::{
+    BitSet, Entities, Entity, Join, ReadStorage, ReaderId, System, World, WorldExt, WriteStorage,
+};
+
+// Hierarchies deeper than this are assumed to contain a cycle
+const MAX_DEPTH: usize = 64;
+
+// Computes each entity's `GlobalTransform` from its `Transform` and those of
+// its parents. Only entities whose transform or parent changed since the
+// last run are recomputed, along with everything attached to them.
+// Parents need a `Transform` to pass their position on to their children,
+// otherwise the children are positioned relative to the world.
+pub struct TransformHierarchy {
+    transform_events: ReaderId<ComponentEvent>,
+    parent_events: ReaderId<ComponentEvent>,
+    // Components added before the readers were registered weren't seen, so
+    // everything is computed on the first run
+    first_run: bool,
+}
+
+impl TransformHierarchy {
+    pub fn new(world: &mut World) -> Self {
+        let transform_events = world.write_storage::<Transform>().register_reader();
+        let parent_events = world.write_storage::<Parent>().register_reader();
+
+        Self {
+            transform_events,
+            parent_events,
+            first_run: true,
+        }
+    }
+}
+
+impl<'a> System<'a> for TransformHierarchy {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Transform>,
+        WriteStorage<'a, Parent>,
+        WriteStorage<'a, Children>,
+        WriteStorage<'a, GlobalTransform>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, transforms, mut parents, mut children, mut globals): Self::SystemData,
+    ) {
+        let mut dirty = BitSet::new();
+        let mut hierarchy_changed = self.first_run;
+        let mut removed_any = false;
+
+        if self.first_run {
+            for (entity, _) in (&entities, &transforms).join() {
+                dirty.add(entity.id());
+            }
+            self.first_run = false;
+        }
+
+        for event in transforms.channel().read(&mut self.transform_events) {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    dirty.add(*id);
+                }
+                ComponentEvent::Removed(id) => {
+                    dirty.add(*id);
+                    removed_any = true;
+                }
+            }
+        }
+        for event in parents.channel().read(&mut self.parent_events) {
+            match event {
+                ComponentEvent::Inserted(id)
+                | ComponentEvent::Modified(id)
+                | ComponentEvent::Removed(id) => {
+                    dirty.add(*id);
+                }
+            }
+            hierarchy_changed = true;
+        }
+
+        // Detach entities whose parent was deleted, they stay where they
+        // were relative to the world
+        if removed_any || hierarchy_changed {
+            let orphans: Vec<Entity> = (&entities, &parents)
+                .join()
+                .filter(|(_, parent)| !entities.is_alive(parent.0))
+                .map(|(entity, _)| entity)
+                .collect();
+            for orphan in orphans {
+                parents.remove(orphan);
+                dirty.add(orphan.id());
+                hierarchy_changed = true;
+            }
+
+            // Skip the removals that were just made
+            parents.channel().read(&mut self.parent_events).count();
+        }
+
+        // The children lists are only rebuilt when entities are attached or
+        // detached, which is rare compared to things moving
+        if hierarchy_changed {
+            children.clear();
+            for (entity, parent) in (&entities, &parents).join() {
+                match children.get_mut(parent.0) {
+                    Some(list) => list.0.push(entity),
+                    None => {
+                        let _ = children.insert(parent.0, Children(vec![entity]));
+                    }
+                }
+            }
+        }
+
+        // Start from the dirty entities without dirty ancestors so every
+        // entity is only computed once, after its parent
+        let mut stack = Vec::new();
+        for id in (&dirty).join() {
+            let entity = entities.entity(id);
+            if !entities.is_alive(entity) {
+                continue;
+            }
+
+            let mut ancestor = parents.get(entity).map(|parent| parent.0);
+            let mut has_dirty_ancestor = false;
+            let mut depth = 0;
+            while let Some(current) = ancestor {
+                if dirty.contains(current.id()) || depth > MAX_DEPTH {
+                    has_dirty_ancestor = true;
+                    break;
+                }
+                ancestor = parents.get(current).map(|parent| parent.0);
+                depth += 1;
+            }
+            if has_dirty_ancestor {
+                continue;
+            }
+
+            let parent_transform = parents
+                .get(entity)
+                .and_then(|parent| globals.get(parent.0))
+                .map_or_else(Matrix4::identity, |global| global.0);
+            stack.push((entity, parent_transform, 0));
+        }
+
+        while let Some((entity, parent_transform, depth)) = stack.pop() {
+            if depth > MAX_DEPTH {
+                println!("Transform hierarchy is too deep or contains a cycle");
+                continue;
+            }
+
+            let global = match transforms.get(entity) {
+                Some(transform) => {
+                    let global = parent_transform * transform.get_object_transform();
+                    let _ = globals.insert(entity, GlobalTransform(global));
+                    global
+                }
+                None => {
+                    globals.remove(entity);
+                    Matrix4::identity()
+                }
+            };
+
+            if let Some(list) = children.get(entity) {
+                for child in list.0.iter() {
+                    stack.push((*child, global, depth + 1));
+                }
+            }
+        }
+    }
+}
+
+// Delete an entity along with everything attached to it
+pub fn delete_with_children(world: &World, entity: Entity) {
+    let entities = world.entities();
+    let children = world.read_storage::<Children>();
+
+    let mut stack = vec![entity];
+    while let Some(current) = stack.pop() {
+        if let Some(list) = children.get(current) {
+            stack.extend(list.0.iter().copied());
+        }
+        let _ = entities.delete(current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{UnitQuaternion, Vector3};
+    use specs::{Builder, RunNow};
+
+    fn setup() -> (World, TransformHierarchy) {
+        let mut world = World::new();
+        world.register::<Transform>();
+        world.register::<Parent>();
+        world.register::<Children>();
+        world.register::<GlobalTransform>();
+        let hierarchy = TransformHierarchy::new(&mut world);
+        (world, hierarchy)
+    }
+
+    fn run(world: &mut World, hierarchy: &mut TransformHierarchy) {
+        hierarchy.run_now(world);
+        world.maintain();
+    }
+
+    fn at(x: f32) -> Transform {
+        Transform::new(
+            Vector3::new(x, 0.0, 0.0),
+            UnitQuaternion::identity(),
+            Vector3::new(1.0, 1.0, 1.0),
+        )
+    }
+
+    fn spawn(world: &mut World, x: f32, parent: Option<Entity>) -> Entity {
+        let builder = world.create_entity().with(at(x));
+        match parent {
+            Some(parent) => builder.with(Parent(parent)).build(),
+            None => builder.build(),
+        }
+    }
+
+    fn global_x(world: &World, entity: Entity) -> Option<f32> {
+        world
+            .read_storage::<GlobalTransform>()
+            .get(entity)
+            .map(|global| global.0[(0, 3)])
+    }
+
+    #[test]
+    fn changes_are_passed_down_to_children() {
+        let (mut world, mut hierarchy) = setup();
+        // Created before the hierarchy saw anything, so the first run has
+        // to find it
+        let parent = spawn(&mut world, 1.0, None);
+        run(&mut world, &mut hierarchy);
+        let child = spawn(&mut world, 2.0, Some(parent));
+        let grandchild = spawn(&mut world, 3.0, Some(child));
+        run(&mut world, &mut hierarchy);
+        assert_eq!(global_x(&world, parent), Some(1.0));
+        assert_eq!(global_x(&world, child), Some(3.0));
+        assert_eq!(global_x(&world, grandchild), Some(6.0));
+
+        *world.write_storage::<Transform>().get_mut(parent).unwrap() = at(10.0);
+        run(&mut world, &mut hierarchy);
+        assert_eq!(global_x(&world, grandchild), Some(15.0));
+
+        // Detached entities are positioned relative to the world
+        world.write_storage::<Parent>().remove(child);
+        run(&mut world, &mut hierarchy);
+        assert_eq!(global_x(&world, child), Some(2.0));
+        assert_eq!(global_x(&world, grandchild), Some(5.0));
+        assert!(world.read_storage::<Children>().get(parent).is_none());
+
+        world.write_storage::<Transform>().remove(child);
+        run(&mut world, &mut hierarchy);
+        assert_eq!(global_x(&world, child), None);
+        assert_eq!(global_x(&world, grandchild), Some(3.0));
+    }
+
+    #[test]
+    fn children_of_deleted_parents_are_detached() {
+        let (mut world, mut hierarchy) = setup();
+        let parent = spawn(&mut world, 1.0, None);
+        let child = spawn(&mut world, 2.0, Some(parent));
+        run(&mut world, &mut hierarchy);
+
+        world.delete_entity(parent).unwrap();
+        run(&mut world, &mut hierarchy);
+        assert!(world.read_storage::<Parent>().get(child).is_none());
+        assert_eq!(global_x(&world, child), Some(2.0));
+    }
+
+    #[test]
+    fn cycles_are_stopped() {
+        let (mut world, mut hierarchy) = setup();
+        let a = spawn(&mut world, 1.0, None);
+        let b = spawn(&mut world, 1.0, Some(a));
+        world
+            .write_storage::<Parent>()
+            .insert(a, Parent(b))
+            .unwrap();
+        run(&mut world, &mut hierarchy);
+        assert!(world.is_alive(a) && world.is_alive(b));
+    }
+
+    #[test]
+    fn children_are_deleted_with_their_parents() {
+        let (mut world, mut hierarchy) = setup();
+        let parent = spawn(&mut world, 1.0, None);
+        let child = spawn(&mut world, 2.0, Some(parent));
+        let grandchild = spawn(&mut world, 3.0, Some(child));
+        let other = spawn(&mut world, 4.0, None);
+        run(&mut world, &mut hierarchy);
+
+        delete_with_children(&world, child);
+        world.maintain();
+        assert!(!world.is_alive(child) && !world.is_alive(grandchild));
+        assert!(world.is_alive(parent) && world.is_alive(other));
+    }
+}
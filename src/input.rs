@@ -0,0 +1,163 @@
+use crate::plugin::{Plugin, WorldBuilder};
+use glfw::{Action, Key, MouseButton, WindowEvent};
+use std::collections::HashSet;
+
+// The state of the keyboard and mouse, collected from the window events so
+// systems can read it as a resource.
+// Everything named "this frame" is cleared by `end_frame`.
+#[derive(Debug, Clone, Default)]
+pub struct InputState {
+    keys_down: HashSet<Key>,
+    keys_pressed: HashSet<Key>,
+    buttons_down: HashSet<MouseButton>,
+    buttons_pressed: HashSet<MouseButton>,
+    buttons_released: HashSet<MouseButton>,
+
+    // Cursor position in screen coordinates from the top left of the window
+    cursor: Option<(f64, f64)>,
+    cursor_delta: (f64, f64),
+    cursor_in_window: bool,
+
+    // Vertical scroll wheel movement this frame
+    scroll: f64,
+
+    window_size: (i32, i32),
+    focused: bool,
+}
+
+impl InputState {
+    pub fn new(window_size: (i32, i32)) -> Self {
+        // The window is only told when the cursor enters or leaves it, so
+        // assume the cursor starts inside. Until it moves there's no cursor
+        // position to use anyway.
+        Self {
+            window_size,
+            focused: true,
+            cursor_in_window: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::Key(key, _, Action::Press, _) => {
+                self.keys_down.insert(*key);
+                self.keys_pressed.insert(*key);
+            }
+            WindowEvent::Key(key, _, Action::Release, _) => {
+                self.keys_down.remove(key);
+            }
+            WindowEvent::MouseButton(button, Action::Press, _) => {
+                self.buttons_down.insert(*button);
+                self.buttons_pressed.insert(*button);
+            }
+            WindowEvent::MouseButton(button, Action::Release, _) => {
+                self.buttons_down.remove(button);
+                self.buttons_released.insert(*button);
+            }
+            WindowEvent::CursorPos(x, y) => {
+                if let Some((last_x, last_y)) = self.cursor {
+                    self.cursor_delta.0 += x - last_x;
+                    self.cursor_delta.1 += y - last_y;
+                }
+                self.cursor = Some((*x, *y));
+            }
+            WindowEvent::CursorEnter(entered) => self.cursor_in_window = *entered,
+            WindowEvent::Scroll(_, y) => self.scroll += y,
+            WindowEvent::Size(w, h) => self.window_size = (*w, *h),
+            WindowEvent::Focus(focused) => {
+                self.focused = *focused;
+
+                // Releases aren't reported while the window isn't focused,
+                // so forget everything to avoid keys getting stuck down
+                if !focused {
+                    self.keys_down.clear();
+                    self.buttons_down.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Clear everything that only lasts a single frame
+    pub fn end_frame(&mut self) {
+        self.keys_pressed.clear();
+        self.buttons_pressed.clear();
+        self.buttons_released.clear();
+        self.cursor_delta = (0.0, 0.0);
+        self.scroll = 0.0;
+    }
+
+    pub fn is_key_down(&self, key: Key) -> bool {
+        self.keys_down.contains(&key)
+    }
+
+    pub fn was_key_pressed(&self, key: Key) -> bool {
+        self.keys_pressed.contains(&key)
+    }
+
+    pub fn is_button_down(&self, button: MouseButton) -> bool {
+        self.buttons_down.contains(&button)
+    }
+
+    pub fn was_button_pressed(&self, button: MouseButton) -> bool {
+        self.buttons_pressed.contains(&button)
+    }
+
+    pub fn was_button_released(&self, button: MouseButton) -> bool {
+        self.buttons_released.contains(&button)
+    }
+
+    pub fn cursor(&self) -> Option<(f64, f64)> {
+        self.cursor
+    }
+
+    pub fn cursor_delta(&self) -> (f64, f64) {
+        self.cursor_delta
+    }
+
+    pub fn is_cursor_in_window(&self) -> bool {
+        self.cursor_in_window
+    }
+
+    pub fn scroll(&self) -> f64 {
+        self.scroll
+    }
+
+    pub fn window_size(&self) -> (i32, i32) {
+        self.window_size
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        if self.window_size.1 <= 0 {
+            1.0
+        } else {
+            self.window_size.0 as f32 / self.window_size.1 as f32
+        }
+    }
+}
+
+// Adds the input state. The window forwards its events to it.
+pub struct InputPlugin {
+    window_size: (i32, i32),
+}
+
+impl InputPlugin {
+    pub fn new(window_size: (i32, i32)) -> Self {
+        Self { window_size }
+    }
+}
+
+impl Plugin for InputPlugin {
+    fn name(&self) -> &'static str {
+        "input"
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder.insert_resource(InputState::new(self.window_size));
+    }
+}
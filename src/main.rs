@@ -1,54 +1,84 @@
-use gl::types::{GLfloat, GLint, GLushort};
+use autosave::{Autosave, AutosaveConfig};
+use buildings::BuildingPlugin;
+use camera::CameraPlugin;
+use demand::DemandPlugin;
+use economy::EconomyPlugin;
+use gl::types::GLushort;
 use gl_bindings::{gl, Gl};
 use glfw::{
     Action, Context, Glfw, Key, OpenGlProfileHint, SwapInterval, Window, WindowEvent, WindowHint,
 };
-use nalgebra::{Matrix4, Orthographic3, UnitQuaternion};
-use render::{Index, Mesh, Shader, ShaderProgram, Uniform, Vec3, VertexAttrib};
-use specs::World;
-use std::ffi::CString;
-use std::ops::Deref;
+use input::{InputPlugin, InputState};
+use mapgen::MapGenParams;
+use nalgebra::{UnitQuaternion, Vector2, Vector3, Vector4};
+use pathfinding::PathfindingPlugin;
+use picking::PickingPlugin;
+use plugin::{GameWorld, WorldBuilder};
+use render::assets::{Assets, Handle};
+use render::loader::{AsyncLoader, MeshData, TextureData};
+use render::program_cache::ProgramBinaryCache;
+use render::shader_source::{EmbeddedFileSystem, ShaderDefines, ShaderLoader};
+use render::{Index, Mesh, ShaderProgram, ShaderStage, Texture, Vec3, VertexAttrib};
+use renderer::{Bounds, RenderPlugin, Renderer, Vertex, WorldMesh};
+use roads::{RoadNetwork, RoadPlugin, RoadToolPlugin, RoadType};
+use simulation::{AnimationPlugin, FixedTimestep};
+use specs::{Builder, Dispatcher, World, WorldExt};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Receiver;
-use std::time::SystemTime;
-use world::Transform;
-
-#[macro_use]
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tilemap::{Terrain, TileMap, TileMapPlugin, Zone};
+use tools::{TileHighlights, ToolStatus, ToolsPlugin};
+use traffic::TrafficPlugin;
+use world::{
+    Camera, DeltaTime, InterpolatedTransform, MapBounds, MapInfo, MeshRenderer, Parent, Pickable,
+    SimulationTime, Spin, Sprite, Transform,
+};
+use zoning::ZoningPlugin;
+
+pub mod autosave;
+pub mod buildings;
+pub mod camera;
+pub mod demand;
+pub mod economy;
+pub mod hierarchy;
+pub mod input;
+pub mod map_image;
+pub mod mapgen;
+pub mod pathfinding;
+pub mod picking;
+pub mod plugin;
+pub mod renderer;
+pub mod roads;
+pub mod save;
+pub mod simulation;
+pub mod tile_renderer;
+pub mod tilemap;
+pub mod tools;
+pub mod traffic;
 pub mod world;
+pub mod zoning;
 
-#[derive(render_derive::VertexAttribPointers, Copy, Clone, Debug, PartialEq)]
-#[repr(C, packed)]
-pub struct Vertex {
-    #[location = 0]
-    pub pos: Vec3,
+// Number of threads decoding assets in the background
+const LOADER_THREADS: usize = 2;
 
-    #[location = 1]
-    pub col: Vec3,
-}
-
-impl Vertex {
-    pub fn new(pos: Vec3, col: Vec3) -> Self {
-        Self { pos, col }
-    }
-}
+// How many times the simulation is stepped each second, and how many steps
+// can be run in a single frame to catch up before time is skipped
+const SIMULATION_TICKS_PER_SECOND: u32 = 30;
+const MAX_SIMULATION_STEPS: u32 = 5;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-struct Mat4(Matrix4<f32>);
+// Maximum number of bytes of loaded assets to upload to the GPU each frame
+const UPLOAD_BUDGET_BYTES: usize = 4 * 1024 * 1024;
 
-impl Deref for Mat4 {
-    type Target = Matrix4<f32>;
+// Size of the map in tiles
+const MAP_WIDTH: u32 = 128;
+const MAP_HEIGHT: u32 = 128;
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
+// Portion of an imported heightmap's range that's under water
+const IMPORT_SEA_LEVEL: f32 = 0.35;
 
-impl Uniform for Mat4 {
-    fn set_uniform(&self, gl: &Gl, location: GLint) {
-        unsafe {
-            gl.UniformMatrix4fv(location, 1, gl::FALSE, self.as_ptr() as *const GLfloat);
-        }
-    }
-}
+// Where map pictures are saved, and how many pixels across each tile is
+const EXPORT_DIRECTORY: &str = "exports";
+const OVERVIEW_SCALE: u32 = 4;
 
 struct App<V: VertexAttrib, I: Index> {
     // Window
@@ -56,18 +86,32 @@ struct App<V: VertexAttrib, I: Index> {
     window: Window,
     events: Receiver<(f64, WindowEvent)>,
 
-    // The world
+    // The world. The simulation systems run at a fixed rate while the frame
+    // systems run once before drawing each frame.
     world: World,
+    simulation: Dispatcher<'static, 'static>,
+    frame_systems: Dispatcher<'static, 'static>,
+    timestep: FixedTimestep,
+    autosave: Autosave,
+
+    // GPU assets
+    meshes: Assets<Mesh<V, I>>,
+    shaders: Assets<ShaderProgram>,
+    textures: Assets<Texture>,
+    mesh_loader: AsyncLoader<WorldMesh, MeshData<Vertex, GLushort>>,
+    texture_loader: AsyncLoader<Texture, TextureData>,
 
-    // Draw testing
-    shader: ShaderProgram,
-    mesh: Mesh<V, I>,
+    // Drawing
+    renderer: Renderer,
 
     // Loop testing
-    last_print_time: SystemTime,
+    last_frame_time: Instant,
+    last_print_time: Instant,
     frames: i32,
-    dir: bool,
-    red: f32,
+
+    // What the window's title shows, next to what the current tool says
+    title: String,
+    tool_status: Option<String>,
 
     // GL handle
     gl: Gl,
@@ -118,74 +162,372 @@ impl App<Vertex, GLushort> {
         // Tell OpenGL how to access methods and get an instance of the Gl struct
         let gl = Gl::load_with(|s| window.get_proc_address(s) as *const _);
 
+        // Load the shaders and meshes
+        let mut shaders = Assets::new();
+        let shader_loader = Self::init_shader_loader(&gl);
+        let shader = Self::init_test_shaders(&gl, &shader_loader, &mut shaders);
+        let renderer =
+            Renderer::new(&gl, &shader_loader, &mut shaders).expect("failed to create renderer");
+
+        // Models and textures are decoded in the background. Meshes aren't
+        // drawn until they're uploaded, and textures show up as plain white.
+        let mut meshes = Assets::new();
+        let mut mesh_loader = AsyncLoader::new(
+            LOADER_THREADS,
+            |path: &str| {
+                MeshData::decode_obj(path, |position, color| {
+                    Vertex::new(
+                        Vec3::new(position[0], position[1], position[2]),
+                        Vec3::new(color[0], color[1], color[2]),
+                    )
+                })
+            },
+            |gl, data: MeshData<Vertex, GLushort>| data.upload(gl),
+        );
+        let mut textures = Assets::new();
+        textures.set_placeholder(Texture::new_rgba(&gl, 1, 1, &[255, 255, 255, 255]));
+        let mut texture_loader = AsyncLoader::new(
+            LOADER_THREADS,
+            TextureData::decode_png,
+            |gl, data: TextureData| data.upload(gl),
+        );
+        let mesh = mesh_loader.load(&mut meshes, &Self::asset_path("models/quad.obj"));
+        let checker = texture_loader.load(&mut textures, &Self::asset_path("textures/checker.png"));
+
+        // Carry on from the last autosave when asked to, otherwise start a
+        // new city
+        let autosave_config = AutosaveConfig::default();
+        let window_size = window.get_size();
+        let resumed = if Self::resume_requested() {
+            match autosave::load_latest(&autosave_config, || Self::build_world(window_size)) {
+                Ok(resumed) => Some(resumed),
+                Err(err) => {
+                    println!("Couldn't resume, starting a new city: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let GameWorld {
+            world,
+            simulation,
+            frame_systems,
+        } = match resumed {
+            Some((mut game, header)) => {
+                println!("Resuming \"{}\"", header.map.name);
+                game.world.insert(header.map);
+                game
+            }
+            None => {
+                let mut game = Self::build_world(window_size).expect("failed to build world");
+                Self::init_new_city(&mut game.world, mesh, shader, checker);
+                game
+            }
+        };
+
         Self {
             glfw,
             window,
             events,
 
-            world: create_world!(),
-            shader: Self::init_test_shaders(&gl),
-            mesh: Self::init_test_mesh(&gl),
+            world,
+            simulation,
+            frame_systems,
+            timestep: FixedTimestep::new(SIMULATION_TICKS_PER_SECOND, MAX_SIMULATION_STEPS),
+            autosave: Autosave::new(autosave_config),
 
-            last_print_time: SystemTime::now(),
+            meshes,
+            shaders,
+            textures,
+            mesh_loader,
+            texture_loader,
+
+            renderer,
+
+            last_frame_time: Instant::now(),
+            last_print_time: Instant::now(),
             frames: 0,
-            dir: false,
-            red: 0.0,
+            title: "Citey".to_owned(),
+            tool_status: None,
 
             gl,
         }
     }
 
-    fn create_shader_program(
-        gl: &Gl,
-        vertex_shader: &str,
-        fragment_shader: &str,
-        uniforms: Vec<String>,
-    ) -> Result<ShaderProgram, String> {
-        let vert_shader = Shader::new_from_source(
-            &gl,
-            gl::VERTEX_SHADER,
-            &CString::new(vertex_shader).unwrap(),
-        )?;
-
-        let frag_shader = Shader::new_from_source(
-            &gl,
-            gl::FRAGMENT_SHADER,
-            &CString::new(fragment_shader).unwrap(),
-        )?;
-
-        ShaderProgram::new_from_shaders(&gl, vec![vert_shader, frag_shader], uniforms)
+    fn build_world(window_size: (i32, i32)) -> Result<GameWorld, String> {
+        WorldBuilder::new()
+            .with_plugin(InputPlugin::new(window_size))
+            .with_plugin(CameraPlugin)
+            .with_plugin(PickingPlugin)
+            .with_plugin(RenderPlugin)
+            .with_plugin(AnimationPlugin)
+            .with_plugin(EconomyPlugin)
+            .with_plugin(ToolsPlugin)
+            .with_plugin(ZoningPlugin)
+            .with_plugin(DemandPlugin)
+            .with_plugin(BuildingPlugin::from_directory(
+                Self::assets_dir().join("buildings"),
+            ))
+            .with_plugin(RoadPlugin)
+            .with_plugin(RoadToolPlugin)
+            .with_plugin(PathfindingPlugin)
+            .with_plugin(TrafficPlugin)
+            .with_plugin(TileMapPlugin::generated(MapGenParams {
+                seed: Self::map_seed(),
+                width: MAP_WIDTH,
+                height: MAP_HEIGHT,
+                ..MapGenParams::default()
+            }))
+            .build()
+    }
+
+    // A camera looking at the middle of a freshly generated map
+    fn init_new_city(
+        world: &mut World,
+        mesh: Handle<WorldMesh>,
+        shader: Handle<ShaderProgram>,
+        checker: Handle<Texture>,
+    ) {
+        world.insert(MapInfo::new("New City", MAP_WIDTH, MAP_HEIGHT));
+        world.insert(MapBounds::new(
+            Vector2::new(0.0, 0.0),
+            Vector2::new(MAP_WIDTH as f32, MAP_HEIGHT as f32),
+        ));
+        let center = Vector2::new(MAP_WIDTH as f32, MAP_HEIGHT as f32) * 0.5;
+        world.create_entity().with(Camera::new(center, 1.0)).build();
+        Self::import_map_images(&mut world.write_resource::<TileMap>());
+        Self::init_test_map(
+            &mut world.write_resource::<TileMap>(),
+            &mut world.write_resource::<RoadNetwork>(),
+        );
+        Self::init_test_entities(world, center, mesh, shader, checker);
     }
 
-    fn init_test_shaders(gl: &Gl) -> ShaderProgram {
-        let vert_shader = include_str!("shader/basic_vertex.glsl");
-        let frag_shader = include_str!("shader/basic_fragment.glsl");
+    fn init_shader_loader(gl: &Gl) -> ShaderLoader {
+        // Linked programs are cached on disk to speed up the next startup
+        // when the driver supports it
+        let binary_cache = ProgramBinaryCache::new(gl, "cache/shaders");
+        if binary_cache.is_none() {
+            println!("Program binaries are not supported, shaders won't be cached");
+        }
+
+        // The shaders are embedded into the executable so they can include
+        // each other without depending on the working directory
+        ShaderLoader::new(
+            EmbeddedFileSystem::new()
+                .with_file("camera.glsl", include_str!("shader/camera.glsl"))
+                .with_file(
+                    "basic_vertex.glsl",
+                    include_str!("shader/basic_vertex.glsl"),
+                )
+                .with_file(
+                    "basic_fragment.glsl",
+                    include_str!("shader/basic_fragment.glsl"),
+                )
+                .with_file(
+                    "sprite_vertex.glsl",
+                    include_str!("shader/sprite_vertex.glsl"),
+                )
+                .with_file(
+                    "sprite_fragment.glsl",
+                    include_str!("shader/sprite_fragment.glsl"),
+                )
+                .with_file("tile_vertex.glsl", include_str!("shader/tile_vertex.glsl"))
+                .with_file(
+                    "tile_fragment.glsl",
+                    include_str!("shader/tile_fragment.glsl"),
+                ),
+        )
+        .with_binary_cache(binary_cache)
+    }
 
+    fn init_test_shaders(
+        gl: &Gl,
+        shader_loader: &ShaderLoader,
+        shaders: &mut Assets<ShaderProgram>,
+    ) -> Handle<ShaderProgram> {
         // Uniforms are defined when the shader program is created to prevent
         // the slowdown possibly incurred by getting the location of a shader
         // at runtime
-        let uniforms = vec!["projection_matrix", "red"]
-            .into_iter()
-            .map(|s| s.to_owned())
-            .collect();
+        shader_loader
+            .load_program(
+                gl,
+                shaders,
+                &[
+                    (ShaderStage::Vertex, "basic_vertex.glsl"),
+                    (ShaderStage::Fragment, "basic_fragment.glsl"),
+                ],
+                &ShaderDefines::new(),
+                &["projection_matrix", "object_matrix"],
+            )
+            .unwrap()
+    }
 
-        Self::create_shader_program(gl, vert_shader, frag_shader, uniforms).unwrap()
+    // Where the game's data files are. Set `CITEY_ASSETS` to use another
+    // folder, otherwise the one next to the sources is used while developing
+    // and the one in the working directory after that.
+    fn assets_dir() -> PathBuf {
+        if let Some(directory) = std::env::var_os("CITEY_ASSETS") {
+            return PathBuf::from(directory);
+        }
+        let development = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets");
+        if cfg!(debug_assertions) && development.is_dir() {
+            development
+        } else {
+            PathBuf::from("assets")
+        }
     }
 
-    fn init_test_mesh(gl: &Gl) -> Mesh<Vertex, GLushort> {
-        let vertex_data: Vec<Vertex> = vec![
-            // Bottom left
-            Vertex::new((-1.0, -1.0, -0.5).into(), (1.0, 0.0, 0.0).into()),
-            // Top left
-            Vertex::new((-1.0, 1.0, -0.5).into(), (1.0, 1.0, 0.0).into()),
-            // Top right
-            Vertex::new((1.0, 1.0, -0.5).into(), (0.0, 1.0, 0.0).into()),
-            // Bottom right
-            Vertex::new((1.0, -1.0, -0.5).into(), (0.0, 0.0, 1.0).into()),
+    fn asset_path(name: &str) -> String {
+        Self::assets_dir().join(name).to_string_lossy().into_owned()
+    }
+
+    // Set `CITEY_RESUME` to carry on from the newest autosave after a crash
+    // or a restart
+    fn resume_requested() -> bool {
+        std::env::var_os("CITEY_RESUME").is_some()
+    }
+
+    // The seed can be set with `CITEY_SEED` to play a map again, otherwise
+    // a new one is picked from the clock
+    fn map_seed() -> u64 {
+        let seed = std::env::var("CITEY_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_secs())
+            });
+        println!("Generating map with seed {}", seed);
+        seed
+    }
+
+    // Images made in other tools can replace parts of the generated map.
+    // `CITEY_HEIGHTMAP` is a greyscale image of the ground's height and
+    // `CITEY_MASKS` marks water and forests with `WATER_MASK` and
+    // `FOREST_MASK`.
+    fn import_map_images(map: &mut TileMap) {
+        let heightmap = std::env::var_os("CITEY_HEIGHTMAP").map(|path| {
+            map_image::load_png(Path::new(&path))
+                .and_then(|image| map_image::import_heightmap(map, &image, IMPORT_SEA_LEVEL))
+        });
+        if let Some(Err(err)) = heightmap {
+            println!("Failed to import heightmap: {}", err);
+        }
+
+        let masks = std::env::var_os("CITEY_MASKS").map(|path| {
+            map_image::load_png(Path::new(&path))
+                .and_then(|image| map_image::import_masks(map, &image))
+        });
+        if let Some(Err(err)) = masks {
+            println!("Failed to import masks: {}", err);
+        }
+    }
+
+    // A crossroads in the middle of the map with a zone in each corner,
+    // leaving out any water in the way
+    fn init_test_map(map: &mut TileMap, network: &mut RoadNetwork) {
+        let (mid_x, mid_y) = (map.width() as i32 / 2, map.height() as i32 / 2);
+        let zones = [
+            ((-12, 2), (-2, 12), Zone::Residential),
+            ((2, 2), (12, 12), Zone::Commercial),
+            ((2, -12), (12, -2), Zone::Industrial),
+            ((-12, -12), (-2, -2), Zone::Residential),
         ];
-        let index_data: Vec<GLushort> = vec![0, 1, 2, 0, 2, 3];
+        for &(min, max, zone) in zones.iter() {
+            let area: Vec<_> = map
+                .rect(
+                    (mid_x + min.0, mid_y + min.1),
+                    (mid_x + max.0, mid_y + max.1),
+                )
+                .collect();
+            for pos in area {
+                map.update(pos, |tile| {
+                    if tile.terrain != Terrain::Water {
+                        tile.zone = zone;
+                    }
+                });
+            }
+        }
 
-        Mesh::create(gl, vertex_data, index_data)
+        // Each arm of the crossroads stops at the first water in its way
+        let arms = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+        for &(dx, dy) in arms.iter() {
+            let center = (mid_x, mid_y);
+            let length = (1..=16)
+                .take_while(|i| {
+                    let plan = roads::plan_road(
+                        map,
+                        network,
+                        center,
+                        (mid_x + dx * i, mid_y + dy * i),
+                        RoadType::Avenue,
+                    );
+                    plan.blocked.is_empty()
+                })
+                .last();
+            if let Some(length) = length {
+                let end = (mid_x + dx * length, mid_y + dy * length);
+                roads::place_road(map, network, center, end, RoadType::Avenue);
+            }
+        }
+    }
+
+    // A spinning quad with a smaller one attached to it, and a row of sprites
+    fn init_test_entities(
+        world: &mut World,
+        center: Vector2<f32>,
+        mesh: Handle<WorldMesh>,
+        shader: Handle<ShaderProgram>,
+        checker: Handle<Texture>,
+    ) {
+        let bounds = Bounds::new(Vector3::new(-1.0, -1.0, -0.5), Vector3::new(1.0, 1.0, -0.5));
+
+        let parent = world
+            .create_entity()
+            .with(Transform::new(
+                Vector3::new(center.x, center.y, 0.0),
+                UnitQuaternion::identity(),
+                Vector3::new(1.0, 1.0, 1.0),
+            ))
+            .with(MeshRenderer::new(mesh.clone(), shader.clone(), bounds))
+            .with(Pickable::new(bounds.min, bounds.max))
+            .with(Spin(1.0))
+            .with(InterpolatedTransform::default())
+            .build();
+        world
+            .create_entity()
+            .with(Transform::new(
+                Vector3::new(3.0, 0.0, 0.0),
+                UnitQuaternion::identity(),
+                Vector3::new(0.5, 0.5, 1.0),
+            ))
+            .with(Parent(parent))
+            .with(MeshRenderer::new(mesh, shader, bounds))
+            .with(Pickable::new(bounds.min, bounds.max))
+            .with(InterpolatedTransform::default())
+            .build();
+
+        for i in 0..5 {
+            world
+                .create_entity()
+                .with(Transform::new(
+                    Vector3::new(center.x + i as f32 * 2.0 - 4.0, center.y - 4.0, 0.0),
+                    UnitQuaternion::identity(),
+                    Vector3::new(1.0, 1.0, 1.0),
+                ))
+                .with(
+                    Sprite::new(checker.clone(), Vector2::new(1.5, 1.5)).with_color(Vector4::new(
+                        1.0,
+                        1.0 - i as f32 * 0.2,
+                        1.0,
+                        1.0,
+                    )),
+                )
+                .build();
+        }
     }
 
     fn start_game(&mut self) {
@@ -205,44 +547,76 @@ impl App<Vertex, GLushort> {
             self.gl.Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
         }
 
-        // Uniform testing
-        if self.dir {
-            self.red += 1.0f32 / 120.0f32;
-            if self.red >= 1.0f32 {
-                self.dir = !self.dir;
-            }
-        } else {
-            self.red -= 1.0f32 / 120.0f32;
-            if self.red <= 0.0f32 {
-                self.dir = !self.dir;
-            }
-        }
+        // Step the simulation as many times as needed to catch up to the
+        // current time, then move the camera
+        let elapsed = self.update_delta_time();
+        self.run_simulation(elapsed);
+        self.frame_systems.dispatch(&self.world);
+        self.world.maintain();
+        self.autosave.update(&self.world);
 
-        // Update projection matrix
+        // Draw the world between the last two simulation steps
         let win_size = self.window.get_size();
         let aspect = win_size.0 as f32 / win_size.1 as f32;
-        let projection_ortho =
-            Orthographic3::<f32>::new(aspect * -1.5, aspect * 1.5, -1.5, 1.5, 0.1, 1.0);
-
-        // Draw a triangle
-        self.shader.bind();
-        self.shader.set_uniform("red", &self.red);
-        self.shader
-            .set_uniform("projection_matrix", &Mat4(*projection_ortho.as_matrix()));
-        //        self.shader
-        //            .set_uniform("object_matrix", &Mat4(transform_mat));
-        self.mesh.render();
-        self.shader.unbind();
+        self.renderer.render(
+            &self.world,
+            aspect,
+            self.timestep.alpha(),
+            &self.meshes,
+            &self.shaders,
+            &self.textures,
+        );
 
         // Display changes in the window
         self.window.swap_buffers();
 
+        // Forget the input and tool feedback that only last a single frame
+        self.world.write_resource::<InputState>().end_frame();
+        self.world.write_resource::<TileHighlights>().0.clear();
+        let tool_status = self.world.write_resource::<ToolStatus>().0.take();
+
+        // Upload some of the assets that finished loading in the background
+        self.mesh_loader
+            .update(&self.gl, &mut self.meshes, UPLOAD_BUDGET_BYTES);
+        self.texture_loader
+            .update(&self.gl, &mut self.textures, UPLOAD_BUDGET_BYTES);
+
+        // Destroy the GPU resources that are no longer referenced now that
+        // the frame is done with them
+        self.meshes.maintain();
+        self.shaders.maintain();
+        self.textures.maintain();
+
         // Update frame counter
         let current_fps = self.update_frame_counter();
         if current_fps >= 0 {
-            self.window
-                .set_title(&format!("Citey | FPS: {}", current_fps));
+            let live_assets =
+                self.meshes.stats().live + self.shaders.stats().live + self.textures.stats().live;
+            let progress = self.mesh_loader.progress() + self.texture_loader.progress();
+
+            self.title = if progress.is_done() {
+                format!("Citey | FPS: {} | Assets: {}", current_fps, live_assets)
+            } else {
+                format!(
+                    "Citey | FPS: {} | Loading: {:.0}%",
+                    current_fps,
+                    progress.fraction() * 100.0
+                )
+            };
+            self.update_title(tool_status);
+        } else if tool_status != self.tool_status {
+            self.update_title(tool_status);
+        }
+    }
+
+    fn update_title(&mut self, tool_status: Option<String>) {
+        match &tool_status {
+            Some(status) => self
+                .window
+                .set_title(&format!("{} | {}", self.title, status)),
+            None => self.window.set_title(&self.title),
         }
+        self.tool_status = tool_status;
     }
 
     fn handle_window_events(&mut self) {
@@ -256,6 +630,9 @@ impl App<Vertex, GLushort> {
 
         // Loop through all of the captured events and try to handle them
         for (_, event) in e {
+            self.world
+                .write_resource::<InputState>()
+                .handle_event(&event);
             self.handle_window_event(&event);
         }
     }
@@ -276,15 +653,61 @@ impl App<Vertex, GLushort> {
             glfw::WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                 self.window.set_should_close(true)
             }
+            glfw::WindowEvent::Key(Key::F12, _, Action::Press, _) => self.export_map_images(),
             _ => {}
         }
     }
 
+    // Save a picture of the city and its heightmap to share or edit
+    fn export_map_images(&self) {
+        let map = match self.world.try_fetch::<TileMap>() {
+            Some(map) => map,
+            None => return,
+        };
+
+        let tick = self.world.fetch::<SimulationTime>().tick;
+        let directory = Path::new(EXPORT_DIRECTORY);
+        let overview = directory.join(format!("overview_{}.png", tick));
+        let heightmap = directory.join(format!("heightmap_{}.png", tick));
+        let exported =
+            map_image::save_png(&overview, &map_image::render_overview(&map, OVERVIEW_SCALE))
+                .and_then(|_| map_image::save_png(&heightmap, &map_image::export_heightmap(&map)));
+        match exported {
+            Ok(()) => println!("Exported the map to {}", overview.display()),
+            Err(err) => println!("Failed to export the map: {}", err),
+        }
+    }
+
+    // Returns the time since the last frame
+    fn update_delta_time(&mut self) -> Duration {
+        let current_frame_time = Instant::now();
+        let delta = current_frame_time.duration_since(self.last_frame_time);
+        self.last_frame_time = current_frame_time;
+
+        // Avoid huge jumps after the window was blocked (like while dragging)
+        self.world.insert(DeltaTime(delta.as_secs_f32().min(0.25)));
+
+        delta
+    }
+
+    fn run_simulation(&mut self, elapsed: Duration) {
+        let steps = self.timestep.advance(elapsed);
+        for _ in 0..steps {
+            {
+                let mut time = self.world.write_resource::<SimulationTime>();
+                time.tick += 1;
+                time.step = self.timestep.step().as_secs_f32();
+            }
+
+            self.simulation.dispatch(&self.world);
+            self.world.maintain();
+        }
+    }
+
     fn update_frame_counter(&mut self) -> i32 {
-        let current_loop_time = SystemTime::now();
+        let current_loop_time = Instant::now();
         if current_loop_time
             .duration_since(self.last_print_time)
-            .unwrap()
             .as_secs()
             >= 1
         {
@@ -0,0 +1,290 @@
+use crate::tilemap::{Terrain, Tile, TileFlags, TileMap, Zone};
+use render::loader::TextureData;
+use std::fs;
+use std::path::Path;
+
+// Colors that mark tiles in mask images. Pixels of other colors leave their
+// tiles alone.
+pub const WATER_MASK: [u8; 3] = [0, 0, 255];
+pub const FOREST_MASK: [u8; 3] = [0, 255, 0];
+
+// How far a pixel's color can be from a mask color and still count, added up
+// over the channels
+const MASK_TOLERANCE: i32 = 96;
+
+// The pixel covering the tile, with the top of the image at the top of the
+// map. Images of a different size than the map are stretched to fit.
+fn pixel_at(image: &TextureData, map: &TileMap, (x, y): (i32, i32)) -> [u8; 4] {
+    let px = (x as u64 * u64::from(image.width) / u64::from(map.width())) as u32;
+    let py = ((map.height() as i32 - 1 - y) as u64 * u64::from(image.height)
+        / u64::from(map.height())) as u32;
+    let i = ((py * image.width + px) * 4) as usize;
+
+    [
+        image.pixels[i],
+        image.pixels[i + 1],
+        image.pixels[i + 2],
+        image.pixels[i + 3],
+    ]
+}
+
+fn check_size(image: &TextureData) -> Result<(), String> {
+    if image.width == 0 || image.height == 0 {
+        Err("the image is empty".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+fn all_tiles(map: &TileMap) -> Vec<(i32, i32)> {
+    map.rect((0, 0), (map.width() as i32 - 1, map.height() as i32 - 1))
+        .collect()
+}
+
+// Set the height of every tile from the brightness of a greyscale image.
+// Tiles lower than `sea_level`, from 0 to 1, are flooded and land that comes
+// out of the water becomes grass.
+pub fn import_heightmap(
+    map: &mut TileMap,
+    image: &TextureData,
+    sea_level: f32,
+) -> Result<(), String> {
+    check_size(image)?;
+
+    let sea_level = sea_level * 255.0;
+    for pos in all_tiles(map) {
+        let height = pixel_at(image, map, pos)[0];
+        map.update(pos, |tile| {
+            tile.height = height;
+            if f32::from(height) < sea_level {
+                tile.terrain = Terrain::Water;
+                tile.forest = 0;
+            } else if tile.terrain == Terrain::Water {
+                tile.terrain = Terrain::Grass;
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn matches_mask(pixel: [u8; 4], mask: [u8; 3]) -> bool {
+    let distance: i32 = mask
+        .iter()
+        .zip(pixel.iter())
+        .map(|(a, b)| (i32::from(*a) - i32::from(*b)).abs())
+        .sum();
+    pixel[3] > 0 && distance <= MASK_TOLERANCE
+}
+
+// Mark tiles as water or forest from an image colored with `WATER_MASK` and
+// `FOREST_MASK`
+pub fn import_masks(map: &mut TileMap, image: &TextureData) -> Result<(), String> {
+    check_size(image)?;
+
+    for pos in all_tiles(map) {
+        let pixel = pixel_at(image, map, pos);
+        if matches_mask(pixel, WATER_MASK) {
+            map.update(pos, |tile| {
+                tile.terrain = Terrain::Water;
+                tile.forest = 0;
+            });
+        } else if matches_mask(pixel, FOREST_MASK) {
+            map.update(pos, |tile| {
+                if tile.terrain != Terrain::Water {
+                    tile.forest = 255;
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// A greyscale image of the heights of the map, one pixel per tile, that
+// `import_heightmap` can read back
+pub fn export_heightmap(map: &TileMap) -> TextureData {
+    render_image(map, 1, |tile| [tile.height, tile.height, tile.height])
+}
+
+// A top-down picture of the city with `scale` pixels across each tile
+pub fn render_overview(map: &TileMap, scale: u32) -> TextureData {
+    render_image(map, scale.max(1), overview_color)
+}
+
+fn render_image<F>(map: &TileMap, scale: u32, color: F) -> TextureData
+where
+    F: Fn(&Tile) -> [u8; 3],
+{
+    let width = map.width() * scale;
+    let height = map.height() * scale;
+    let mut pixels = vec![0; (width * height * 4) as usize];
+    for (x, y) in all_tiles(map) {
+        let [r, g, b] = color(map.get((x, y)).unwrap());
+        let left = x as u32 * scale;
+        let top = (map.height() - 1 - y as u32) * scale;
+        for py in top..top + scale {
+            for px in left..left + scale {
+                let i = ((py * width + px) * 4) as usize;
+                pixels[i..i + 4].copy_from_slice(&[r, g, b, 255]);
+            }
+        }
+    }
+
+    TextureData {
+        width,
+        height,
+        pixels,
+    }
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], amount: f32) -> [f32; 3] {
+    [
+        a[0] + (b[0] - a[0]) * amount,
+        a[1] + (b[1] - a[1]) * amount,
+        a[2] + (b[2] - a[2]) * amount,
+    ]
+}
+
+fn overview_color(tile: &Tile) -> [u8; 3] {
+    let mut color = match tile.terrain {
+        Terrain::Grass => [80.0, 150.0, 65.0],
+        Terrain::Dirt => [130.0, 100.0, 65.0],
+        Terrain::Sand => [220.0, 205.0, 150.0],
+        Terrain::Rock => [120.0, 120.0, 125.0],
+        Terrain::Water => [45.0, 95.0, 180.0],
+    };
+
+    // Higher ground is lighter, like on the map in the game
+    let shade = 0.7 + 0.3 * f32::from(tile.height) / 255.0;
+    color = [color[0] * shade, color[1] * shade, color[2] * shade];
+    color = mix(
+        color,
+        [25.0, 70.0, 30.0],
+        0.7 * f32::from(tile.forest) / 255.0,
+    );
+
+    let zone = match tile.zone {
+        Zone::Unzoned => None,
+        Zone::Residential => Some([75.0, 230.0, 75.0]),
+        Zone::Commercial => Some([75.0, 130.0, 255.0]),
+        Zone::Industrial => Some([255.0, 205.0, 50.0]),
+    };
+    if let Some(zone) = zone {
+        color = mix(color, zone, 0.35);
+    }
+    if tile.owner.is_some() {
+        color = mix(color, [60.0, 50.0, 45.0], 0.6);
+    }
+    if tile.flags.contains(TileFlags::ROAD) {
+        color = [75.0, 75.0, 80.0];
+    }
+
+    [color[0] as u8, color[1] as u8, color[2] as u8]
+}
+
+pub fn load_png(path: &Path) -> Result<TextureData, String> {
+    TextureData::decode_png(&path.to_string_lossy())
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))
+}
+
+pub fn save_png(path: &Path, image: &TextureData) -> Result<(), String> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)
+            .map_err(|err| format!("failed to create {}: {}", directory.display(), err))?;
+    }
+    fs::write(path, image.encode_png()?)
+        .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapgen::{self, MapGenParams};
+
+    fn image(width: u32, height: u32, pixel: impl Fn(u32, u32) -> [u8; 4]) -> TextureData {
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| pixel(x, y).to_vec())
+            .collect();
+        TextureData {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn round_trip_png(image: &TextureData) -> TextureData {
+        TextureData::read_png(&image.encode_png().unwrap()[..]).unwrap()
+    }
+
+    #[test]
+    fn heightmaps_round_trip() {
+        let map = mapgen::generate(&MapGenParams {
+            seed: 5,
+            width: 40,
+            height: 24,
+            ..MapGenParams::default()
+        });
+        let exported = round_trip_png(&export_heightmap(&map));
+        assert_eq!((exported.width, exported.height), (40, 24));
+
+        let mut imported = TileMap::new(40, 24);
+        import_heightmap(&mut imported, &exported, 0.0).unwrap();
+        for pos in all_tiles(&map) {
+            assert_eq!(
+                imported.get(pos).unwrap().height,
+                map.get(pos).unwrap().height
+            );
+        }
+    }
+
+    #[test]
+    fn heightmaps_are_stretched_and_flooded() {
+        // Dark on the left half, bright on the right, at twice the map's size
+        let heightmap = image(16, 8, |x, _| {
+            let grey = if x < 8 { 20 } else { 200 };
+            [grey, grey, grey, 255]
+        });
+
+        let mut map = TileMap::new(8, 4);
+        import_heightmap(&mut map, &heightmap, 0.3).unwrap();
+        assert_eq!(map.get((0, 0)).unwrap().terrain, Terrain::Water);
+        assert_eq!(map.get((3, 3)).unwrap().height, 20);
+        assert_eq!(map.get((4, 0)).unwrap().terrain, Terrain::Grass);
+        assert_eq!(map.get((7, 3)).unwrap().height, 200);
+    }
+
+    #[test]
+    fn masks_mark_water_and_forest() {
+        // The top row is water, the bottom row is forest and the rest of the
+        // image doesn't touch the map
+        let masks = image(4, 3, |_, y| match y {
+            0 => [10, 0, 250, 255],
+            2 => [0, 255, 0, 255],
+            _ => [255, 0, 0, 255],
+        });
+
+        let mut map = TileMap::new(4, 3);
+        import_masks(&mut map, &masks).unwrap();
+        assert_eq!(map.get((1, 2)).unwrap().terrain, Terrain::Water);
+        assert_eq!(map.get((1, 1)).unwrap(), &Tile::default());
+        assert_eq!(map.get((1, 0)).unwrap().forest, 255);
+    }
+
+    // Changes to map generation change this picture. Update the hash only
+    // when the change is on purpose.
+    #[test]
+    fn generated_map_overview_is_unchanged() {
+        let map = mapgen::generate(&MapGenParams {
+            seed: 20_200_101,
+            width: 96,
+            height: 64,
+            ..MapGenParams::default()
+        });
+        let overview = render_overview(&map, 2);
+
+        assert_eq!((overview.width, overview.height), (192, 128));
+        assert_eq!(crc32fast::hash(&overview.pixels), 2_685_730_845);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::tilemap::{Terrain, Tile, TileMap, MAX_MAP_SIZE};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use std::collections::VecDeque;
+
+// Size of the largest hills and lakes in tiles
+const HEIGHT_SCALE: f32 = 64.0;
+const MOISTURE_SCALE: f32 = 48.0;
+const FOREST_SCALE: f32 = 24.0;
+const ORE_SCALE: f32 = 16.0;
+const OCTAVES: u32 = 5;
+
+// Normalized heights above this are bare rock
+const ROCK_LEVEL: f32 = 0.8;
+// Land this close above the sea level is beach
+const BEACH_HEIGHT: f32 = 0.03;
+// Tiles further than this from water get no fertility bonus from it
+const WATER_REACH: u32 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MapGenParams {
+    // Maps generated with the same seed and parameters are always the same
+    pub seed: u64,
+    pub width: u32,
+    pub height: u32,
+    // Portion of the height range that's under water, from 0 to 1
+    pub sea_level: f32,
+    // How much smaller details add to the shape of the land, from 0 for
+    // smooth hills to 1 for very rough ground
+    pub roughness: f32,
+    pub rivers: u32,
+}
+
+impl Default for MapGenParams {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            width: 128,
+            height: 128,
+            sea_level: 0.35,
+            roughness: 0.5,
+            rivers: 3,
+        }
+    }
+}
+
+// Seeded 2D gradient noise
+struct Noise {
+    permutation: Vec<u8>,
+}
+
+impl Noise {
+    fn new(rng: &mut Pcg32) -> Self {
+        let mut permutation: Vec<u8> = (0..=255).collect();
+        permutation.shuffle(rng);
+        let repeated = permutation.clone();
+        permutation.extend(repeated);
+
+        Self { permutation }
+    }
+
+    fn gradient(&self, x: i32, y: i32, dx: f32, dy: f32) -> f32 {
+        let hash = self.permutation
+            [self.permutation[(x & 255) as usize] as usize + (y & 255) as usize]
+            & 7;
+        match hash {
+            0 => dx + dy,
+            1 => dx - dy,
+            2 => -dx + dy,
+            3 => -dx - dy,
+            4 => dx,
+            5 => -dx,
+            6 => dy,
+            _ => -dy,
+        }
+    }
+
+    // Roughly between -1 and 1
+    fn get(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        let (u, v) = (fade(dx), fade(dy));
+        lerp(
+            lerp(
+                self.gradient(x0, y0, dx, dy),
+                self.gradient(x0 + 1, y0, dx - 1.0, dy),
+                u,
+            ),
+            lerp(
+                self.gradient(x0, y0 + 1, dx, dy - 1.0),
+                self.gradient(x0 + 1, y0 + 1, dx - 1.0, dy - 1.0),
+                u,
+            ),
+            v,
+        )
+    }
+
+    // Several octaves of noise added together, each twice as detailed as the
+    // last and `persistence` times as strong
+    fn fractal(&self, x: f32, y: f32, scale: f32, persistence: f32) -> f32 {
+        let mut total = 0.0;
+        let mut max = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0 / scale;
+        for _ in 0..OCTAVES {
+            total += self.get(x * frequency, y * frequency) * amplitude;
+            max += amplitude;
+            amplitude *= persistence;
+            frequency *= 2.0;
+        }
+
+        total / max
+    }
+}
+
+// A value for every tile of the map, row by row
+struct Field<T> {
+    width: i32,
+    height: i32,
+    values: Vec<T>,
+}
+
+impl<T: Copy> Field<T> {
+    fn new(width: u32, height: u32, value: T) -> Self {
+        Self {
+            width: width as i32,
+            height: height as i32,
+            values: vec![value; width as usize * height as usize],
+        }
+    }
+
+    fn from_fn<F: FnMut(i32, i32) -> T>(width: u32, height: u32, mut value: F) -> Self {
+        let values = (0..height as i32)
+            .flat_map(|y| (0..width as i32).map(move |x| (x, y)))
+            .map(|(x, y)| value(x, y))
+            .collect();
+
+        Self {
+            width: width as i32,
+            height: height as i32,
+            values,
+        }
+    }
+
+    fn contains(&self, (x, y): (i32, i32)) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    fn get(&self, (x, y): (i32, i32)) -> T {
+        self.values[(y * self.width + x) as usize]
+    }
+
+    fn set(&mut self, (x, y): (i32, i32), value: T) {
+        self.values[(y * self.width + x) as usize] = value;
+    }
+
+    fn neighbors(&self, (x, y): (i32, i32)) -> impl Iterator<Item = (i32, i32)> + '_ {
+        const NEIGHBORS: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+        NEIGHBORS
+            .iter()
+            .map(move |(dx, dy)| (x + dx, y + dy))
+            .filter(move |pos| self.contains(*pos))
+    }
+}
+
+// Generate a new map. The same parameters always give the same map, so maps
+// can be shared by their seed. Sizes are kept from 1 to `MAX_MAP_SIZE`.
+pub fn generate(params: &MapGenParams) -> TileMap {
+    let MapGenParams {
+        seed,
+        width,
+        height,
+        sea_level,
+        roughness,
+        rivers,
+    } = *params;
+    let width = width.clamp(1, MAX_MAP_SIZE);
+    let height = height.clamp(1, MAX_MAP_SIZE);
+    if (width, height) != (params.width, params.height) {
+        println!(
+            "Can't generate a {}x{} map, generating a {}x{} one instead",
+            params.width, params.height, width, height
+        );
+    }
+    let roughness = roughness.clamp(0.0, 1.0);
+
+    // Every layer gets its own noise so changing one doesn't move the others
+    let mut rng = Pcg32::seed_from_u64(seed);
+    let height_noise = Noise::new(&mut rng);
+    let moisture_noise = Noise::new(&mut rng);
+    let forest_noise = Noise::new(&mut rng);
+    let ore_noise = Noise::new(&mut rng);
+
+    // Stretch the heights to fill the whole range so the sea level means the
+    // same thing for every seed
+    let mut heights = Field::from_fn(width, height, |x, y| {
+        height_noise.fractal(x as f32, y as f32, HEIGHT_SCALE, roughness)
+    });
+    let lowest = heights.values.iter().cloned().fold(f32::MAX, f32::min);
+    let highest = heights.values.iter().cloned().fold(f32::MIN, f32::max);
+    let range = (highest - lowest).max(f32::EPSILON);
+    for value in heights.values.iter_mut() {
+        *value = (*value - lowest) / range;
+    }
+
+    let mut water = Field::from_fn(width, height, |x, y| heights.get((x, y)) < sea_level);
+    for _ in 0..rivers {
+        carve_river(&mut rng, &mut heights, &mut water, sea_level);
+    }
+    let water_distance = distance_to_water(&water);
+
+    let mut map = TileMap::new(width, height);
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let pos = (x, y);
+            let height = heights.get(pos);
+            let moisture = moisture_noise.fractal(x as f32, y as f32, MOISTURE_SCALE, 0.5);
+            let terrain = if water.get(pos) {
+                Terrain::Water
+            } else if height < sea_level + BEACH_HEIGHT {
+                Terrain::Sand
+            } else if height > ROCK_LEVEL {
+                Terrain::Rock
+            } else if moisture < -0.25 {
+                Terrain::Dirt
+            } else {
+                Terrain::Grass
+            };
+
+            let mut tile = Tile {
+                terrain,
+                height: (height.clamp(0.0, 1.0) * 255.0).round() as u8,
+                ..Tile::default()
+            };
+
+            // Trees grow in patches on wet enough land
+            if let Terrain::Grass | Terrain::Dirt = terrain {
+                let forest =
+                    forest_noise.fractal(x as f32, y as f32, FOREST_SCALE, 0.5) + moisture * 0.5;
+                tile.forest = to_byte(forest / 0.3);
+            }
+
+            // Ore is found in veins, mostly up in the hills
+            if terrain != Terrain::Water {
+                let vein = ore_noise.fractal(x as f32, y as f32, ORE_SCALE, 0.5);
+                tile.ore = to_byte((vein - 0.1) / 0.3 * (0.5 + height));
+            }
+
+            // Wet ground near water is the best for farming
+            if let Terrain::Grass | Terrain::Dirt = terrain {
+                let near_water = 1.0 - water_distance.get(pos) as f32 / WATER_REACH as f32;
+                tile.fertility = to_byte((moisture * 0.5 + 0.5) * 0.6 + near_water * 0.4);
+            }
+
+            map.set(pos, tile);
+        }
+    }
+
+    map
+}
+
+fn to_byte(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+// Run a river downhill from a random spot high up until it reaches other
+// water or the edge of the map. Where it gets stuck in a dip, it digs
+// through the lowest side.
+fn carve_river(rng: &mut Pcg32, heights: &mut Field<f32>, water: &mut Field<bool>, sea_level: f32) {
+    let source_level = sea_level + (1.0 - sea_level) * 0.4;
+    let source = (0..100)
+        .map(|_| {
+            (
+                rng.gen_range(0, heights.width),
+                rng.gen_range(0, heights.height),
+            )
+        })
+        .find(|&pos| !water.get(pos) && heights.get(pos) > source_level);
+    let mut pos = match source {
+        Some(pos) => pos,
+        None => return,
+    };
+
+    let mut river = Field::new(heights.width as u32, heights.height as u32, false);
+    loop {
+        river.set(pos, true);
+        water.set(pos, true);
+
+        let at_edge =
+            pos.0 == 0 || pos.1 == 0 || pos.0 == heights.width - 1 || pos.1 == heights.height - 1;
+        if at_edge {
+            break;
+        }
+
+        let next = heights
+            .neighbors(pos)
+            .filter(|&next| !river.get(next))
+            .min_by(|&a, &b| {
+                heights
+                    .get(a)
+                    .partial_cmp(&heights.get(b))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        let next = match next {
+            Some(next) => next,
+            None => break,
+        };
+        if water.get(next) {
+            break;
+        }
+
+        // Water can't flow uphill, so dig the next tile down to this one
+        let here = heights.get(pos);
+        if heights.get(next) > here {
+            heights.set(next, here);
+        }
+        pos = next;
+    }
+}
+
+// How many tiles away the nearest water is, up to `WATER_REACH`
+fn distance_to_water(water: &Field<bool>) -> Field<u32> {
+    let mut distance = Field::new(water.width as u32, water.height as u32, WATER_REACH);
+    let mut queue = VecDeque::new();
+    for y in 0..water.height {
+        for x in 0..water.width {
+            if water.get((x, y)) {
+                distance.set((x, y), 0);
+                queue.push_back((x, y));
+            }
+        }
+    }
+
+    while let Some(pos) = queue.pop_front() {
+        let next_distance = distance.get(pos) + 1;
+        if next_distance >= WATER_REACH {
+            continue;
+        }
+        for next in water.neighbors(pos) {
+            if distance.get(next) > next_distance {
+                distance.set(next, next_distance);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(seed: u64) -> MapGenParams {
+        MapGenParams {
+            seed,
+            width: 80,
+            height: 48,
+            ..MapGenParams::default()
+        }
+    }
+
+    fn tiles(map: &TileMap) -> Vec<Tile> {
+        map.rect((0, 0), (map.width() as i32 - 1, map.height() as i32 - 1))
+            .map(|pos| *map.get(pos).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_map() {
+        assert_eq!(
+            tiles(&generate(&params(1234))),
+            tiles(&generate(&params(1234)))
+        );
+        assert_ne!(
+            tiles(&generate(&params(1234))),
+            tiles(&generate(&params(4321)))
+        );
+    }
+
+    #[test]
+    fn sea_level_controls_the_water() {
+        let water = |sea_level| {
+            let map = generate(&MapGenParams {
+                sea_level,
+                rivers: 0,
+                ..params(7)
+            });
+            tiles(&map)
+                .iter()
+                .filter(|tile| tile.terrain == Terrain::Water)
+                .count()
+        };
+
+        assert_eq!(water(0.0), 0);
+        assert!(water(0.3) < water(0.6));
+        assert_eq!(water(1.01), 80 * 48);
+    }
+
+    #[test]
+    fn rivers_add_water() {
+        let dry = generate(&MapGenParams {
+            rivers: 0,
+            ..params(99)
+        });
+        let wet = generate(&MapGenParams {
+            rivers: 4,
+            ..params(99)
+        });
+        let count = |map: &TileMap| {
+            tiles(map)
+                .iter()
+                .filter(|tile| tile.terrain == Terrain::Water)
+                .count()
+        };
+
+        assert!(count(&wet) > count(&dry));
+    }
+
+    #[test]
+    fn empty_maps_are_made_one_tile_wide() {
+        for &(width, height) in [(0, 0), (0, 10), (10, 0)].iter() {
+            let map = generate(&MapGenParams {
+                width,
+                height,
+                rivers: 4,
+                ..params(3)
+            });
+            assert_eq!((map.width(), map.height()), (width.max(1), height.max(1)));
+        }
+    }
+}
@@ -0,0 +1,807 @@
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::roads::{NodeId, RoadNetwork, SegmentId};
+use nalgebra::Vector2;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+// Networks with fewer nodes than this are searched directly, since building
+// the hierarchy would take longer than it saves
+const HIERARCHY_MIN_NODES: usize = 1024;
+
+// The width of the square clusters of the hierarchy in tiles
+const CLUSTER_SIZE: f32 = 16.0;
+
+// Trips to clusters at most this many clusters away are searched road by
+// road instead of with the hierarchy
+const DIRECT_SEARCH_CLUSTERS: i32 = 2;
+
+// Only this many of the fastest roads crossing between two clusters are used
+// by the hierarchy
+const MAX_BORDER_CROSSINGS: usize = 2;
+
+// The cache is emptied when it holds this many paths
+const MAX_CACHED_PATHS: usize = 4096;
+
+// How much slower roads get as they fill up. Travel times follow the BPR
+// curve traffic engineers use: `1 + CONGESTION_FACTOR * load^4`.
+const CONGESTION_FACTOR: f32 = 0.15;
+
+// Cached paths are kept until the load on some road has changed by more than
+// this since they were found
+const REROUTE_LOAD_CHANGE: f32 = 0.2;
+
+// A route over the roads from the first node to the last one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Path {
+    pub nodes: Vec<NodeId>,
+    // The segments between each pair of nodes
+    pub segments: Vec<SegmentId>,
+    // How long driving the route takes in seconds
+    pub cost: f32,
+}
+
+// What makes driving along each road take longer than its speed limit says
+#[derive(Debug, Clone, Default)]
+pub struct TravelCosts {
+    // How full each segment is, as vehicles on it over its capacity
+    congestion: HashMap<SegmentId, f32>,
+    // Increased every time the congestion changes
+    revision: u64,
+}
+
+impl TravelCosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn congestion(&self, segment: SegmentId) -> f32 {
+        self.congestion.get(&segment).cloned().unwrap_or(0.0)
+    }
+
+    // Replace the congestion of every segment at once, so the path finder
+    // only has to update its costs once
+    pub fn set_congestion(&mut self, congestion: HashMap<SegmentId, f32>) {
+        self.congestion = congestion;
+        self.revision += 1;
+    }
+
+    // How long driving the whole segment takes in seconds
+    pub fn segment_cost(&self, network: &RoadNetwork, segment: SegmentId) -> Option<f32> {
+        let road_type = network.segment(segment)?.road_type;
+        let length = network.segment_length(segment)?;
+        let load = self.congestion(segment);
+        Some(length / road_type.speed() * (1.0 + CONGESTION_FACTOR * load.powi(4)))
+    }
+}
+
+// A road leaving a node of the graph
+#[derive(Debug, Copy, Clone)]
+struct Edge {
+    to: usize,
+    segment: SegmentId,
+    cost: f32,
+    speed: f32,
+}
+
+// The road network packed for searching, with the nodes numbered from 0 and
+// the cost of every segment worked out once instead of on every search
+#[derive(Debug, Clone)]
+pub struct RoadGraph {
+    ids: Vec<NodeId>,
+    indices: HashMap<NodeId, usize>,
+    positions: Vec<Vector2<f32>>,
+    edges: Vec<Vec<Edge>>,
+    // The fastest road in the network, which keeps the heuristic from ever
+    // overestimating
+    max_speed: f32,
+}
+
+impl RoadGraph {
+    pub fn new(network: &RoadNetwork, costs: &TravelCosts) -> Self {
+        let ids: Vec<NodeId> = network.nodes().map(|(id, _)| id).collect();
+        let indices: HashMap<NodeId, usize> =
+            ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let positions = network.nodes().map(|(_, node)| node.position).collect();
+
+        let mut edges = vec![Vec::new(); ids.len()];
+        let mut max_speed: f32 = 0.0;
+        for (segment, road) in network.segments() {
+            let cost = match costs.segment_cost(network, segment) {
+                Some(cost) => cost,
+                None => continue,
+            };
+            let (from, to) = (indices[&road.from], indices[&road.to]);
+            let speed = road.road_type.speed();
+            edges[from].push(Edge {
+                to,
+                segment,
+                cost,
+                speed,
+            });
+            edges[to].push(Edge {
+                to: from,
+                segment,
+                cost,
+                speed,
+            });
+            max_speed = max_speed.max(speed);
+        }
+
+        Self {
+            ids,
+            indices,
+            positions,
+            edges,
+            max_speed,
+        }
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    // Work out the cost of every segment again after the congestion changed
+    fn update_costs(&mut self, network: &RoadNetwork, costs: &TravelCosts) {
+        for edge in self.edges.iter_mut().flatten() {
+            if let Some(cost) = costs.segment_cost(network, edge.segment) {
+                edge.cost = cost;
+            }
+        }
+    }
+
+    // The least time it could take to drive between two nodes
+    fn heuristic(&self, from: usize, to: usize) -> f32 {
+        (self.positions[to] - self.positions[from]).norm() / self.max_speed
+    }
+
+    fn cluster(&self, node: usize) -> (i32, i32) {
+        let position = self.positions[node];
+        (
+            (position.x / CLUSTER_SIZE).floor() as i32,
+            (position.y / CLUSTER_SIZE).floor() as i32,
+        )
+    }
+
+    // Turn the nodes and edges found by a search into a path
+    fn path(&self, nodes: &[usize], segments: Vec<SegmentId>, cost: f32) -> Path {
+        Path {
+            nodes: nodes.iter().map(|node| self.ids[*node]).collect(),
+            segments,
+            cost,
+        }
+    }
+}
+
+// A node waiting to be searched, ordered so the heap pops the lowest
+// estimated cost first
+struct Open {
+    estimate: f32,
+    cost: f32,
+    node: usize,
+}
+
+impl PartialEq for Open {
+    fn eq(&self, other: &Self) -> bool {
+        self.estimate == other.estimate
+    }
+}
+
+impl Eq for Open {}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimate
+            .partial_cmp(&self.estimate)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+// The cost of the cheapest path found to each node, and the node and edge
+// it was reached from
+type Reached<E> = HashMap<usize, (f32, Option<(usize, E)>)>;
+
+// A* from `start` to `goal`, or Dijkstra to every node reachable when there
+// is no goal. `neighbors` adds the nodes next to a node, along with the edge
+// to them and its cost. Returns the cost of the cheapest path to each node
+// reached and the step taken into it.
+fn search<E, F, H>(start: usize, goal: Option<usize>, mut neighbors: F, heuristic: H) -> Reached<E>
+where
+    E: Clone,
+    F: FnMut(usize, &mut Vec<(usize, E, f32)>),
+    H: Fn(usize) -> f32,
+{
+    let mut open = BinaryHeap::new();
+    let mut reached = HashMap::new();
+    let mut next = Vec::new();
+
+    reached.insert(start, (0.0, None));
+    open.push(Open {
+        estimate: heuristic(start),
+        cost: 0.0,
+        node: start,
+    });
+
+    while let Some(Open { cost, node, .. }) = open.pop() {
+        if Some(node) == goal {
+            break;
+        }
+        // Skip nodes that were reached more cheaply since they were queued
+        if reached.get(&node).is_some_and(|(best, _)| cost > *best) {
+            continue;
+        }
+
+        next.clear();
+        neighbors(node, &mut next);
+        for (neighbor, edge, edge_cost) in next.drain(..) {
+            let cost = cost + edge_cost;
+            if reached
+                .get(&neighbor)
+                .is_some_and(|(best, _)| cost >= *best)
+            {
+                continue;
+            }
+
+            reached.insert(neighbor, (cost, Some((node, edge))));
+            open.push(Open {
+                estimate: cost + heuristic(neighbor),
+                cost,
+                node: neighbor,
+            });
+        }
+    }
+
+    reached
+}
+
+// Follow the steps found by `search` back from `end` to the start. Returns
+// the nodes and edges along the way, and the cost.
+fn steps_to<E: Clone>(reached: &Reached<E>, end: usize) -> Option<(Vec<usize>, Vec<E>, f32)> {
+    let (cost, _) = reached.get(&end)?;
+    let mut nodes = vec![end];
+    let mut edges = Vec::new();
+    while let Some((_, Some((previous, edge)))) = reached.get(nodes.last().unwrap()) {
+        edges.push(edge.clone());
+        nodes.push(*previous);
+    }
+    nodes.reverse();
+    edges.reverse();
+    Some((nodes, edges, *cost))
+}
+
+fn road_neighbors(graph: &RoadGraph, node: usize, next: &mut Vec<(usize, SegmentId, f32)>) {
+    next.extend(
+        graph.edges[node]
+            .iter()
+            .map(|edge| (edge.to, edge.segment, edge.cost)),
+    );
+}
+
+// The fastest path between two nodes, searching the whole network
+pub fn find_path(graph: &RoadGraph, from: NodeId, to: NodeId) -> Option<Path> {
+    let (from, to) = (*graph.indices.get(&from)?, *graph.indices.get(&to)?);
+    let reached = search(
+        from,
+        Some(to),
+        |node, next| road_neighbors(graph, node, next),
+        |node| graph.heuristic(node, to),
+    );
+    steps_to(&reached, to).map(|(nodes, segments, cost)| graph.path(&nodes, segments, cost))
+}
+
+// The fastest paths from `start` to every node in its cluster that can be
+// reached without leaving the cluster
+fn search_cluster(graph: &RoadGraph, start: usize) -> Reached<SegmentId> {
+    let cluster = graph.cluster(start);
+    search(
+        start,
+        None,
+        |node, next| {
+            road_neighbors(graph, node, next);
+            next.retain(|(neighbor, _, _)| graph.cluster(*neighbor) == cluster);
+        },
+        |_| 0.0,
+    )
+}
+
+// The fastest path within a cluster, as graph nodes
+#[derive(Debug, Clone)]
+struct ClusterPath {
+    nodes: Vec<usize>,
+    segments: Vec<SegmentId>,
+    cost: f32,
+}
+
+impl ClusterPath {
+    fn new((nodes, segments, cost): (Vec<usize>, Vec<SegmentId>, f32)) -> Self {
+        Self {
+            nodes,
+            segments,
+            cost,
+        }
+    }
+
+    fn reversed(mut self) -> Self {
+        self.nodes.reverse();
+        self.segments.reverse();
+        self
+    }
+
+    fn end(&self) -> usize {
+        *self.nodes.last().unwrap()
+    }
+}
+
+// The network split into square clusters, with the fastest paths between
+// the nodes on the edges of each cluster worked out ahead of time. Searches
+// hop between those nodes instead of over every road, and only search the
+// clusters the path starts and ends in road by road, or the whole way for
+// trips to nearby clusters. Only the fastest roads
+// between clusters are used, so paths found this way may be a little slower
+// than the fastest one.
+#[derive(Debug, Clone)]
+pub struct PathHierarchy {
+    // The nodes with roads leaving their cluster, in each cluster
+    entrances: HashMap<(i32, i32), Vec<usize>>,
+    // The paths leaving each entrance for other entrances
+    edges: HashMap<usize, Vec<ClusterPath>>,
+}
+
+// How a search over the hierarchy got to a node
+#[derive(Debug, Copy, Clone)]
+enum Hop {
+    // The path at this index in the hierarchy's edges of the previous node
+    Edge(usize),
+    // Within the cluster the search started or ended in
+    Start,
+    Goal,
+}
+
+impl PathHierarchy {
+    pub fn build(graph: &RoadGraph) -> Self {
+        let mut entrances: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        let mut edges: HashMap<usize, Vec<ClusterPath>> = HashMap::new();
+
+        // Find the roads crossing each border between two clusters
+        let mut borders: HashMap<_, Vec<(usize, Edge)>> = HashMap::new();
+        for (from, leaving) in graph.edges.iter().enumerate() {
+            for edge in leaving.iter() {
+                let clusters = (graph.cluster(from), graph.cluster(edge.to));
+                if clusters.0 < clusters.1 {
+                    borders.entry(clusters).or_default().push((from, *edge));
+                }
+            }
+        }
+
+        // The fastest of them, spread out along the border, join the
+        // entrances on either side
+        for crossings in borders.values_mut() {
+            let fastest = crossings
+                .iter()
+                .map(|(_, edge)| edge.speed)
+                .fold(0.0, f32::max);
+            crossings.retain(|(_, edge)| edge.speed >= fastest);
+            crossings.sort_by(|(a, _), (b, _)| {
+                let (a, b) = (graph.positions[*a], graph.positions[*b]);
+                (a.x + a.y)
+                    .partial_cmp(&(b.x + b.y))
+                    .unwrap_or(Ordering::Equal)
+            });
+
+            let kept = crossings.len().min(MAX_BORDER_CROSSINGS);
+            for i in 0..kept {
+                let (from, edge) = crossings[(2 * i + 1) * crossings.len() / (2 * kept)];
+                for &(a, b) in [(from, edge.to), (edge.to, from)].iter() {
+                    let cluster = entrances.entry(graph.cluster(a)).or_default();
+                    if !cluster.contains(&a) {
+                        cluster.push(a);
+                    }
+                    edges.entry(a).or_default().push(ClusterPath {
+                        nodes: vec![a, b],
+                        segments: vec![edge.segment],
+                        cost: edge.cost,
+                    });
+                }
+            }
+        }
+
+        // And so do the roads through each cluster
+        for cluster in entrances.values() {
+            for &start in cluster.iter() {
+                let reached = search_cluster(graph, start);
+                let paths = cluster
+                    .iter()
+                    .filter(|end| **end != start)
+                    .filter_map(|end| steps_to(&reached, *end))
+                    .map(ClusterPath::new);
+                edges.entry(start).or_default().extend(paths);
+            }
+        }
+
+        Self { entrances, edges }
+    }
+
+    // Work out the cost of the paths between entrances again after the
+    // congestion changed. The paths themselves are kept until the roads
+    // change.
+    fn update_costs(&mut self, network: &RoadNetwork, costs: &TravelCosts) {
+        for path in self.edges.values_mut().flatten() {
+            path.cost = path
+                .segments
+                .iter()
+                .filter_map(|segment| costs.segment_cost(network, *segment))
+                .sum();
+        }
+    }
+
+    pub fn find_path(&self, graph: &RoadGraph, from: NodeId, to: NodeId) -> Option<Path> {
+        let (from_id, to_id) = (from, to);
+        let (from, to) = (*graph.indices.get(&from)?, *graph.indices.get(&to)?);
+        let (from_cluster, to_cluster) = (graph.cluster(from), graph.cluster(to));
+
+        // Short trips are quick to search road by road, and would go out of
+        // their way to get to the roads the hierarchy uses
+        let apart = (from_cluster.0 - to_cluster.0)
+            .abs()
+            .max((from_cluster.1 - to_cluster.1).abs());
+        if apart <= DIRECT_SEARCH_CLUSTERS {
+            return find_path(graph, from_id, to_id);
+        }
+
+        let from_reached = search_cluster(graph, from);
+        let to_reached = search_cluster(graph, to);
+        let no_entrances = Vec::new();
+        let from_entrances = self.entrances.get(&from_cluster).unwrap_or(&no_entrances);
+        let to_entrances = self.entrances.get(&to_cluster).unwrap_or(&no_entrances);
+
+        let reached = search(
+            from,
+            Some(to),
+            |node, next| {
+                if node == from {
+                    for &entrance in from_entrances.iter() {
+                        if let Some((cost, _)) = from_reached.get(&entrance) {
+                            next.push((entrance, Hop::Start, *cost));
+                        }
+                    }
+                }
+                if let Some(paths) = self.edges.get(&node) {
+                    for (i, path) in paths.iter().enumerate() {
+                        next.push((path.end(), Hop::Edge(i), path.cost));
+                    }
+                }
+                if to_entrances.contains(&node) {
+                    if let Some((cost, _)) = to_reached.get(&node) {
+                        next.push((to, Hop::Goal, *cost));
+                    }
+                }
+            },
+            |node| graph.heuristic(node, to),
+        );
+
+        // Put the whole path back together from the hops
+        let hierarchical = steps_to(&reached, to).and_then(|(nodes, hops, _)| {
+            let mut path = ClusterPath::new((vec![from], Vec::new(), 0.0));
+            for (pair, hop) in nodes.windows(2).zip(hops.iter()) {
+                let step = match hop {
+                    Hop::Edge(i) => self.edges[&pair[0]][*i].clone(),
+                    Hop::Start => ClusterPath::new(steps_to(&from_reached, pair[1])?),
+                    Hop::Goal => ClusterPath::new(steps_to(&to_reached, pair[0])?).reversed(),
+                };
+                path.nodes.extend_from_slice(&step.nodes[1..]);
+                path.segments.extend_from_slice(&step.segments);
+                path.cost += step.cost;
+            }
+            Some(path)
+        });
+
+        // Roads left out of the hierarchy can be the only way between two
+        // nodes, so make sure there really is no path
+        match hierarchical {
+            Some(path) => Some(graph.path(&path.nodes, path.segments, path.cost)),
+            None => find_path(graph, from_id, to_id),
+        }
+    }
+}
+
+// Finds paths over the roads and remembers them until the roads or the
+// traffic on them change
+#[derive(Debug, Default)]
+pub struct PathFinder {
+    cache: HashMap<(NodeId, NodeId), Option<Path>>,
+    graph: Option<RoadGraph>,
+    hierarchy: Option<PathHierarchy>,
+    // The revisions of the network the graph was built from and of the costs
+    // it was last updated with
+    network_revision: Option<u64>,
+    costs_revision: Option<u64>,
+    // The congestion when the cached paths were found
+    cached_congestion: HashMap<SegmentId, f32>,
+}
+
+impl PathFinder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cached_paths(&self) -> usize {
+        self.cache.len()
+    }
+
+    // Rebuild everything when the roads change. When only the congestion
+    // does, update the costs in place, and forget the cached paths once the
+    // load on some road has changed enough to send traffic another way.
+    fn update(&mut self, network: &RoadNetwork, costs: &TravelCosts) {
+        if self.network_revision != Some(network.revision()) {
+            self.cache.clear();
+            self.graph = None;
+            self.hierarchy = None;
+            self.network_revision = Some(network.revision());
+            self.costs_revision = Some(costs.revision());
+            self.cached_congestion = costs.congestion.clone();
+            return;
+        }
+        if self.costs_revision == Some(costs.revision()) {
+            return;
+        }
+        self.costs_revision = Some(costs.revision());
+
+        if let Some(graph) = &mut self.graph {
+            graph.update_costs(network, costs);
+        }
+        if let Some(hierarchy) = &mut self.hierarchy {
+            hierarchy.update_costs(network, costs);
+        }
+
+        let moved = |from: &HashMap<SegmentId, f32>, to: &HashMap<SegmentId, f32>| {
+            from.iter().any(|(segment, load)| {
+                (load - to.get(segment).cloned().unwrap_or(0.0)).abs() > REROUTE_LOAD_CHANGE
+            })
+        };
+        if moved(&costs.congestion, &self.cached_congestion)
+            || moved(&self.cached_congestion, &costs.congestion)
+        {
+            self.cache.clear();
+            self.cached_congestion = costs.congestion.clone();
+        }
+    }
+
+    pub fn find_path(
+        &mut self,
+        network: &RoadNetwork,
+        costs: &TravelCosts,
+        from: NodeId,
+        to: NodeId,
+    ) -> Option<Path> {
+        self.update(network, costs);
+        if let Some(path) = self.cache.get(&(from, to)) {
+            return path.clone();
+        }
+
+        let graph = self
+            .graph
+            .get_or_insert_with(|| RoadGraph::new(network, costs));
+        let path = if graph.node_count() < HIERARCHY_MIN_NODES {
+            find_path(graph, from, to)
+        } else {
+            self.hierarchy
+                .get_or_insert_with(|| PathHierarchy::build(graph))
+                .find_path(graph, from, to)
+        };
+
+        if self.cache.len() >= MAX_CACHED_PATHS {
+            self.cache.clear();
+        }
+        self.cache.insert((from, to), path.clone());
+        path
+    }
+}
+
+pub struct PathfindingPlugin;
+
+impl Plugin for PathfindingPlugin {
+    fn name(&self) -> &'static str {
+        "pathfinding"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["roads"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .insert_resource(TravelCosts::new())
+            .insert_resource(PathFinder::new());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roads::{tile_center, RoadType};
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+    use std::time::Instant;
+
+    // A grid of streets every `spacing` tiles with an avenue every fourth
+    // street
+    fn grid(size: i32, spacing: i32) -> RoadNetwork {
+        let mut network = RoadNetwork::new();
+        for (i, line) in (0..=size).step_by(spacing as usize).enumerate() {
+            let road_type = if i % 4 == 0 {
+                RoadType::Avenue
+            } else {
+                RoadType::Street
+            };
+            network.add_road(tile_center((0, line)), tile_center((size, line)), road_type);
+            network.add_road(tile_center((line, 0)), tile_center((line, size)), road_type);
+        }
+        network
+    }
+
+    fn node(network: &RoadNetwork, tile: (i32, i32)) -> NodeId {
+        network.node_at(tile_center(tile)).unwrap()
+    }
+
+    fn check_path(network: &RoadNetwork, path: &Path, from: NodeId, to: NodeId) {
+        assert_eq!(path.nodes.first(), Some(&from));
+        assert_eq!(path.nodes.last(), Some(&to));
+        assert_eq!(path.nodes.len(), path.segments.len() + 1);
+        for (pair, segment) in path.nodes.windows(2).zip(path.segments.iter()) {
+            assert_eq!(network.segment_between(pair[0], pair[1]), Some(*segment));
+        }
+    }
+
+    #[test]
+    fn paths_prefer_faster_roads() {
+        // A direct street, and a highway around it that's longer but faster
+        let mut network = RoadNetwork::new();
+        network.add_road(tile_center((0, 0)), tile_center((10, 0)), RoadType::Street);
+        network.add_road(tile_center((0, 0)), tile_center((0, 2)), RoadType::Highway);
+        network.add_road(tile_center((0, 2)), tile_center((10, 2)), RoadType::Highway);
+        network.add_road(
+            tile_center((10, 2)),
+            tile_center((10, 0)),
+            RoadType::Highway,
+        );
+
+        let (from, to) = (node(&network, (0, 0)), node(&network, (10, 0)));
+        let graph = RoadGraph::new(&network, &TravelCosts::new());
+        let path = find_path(&graph, from, to).unwrap();
+        check_path(&network, &path, from, to);
+        assert_eq!(path.segments.len(), 3);
+        assert!((path.cost - 14.0 / 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn congestion_sends_paths_around_jams() {
+        let mut network = RoadNetwork::new();
+        let direct = network.add_road(tile_center((0, 0)), tile_center((10, 0)), RoadType::Street);
+        network.add_road(tile_center((0, 0)), tile_center((0, 3)), RoadType::Street);
+        network.add_road(tile_center((0, 3)), tile_center((10, 3)), RoadType::Street);
+        network.add_road(tile_center((10, 3)), tile_center((10, 0)), RoadType::Street);
+
+        let (from, to) = (node(&network, (0, 0)), node(&network, (10, 0)));
+        let mut costs = TravelCosts::new();
+        let mut finder = PathFinder::new();
+        assert_eq!(
+            finder
+                .find_path(&network, &costs, from, to)
+                .unwrap()
+                .segments,
+            direct
+        );
+        assert_eq!(finder.cached_paths(), 1);
+
+        // A little traffic isn't worth finding new paths for
+        finder.find_path(&network, &costs, to, from);
+        costs.set_congestion(direct.iter().map(|id| (*id, 0.1)).collect());
+        finder.find_path(&network, &costs, from, to);
+        assert_eq!(finder.cached_paths(), 2);
+
+        costs.set_congestion(direct.iter().map(|id| (*id, 2.0)).collect());
+        let path = finder.find_path(&network, &costs, from, to).unwrap();
+        assert_eq!(path.segments.len(), 3);
+    }
+
+    #[test]
+    fn cached_paths_are_forgotten_when_roads_change() {
+        let mut network = RoadNetwork::new();
+        network.add_road(tile_center((0, 0)), tile_center((4, 0)), RoadType::Street);
+        network.add_road(tile_center((8, 0)), tile_center((12, 0)), RoadType::Street);
+
+        let (from, to) = (node(&network, (0, 0)), node(&network, (12, 0)));
+        let costs = TravelCosts::new();
+        let mut finder = PathFinder::new();
+        assert_eq!(finder.find_path(&network, &costs, from, to), None);
+
+        network.add_road(tile_center((4, 0)), tile_center((8, 0)), RoadType::Street);
+        let path = finder.find_path(&network, &costs, from, to).unwrap();
+        check_path(&network, &path, from, to);
+    }
+
+    #[test]
+    fn hierarchical_paths_are_close_to_the_fastest() {
+        let network = grid(64, 4);
+        let graph = RoadGraph::new(&network, &TravelCosts::new());
+        let hierarchy = PathHierarchy::build(&graph);
+
+        let mut rng = Pcg32::seed_from_u64(7);
+        let nodes: Vec<NodeId> = network.nodes().map(|(id, _)| id).collect();
+        let (mut fastest_cost, mut hierarchical_cost) = (0.0, 0.0);
+        for _ in 0..100 {
+            let from = nodes[rng.gen_range(0, nodes.len())];
+            let to = nodes[rng.gen_range(0, nodes.len())];
+            let fastest = find_path(&graph, from, to).unwrap();
+            let path = hierarchy.find_path(&graph, from, to).unwrap();
+            check_path(&network, &path, from, to);
+            assert!(path.cost >= fastest.cost - 1e-3);
+            assert!(path.cost <= fastest.cost * 2.0 + 1e-3);
+
+            fastest_cost += fastest.cost;
+            hierarchical_cost += path.cost;
+        }
+        assert!(hierarchical_cost <= fastest_cost * 1.1);
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn benchmark_paths_on_large_grids() {
+        for &size in [64, 128, 256].iter() {
+            let network = grid(size, 4);
+            let nodes: Vec<NodeId> = network.nodes().map(|(id, _)| id).collect();
+            let mut rng = Pcg32::seed_from_u64(size as u64);
+            let pairs: Vec<(NodeId, NodeId)> = (0..1000)
+                .map(|_| {
+                    (
+                        nodes[rng.gen_range(0, nodes.len())],
+                        nodes[rng.gen_range(0, nodes.len())],
+                    )
+                })
+                .collect();
+
+            let started = Instant::now();
+            let graph = RoadGraph::new(&network, &TravelCosts::new());
+            let hierarchy = PathHierarchy::build(&graph);
+            let build = started.elapsed();
+
+            let started = Instant::now();
+            let mut direct_cost = 0.0;
+            for &(from, to) in pairs.iter() {
+                direct_cost += find_path(&graph, from, to).unwrap().cost;
+            }
+            let direct = started.elapsed();
+
+            let started = Instant::now();
+            let mut hierarchical_cost = 0.0;
+            for &(from, to) in pairs.iter() {
+                hierarchical_cost += hierarchy.find_path(&graph, from, to).unwrap().cost;
+            }
+            let hierarchical = started.elapsed();
+
+            println!(
+                "{0}x{0} grid, {1} nodes, {2} paths: A* {3:?}, hierarchy {4:?} \
+                 (built in {5:?}), {6:.1}% slower paths",
+                size,
+                nodes.len(),
+                pairs.len(),
+                direct,
+                hierarchical,
+                build,
+                (hierarchical_cost / direct_cost - 1.0) * 100.0
+            );
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use crate::input::InputState;
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::world::{Camera, GlobalTransform, Pickable};
+use glfw::MouseButton;
+use nalgebra::{Matrix4, Point3, Vector2, Vector3};
+use specs::{Entities, Entity, Join, Read, ReadStorage, System, Write};
+
+// What's under the cursor and what the player has clicked on. Tools read
+// the hovered tile from here rather than doing their own picking.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Selection {
+    pub hovered_point: Option<Vector2<f32>>,
+    pub hovered_tile: Option<(i32, i32)>,
+    pub hovered_entity: Option<Entity>,
+    pub selected_entity: Option<Entity>,
+}
+
+// A ray starting at `origin`. Points along it are `origin + direction * t`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn from_cursor(
+        camera: &Camera,
+        cursor: (f64, f64),
+        window_size: (i32, i32),
+    ) -> Option<Self> {
+        camera
+            .cursor_ray(cursor, window_size)
+            .map(|(origin, direction)| Self { origin, direction })
+    }
+
+    pub fn point_at(&self, t: f32) -> Point3<f32> {
+        self.origin + self.direction * t
+    }
+
+    // Where the ray crosses the ground plane (z = 0)
+    pub fn ground_point(&self) -> Option<Vector2<f32>> {
+        if self.direction.z.abs() < f32::EPSILON {
+            return None;
+        }
+
+        let point = self.point_at(-self.origin.z / self.direction.z);
+        Some(Vector2::new(point.x, point.y))
+    }
+
+    // The distance along the ray to where it enters the entity's bounds,
+    // tested in the entity's local space so rotation and scale are exact
+    pub fn intersect_bounds(
+        &self,
+        object_transform: &Matrix4<f32>,
+        bounds: &Pickable,
+    ) -> Option<f32> {
+        let inverse = object_transform.try_inverse()?;
+        let origin = inverse.transform_point(&self.origin);
+        let direction = inverse.transform_vector(&self.direction);
+
+        // Slab test against each axis of the box
+        let mut t_min = f32::NEG_INFINITY;
+        let mut t_max = f32::INFINITY;
+        for axis in 0..3 {
+            let (min, max) = (bounds.min[axis], bounds.max[axis]);
+            if direction[axis].abs() < f32::EPSILON {
+                // Parallel to the slab, so it has to start inside it
+                if origin[axis] < min || origin[axis] > max {
+                    return None;
+                }
+                continue;
+            }
+
+            let t1 = (min - origin[axis]) / direction[axis];
+            let t2 = (max - origin[axis]) / direction[axis];
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+            if t_min > t_max {
+                return None;
+            }
+        }
+
+        if t_max < 0.0 {
+            None
+        } else {
+            Some(t_min.max(0.0))
+        }
+    }
+}
+
+// Tiles are one world unit wide with their corner at integer coordinates
+pub fn tile_at(point: Vector2<f32>) -> (i32, i32) {
+    (point.x.floor() as i32, point.y.floor() as i32)
+}
+
+// The closest pickable entity along the ray
+pub fn pick_entity<'a, I>(ray: &Ray, candidates: I) -> Option<Entity>
+where
+    I: IntoIterator<Item = (Entity, &'a GlobalTransform, &'a Pickable)>,
+{
+    candidates
+        .into_iter()
+        .filter_map(|(entity, transform, bounds)| {
+            ray.intersect_bounds(&transform.0, bounds)
+                .map(|distance| (entity, distance))
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(entity, _)| entity)
+}
+
+// Updates the selection from the cursor every frame and selects the
+// hovered entity on left click
+#[derive(Default)]
+pub struct PickingSystem;
+
+impl<'a> System<'a> for PickingSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, InputState>,
+        ReadStorage<'a, Camera>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, Pickable>,
+        Write<'a, Selection>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, input, cameras, transforms, pickables, mut selection): Self::SystemData,
+    ) {
+        let ray = match (input.cursor(), (&cameras).join().next()) {
+            (Some(cursor), Some(camera)) if input.is_cursor_in_window() => {
+                Ray::from_cursor(camera, cursor, input.window_size())
+            }
+            _ => None,
+        };
+
+        selection.hovered_point = ray.and_then(|ray| ray.ground_point());
+        selection.hovered_tile = selection.hovered_point.map(tile_at);
+        selection.hovered_entity =
+            ray.and_then(|ray| pick_entity(&ray, (&entities, &transforms, &pickables).join()));
+
+        if input.was_button_pressed(MouseButton::Button1) {
+            selection.selected_entity = selection.hovered_entity;
+        }
+
+        // Forget selected entities that were deleted
+        if let Some(selected) = selection.selected_entity {
+            if !entities.is_alive(selected) {
+                selection.selected_entity = None;
+            }
+        }
+    }
+}
+
+pub struct PickingPlugin;
+
+impl Plugin for PickingPlugin {
+    fn name(&self) -> &'static str {
+        "picking"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["camera"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .save_component::<Pickable>("pickable")
+            .insert_resource(Selection::default())
+            .add_frame_system(PickingSystem, "picking", &["camera_controller"]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Translation3, UnitQuaternion};
+    use specs::{Builder, World, WorldExt};
+    use std::f32::consts::FRAC_PI_4;
+
+    fn down_from(x: f32, y: f32) -> Ray {
+        Ray {
+            origin: Point3::new(x, y, 10.0),
+            direction: Vector3::new(0.0, 0.0, -1.0),
+        }
+    }
+
+    #[test]
+    fn rays_cross_the_ground_where_they_point() {
+        let ray = Ray {
+            origin: Point3::new(1.0, 2.0, 10.0),
+            direction: Vector3::new(1.0, 0.0, -2.0),
+        };
+        assert_eq!(ray.ground_point(), Some(Vector2::new(6.0, 2.0)));
+        let flat = Ray {
+            direction: Vector3::new(1.0, 0.0, 0.0),
+            ..ray
+        };
+        assert_eq!(flat.ground_point(), None);
+        assert_eq!(tile_at(Vector2::new(-0.5, 1.5)), (-1, 1));
+    }
+
+    #[test]
+    fn the_middle_of_the_window_points_at_the_camera_position() {
+        let mut camera = Camera::new(Vector2::new(5.0, 7.0), 2.0);
+        camera.rotation_steps = 1;
+        camera.isometric = true;
+        let ray = Ray::from_cursor(&camera, (400.0, 300.0), (800, 600)).unwrap();
+        let point = ray.ground_point().unwrap();
+        assert!((point - camera.position).norm() < 1e-3, "{}", point);
+
+        assert_eq!(Ray::from_cursor(&camera, (0.0, 0.0), (0, 600)), None);
+    }
+
+    #[test]
+    fn bounds_are_hit_in_the_entitys_space() {
+        let unit = Pickable::default();
+        let identity = Matrix4::identity();
+        assert_eq!(
+            down_from(0.0, 0.0).intersect_bounds(&identity, &unit),
+            Some(9.5)
+        );
+        assert_eq!(down_from(2.0, 0.0).intersect_bounds(&identity, &unit), None);
+
+        // Rays starting inside hit straight away, and boxes behind them aren't hit
+        let inside = Ray {
+            origin: Point3::new(0.0, 0.0, 0.0),
+            ..down_from(0.0, 0.0)
+        };
+        assert_eq!(inside.intersect_bounds(&identity, &unit), Some(0.0));
+        let above = Translation3::new(0.0, 0.0, 20.0).to_homogeneous();
+        assert_eq!(down_from(0.0, 0.0).intersect_bounds(&above, &unit), None);
+
+        // A box four times as long as it's wide, turned to point diagonally
+        let turned = Matrix4::new_translation(&Vector3::new(10.0, 0.0, 0.0))
+            * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), FRAC_PI_4).to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(4.0, 1.0, 1.0));
+        assert!(down_from(11.2, 1.2)
+            .intersect_bounds(&turned, &unit)
+            .is_some());
+        assert!(down_from(11.2, -1.2)
+            .intersect_bounds(&turned, &unit)
+            .is_none());
+
+        // Nothing can be hit through a transform that flattens everything
+        let flat = Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 1.0, 0.0));
+        assert_eq!(down_from(0.0, 0.0).intersect_bounds(&flat, &unit), None);
+    }
+
+    #[test]
+    fn the_closest_entity_is_picked() {
+        let mut world = World::new();
+        let low = world.create_entity().build();
+        let high = world.create_entity().build();
+        let at = |z: f32| GlobalTransform(Translation3::new(0.0, 0.0, z).to_homogeneous());
+        let (low_transform, high_transform) = (at(0.0), at(2.0));
+        let unit = Pickable::default();
+
+        let candidates = vec![(low, &low_transform, &unit), (high, &high_transform, &unit)];
+        assert_eq!(
+            pick_entity(&down_from(0.0, 0.0), candidates.clone()),
+            Some(high)
+        );
+        assert_eq!(pick_entity(&down_from(3.0, 0.0), candidates), None);
+    }
+}
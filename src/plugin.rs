@@ -0,0 +1,386 @@
+use crate::hierarchy::TransformHierarchy;
+use crate::save::{MapEntities, SaveRegistry};
+use crate::simulation::StorePreviousTransforms;
+use crate::world::{
+    Children, DeltaTime, GlobalTransform, InterpolatedTransform, MapInfo, Parent, SimulationTime,
+    Transform,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use specs::shred::Resource;
+use specs::shrev::EventChannel;
+use specs::{Component, Dispatcher, DispatcherBuilder, System, World, WorldExt};
+use std::collections::HashMap;
+
+// A part of the game that adds its components, resources, events and
+// systems to the world. Plugins are built after the plugins they depend on,
+// so their systems can run after (depend on) the systems of those plugins.
+pub trait Plugin {
+    // Unique name that other plugins use to depend on this one
+    fn name(&self) -> &'static str;
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    fn build(&self, builder: &mut WorldBuilder);
+}
+
+// A world along with the systems added by its plugins. The simulation
+// systems run at a fixed rate while the frame systems run once per frame.
+pub struct GameWorld {
+    pub world: World,
+    pub simulation: Dispatcher<'static, 'static>,
+    pub frame_systems: Dispatcher<'static, 'static>,
+}
+
+// Builds a world out of plugins. The transform hierarchy is always included:
+// the simulation starts by remembering transforms for interpolation and ends
+// by updating the global transforms, with the plugins' systems in between.
+pub struct WorldBuilder {
+    world: World,
+    simulation: DispatcherBuilder<'static, 'static>,
+    frame_systems: DispatcherBuilder<'static, 'static>,
+    plugins: Vec<Box<dyn Plugin>>,
+    saved: SaveRegistry,
+}
+
+impl WorldBuilder {
+    pub fn new() -> Self {
+        let mut builder = Self {
+            world: World::new(),
+            simulation: DispatcherBuilder::new(),
+            frame_systems: DispatcherBuilder::new(),
+            plugins: Vec::new(),
+            saved: SaveRegistry::new(),
+        };
+
+        builder
+            .register::<Children>()
+            .register::<GlobalTransform>()
+            .register::<InterpolatedTransform>()
+            .insert_resource(DeltaTime::default())
+            .insert_resource(SimulationTime::default())
+            .insert_resource(MapInfo::default())
+            .save_component::<Transform>("transform")
+            .save_mapped_component::<Parent>("parent")
+            .save_resource::<SimulationTime>("simulation_time")
+            .add_simulation_system(StorePreviousTransforms, "store_previous_transforms", &[])
+            .add_simulation_barrier();
+
+        builder
+    }
+
+    pub fn with_plugin<P: Plugin + 'static>(mut self, plugin: P) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+
+    pub fn register<C>(&mut self) -> &mut Self
+    where
+        C: Component,
+        C::Storage: Default,
+    {
+        self.world.register::<C>();
+        self
+    }
+
+    pub fn insert_resource<R: Resource>(&mut self, resource: R) -> &mut Self {
+        self.world.insert(resource);
+        self
+    }
+
+    // Register a component that's written to save files under the given
+    // name. Components that can be recomputed on load shouldn't be saved.
+    pub fn save_component<C>(&mut self, name: &str) -> &mut Self
+    where
+        C: Component + Serialize + DeserializeOwned + Clone + Send,
+        C::Storage: Default,
+    {
+        self.world.register::<C>();
+        self.saved.add_component::<C>(name);
+        self
+    }
+
+    // Like `save_component`, for components that refer to other entities
+    pub fn save_mapped_component<C>(&mut self, name: &str) -> &mut Self
+    where
+        C: Component + MapEntities,
+        C::Storage: Default,
+    {
+        self.world.register::<C>();
+        self.saved.add_mapped_component::<C>(name);
+        self
+    }
+
+    // Save the resource if it's in the world when saving
+    pub fn save_resource<R>(&mut self, name: &str) -> &mut Self
+    where
+        R: Resource + Serialize + DeserializeOwned + Clone,
+    {
+        self.saved.add_resource::<R>(name);
+        self
+    }
+
+    pub fn save_mapped_resource<R>(&mut self, name: &str) -> &mut Self
+    where
+        R: Resource + MapEntities,
+    {
+        self.saved.add_mapped_resource::<R>(name);
+        self
+    }
+
+    // Upgrade a saved component from `from_version` to the next version,
+    // see `SaveRegistry::add_component_migration`
+    pub fn migrate_component<Old, New, F>(
+        &mut self,
+        name: &str,
+        from_version: u32,
+        migrate: F,
+    ) -> &mut Self
+    where
+        Old: DeserializeOwned,
+        New: Serialize,
+        F: Fn(Old) -> New + Send + Sync + 'static,
+    {
+        self.saved
+            .add_component_migration(name, from_version, migrate);
+        self
+    }
+
+    pub fn migrate_resource<Old, New, F>(
+        &mut self,
+        name: &str,
+        from_version: u32,
+        migrate: F,
+    ) -> &mut Self
+    where
+        Old: DeserializeOwned,
+        New: Serialize,
+        F: Fn(Old) -> New + Send + Sync + 'static,
+    {
+        self.saved
+            .add_resource_migration(name, from_version, migrate);
+        self
+    }
+
+    // Add a channel that systems can send events of this type through
+    pub fn add_event<E: Send + Sync + 'static>(&mut self) -> &mut Self {
+        if !self.world.has_value::<EventChannel<E>>() {
+            self.world.insert(EventChannel::<E>::new());
+        }
+        self
+    }
+
+    pub fn add_simulation_system<S>(
+        &mut self,
+        system: S,
+        name: &str,
+        dependencies: &[&str],
+    ) -> &mut Self
+    where
+        S: for<'a> System<'a> + Send + 'static,
+    {
+        self.simulation.add(system, name, dependencies);
+        self
+    }
+
+    // Every simulation system added after this runs after all of the ones
+    // added before it
+    pub fn add_simulation_barrier(&mut self) -> &mut Self {
+        self.simulation.add_barrier();
+        self
+    }
+
+    pub fn add_frame_system<S>(&mut self, system: S, name: &str, dependencies: &[&str]) -> &mut Self
+    where
+        S: for<'a> System<'a> + Send + 'static,
+    {
+        self.frame_systems.add(system, name, dependencies);
+        self
+    }
+
+    pub fn build(mut self) -> Result<GameWorld, String> {
+        let plugins = sort_plugins(std::mem::take(&mut self.plugins))?;
+        for plugin in plugins.iter() {
+            plugin.build(&mut self);
+        }
+
+        let transform_hierarchy = TransformHierarchy::new(&mut self.world);
+        self.add_simulation_barrier().add_simulation_system(
+            transform_hierarchy,
+            "transform_hierarchy",
+            &[],
+        );
+
+        let mut world = self.world;
+        world.insert(self.saved);
+        let mut simulation = self.simulation.build();
+        simulation.setup(&mut world);
+        let mut frame_systems = self.frame_systems.build();
+        frame_systems.setup(&mut world);
+
+        Ok(GameWorld {
+            world,
+            simulation,
+            frame_systems,
+        })
+    }
+}
+
+impl Default for WorldBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum VisitState {
+    NotVisited,
+    Visiting,
+    Visited,
+}
+
+// Order the plugins so every plugin comes after its dependencies. Plugins
+// otherwise keep the order they were added in.
+fn sort_plugins(plugins: Vec<Box<dyn Plugin>>) -> Result<Vec<Box<dyn Plugin>>, String> {
+    let mut indices = HashMap::new();
+    for (i, plugin) in plugins.iter().enumerate() {
+        if indices.insert(plugin.name(), i).is_some() {
+            return Err(format!("plugin \"{}\" was added twice", plugin.name()));
+        }
+    }
+
+    let mut states = vec![VisitState::NotVisited; plugins.len()];
+    let mut order = Vec::with_capacity(plugins.len());
+    for i in 0..plugins.len() {
+        visit_plugin(i, &plugins, &indices, &mut states, &mut order)?;
+    }
+
+    let mut plugins: Vec<Option<Box<dyn Plugin>>> = plugins.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .filter_map(|i| plugins[i].take())
+        .collect())
+}
+
+fn visit_plugin(
+    i: usize,
+    plugins: &[Box<dyn Plugin>],
+    indices: &HashMap<&'static str, usize>,
+    states: &mut Vec<VisitState>,
+    order: &mut Vec<usize>,
+) -> Result<(), String> {
+    match states[i] {
+        VisitState::Visited => return Ok(()),
+        VisitState::Visiting => {
+            return Err(format!(
+                "plugin \"{}\" has a circular dependency",
+                plugins[i].name()
+            ))
+        }
+        VisitState::NotVisited => {}
+    }
+
+    states[i] = VisitState::Visiting;
+    for dependency in plugins[i].dependencies() {
+        match indices.get(dependency) {
+            Some(&dependency) => visit_plugin(dependency, plugins, indices, states, order)?,
+            None => {
+                return Err(format!(
+                    "plugin \"{}\" depends on \"{}\", which wasn't added",
+                    plugins[i].name(),
+                    dependency
+                ))
+            }
+        }
+    }
+    states[i] = VisitState::Visited;
+    order.push(i);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The names of the plugins in the order they were built
+    #[derive(Default)]
+    struct BuildOrder(Vec<&'static str>);
+
+    struct TestPlugin(&'static str, Vec<&'static str>);
+
+    impl Plugin for TestPlugin {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn dependencies(&self) -> Vec<&'static str> {
+            self.1.clone()
+        }
+
+        fn build(&self, builder: &mut WorldBuilder) {
+            builder
+                .world_mut()
+                .entry::<BuildOrder>()
+                .or_insert_with(BuildOrder::default)
+                .0
+                .push(self.0);
+        }
+    }
+
+    fn build(plugins: Vec<TestPlugin>) -> Result<Vec<&'static str>, String> {
+        let mut builder = WorldBuilder::new();
+        for plugin in plugins {
+            builder = builder.with_plugin(plugin);
+        }
+        let game = builder.build()?;
+        let order = game.world.fetch::<BuildOrder>().0.clone();
+        Ok(order)
+    }
+
+    #[test]
+    fn plugins_are_built_after_their_dependencies() {
+        let order = build(vec![
+            TestPlugin("c", vec!["b"]),
+            TestPlugin("a", vec![]),
+            TestPlugin("b", vec!["a"]),
+            TestPlugin("d", vec![]),
+            TestPlugin("e", vec!["a", "d"]),
+        ]);
+        assert_eq!(order, Ok(vec!["a", "b", "c", "d", "e"]));
+    }
+
+    #[test]
+    fn broken_dependencies_are_errors() {
+        let missing = build(vec![TestPlugin("a", vec!["b"])]);
+        assert_eq!(
+            missing,
+            Err("plugin \"a\" depends on \"b\", which wasn't added".to_owned())
+        );
+
+        let cycle = build(vec![
+            TestPlugin("a", vec!["c"]),
+            TestPlugin("b", vec!["a"]),
+            TestPlugin("c", vec!["b"]),
+        ]);
+        assert_eq!(
+            cycle,
+            Err("plugin \"a\" has a circular dependency".to_owned())
+        );
+
+        let itself = build(vec![TestPlugin("a", vec!["a"])]);
+        assert_eq!(
+            itself,
+            Err("plugin \"a\" has a circular dependency".to_owned())
+        );
+
+        let twice = build(vec![TestPlugin("a", vec![]), TestPlugin("a", vec![])]);
+        assert_eq!(twice, Err("plugin \"a\" was added twice".to_owned()));
+    }
+}
@@ -0,0 +1,525 @@
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::roads::RoadNetwork;
+use crate::tile_renderer::TileMapRenderer;
+use crate::tilemap::TileMap;
+use crate::tools::TileHighlights;
+use crate::traffic;
+use crate::world::{
+    Building, Camera, GlobalTransform, InterpolatedTransform, MeshRenderer, Sprite, Vehicle,
+};
+use gl::types::{GLfloat, GLint, GLushort};
+use gl_bindings::{gl, Gl};
+use nalgebra::{Matrix4, Vector3, Vector4};
+use render::assets::{Assets, Handle};
+use render::shader_source::{ShaderDefines, ShaderLoader};
+use render::{
+    InstanceBuffer, Mesh, ShaderProgram, ShaderStage, Texture, Uniform, Vec2, Vec3, Vec4,
+};
+use specs::storage::ComponentEvent;
+use specs::{Join, ReaderId, World, WorldExt};
+use std::ops::Deref;
+
+// The texture unit sprite textures are bound to
+const SPRITE_TEXTURE_UNIT: u32 = 0;
+
+// The paint colors vehicles are drawn in
+const VEHICLE_COLORS: [[f32; 4]; 4] = [
+    [0.85, 0.2, 0.2, 1.0],
+    [0.2, 0.45, 0.85, 1.0],
+    [0.95, 0.95, 0.9, 1.0],
+    [0.95, 0.75, 0.2, 1.0],
+];
+
+#[derive(render_derive::VertexAttribPointers, Copy, Clone, Debug, PartialEq)]
+#[repr(C, packed)]
+pub struct Vertex {
+    #[location = 0]
+    pub pos: Vec3,
+
+    #[location = 1]
+    pub col: Vec3,
+}
+
+impl Vertex {
+    pub fn new(pos: Vec3, col: Vec3) -> Self {
+        Self { pos, col }
+    }
+}
+
+// The type of the meshes drawn by `MeshRenderer`s
+pub type WorldMesh = Mesh<Vertex, GLushort>;
+
+#[derive(render_derive::VertexAttribPointers, Copy, Clone, Debug)]
+#[repr(C, packed)]
+struct SpriteVertex {
+    #[location = 0]
+    pos: Vec2,
+
+    #[location = 1]
+    uv: Vec2,
+}
+
+// Everything that differs between sprites, so any number of sprites with the
+// same texture can be drawn at once
+#[derive(render_derive::VertexAttribPointers, Copy, Clone, Debug)]
+#[repr(C, packed)]
+struct SpriteInstance {
+    #[location = 2]
+    #[divisor = 1]
+    object_matrix_0: Vec4,
+
+    #[location = 3]
+    #[divisor = 1]
+    object_matrix_1: Vec4,
+
+    #[location = 4]
+    #[divisor = 1]
+    object_matrix_2: Vec4,
+
+    #[location = 5]
+    #[divisor = 1]
+    object_matrix_3: Vec4,
+
+    #[location = 6]
+    #[divisor = 1]
+    color: Vec4,
+}
+
+impl SpriteInstance {
+    fn new(object_matrix: &Matrix4<f32>, color: &Vector4<f32>) -> Self {
+        let column = |i: usize| {
+            let c = object_matrix.column(i);
+            Vec4::new(c[0], c[1], c[2], c[3])
+        };
+
+        Self {
+            object_matrix_0: column(0),
+            object_matrix_1: column(1),
+            object_matrix_2: column(2),
+            object_matrix_3: column(3),
+            color: Vec4::new(color.x, color.y, color.z, color.w),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mat4(pub Matrix4<f32>);
+
+impl Deref for Mat4 {
+    type Target = Matrix4<f32>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Uniform for Mat4 {
+    fn set_uniform(&self, gl: &Gl, location: GLint) {
+        unsafe {
+            gl.UniformMatrix4fv(location, 1, gl::FALSE, self.as_ptr() as *const GLfloat);
+        }
+    }
+}
+
+// An axis aligned box in an entity's local space
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bounds {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Bounds {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn corners(&self) -> [Vector3<f32>; 8] {
+        let (min, max) = (self.min, self.max);
+        [
+            Vector3::new(min.x, min.y, min.z),
+            Vector3::new(max.x, min.y, min.z),
+            Vector3::new(min.x, max.y, min.z),
+            Vector3::new(max.x, max.y, min.z),
+            Vector3::new(min.x, min.y, max.z),
+            Vector3::new(max.x, min.y, max.z),
+            Vector3::new(min.x, max.y, max.z),
+            Vector3::new(max.x, max.y, max.z),
+        ]
+    }
+
+    // Whether any part of the box is inside the view once transformed by the
+    // given object-to-clip-space matrix
+    pub fn is_visible(&self, object_to_clip: &Matrix4<f32>) -> bool {
+        let corners: Vec<Vector4<f32>> = self
+            .corners()
+            .iter()
+            .map(|corner| object_to_clip * corner.push(1.0))
+            .collect();
+
+        // The box is hidden when all of its corners are on the outside of the
+        // same clip plane
+        for axis in 0..3 {
+            if corners.iter().all(|c| c[axis] < -c.w) || corners.iter().all(|c| c[axis] > c.w) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // The normalized depth of the center of the box, smaller is closer
+    pub fn depth(&self, object_to_clip: &Matrix4<f32>) -> f32 {
+        let center = object_to_clip * self.center().push(1.0);
+        center.z / center.w
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    pub meshes: usize,
+    pub sprites: usize,
+    pub culled: usize,
+    pub draw_calls: usize,
+    // Tile map chunks drawn, and remeshed because their tiles changed
+    pub chunks: usize,
+    pub chunks_rebuilt: usize,
+}
+
+struct MeshDraw<'a> {
+    renderer: &'a MeshRenderer,
+    object_matrix: Matrix4<f32>,
+    depth: f32,
+}
+
+struct SpriteDraw<'a> {
+    texture: &'a Handle<Texture>,
+    instance: SpriteInstance,
+    depth: f32,
+}
+
+// Draws the tile map, then every entity with a `GlobalTransform` and a
+// `MeshRenderer` or `Sprite` from the point of view of the first camera.
+// Entities outside of the view are skipped. Meshes are sorted by material
+// and mesh to avoid rebinding, then front to back. Sprites are drawn after
+// the meshes, one instanced draw for each texture.
+pub struct Renderer {
+    gl: Gl,
+    sprite_shader: Handle<ShaderProgram>,
+    sprite_quad: Mesh<SpriteVertex, GLushort>,
+    sprite_instances: InstanceBuffer<SpriteInstance>,
+    tile_map: TileMapRenderer,
+    // Changes to buildings since the building mesh was last updated
+    building_events: Option<ReaderId<ComponentEvent>>,
+    stats: RenderStats,
+}
+
+impl Renderer {
+    pub fn new(
+        gl: &Gl,
+        shader_loader: &ShaderLoader,
+        shaders: &mut Assets<ShaderProgram>,
+    ) -> Result<Self, String> {
+        let sprite_shader = shader_loader.load_program(
+            gl,
+            shaders,
+            &[
+                (ShaderStage::Vertex, "sprite_vertex.glsl"),
+                (ShaderStage::Fragment, "sprite_fragment.glsl"),
+            ],
+            &ShaderDefines::new(),
+            &["projection_matrix", "sprite_texture"],
+        )?;
+
+        // A unit square centered on the origin, sprites scale it to their size
+        let sprite_quad = Mesh::create(
+            gl,
+            vec![
+                SpriteVertex {
+                    pos: (-0.5, -0.5).into(),
+                    uv: (0.0, 1.0).into(),
+                },
+                SpriteVertex {
+                    pos: (-0.5, 0.5).into(),
+                    uv: (0.0, 0.0).into(),
+                },
+                SpriteVertex {
+                    pos: (0.5, 0.5).into(),
+                    uv: (1.0, 0.0).into(),
+                },
+                SpriteVertex {
+                    pos: (0.5, -0.5).into(),
+                    uv: (1.0, 1.0).into(),
+                },
+            ],
+            vec![0, 1, 2, 0, 2, 3],
+        );
+
+        Ok(Self {
+            gl: gl.clone(),
+            sprite_shader,
+            sprite_quad,
+            sprite_instances: InstanceBuffer::new(gl),
+            tile_map: TileMapRenderer::new(gl, shader_loader, shaders)?,
+            building_events: None,
+            stats: RenderStats::default(),
+        })
+    }
+
+    // `alpha` is how far between the last two simulation steps to draw
+    // interpolated entities
+    pub fn render(
+        &mut self,
+        world: &World,
+        aspect: f32,
+        alpha: f32,
+        meshes: &Assets<WorldMesh>,
+        shaders: &Assets<ShaderProgram>,
+        textures: &Assets<Texture>,
+    ) {
+        self.stats = RenderStats::default();
+
+        let cameras = world.read_storage::<Camera>();
+        let view_projection = match (&cameras).join().next() {
+            Some(camera) => camera.view_projection(aspect),
+            None => return,
+        };
+
+        let globals = world.read_storage::<GlobalTransform>();
+        let mesh_renderers = world.read_storage::<MeshRenderer>();
+        let sprites = world.read_storage::<Sprite>();
+        let interpolated = world.read_storage::<InterpolatedTransform>();
+        let object_matrix =
+            |global: &GlobalTransform, interpolated: Option<&InterpolatedTransform>| {
+                match interpolated {
+                    Some(interpolated) => interpolated.interpolate(&global.0, alpha),
+                    None => global.0,
+                }
+            };
+
+        // Collect everything that's in view
+        let mut mesh_draws = Vec::new();
+        for (global, renderer, interpolated) in
+            (&globals, &mesh_renderers, interpolated.maybe()).join()
+        {
+            let object_matrix = object_matrix(global, interpolated);
+            let object_to_clip = view_projection * object_matrix;
+            if !renderer.bounds.is_visible(&object_to_clip) {
+                self.stats.culled += 1;
+                continue;
+            }
+
+            mesh_draws.push(MeshDraw {
+                renderer,
+                object_matrix,
+                depth: renderer.bounds.depth(&object_to_clip),
+            });
+        }
+
+        let mut sprite_draws = Vec::new();
+        for (global, sprite, interpolated) in (&globals, &sprites, interpolated.maybe()).join() {
+            let object_matrix = object_matrix(global, interpolated)
+                * Matrix4::new_nonuniform_scaling(&sprite.size.push(1.0));
+            let object_to_clip = view_projection * object_matrix;
+            let bounds = Bounds::new(Vector3::new(-0.5, -0.5, 0.0), Vector3::new(0.5, 0.5, 0.0));
+            if !bounds.is_visible(&object_to_clip) {
+                self.stats.culled += 1;
+                continue;
+            }
+
+            sprite_draws.push(SpriteDraw {
+                texture: &sprite.texture,
+                instance: SpriteInstance::new(&object_matrix, &sprite.color),
+                depth: bounds.depth(&object_to_clip),
+            });
+        }
+
+        // The map is under everything else
+        if let Some(map) = world.try_fetch::<TileMap>() {
+            self.stats.chunks_rebuilt = self.tile_map.update(&map);
+            if let Some(network) = world.try_fetch::<RoadNetwork>() {
+                self.tile_map.update_roads(&network);
+            }
+            if let Some(highlights) = world.try_fetch::<TileHighlights>() {
+                self.tile_map.update_highlights(&highlights);
+            }
+
+            // Everything counts as changed until there's a reader
+            let changed = match &mut self.building_events {
+                Some(events) => {
+                    world
+                        .read_storage::<Building>()
+                        .channel()
+                        .read(events)
+                        .count()
+                        > 0
+                }
+                None => {
+                    let events = world.write_storage::<Building>().register_reader();
+                    self.building_events = Some(events);
+                    true
+                }
+            };
+            let buildings = world.read_storage::<Building>();
+            self.tile_map
+                .update_buildings(&map, changed, (&buildings).join());
+
+            // Vehicles are drawn on the roads, each in one of a few colors
+            let vehicles = world.read_storage::<Vehicle>();
+            let scale = Matrix4::new_nonuniform_scaling(&traffic::vehicle_scale());
+            let vehicle_draws: Vec<(Matrix4<f32>, Vector4<f32>)> =
+                (&world.entities(), &globals, &vehicles, interpolated.maybe())
+                    .join()
+                    .map(|(entity, global, _, interpolated)| {
+                        let color = VEHICLE_COLORS[entity.id() as usize % VEHICLE_COLORS.len()];
+                        (
+                            object_matrix(global, interpolated) * scale,
+                            Vector4::from(color),
+                        )
+                    })
+                    .collect();
+            self.tile_map.update_vehicles(&vehicle_draws);
+            self.tile_map
+                .render(&view_projection, shaders, &mut self.stats);
+        }
+
+        unsafe {
+            self.gl.Enable(gl::DEPTH_TEST);
+        }
+        self.draw_meshes(mesh_draws, &view_projection, meshes, shaders);
+        self.draw_sprites(sprite_draws, &view_projection, shaders, textures);
+        unsafe {
+            self.gl.Disable(gl::DEPTH_TEST);
+        }
+    }
+
+    pub fn stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    fn draw_meshes(
+        &mut self,
+        mut draws: Vec<MeshDraw>,
+        view_projection: &Matrix4<f32>,
+        meshes: &Assets<WorldMesh>,
+        shaders: &Assets<ShaderProgram>,
+    ) {
+        // Front to back within each batch so hidden pixels fail the depth
+        // test early
+        draws.sort_by(|a, b| {
+            (a.renderer.material.id(), a.renderer.mesh.id())
+                .cmp(&(b.renderer.material.id(), b.renderer.mesh.id()))
+                .then(
+                    a.depth
+                        .partial_cmp(&b.depth)
+                        .unwrap_or(std::cmp::Ordering::Equal),
+                )
+        });
+
+        let mut bound_material = None;
+        for draw in draws.iter() {
+            let (shader, mesh) = match (
+                shaders.get(&draw.renderer.material),
+                meshes.get(&draw.renderer.mesh),
+            ) {
+                (Some(shader), Some(mesh)) => (shader, mesh),
+                _ => continue,
+            };
+
+            if bound_material != Some(draw.renderer.material.id()) {
+                shader.bind();
+                shader.set_uniform("projection_matrix", &Mat4(*view_projection));
+                bound_material = Some(draw.renderer.material.id());
+            }
+
+            shader.set_uniform("object_matrix", &Mat4(draw.object_matrix));
+            mesh.render();
+            self.stats.meshes += 1;
+            self.stats.draw_calls += 1;
+        }
+
+        ShaderProgram::unbind_all(&self.gl);
+    }
+
+    fn draw_sprites(
+        &mut self,
+        mut draws: Vec<SpriteDraw>,
+        view_projection: &Matrix4<f32>,
+        shaders: &Assets<ShaderProgram>,
+        textures: &Assets<Texture>,
+    ) {
+        let shader = match shaders.get(&self.sprite_shader) {
+            Some(shader) if !draws.is_empty() => shader,
+            _ => return,
+        };
+
+        // Back to front within each texture so transparent edges blend with
+        // what's behind them
+        draws.sort_by(|a, b| {
+            a.texture.id().cmp(&b.texture.id()).then(
+                b.depth
+                    .partial_cmp(&a.depth)
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
+        });
+
+        unsafe {
+            self.gl.Enable(gl::BLEND);
+            self.gl.BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            self.gl.DepthMask(gl::FALSE);
+        }
+        shader.bind();
+        shader.set_uniform("projection_matrix", &Mat4(*view_projection));
+        shader.set_uniform("sprite_texture", &(SPRITE_TEXTURE_UNIT as i32));
+
+        // Draw each run of sprites sharing a texture at once
+        let mut start = 0;
+        while start < draws.len() {
+            let texture_handle = draws[start].texture;
+            let end = draws[start..]
+                .iter()
+                .position(|draw| draw.texture.id() != texture_handle.id())
+                .map_or(draws.len(), |offset| start + offset);
+
+            if let Some(texture) = textures.get(texture_handle) {
+                self.sprite_instances
+                    .update(draws[start..end].iter().map(|draw| draw.instance).collect());
+                texture.bind(SPRITE_TEXTURE_UNIT);
+                self.sprite_quad.render_instanced(&self.sprite_instances);
+                texture.unbind(SPRITE_TEXTURE_UNIT);
+
+                self.stats.sprites += end - start;
+                self.stats.draw_calls += 1;
+            }
+
+            start = end;
+        }
+
+        shader.unbind();
+        unsafe {
+            self.gl.DepthMask(gl::TRUE);
+            self.gl.Disable(gl::BLEND);
+        }
+    }
+}
+
+// Adds the components the renderer draws. The renderer itself lives outside
+// the world because it owns GPU resources.
+pub struct RenderPlugin;
+
+impl Plugin for RenderPlugin {
+    fn name(&self) -> &'static str {
+        "render"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["camera"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder.register::<MeshRenderer>().register::<Sprite>();
+    }
+}
@@ -0,0 +1,1054 @@
+use crate::economy::Treasury;
+use crate::input::InputState;
+use crate::picking::Selection;
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::tilemap::{self, Terrain, TileFlags, TileMap, Zone, ZoneDensity};
+use crate::tools::{ActiveTool, TileHighlights, Tool, ToolStatus};
+use glfw::MouseButton;
+use nalgebra::{Vector2, Vector4};
+use serde::{Deserialize, Serialize};
+use specs::{Read, System, Write};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Points closer than this are the same point
+const EPSILON: f32 = 1e-3;
+
+// What bulldozing a tile of road costs
+pub const BULLDOZE_COST: i64 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RoadType {
+    Street,
+    Avenue,
+    Highway,
+}
+
+impl RoadType {
+    pub const ALL: [RoadType; 3] = [RoadType::Street, RoadType::Avenue, RoadType::Highway];
+
+    // How many vehicles fit on a tile of the road before it's jammed
+    pub fn capacity(self) -> u32 {
+        match self {
+            RoadType::Street => 4,
+            RoadType::Avenue => 8,
+            RoadType::Highway => 12,
+        }
+    }
+
+    // How fast vehicles drive on the road in tiles per second
+    pub fn speed(self) -> f32 {
+        match self {
+            RoadType::Street => 2.0,
+            RoadType::Avenue => 3.0,
+            RoadType::Highway => 5.0,
+        }
+    }
+
+    // Lanes in each direction
+    pub fn lanes(self) -> u8 {
+        match self {
+            RoadType::Street => 1,
+            RoadType::Avenue => 2,
+            RoadType::Highway => 3,
+        }
+    }
+
+    pub fn cost_per_tile(self) -> i64 {
+        match self {
+            RoadType::Street => 25,
+            RoadType::Avenue => 50,
+            RoadType::Highway => 100,
+        }
+    }
+
+    // Width of the road's surface in tiles
+    pub fn width(self) -> f32 {
+        match self {
+            RoadType::Street => 0.6,
+            RoadType::Avenue => 0.8,
+            RoadType::Highway => 0.95,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RoadType::Street => "Street",
+            RoadType::Avenue => "Avenue",
+            RoadType::Highway => "Highway",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NodeId(pub u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SegmentId(pub u32);
+
+// Where roads end or meet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoadNode {
+    pub position: Vector2<f32>,
+    pub segments: Vec<SegmentId>,
+}
+
+// A straight piece of road between two nodes
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoadSegment {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub road_type: RoadType,
+}
+
+impl RoadSegment {
+    pub fn other_end(&self, node: NodeId) -> NodeId {
+        if self.from == node {
+            self.to
+        } else {
+            self.from
+        }
+    }
+}
+
+static REVISIONS: AtomicU64 = AtomicU64::new(0);
+
+// Revisions are unique across every network, so a loaded network is never
+// mistaken for the one it replaced
+fn next_revision() -> u64 {
+    REVISIONS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+fn cross(a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+// Where the lines a-b and p-q cross, as how far along each one it is from 0
+// to 1. Parallel lines never cross.
+fn intersect(
+    a: Vector2<f32>,
+    b: Vector2<f32>,
+    p: Vector2<f32>,
+    q: Vector2<f32>,
+) -> Option<(f32, f32)> {
+    let (r, s) = (b - a, q - p);
+    let denominator = cross(r, s);
+    if denominator.abs() < EPSILON {
+        return None;
+    }
+
+    let t = cross(p - a, s) / denominator;
+    let u = cross(p - a, r) / denominator;
+    let inside = |value: f32| value > -EPSILON && value < 1.0 + EPSILON;
+    if inside(t) && inside(u) {
+        Some((t.clamp(0.0, 1.0), u.clamp(0.0, 1.0)))
+    } else {
+        None
+    }
+}
+
+// How far along the line a-b the point is from 0 to 1, if it's on it
+fn along(a: Vector2<f32>, b: Vector2<f32>, point: Vector2<f32>) -> Option<f32> {
+    let r = b - a;
+    let t = (point - a).dot(&r) / r.norm_squared();
+    if !(-EPSILON..=1.0 + EPSILON).contains(&t) || (a + r * t - point).norm() > EPSILON {
+        None
+    } else {
+        Some(t.clamp(0.0, 1.0))
+    }
+}
+
+fn is_interior(t: f32) -> bool {
+    t > EPSILON && t < 1.0 - EPSILON
+}
+
+// The center of a tile, where grid-snapped roads run through
+pub fn tile_center((x, y): (i32, i32)) -> Vector2<f32> {
+    Vector2::new(x as f32 + 0.5, y as f32 + 0.5)
+}
+
+fn tile_of(point: Vector2<f32>) -> (i32, i32) {
+    (point.x.floor() as i32, point.y.floor() as i32)
+}
+
+// The roads of the city as a graph of nodes joined by straight segments.
+// Roads that cross are joined at a node, so vehicles can turn wherever two
+// roads meet.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoadNetwork {
+    nodes: BTreeMap<NodeId, RoadNode>,
+    segments: BTreeMap<SegmentId, RoadSegment>,
+    next_node: u32,
+    next_segment: u32,
+    // Changed every time the roads change
+    #[serde(skip, default = "next_revision")]
+    revision: u64,
+}
+
+impl RoadNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&RoadNode> {
+        self.nodes.get(&id)
+    }
+
+    pub fn segment(&self, id: SegmentId) -> Option<&RoadSegment> {
+        self.segments.get(&id)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &RoadNode)> {
+        self.nodes.iter().map(|(id, node)| (*id, node))
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = (SegmentId, &RoadSegment)> {
+        self.segments.iter().map(|(id, segment)| (*id, segment))
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    // The positions of the segment's ends
+    pub fn segment_ends(&self, id: SegmentId) -> Option<(Vector2<f32>, Vector2<f32>)> {
+        let segment = self.segments.get(&id)?;
+        Some((
+            self.nodes[&segment.from].position,
+            self.nodes[&segment.to].position,
+        ))
+    }
+
+    pub fn segment_length(&self, id: SegmentId) -> Option<f32> {
+        self.segment_ends(id).map(|(from, to)| (to - from).norm())
+    }
+
+    // The tiles the segment runs over
+    pub fn segment_tiles(&self, id: SegmentId) -> Vec<(i32, i32)> {
+        match self.segment_ends(id) {
+            Some((from, to)) => tilemap::line_tiles(tile_of(from), tile_of(to)),
+            None => Vec::new(),
+        }
+    }
+
+    // The best road on each tile that has one
+    pub fn tile_types(&self) -> HashMap<(i32, i32), RoadType> {
+        let mut tiles = HashMap::new();
+        for (&id, segment) in self.segments.iter() {
+            for tile in self.segment_tiles(id) {
+                let best = tiles.entry(tile).or_insert(segment.road_type);
+                *best = (*best).max(segment.road_type);
+            }
+        }
+        tiles
+    }
+
+    // The segments running over the tile
+    pub fn segments_at(&self, tile: (i32, i32)) -> Vec<SegmentId> {
+        self.segments
+            .keys()
+            .cloned()
+            .filter(|&id| self.segment_tiles(id).contains(&tile))
+            .collect()
+    }
+
+    // The segment lying on the line through `a` and `b` that the point is
+    // on, such as one of the pieces a road was split into or the road it was
+    // joined into
+    pub fn segment_along(
+        &self,
+        a: Vector2<f32>,
+        b: Vector2<f32>,
+        point: Vector2<f32>,
+    ) -> Option<SegmentId> {
+        let direction = (b - a).normalize();
+        let on_line = |p: Vector2<f32>| {
+            let offset = p - a;
+            (offset - direction * offset.dot(&direction)).norm() <= EPSILON
+        };
+        self.segments.keys().cloned().find(|&id| {
+            let (p, q) = self.segment_ends(id).unwrap();
+            along(p, q, point).is_some() && on_line(p) && on_line(q)
+        })
+    }
+
+    pub fn node_at(&self, position: Vector2<f32>) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .find(|(_, node)| (node.position - position).norm() < EPSILON)
+            .map(|(id, _)| *id)
+    }
+
+    // The node closest to the point, for getting onto the roads from a tile
+    pub fn nearest_node(&self, position: Vector2<f32>) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .map(|(id, node)| (*id, (node.position - position).norm_squared()))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(CmpOrdering::Equal))
+            .map(|(id, _)| id)
+    }
+
+    pub fn segment_between(&self, a: NodeId, b: NodeId) -> Option<SegmentId> {
+        self.nodes.get(&a)?.segments.iter().cloned().find(|id| {
+            let segment = &self.segments[id];
+            (segment.from, segment.to) == (a, b) || (segment.from, segment.to) == (b, a)
+        })
+    }
+
+    fn add_node(&mut self, position: Vector2<f32>) -> NodeId {
+        if let Some(id) = self.node_at(position) {
+            return id;
+        }
+
+        let id = NodeId(self.next_node);
+        self.next_node += 1;
+        self.nodes.insert(
+            id,
+            RoadNode {
+                position,
+                segments: Vec::new(),
+            },
+        );
+        id
+    }
+
+    fn add_segment(&mut self, from: NodeId, to: NodeId, road_type: RoadType) -> SegmentId {
+        let id = SegmentId(self.next_segment);
+        self.next_segment += 1;
+        self.segments.insert(
+            id,
+            RoadSegment {
+                from,
+                to,
+                road_type,
+            },
+        );
+        self.nodes.get_mut(&from).unwrap().segments.push(id);
+        self.nodes.get_mut(&to).unwrap().segments.push(id);
+        id
+    }
+
+    fn detach_segment(&mut self, id: SegmentId) -> Option<RoadSegment> {
+        let segment = self.segments.remove(&id)?;
+        for node in [segment.from, segment.to].iter() {
+            if let Some(node) = self.nodes.get_mut(node) {
+                node.segments.retain(|other| *other != id);
+            }
+        }
+        Some(segment)
+    }
+
+    // Cut the segment running through the point in two, joined by a node at
+    // the point
+    fn split_at(&mut self, point: Vector2<f32>) {
+        let split = self.segments.keys().cloned().find(|&id| {
+            let (from, to) = self.segment_ends(id).unwrap();
+            along(from, to, point).is_some_and(is_interior)
+        });
+        if let Some(id) = split {
+            let segment = self.detach_segment(id).unwrap();
+            let middle = self.add_node(point);
+            self.add_segment(segment.from, middle, segment.road_type);
+            self.add_segment(middle, segment.to, segment.road_type);
+        }
+    }
+
+    // Add a straight road from `a` to `b`. Where it crosses or touches other
+    // roads, both are split and joined at a node. Parts that run along
+    // existing roads replace them. Returns the segments the new road is made
+    // of.
+    pub fn add_road(
+        &mut self,
+        a: Vector2<f32>,
+        b: Vector2<f32>,
+        road_type: RoadType,
+    ) -> Vec<SegmentId> {
+        if (b - a).norm() < EPSILON {
+            return Vec::new();
+        }
+
+        // Find where the new road meets the others, both along the new road
+        // and in the middle of the existing ones
+        let mut cuts = vec![0.0, 1.0];
+        let mut splits = Vec::new();
+        for &id in self.segments.keys() {
+            let (p, q) = self.segment_ends(id).unwrap();
+            match intersect(a, b, p, q) {
+                Some((t, u)) => {
+                    cuts.push(t);
+                    if is_interior(u) {
+                        splits.push(a + (b - a) * t);
+                    }
+                }
+                // Parallel roads only meet if one runs along the other
+                None => {
+                    cuts.extend([p, q].iter().filter_map(|point| along(a, b, *point)));
+                    splits.extend(
+                        [a, b]
+                            .iter()
+                            .filter(|point| along(p, q, **point).is_some_and(is_interior)),
+                    );
+                }
+            }
+        }
+
+        for point in splits {
+            self.split_at(point);
+        }
+
+        cuts.sort_by(|x, y| x.partial_cmp(y).unwrap_or(CmpOrdering::Equal));
+        let mut points: Vec<Vector2<f32>> = cuts.iter().map(|t| a + (b - a) * *t).collect();
+        points.dedup_by(|x, y| (*x - *y).norm() < EPSILON);
+
+        let mut added = Vec::new();
+        for pair in points.windows(2) {
+            let from = self.add_node(pair[0]);
+            let to = self.add_node(pair[1]);
+            let id = match self.segment_between(from, to) {
+                Some(id) => {
+                    self.segments.get_mut(&id).unwrap().road_type = road_type;
+                    id
+                }
+                None => self.add_segment(from, to, road_type),
+            };
+            added.push(id);
+        }
+
+        self.revision = next_revision();
+        added
+    }
+
+    // Remove the segment along with any nodes left without roads. Nodes left
+    // in the middle of a straight road are joined back up.
+    pub fn remove_segment(&mut self, id: SegmentId) -> bool {
+        let segment = match self.detach_segment(id) {
+            Some(segment) => segment,
+            None => return false,
+        };
+
+        for node in [segment.from, segment.to].iter() {
+            match self.nodes.get(node) {
+                Some(found) if found.segments.is_empty() => {
+                    self.nodes.remove(node);
+                }
+                Some(_) => self.merge_node(*node),
+                None => {}
+            }
+        }
+
+        self.revision = next_revision();
+        true
+    }
+
+    // Remove the part of a road between two points on it, cutting the road
+    // at the points first. Returns false if no road runs between them.
+    pub fn remove_between(&mut self, a: Vector2<f32>, b: Vector2<f32>) -> bool {
+        self.split_at(a);
+        self.split_at(b);
+        let (a, b) = match (self.node_at(a), self.node_at(b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return false,
+        };
+        match self.segment_between(a, b) {
+            Some(id) => self.remove_segment(id),
+            None => {
+                // Undo the cuts
+                self.merge_node(a);
+                self.merge_node(b);
+                false
+            }
+        }
+    }
+
+    // Replace a node between two segments of the same road running straight
+    // through it with a single segment
+    fn merge_node(&mut self, id: NodeId) {
+        let node = &self.nodes[&id];
+        if node.segments.len() != 2 {
+            return;
+        }
+
+        let (first, second) = (
+            self.segments[&node.segments[0]],
+            self.segments[&node.segments[1]],
+        );
+        let (a, b) = (first.other_end(id), second.other_end(id));
+        let (pa, pb) = (self.nodes[&a].position, self.nodes[&b].position);
+        let straight = cross(node.position - pa, pb - node.position).abs() < EPSILON
+            && (node.position - pa).dot(&(pb - node.position)) > 0.0;
+        if first.road_type != second.road_type || a == b || !straight {
+            return;
+        }
+
+        let (first_id, second_id) = (node.segments[0], node.segments[1]);
+        self.detach_segment(first_id);
+        self.detach_segment(second_id);
+        self.nodes.remove(&id);
+        self.add_segment(a, b, first.road_type);
+    }
+
+    // Flag the tiles that have roads on them, and clear the flag from the
+    // ones that don't. Zones can't be on roads, so roads clear them.
+    pub fn update_map_tiles(&self, map: &mut TileMap, tiles: &[(i32, i32)]) {
+        let roads: HashSet<(i32, i32)> = self.tile_types().keys().cloned().collect();
+        for tile in tiles {
+            let road = roads.contains(tile);
+            map.update(*tile, |tile| {
+                tile.flags.set(TileFlags::ROAD, road);
+                if road {
+                    tile.zone = Zone::Unzoned;
+                    tile.density = ZoneDensity::Low;
+                }
+            });
+        }
+    }
+}
+
+// What building a road would do
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoadPlan {
+    pub tiles: Vec<(i32, i32)>,
+    // Tiles the road can't be built on
+    pub blocked: Vec<(i32, i32)>,
+    // Tiles that don't already have this road are paid for
+    pub cost: i64,
+}
+
+pub fn plan_road(
+    map: &TileMap,
+    network: &RoadNetwork,
+    from: (i32, i32),
+    to: (i32, i32),
+    road_type: RoadType,
+) -> RoadPlan {
+    let tiles = tilemap::line_tiles(from, to);
+    let blocked = tiles
+        .iter()
+        .cloned()
+        .filter(|pos| !matches!(map.get(*pos), Some(tile) if tile.terrain != Terrain::Water))
+        .collect();
+
+    let existing = network.tile_types();
+    let new_tiles = tiles
+        .iter()
+        .filter(|pos| existing.get(pos) != Some(&road_type))
+        .count();
+
+    RoadPlan {
+        tiles,
+        blocked,
+        cost: new_tiles as i64 * road_type.cost_per_tile(),
+    }
+}
+
+// Build a road between the centers of the two tiles without checking
+// anything or paying for it
+pub fn place_road(
+    map: &mut TileMap,
+    network: &mut RoadNetwork,
+    from: (i32, i32),
+    to: (i32, i32),
+    road_type: RoadType,
+) -> Vec<SegmentId> {
+    let added = network.add_road(tile_center(from), tile_center(to), road_type);
+    network.update_map_tiles(map, &tilemap::line_tiles(from, to));
+    added
+}
+
+// Build a road between the centers of the two tiles, charging the treasury
+// for it
+pub fn build_road(
+    map: &mut TileMap,
+    network: &mut RoadNetwork,
+    treasury: &mut Treasury,
+    from: (i32, i32),
+    to: (i32, i32),
+    road_type: RoadType,
+) -> Result<Vec<SegmentId>, String> {
+    if from == to {
+        return Err("drag to build a road".to_owned());
+    }
+
+    let plan = plan_road(map, network, from, to, road_type);
+    if !plan.blocked.is_empty() {
+        return Err("roads can't be built on water or off the map".to_owned());
+    }
+    if !treasury.spend(plan.cost) {
+        return Err(format!(
+            "the road costs ${}, but the city only has ${}",
+            plan.cost, treasury.funds
+        ));
+    }
+
+    Ok(place_road(map, network, from, to, road_type))
+}
+
+// A part of a road between two points on it
+type RoadPart = (Vector2<f32>, Vector2<f32>);
+
+// Where roads have to be cut to clear them from the tiles, as the two ends
+// of each part that's removed, and the tiles with roads that get cleared.
+// Each part runs from the center of the last tile kept before the tiles to
+// the first one kept after them, so no road is left on the tiles.
+fn bulldoze_plan(network: &RoadNetwork, tiles: &[(i32, i32)]) -> (Vec<RoadPart>, Vec<(i32, i32)>) {
+    let tiles: HashSet<(i32, i32)> = tiles.iter().cloned().collect();
+    let mut cuts = Vec::new();
+    let mut cleared = HashSet::new();
+    for (id, _) in network.segments() {
+        let (from, to) = network.segment_ends(id).unwrap();
+        let segment_tiles = network.segment_tiles(id);
+        // The point along the segment next to the tile's center
+        let point_at = |index: usize| {
+            let offset = to - from;
+            let t = (tile_center(segment_tiles[index]) - from).dot(&offset) / offset.norm_squared();
+            from + offset * t.clamp(0.0, 1.0)
+        };
+
+        // Each run of the segment's tiles that are bulldozed
+        let mut i = 0;
+        while i < segment_tiles.len() {
+            if !tiles.contains(&segment_tiles[i]) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < segment_tiles.len() && tiles.contains(&segment_tiles[i]) {
+                cleared.insert(segment_tiles[i]);
+                i += 1;
+            }
+
+            let a = if start == 0 {
+                from
+            } else {
+                point_at(start - 1)
+            };
+            let b = if i == segment_tiles.len() {
+                to
+            } else {
+                point_at(i)
+            };
+            cuts.push((a, b));
+        }
+    }
+
+    (cuts, cleared.into_iter().collect())
+}
+
+// Remove the roads from the tiles, charging the treasury for each tile
+// cleared. Roads running on past the tiles are cut at their edges and the
+// rest of them is kept. Returns the number of pieces of road removed.
+pub fn bulldoze(
+    map: &mut TileMap,
+    network: &mut RoadNetwork,
+    treasury: &mut Treasury,
+    tiles: &[(i32, i32)],
+) -> Result<usize, String> {
+    let (cuts, cleared) = bulldoze_plan(network, tiles);
+    let cost = cleared.len() as i64 * BULLDOZE_COST;
+    if !treasury.spend(cost) {
+        return Err(format!(
+            "bulldozing costs ${}, but the city only has ${}",
+            cost, treasury.funds
+        ));
+    }
+
+    let removed = cuts
+        .iter()
+        .filter(|(a, b)| network.remove_between(*a, *b))
+        .count();
+    // The tiles the cut roads end on keep their road
+    let mut changed = cleared;
+    for (a, b) in cuts {
+        changed.push(tile_of(a));
+        changed.push(tile_of(b));
+    }
+    network.update_map_tiles(map, &changed);
+    Ok(removed)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoadToolMode {
+    Build(RoadType),
+    Bulldoze,
+}
+
+impl RoadToolMode {
+    // What the road tool does with `tool` in use, or `None` when another
+    // tool is in use
+    pub fn of(tool: Option<Tool>) -> Option<Self> {
+        match tool {
+            Some(Tool::Road(road_type)) => Some(RoadToolMode::Build(road_type)),
+            Some(Tool::Bulldoze) => Some(RoadToolMode::Bulldoze),
+            _ => None,
+        }
+    }
+}
+
+// The road tool the player is using
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoadTool {
+    // What the tool did last frame, so drags are dropped when it changes
+    mode: Option<RoadToolMode>,
+    // Where the road being dragged started
+    drag_start: Option<(i32, i32)>,
+    last_error: Option<String>,
+}
+
+// Roads are built straight along the grid or diagonally, whichever is
+// closest to the direction dragged
+pub fn snap_to_grid(start: (i32, i32), end: (i32, i32)) -> (i32, i32) {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    if dx.abs() > 2 * dy.abs() {
+        (end.0, start.1)
+    } else if dy.abs() > 2 * dx.abs() {
+        (start.0, end.1)
+    } else {
+        let length = dx.abs().max(dy.abs());
+        (
+            start.0 + length * dx.signum(),
+            start.1 + length * dy.signum(),
+        )
+    }
+}
+
+impl RoadTool {
+    fn switch_to(&mut self, mode: Option<RoadToolMode>) {
+        if self.mode != mode {
+            self.mode = mode;
+            self.drag_start = None;
+        }
+    }
+
+    fn report<T>(&mut self, result: Result<T, String>) {
+        match result {
+            Ok(_) => self.last_error = None,
+            Err(err) => {
+                if self.last_error.as_ref() != Some(&err) {
+                    println!("Can't do that: {}", err);
+                }
+                self.last_error = Some(err);
+            }
+        }
+    }
+}
+
+// Builds and bulldozes roads with the road tool:
+// - the street, avenue, highway and bulldozer tools use it, see
+//   `ToolSwitchSystem`
+// - dragging with the left mouse button builds a road along the grid or
+//   bulldozes the roads along the line
+// The tiles that would change are highlighted and the cost is shown.
+#[derive(Default)]
+pub struct RoadToolSystem;
+
+impl<'a> System<'a> for RoadToolSystem {
+    type SystemData = (
+        Read<'a, InputState>,
+        Read<'a, Selection>,
+        Read<'a, ActiveTool>,
+        Write<'a, RoadTool>,
+        Option<Write<'a, TileMap>>,
+        Write<'a, RoadNetwork>,
+        Write<'a, Treasury>,
+        Write<'a, TileHighlights>,
+        Write<'a, ToolStatus>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            input,
+            selection,
+            active,
+            mut tool,
+            map,
+            mut network,
+            mut treasury,
+            mut highlights,
+            mut status,
+        ): Self::SystemData,
+    ) {
+        tool.switch_to(RoadToolMode::of(active.0));
+
+        let (mut map, mode) = match (map, tool.mode) {
+            (Some(map), Some(mode)) => (map, mode),
+            _ => return,
+        };
+        let hovered = match selection.hovered_tile {
+            Some(hovered) if map.contains(hovered) => hovered,
+            _ => return,
+        };
+
+        let button = MouseButton::Button1;
+        if input.was_button_pressed(button) {
+            tool.drag_start = Some(hovered);
+        }
+        let start = tool.drag_start.unwrap_or(hovered);
+        let released = tool.drag_start.is_some() && input.was_button_released(button);
+
+        match mode {
+            RoadToolMode::Build(road_type) => {
+                let end = snap_to_grid(start, hovered);
+                if released {
+                    let result =
+                        build_road(&mut map, &mut network, &mut treasury, start, end, road_type);
+                    tool.report(result);
+                }
+
+                let plan = plan_road(&map, &network, start, end, road_type);
+                let color = Vector4::new(0.6, 0.6, 0.65, 1.0);
+                let blocked = Vector4::new(1.0, 0.2, 0.2, 1.0);
+                highlights.0.extend(plan.tiles.iter().map(|pos| {
+                    if plan.blocked.contains(pos) {
+                        (*pos, blocked)
+                    } else {
+                        (*pos, color)
+                    }
+                }));
+                status.0 = Some(format!(
+                    "{}: {} tiles, ${}",
+                    road_type.name(),
+                    plan.tiles.len(),
+                    plan.cost
+                ));
+            }
+            RoadToolMode::Bulldoze => {
+                let tiles = tilemap::line_tiles(start, hovered);
+                if released {
+                    let result = bulldoze(&mut map, &mut network, &mut treasury, &tiles);
+                    tool.report(result);
+                }
+
+                let (_, cleared) = bulldoze_plan(&network, &tiles);
+                let color = Vector4::new(1.0, 0.5, 0.1, 1.0);
+                highlights
+                    .0
+                    .extend(tiles.iter().chain(cleared.iter()).map(|pos| (*pos, color)));
+                status.0 = Some(format!(
+                    "Bulldoze: {} tiles, ${}",
+                    cleared.len(),
+                    cleared.len() as i64 * BULLDOZE_COST
+                ));
+            }
+        }
+
+        if released {
+            tool.drag_start = None;
+        }
+    }
+}
+
+// Adds the road network. The tool to build roads with is added by
+// `RoadToolPlugin`.
+pub struct RoadPlugin;
+
+impl Plugin for RoadPlugin {
+    fn name(&self) -> &'static str {
+        "roads"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["tile_map"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .insert_resource(RoadNetwork::new())
+            .save_resource::<RoadNetwork>("roads");
+    }
+}
+
+pub struct RoadToolPlugin;
+
+impl Plugin for RoadToolPlugin {
+    fn name(&self) -> &'static str {
+        "road_tool"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["roads", "picking", "tools", "economy"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .insert_resource(RoadTool::default())
+            .add_frame_system(RoadToolSystem, "road_tool", &["picking", "tool_switch"]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (TileMap, RoadNetwork) {
+        (TileMap::new(16, 16), RoadNetwork::new())
+    }
+
+    fn degree(network: &RoadNetwork, tile: (i32, i32)) -> usize {
+        let node = network.node_at(tile_center(tile)).unwrap();
+        network.node(node).unwrap().segments.len()
+    }
+
+    #[test]
+    fn crossing_roads_are_joined() {
+        let (mut map, mut network) = setup();
+        place_road(&mut map, &mut network, (0, 5), (10, 5), RoadType::Street);
+        place_road(&mut map, &mut network, (5, 0), (5, 10), RoadType::Avenue);
+
+        assert_eq!(network.segment_count(), 4);
+        assert_eq!(network.node_count(), 5);
+        assert_eq!(degree(&network, (5, 5)), 4);
+        assert!(map.get((5, 5)).unwrap().flags.contains(TileFlags::ROAD));
+        assert!(map.get((5, 0)).unwrap().flags.contains(TileFlags::ROAD));
+        assert!(!map.get((6, 6)).unwrap().flags.contains(TileFlags::ROAD));
+    }
+
+    #[test]
+    fn roads_ending_on_roads_split_them() {
+        let (mut map, mut network) = setup();
+        place_road(&mut map, &mut network, (0, 5), (10, 5), RoadType::Street);
+        place_road(&mut map, &mut network, (3, 5), (3, 12), RoadType::Street);
+
+        assert_eq!(network.segment_count(), 3);
+        assert_eq!(degree(&network, (3, 5)), 3);
+    }
+
+    #[test]
+    fn overlapping_roads_are_upgraded() {
+        let (mut map, mut network) = setup();
+        place_road(&mut map, &mut network, (0, 5), (10, 5), RoadType::Street);
+        place_road(&mut map, &mut network, (4, 5), (14, 5), RoadType::Highway);
+
+        let types: Vec<RoadType> = network
+            .segments()
+            .map(|(_, segment)| segment.road_type)
+            .collect();
+        assert_eq!(network.segment_count(), 3);
+        assert_eq!(types.iter().filter(|t| **t == RoadType::Highway).count(), 2);
+        assert_eq!(network.tile_types()[&(2, 5)], RoadType::Street);
+        assert_eq!(network.tile_types()[&(7, 5)], RoadType::Highway);
+    }
+
+    #[test]
+    fn bulldozing_removes_roads_and_joins_what_is_left() {
+        let (mut map, mut network) = setup();
+        let mut treasury = Treasury::new(10_000);
+        place_road(&mut map, &mut network, (0, 5), (10, 5), RoadType::Street);
+        place_road(&mut map, &mut network, (5, 0), (5, 5), RoadType::Street);
+        assert_eq!(network.segment_count(), 3);
+
+        // Only the bulldozed part of the side road goes
+        let removed = bulldoze(&mut map, &mut network, &mut treasury, &[(5, 2)]);
+        assert_eq!(removed, Ok(1));
+        assert_eq!(treasury.funds, 10_000 - BULLDOZE_COST);
+        assert_eq!(network.segment_count(), 4);
+        assert!(!map.get((5, 2)).unwrap().flags.contains(TileFlags::ROAD));
+        for &tile in [(5, 0), (5, 1), (5, 3), (5, 4), (5, 5)].iter() {
+            assert!(map.get(tile).unwrap().flags.contains(TileFlags::ROAD));
+        }
+        assert_eq!(degree(&network, (5, 1)), 1);
+        assert_eq!(degree(&network, (5, 3)), 1);
+
+        // Once the rest of it is gone the main road is whole again
+        let removed = bulldoze(
+            &mut map,
+            &mut network,
+            &mut treasury,
+            &[(5, 0), (5, 1), (5, 3), (5, 4)],
+        );
+        assert_eq!(removed, Ok(2));
+        assert_eq!(treasury.funds, 10_000 - 5 * BULLDOZE_COST);
+        assert_eq!(network.segment_count(), 1);
+        assert_eq!(network.node_count(), 2);
+        assert!(!map.get((5, 4)).unwrap().flags.contains(TileFlags::ROAD));
+        assert!(map.get((5, 5)).unwrap().flags.contains(TileFlags::ROAD));
+    }
+
+    #[test]
+    fn bulldozing_the_middle_of_a_road_cuts_it_in_two() {
+        let (mut map, mut network) = setup();
+        let mut treasury = Treasury::new(10_000);
+        place_road(&mut map, &mut network, (0, 5), (10, 5), RoadType::Street);
+        place_road(&mut map, &mut network, (0, 0), (8, 8), RoadType::Avenue);
+
+        let tiles = tilemap::line_tiles((3, 2), (6, 2));
+        assert_eq!(
+            bulldoze(&mut map, &mut network, &mut treasury, &tiles),
+            Ok(0)
+        );
+        assert_eq!(treasury.funds, 10_000);
+
+        let tiles = tilemap::line_tiles((3, 5), (6, 5));
+        // Both roads are split where they cross, so four pieces go
+        let removed = bulldoze(&mut map, &mut network, &mut treasury, &tiles);
+        assert_eq!(removed, Ok(4));
+        assert_eq!(treasury.funds, 10_000 - 4 * BULLDOZE_COST);
+        let road_tiles = network.tile_types();
+        for x in 0..=10 {
+            assert_eq!(
+                road_tiles.contains_key(&(x, 5)),
+                !(3..=6).contains(&x),
+                "{}",
+                x
+            );
+        }
+        // The diagonal road lost the tile it crossed at, and nothing else
+        assert!(!road_tiles.contains_key(&(5, 5)));
+        assert!(road_tiles.contains_key(&(4, 4)) && road_tiles.contains_key(&(6, 6)));
+    }
+
+    #[test]
+    fn building_is_charged_and_checked() {
+        let (mut map, mut network) = setup();
+        let mut treasury = Treasury::new(300);
+        map.update((3, 8), |tile| tile.terrain = Terrain::Water);
+
+        let over_water = build_road(
+            &mut map,
+            &mut network,
+            &mut treasury,
+            (0, 8),
+            (6, 8),
+            RoadType::Street,
+        );
+        assert!(over_water.is_err());
+
+        let road = build_road(
+            &mut map,
+            &mut network,
+            &mut treasury,
+            (0, 0),
+            (5, 0),
+            RoadType::Street,
+        );
+        assert!(road.is_ok());
+        assert_eq!(treasury.funds, 300 - 6 * 25);
+
+        let too_expensive = build_road(
+            &mut map,
+            &mut network,
+            &mut treasury,
+            (0, 0),
+            (5, 0),
+            RoadType::Highway,
+        );
+        assert!(too_expensive.is_err());
+        assert_eq!(treasury.funds, 300 - 6 * 25);
+    }
+
+    #[test]
+    fn drags_snap_to_the_grid() {
+        assert_eq!(snap_to_grid((0, 0), (9, 2)), (9, 0));
+        assert_eq!(snap_to_grid((0, 0), (-1, -7)), (0, -7));
+        assert_eq!(snap_to_grid((0, 0), (5, -4)), (5, -5));
+    }
+}
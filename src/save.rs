@@ -0,0 +1,1203 @@
+use crate::world::MapInfo;
+use ron::value::RawValue;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use specs::shred::Resource;
+use specs::{BitSet, Builder, Component, Entity, Join, World, WorldExt};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+// Increased whenever the layout of the file changes. Changes to the saved
+// types themselves are handled by each type's own version and migrations.
+//
+// 1: first version
+// 2: every block has the version of the type it holds
+pub const SAVE_FORMAT_VERSION: u32 = 2;
+
+// Binary saves start with these bytes so they can be told apart from RON
+const BINARY_MAGIC: &[u8; 4] = b"CITY";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SaveFormat {
+    // Compact, used for normal saves
+    Binary,
+    // Human readable RON, for debugging
+    Ron,
+}
+
+// Written at the start of every save so it can be checked (and shown in a
+// load menu) without reading the whole file. The header's layout can't
+// change, since it has to be read to know how to read the rest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SaveHeader {
+    pub format_version: u32,
+    pub map: MapInfo,
+    pub entity_count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Block<Data> {
+    name: String,
+    // The version of the type when it was saved
+    version: u32,
+    data: Data,
+}
+
+// Blocks in format version 1, which were all saved as type version 1
+#[derive(Deserialize)]
+struct BlockV1<Data> {
+    name: String,
+    data: Data,
+}
+
+impl<Data> From<BlockV1<Data>> for Block<Data> {
+    fn from(block: BlockV1<Data>) -> Self {
+        Self {
+            name: block.name,
+            version: 1,
+            data: block.data,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SaveBody<B> {
+    components: Vec<B>,
+    resources: Vec<B>,
+}
+
+impl<Data> SaveBody<BlockV1<Data>> {
+    fn upgrade(self) -> SaveBody<Block<Data>> {
+        SaveBody {
+            components: self.components.into_iter().map(Block::from).collect(),
+            resources: self.resources.into_iter().map(Block::from).collect(),
+        }
+    }
+}
+
+impl<Data> SaveBody<Block<Data>> {
+    // Change the data in every block, stopping at the first error
+    fn map_data<New>(
+        self,
+        map: impl Fn(&str, Data) -> Result<New, String>,
+    ) -> Result<SaveBody<Block<New>>, String> {
+        let map_blocks = |blocks: Vec<Block<Data>>| {
+            blocks
+                .into_iter()
+                .map(|block| {
+                    let data = map(&block.name, block.data)?;
+                    Ok(Block {
+                        name: block.name,
+                        version: block.version,
+                        data,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+
+        Ok(SaveBody {
+            components: map_blocks(self.components)?,
+            resources: map_blocks(self.resources)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RonSave<Body> {
+    header: SaveHeader,
+    body: Body,
+}
+
+// A component or resource encoded on its own, so unknown blocks can be
+// reported by name and every type can be decoded separately
+enum BlockData {
+    Binary(Vec<u8>),
+    Ron(Box<RawValue>),
+}
+
+impl BlockData {
+    fn format(&self) -> SaveFormat {
+        match self {
+            BlockData::Binary(_) => SaveFormat::Binary,
+            BlockData::Ron(_) => SaveFormat::Ron,
+        }
+    }
+}
+
+fn encode_binary<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    bincode::serialize(value).map_err(|err| err.to_string())
+}
+
+fn encode_ron<T: Serialize>(value: &T) -> Result<Box<RawValue>, String> {
+    let ron = ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::new())
+        .map_err(|err| err.to_string())?;
+    RawValue::from_boxed_ron(ron.into_boxed_str()).map_err(|err| err.to_string())
+}
+
+fn encode<T: Serialize>(value: &T, format: SaveFormat) -> Result<BlockData, String> {
+    match format {
+        SaveFormat::Binary => encode_binary(value).map(BlockData::Binary),
+        SaveFormat::Ron => encode_ron(value).map(BlockData::Ron),
+    }
+}
+
+fn decode<T: DeserializeOwned>(data: &BlockData) -> Result<T, String> {
+    match data {
+        BlockData::Binary(bytes) => bincode::deserialize(bytes).map_err(|err| err.to_string()),
+        BlockData::Ron(raw) => raw.into_rust().map_err(|err| err.to_string()),
+    }
+}
+
+// Saved data copied out of the world, which can be encoded on another thread
+pub trait Snapshot: Send {
+    fn to_binary(&self) -> Result<Vec<u8>, String>;
+
+    fn to_ron(&self) -> Result<Box<RawValue>, String>;
+}
+
+impl<T: Serialize + Send> Snapshot for T {
+    fn to_binary(&self) -> Result<Vec<u8>, String> {
+        encode_binary(self)
+    }
+
+    fn to_ron(&self) -> Result<Box<RawValue>, String> {
+        encode_ron(self)
+    }
+}
+
+// Maps between live entities and the ids they're saved with. Saved ids are
+// assigned in entity order starting from zero, so saving the same world
+// twice gives the same ids.
+#[derive(Debug, Default)]
+pub struct EntityIds {
+    ids: HashMap<Entity, u32>,
+    entities: HashMap<u32, Entity>,
+}
+
+impl EntityIds {
+    fn new(entities: Vec<Entity>) -> Self {
+        Self::from_pairs(
+            entities
+                .into_iter()
+                .enumerate()
+                .map(|(id, entity)| (id as u32, entity)),
+        )
+    }
+
+    fn from_pairs(pairs: impl Iterator<Item = (u32, Entity)>) -> Self {
+        let entities: HashMap<u32, Entity> = pairs.collect();
+        let ids = entities.iter().map(|(id, entity)| (*entity, *id)).collect();
+
+        Self { ids, entities }
+    }
+
+    pub fn id(&self, entity: Entity) -> Option<u32> {
+        self.ids.get(&entity).copied()
+    }
+
+    pub fn entity(&self, id: u32) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+// Implemented by saved types that refer to entities, which have to be
+// turned into saved ids and back
+pub trait MapEntities: Sized {
+    type Saved: Serialize + DeserializeOwned + Send + 'static;
+
+    // Returning `None` skips saving the value, like when it refers to an
+    // entity that isn't saved
+    fn save(&self, ids: &EntityIds) -> Option<Self::Saved>;
+
+    // Checked while the save is decoded, before the world is changed, so
+    // saved data that can't be loaded fails the whole load
+    fn check(_saved: &Self::Saved) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn load(saved: Self::Saved, ids: &EntityIds) -> Option<Self>;
+}
+
+// How a saved type is converted to and from the data that's written
+trait Conversion<T>: Send + Sync + 'static {
+    type Saved: Serialize + DeserializeOwned + Send + 'static;
+
+    fn save(value: &T, ids: &EntityIds) -> Option<Self::Saved>;
+
+    fn check(_saved: &Self::Saved) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn load(saved: Self::Saved, ids: &EntityIds) -> Option<T>;
+}
+
+// Types without entity references are saved as they are
+struct AsIs;
+
+impl<T: Serialize + DeserializeOwned + Clone + Send + 'static> Conversion<T> for AsIs {
+    type Saved = T;
+
+    fn save(value: &T, _: &EntityIds) -> Option<T> {
+        Some(value.clone())
+    }
+
+    fn load(saved: T, _: &EntityIds) -> Option<T> {
+        Some(saved)
+    }
+}
+
+struct Mapped;
+
+impl<T: MapEntities> Conversion<T> for Mapped {
+    type Saved = T::Saved;
+
+    fn save(value: &T, ids: &EntityIds) -> Option<T::Saved> {
+        value.save(ids)
+    }
+
+    fn check(saved: &T::Saved) -> Result<(), String> {
+        T::check(saved)
+    }
+
+    fn load(saved: T::Saved, ids: &EntityIds) -> Option<T> {
+        T::load(saved, ids)
+    }
+}
+
+trait SavedType: Send + Sync {
+    fn name(&self) -> &str;
+
+    // The entities that have to be saved for this type
+    fn entities(&self, world: &World) -> BitSet;
+
+    // Copy the saved data out of the world
+    fn save(&self, world: &World, ids: &EntityIds) -> Option<Box<dyn Snapshot>>;
+
+    // Decode the saved data without changing the world, so a save that can't
+    // be read doesn't leave the world half loaded
+    fn decode(&self, data: &BlockData) -> Result<Box<dyn Decoded>, String>;
+}
+
+// A decoded component or resource, ready to be added to the world
+trait Decoded {
+    // The saved ids of the entities the data is added to
+    fn entity_ids(&self) -> Vec<u32>;
+
+    fn load(self: Box<Self>, world: &mut World, ids: &EntityIds);
+}
+
+struct SavedComponent<C, Conv> {
+    name: String,
+    _phantom: PhantomData<fn() -> (C, Conv)>,
+}
+
+impl<C, Conv> SavedType for SavedComponent<C, Conv>
+where
+    C: Component,
+    Conv: Conversion<C>,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn entities(&self, world: &World) -> BitSet {
+        let storage = world.read_storage::<C>();
+        let entities = world.entities();
+        (&entities, &storage)
+            .join()
+            .map(|(entity, _)| entity.id())
+            .collect()
+    }
+
+    fn save(&self, world: &World, ids: &EntityIds) -> Option<Box<dyn Snapshot>> {
+        let storage = world.read_storage::<C>();
+        let entities = world.entities();
+        let saved: Vec<(u32, Conv::Saved)> = (&entities, &storage)
+            .join()
+            .filter_map(|(entity, component)| {
+                ids.id(entity)
+                    .and_then(|id| Conv::save(component, ids).map(|saved| (id, saved)))
+            })
+            .collect();
+
+        Some(Box::new(saved))
+    }
+
+    fn decode(&self, data: &BlockData) -> Result<Box<dyn Decoded>, String> {
+        let saved: Vec<(u32, Conv::Saved)> = decode(data)?;
+        for (_, saved) in saved.iter() {
+            Conv::check(saved)?;
+        }
+
+        Ok(Box::new(DecodedComponent::<C, Conv> {
+            saved,
+            _phantom: PhantomData,
+        }))
+    }
+}
+
+struct DecodedComponent<C, Conv: Conversion<C>> {
+    saved: Vec<(u32, Conv::Saved)>,
+    _phantom: PhantomData<fn() -> C>,
+}
+
+impl<C, Conv> Decoded for DecodedComponent<C, Conv>
+where
+    C: Component,
+    Conv: Conversion<C>,
+{
+    fn entity_ids(&self) -> Vec<u32> {
+        self.saved.iter().map(|(id, _)| *id).collect()
+    }
+
+    // The entities were all created from `entity_ids`, so they're alive
+    fn load(self: Box<Self>, world: &mut World, ids: &EntityIds) {
+        let mut storage = world.write_storage::<C>();
+        for (id, saved) in self.saved {
+            if let (Some(entity), Some(component)) = (ids.entity(id), Conv::load(saved, ids)) {
+                storage
+                    .insert(entity, component)
+                    .expect("loaded entities are alive");
+            }
+        }
+    }
+}
+
+struct SavedResource<R, Conv> {
+    name: String,
+    _phantom: PhantomData<fn() -> (R, Conv)>,
+}
+
+impl<R, Conv> SavedType for SavedResource<R, Conv>
+where
+    R: Resource,
+    Conv: Conversion<R>,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn entities(&self, _: &World) -> BitSet {
+        BitSet::new()
+    }
+
+    fn save(&self, world: &World, ids: &EntityIds) -> Option<Box<dyn Snapshot>> {
+        if !world.has_value::<R>() {
+            return None;
+        }
+
+        Conv::save(&*world.fetch::<R>(), ids).map(|saved| Box::new(saved) as Box<dyn Snapshot>)
+    }
+
+    fn decode(&self, data: &BlockData) -> Result<Box<dyn Decoded>, String> {
+        let saved = decode(data)?;
+        Conv::check(&saved)?;
+
+        Ok(Box::new(DecodedResource::<R, Conv> {
+            saved,
+            _phantom: PhantomData,
+        }))
+    }
+}
+
+struct DecodedResource<R, Conv: Conversion<R>> {
+    saved: Conv::Saved,
+    _phantom: PhantomData<fn() -> R>,
+}
+
+impl<R, Conv> Decoded for DecodedResource<R, Conv>
+where
+    R: Resource,
+    Conv: Conversion<R>,
+{
+    fn entity_ids(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    fn load(self: Box<Self>, world: &mut World, ids: &EntityIds) {
+        if let Some(resource) = Conv::load(self.saved, ids) {
+            world.insert(resource);
+        }
+    }
+}
+
+// Every component and resource type that's saved, under the names they're
+// saved with. The names are part of the file format so they shouldn't
+// change once saves exist.
+//
+// Each type starts at version 1. When a saved type changes, a migration is
+// added that converts the old saved data into the new one, which increases
+// the type's version. Older saves are upgraded one version at a time.
+#[derive(Default, Clone)]
+pub struct SaveRegistry {
+    components: Vec<Arc<dyn SavedType>>,
+    resources: Vec<Arc<dyn SavedType>>,
+    // The migrations of each type, from version 1 upwards
+    migrations: HashMap<String, Vec<Migration>>,
+}
+
+type Migration = Arc<dyn Fn(BlockData) -> Result<BlockData, String> + Send + Sync>;
+
+impl SaveRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_component<C>(&mut self, name: &str)
+    where
+        C: Component + Serialize + DeserializeOwned + Clone + Send,
+    {
+        self.push_component(Arc::new(SavedComponent::<C, AsIs> {
+            name: name.to_owned(),
+            _phantom: PhantomData,
+        }));
+    }
+
+    pub fn add_mapped_component<C>(&mut self, name: &str)
+    where
+        C: Component + MapEntities,
+    {
+        self.push_component(Arc::new(SavedComponent::<C, Mapped> {
+            name: name.to_owned(),
+            _phantom: PhantomData,
+        }));
+    }
+
+    pub fn add_resource<R>(&mut self, name: &str)
+    where
+        R: Resource + Serialize + DeserializeOwned + Clone,
+    {
+        self.push_resource(Arc::new(SavedResource::<R, AsIs> {
+            name: name.to_owned(),
+            _phantom: PhantomData,
+        }));
+    }
+
+    pub fn add_mapped_resource<R>(&mut self, name: &str)
+    where
+        R: Resource + MapEntities,
+    {
+        self.push_resource(Arc::new(SavedResource::<R, Mapped> {
+            name: name.to_owned(),
+            _phantom: PhantomData,
+        }));
+    }
+
+    // Upgrade a component saved as `from_version` to the next version.
+    // `Old` and `New` are the saved forms, which for mapped components are
+    // their `MapEntities::Saved` types.
+    pub fn add_component_migration<Old, New, F>(
+        &mut self,
+        name: &str,
+        from_version: u32,
+        migrate: F,
+    ) where
+        Old: DeserializeOwned,
+        New: Serialize,
+        F: Fn(Old) -> New + Send + Sync + 'static,
+    {
+        assert!(
+            self.components.iter().any(|saved| saved.name() == name),
+            "can't migrate unknown component \"{}\"",
+            name
+        );
+
+        self.push_migration(
+            name,
+            from_version,
+            Arc::new(move |data| {
+                let old: Vec<(u32, Old)> = decode(&data)?;
+                let new: Vec<(u32, New)> = old
+                    .into_iter()
+                    .map(|(id, old)| (id, migrate(old)))
+                    .collect();
+                encode(&new, data.format())
+            }),
+        );
+    }
+
+    pub fn add_resource_migration<Old, New, F>(&mut self, name: &str, from_version: u32, migrate: F)
+    where
+        Old: DeserializeOwned,
+        New: Serialize,
+        F: Fn(Old) -> New + Send + Sync + 'static,
+    {
+        assert!(
+            self.resources.iter().any(|saved| saved.name() == name),
+            "can't migrate unknown resource \"{}\"",
+            name
+        );
+
+        self.push_migration(
+            name,
+            from_version,
+            Arc::new(move |data| encode(&migrate(decode(&data)?), data.format())),
+        );
+    }
+
+    // The version the type is saved as now
+    pub fn version(&self, name: &str) -> u32 {
+        self.migrations.get(name).map_or(0, Vec::len) as u32 + 1
+    }
+
+    fn push_migration(&mut self, name: &str, from_version: u32, migration: Migration) {
+        assert_eq!(
+            from_version,
+            self.version(name),
+            "migrations for \"{}\" have to be added in order",
+            name
+        );
+        self.migrations
+            .entry(name.to_owned())
+            .or_default()
+            .push(migration);
+    }
+
+    // Upgrade a block to the current version of its type
+    fn migrate(&self, block: Block<BlockData>) -> Result<BlockData, String> {
+        let Block {
+            name,
+            version: saved_version,
+            mut data,
+        } = block;
+
+        let version = self.version(&name);
+        if saved_version > version {
+            return Err(format!(
+                "\"{}\" was saved by a newer version of the game (version {}, expected at most {})",
+                name, saved_version, version
+            ));
+        }
+        if saved_version == 0 {
+            return Err(format!("\"{}\" has invalid version 0", name));
+        }
+
+        if let Some(migrations) = self.migrations.get(&name) {
+            for (from, migration) in migrations
+                .iter()
+                .enumerate()
+                .skip(saved_version as usize - 1)
+            {
+                data = migration(data).map_err(|err| {
+                    format!(
+                        "failed to upgrade \"{}\" from version {}: {}",
+                        name,
+                        from + 1,
+                        err
+                    )
+                })?;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn push_component(&mut self, saved: Arc<dyn SavedType>) {
+        self.assert_unique(saved.name());
+        self.components.push(saved);
+    }
+
+    fn push_resource(&mut self, saved: Arc<dyn SavedType>) {
+        self.assert_unique(saved.name());
+        self.resources.push(saved);
+    }
+
+    fn assert_unique(&self, name: &str) {
+        assert!(
+            self.components
+                .iter()
+                .chain(self.resources.iter())
+                .all(|saved| saved.name() != name),
+            "\"{}\" is saved twice",
+            name
+        );
+    }
+}
+
+// Everything that would be saved from a world, copied so the world can keep
+// changing while it's encoded and written
+pub struct WorldSnapshot {
+    header: SaveHeader,
+    body: SaveBody<Block<Box<dyn Snapshot>>>,
+}
+
+impl WorldSnapshot {
+    pub fn header(&self) -> &SaveHeader {
+        &self.header
+    }
+
+    pub fn encode(self, format: SaveFormat) -> Result<Vec<u8>, String> {
+        let describe = |name: &str, err: String| format!("failed to save \"{}\": {}", name, err);
+
+        match format {
+            SaveFormat::Binary => {
+                let body = self
+                    .body
+                    .map_data(|name, data| data.to_binary().map_err(|err| describe(name, err)))?;
+
+                let mut bytes = BINARY_MAGIC.to_vec();
+                bincode::serialize_into(&mut bytes, &self.header).map_err(|err| err.to_string())?;
+                bincode::serialize_into(&mut bytes, &body).map_err(|err| err.to_string())?;
+                Ok(bytes)
+            }
+            SaveFormat::Ron => {
+                let save = RonSave {
+                    header: self.header,
+                    body: self
+                        .body
+                        .map_data(|name, data| data.to_ron().map_err(|err| describe(name, err)))?,
+                };
+
+                ron::ser::to_string_pretty(&save, ron::ser::PrettyConfig::new())
+                    .map(String::into_bytes)
+                    .map_err(|err| err.to_string())
+            }
+        }
+    }
+}
+
+// Copy every saved component and resource out of the world. Only entities
+// with at least one saved component are included.
+pub fn snapshot_world(world: &World) -> WorldSnapshot {
+    let registry = SaveRegistry::clone(&world.fetch::<SaveRegistry>());
+
+    let mut saved_entities = BitSet::new();
+    for saved in registry.components.iter() {
+        saved_entities |= &saved.entities(world);
+    }
+    let entities = world.entities();
+    let ids = EntityIds::new(
+        (&entities, &saved_entities)
+            .join()
+            .map(|(entity, _)| entity)
+            .collect(),
+    );
+
+    let header = SaveHeader {
+        format_version: SAVE_FORMAT_VERSION,
+        map: world
+            .try_fetch::<MapInfo>()
+            .map(|map| MapInfo::clone(&map))
+            .unwrap_or_default(),
+        entity_count: ids.len() as u32,
+    };
+
+    let save_blocks = |types: &[Arc<dyn SavedType>]| {
+        types
+            .iter()
+            .filter_map(|saved| {
+                saved.save(world, &ids).map(|data| Block {
+                    name: saved.name().to_owned(),
+                    version: registry.version(saved.name()),
+                    data,
+                })
+            })
+            .collect()
+    };
+    let body = SaveBody {
+        components: save_blocks(&registry.components),
+        resources: save_blocks(&registry.resources),
+    };
+
+    WorldSnapshot { header, body }
+}
+
+pub fn save_world(world: &World, format: SaveFormat) -> Result<Vec<u8>, String> {
+    snapshot_world(world).encode(format)
+}
+
+fn check_version(header: &SaveHeader) -> Result<(), String> {
+    if header.format_version > SAVE_FORMAT_VERSION {
+        return Err(format!(
+            "save is from a newer version of the game (format {}, expected at most {})",
+            header.format_version, SAVE_FORMAT_VERSION
+        ));
+    }
+
+    Ok(())
+}
+
+// Read only the header of a save in either format
+pub fn read_header(bytes: &[u8]) -> Result<SaveHeader, String> {
+    if bytes.starts_with(BINARY_MAGIC) {
+        bincode::deserialize_from(&bytes[BINARY_MAGIC.len()..]).map_err(|err| err.to_string())
+    } else {
+        read_ron::<IgnoredAny>(bytes).map(|save| save.header)
+    }
+}
+
+fn read_ron<Body: DeserializeOwned>(bytes: &[u8]) -> Result<RonSave<Body>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "not a save file".to_owned())?;
+    ron::from_str(text).map_err(|err| format!("invalid save: {}", err))
+}
+
+// Add the entities, components and resources in a save to the world, which
+// should have been built with the same plugins as the one that was saved.
+// Loaded entities get new ids, and references between them are remapped.
+pub fn load_world(world: &mut World, bytes: &[u8]) -> Result<SaveHeader, String> {
+    let (header, body) = if bytes.starts_with(BINARY_MAGIC) {
+        let mut reader = Cursor::new(&bytes[BINARY_MAGIC.len()..]);
+        let header: SaveHeader = bincode::deserialize_from(&mut reader)
+            .map_err(|err| format!("invalid save header: {}", err))?;
+        check_version(&header)?;
+
+        let mut rest = Vec::new();
+        reader
+            .read_to_end(&mut rest)
+            .map_err(|err| err.to_string())?;
+        let body: SaveBody<Block<Vec<u8>>> = match header.format_version {
+            1 => bincode::deserialize::<SaveBody<BlockV1<_>>>(&rest).map(SaveBody::upgrade),
+            _ => bincode::deserialize(&rest),
+        }
+        .map_err(|err| format!("invalid save: {}", err))?;
+
+        (
+            header,
+            body.map_data(|_, data| Ok(BlockData::Binary(data)))?,
+        )
+    } else {
+        let header = read_ron::<IgnoredAny>(bytes)?.header;
+        check_version(&header)?;
+
+        let body: SaveBody<Block<Box<RawValue>>> = match header.format_version {
+            1 => read_ron::<SaveBody<BlockV1<_>>>(bytes)?.body.upgrade(),
+            _ => read_ron(bytes)?.body,
+        };
+
+        (header, body.map_data(|_, data| Ok(BlockData::Ron(data)))?)
+    };
+
+    let registry = SaveRegistry::clone(&world.fetch::<SaveRegistry>());
+    let find = |types: &[Arc<dyn SavedType>], name: &str| {
+        types
+            .iter()
+            .find(|saved| saved.name() == name)
+            .cloned()
+            .ok_or_else(|| format!("save contains unknown type \"{}\"", name))
+    };
+
+    // Check, upgrade and decode everything before changing the world
+    let prepare = |types: &[Arc<dyn SavedType>], blocks: Vec<Block<BlockData>>| {
+        blocks
+            .into_iter()
+            .map(|block| {
+                let saved = find(types, &block.name)?;
+                let name = block.name.clone();
+                let data = registry.migrate(block)?;
+                saved
+                    .decode(&data)
+                    .map_err(|err| format!("failed to load \"{}\": {}", name, err))
+            })
+            .collect::<Result<Vec<_>, String>>()
+    };
+    let components = prepare(&registry.components, body.components)?;
+    let resources = prepare(&registry.resources, body.resources)?;
+
+    // Only the entities the save has data for are created, and they have to
+    // be among the ones the header says were saved
+    let mut entity_ids: Vec<u32> = components
+        .iter()
+        .flat_map(|decoded| decoded.entity_ids())
+        .collect();
+    entity_ids.sort_unstable();
+    entity_ids.dedup();
+    if let Some(id) = entity_ids.last().filter(|id| **id >= header.entity_count) {
+        return Err(format!(
+            "save refers to entity {} but only has {} entities",
+            id, header.entity_count
+        ));
+    }
+
+    let ids = EntityIds::from_pairs(
+        entity_ids
+            .into_iter()
+            .map(|id| (id, world.create_entity().build())),
+    );
+    for decoded in components.into_iter().chain(resources) {
+        decoded.load(world, &ids);
+    }
+    world.insert(header.map.clone());
+
+    Ok(header)
+}
+
+pub fn save_to_file<P: AsRef<Path>>(
+    world: &World,
+    path: P,
+    format: SaveFormat,
+) -> Result<(), String> {
+    write_atomically(path, &save_world(world, format)?)
+}
+
+// Write the file so that it either has the new contents or the old ones,
+// even if the game crashes partway through. The bytes are written to a
+// temporary file next to it, which then replaces the file.
+pub fn write_atomically<P: AsRef<Path>>(path: P, bytes: &[u8]) -> Result<(), String> {
+    let path = path.as_ref();
+    let mut temp_name = path
+        .file_name()
+        .ok_or_else(|| format!("invalid save path {}", path.display()))?
+        .to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    };
+    write().map_err(|err| {
+        let _ = fs::remove_file(&temp_path);
+        format!("failed to write {}: {}", path.display(), err)
+    })?;
+
+    // Make sure the rename itself is stored. Not every platform can open
+    // directories, so this is allowed to fail.
+    if let Some(directory) = path.parent() {
+        if let Ok(directory) = File::open(directory) {
+            let _ = directory.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+pub fn load_from_file<P: AsRef<Path>>(world: &mut World, path: P) -> Result<SaveHeader, String> {
+    let bytes = fs::read(path).map_err(|err| format!("failed to read save: {}", err))?;
+    load_world(world, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::CameraPlugin;
+    use crate::input::InputPlugin;
+    use crate::picking::PickingPlugin;
+    use crate::plugin::{GameWorld, WorldBuilder};
+    use crate::simulation::AnimationPlugin;
+    use crate::world::{Camera, Parent, Pickable, SimulationTime, Spin, Transform};
+    use nalgebra::{UnitQuaternion, Vector2, Vector3};
+
+    fn build_world() -> World {
+        let GameWorld { world, .. } = WorldBuilder::new()
+            .with_plugin(InputPlugin::new((800, 600)))
+            .with_plugin(CameraPlugin)
+            .with_plugin(PickingPlugin)
+            .with_plugin(AnimationPlugin)
+            .build()
+            .unwrap();
+        world
+    }
+
+    fn populate(world: &mut World) {
+        world.insert(MapInfo::new("Round Trip", 32, 16));
+        world.insert(SimulationTime {
+            tick: 42,
+            step: 0.5,
+        });
+
+        // An entity without saved components, which shouldn't be written
+        world.create_entity().build();
+
+        let parent = world
+            .create_entity()
+            .with(Transform::new(
+                Vector3::new(1.0, 2.0, 3.0),
+                UnitQuaternion::from_euler_angles(0.0, 0.0, 1.0),
+                Vector3::new(2.0, 2.0, 1.0),
+            ))
+            .with(Spin(0.25))
+            .with(Pickable::default())
+            .build();
+        world
+            .create_entity()
+            .with(Transform::new(
+                Vector3::new(-4.0, 0.5, 0.0),
+                UnitQuaternion::identity(),
+                Vector3::new(1.0, 1.0, 1.0),
+            ))
+            .with(Parent(parent))
+            .build();
+        world
+            .create_entity()
+            .with(Camera::new(Vector2::new(5.0, -5.0), 2.0))
+            .build();
+    }
+
+    // Everything saved about a world, with entities replaced by their
+    // transforms' positions so worlds with different entity ids compare
+    fn describe(world: &World) -> Vec<String> {
+        let entities = world.entities();
+        let transforms = world.read_storage::<Transform>();
+        let parents = world.read_storage::<Parent>();
+        let spins = world.read_storage::<Spin>();
+        let pickables = world.read_storage::<Pickable>();
+        let cameras = world.read_storage::<Camera>();
+
+        let mut description: Vec<String> = (&entities)
+            .join()
+            .map(|entity| {
+                format!(
+                    "{:?} {:?} {:?} {:?} {:?}",
+                    transforms.get(entity),
+                    parents
+                        .get(entity)
+                        .and_then(|parent| transforms.get(parent.0))
+                        .map(|transform| transform.position),
+                    spins.get(entity),
+                    pickables.get(entity),
+                    cameras.get(entity),
+                )
+            })
+            .filter(|entity| entity != "None None None None None")
+            .collect();
+        description.sort();
+        description.push(format!("{:?}", *world.fetch::<SimulationTime>()));
+        description
+    }
+
+    fn round_trip(format: SaveFormat) {
+        let mut world = build_world();
+        populate(&mut world);
+        let bytes = save_world(&world, format).unwrap();
+
+        let mut loaded = build_world();
+        let header = load_world(&mut loaded, &bytes).unwrap();
+
+        assert_eq!(header.format_version, SAVE_FORMAT_VERSION);
+        assert_eq!(header.map, MapInfo::new("Round Trip", 32, 16));
+        assert_eq!(header.entity_count, 3);
+        assert_eq!(describe(&loaded), describe(&world));
+
+        // Saving the loaded world again gives the same file
+        assert_eq!(save_world(&loaded, format).unwrap(), bytes);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        round_trip(SaveFormat::Binary);
+    }
+
+    #[test]
+    fn ron_round_trip() {
+        round_trip(SaveFormat::Ron);
+    }
+
+    #[test]
+    fn header_can_be_read_alone() {
+        let mut world = build_world();
+        populate(&mut world);
+
+        for format in [SaveFormat::Binary, SaveFormat::Ron].iter() {
+            let header = read_header(&save_world(&world, *format).unwrap()).unwrap();
+            assert_eq!(header.map.name, "Round Trip");
+            assert_eq!(header.entity_count, 3);
+        }
+    }
+
+    #[test]
+    fn newer_saves_are_rejected() {
+        let mut world = build_world();
+        populate(&mut world);
+        let text = String::from_utf8(save_world(&world, SaveFormat::Ron).unwrap()).unwrap();
+        let newer = text.replacen(
+            &format!("format_version: {}", SAVE_FORMAT_VERSION),
+            &format!("format_version: {}", SAVE_FORMAT_VERSION + 1),
+            1,
+        );
+
+        let err = load_world(&mut build_world(), newer.as_bytes()).unwrap_err();
+        assert!(err.contains("newer version"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_types_are_rejected_without_changing_the_world() {
+        let mut world = build_world();
+        populate(&mut world);
+        let bytes = save_world(&world, SaveFormat::Ron).unwrap();
+
+        // A world without the animation plugin doesn't know about spins
+        let GameWorld { mut world, .. } = WorldBuilder::new()
+            .with_plugin(InputPlugin::new((800, 600)))
+            .with_plugin(CameraPlugin)
+            .with_plugin(PickingPlugin)
+            .build()
+            .unwrap();
+        let err = load_world(&mut world, &bytes).unwrap_err();
+        assert!(err.contains("\"spin\""), "{}", err);
+        assert_eq!((&world.entities()).join().count(), 0);
+    }
+
+    #[test]
+    fn damaged_saves_are_rejected_without_changing_the_world() {
+        let mut world = build_world();
+        populate(&mut world);
+        let save = String::from_utf8(save_world(&world, SaveFormat::Ron).unwrap()).unwrap();
+
+        // The transforms before it decode fine, but nothing may be loaded
+        let damaged = save.replace("(1, 0),", "(1, \"zero\"),");
+        assert_ne!(damaged, save);
+        let mut world = build_world();
+        let err = load_world(&mut world, damaged.as_bytes()).unwrap_err();
+        assert!(err.contains("\"parent\""), "{}", err);
+        assert_eq!((&world.entities()).join().count(), 0);
+
+        // Components of entities the header doesn't count
+        let damaged = save.replace("entity_count: 3", "entity_count: 2");
+        assert_ne!(damaged, save);
+        let mut world = build_world();
+        let err = load_world(&mut world, damaged.as_bytes()).unwrap_err();
+        assert!(err.contains("entity 2"), "{}", err);
+        assert_eq!((&world.entities()).join().count(), 0);
+    }
+
+    // Saves written by older versions of the game, which have to keep loading
+    const FIXTURES: &[(&str, &[u8])] = &[
+        ("v1.city", include_bytes!("../fixtures/saves/v1.city")),
+        ("v1.ron", include_bytes!("../fixtures/saves/v1.ron")),
+        ("v2.city", include_bytes!("../fixtures/saves/v2.city")),
+        ("v2.ron", include_bytes!("../fixtures/saves/v2.ron")),
+    ];
+
+    #[test]
+    fn old_saves_still_load() {
+        let mut expected = build_world();
+        populate(&mut expected);
+
+        for (name, bytes) in FIXTURES.iter() {
+            let mut world = build_world();
+            let header = load_world(&mut world, bytes)
+                .unwrap_or_else(|err| panic!("failed to load {}: {}", name, err));
+
+            assert_eq!(header.map, MapInfo::new("Round Trip", 32, 16), "{}", name);
+            assert_eq!(describe(&world), describe(&expected), "{}", name);
+        }
+    }
+
+    // The same test component at three versions of its saved form
+    #[derive(Component, Serialize, Deserialize, Clone)]
+    #[storage(specs::VecStorage)]
+    struct PopulationV1 {
+        people: u16,
+    }
+
+    #[derive(Component, Serialize, Deserialize, Clone)]
+    #[storage(specs::VecStorage)]
+    struct PopulationV2 {
+        adults: u32,
+        children: u32,
+    }
+
+    #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+    #[storage(specs::VecStorage)]
+    struct Population {
+        adults: u32,
+        children: u32,
+        capacity: u32,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Default)]
+    struct TaxRateV1(u8);
+
+    #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+    struct TaxRate(f32);
+
+    fn build_world_at_version(version: u32) -> World {
+        let mut builder = WorldBuilder::new();
+        match version {
+            1 => {
+                builder
+                    .save_component::<PopulationV1>("population")
+                    .save_resource::<TaxRateV1>("tax_rate");
+            }
+            2 => {
+                builder
+                    .save_component::<PopulationV2>("population")
+                    .save_resource::<TaxRate>("tax_rate")
+                    .migrate_component("population", 1, |old: PopulationV1| PopulationV2 {
+                        adults: u32::from(old.people),
+                        children: 0,
+                    })
+                    .migrate_resource("tax_rate", 1, |old: TaxRateV1| {
+                        TaxRate(f32::from(old.0) / 100.0)
+                    });
+            }
+            _ => {
+                builder
+                    .save_component::<Population>("population")
+                    .save_resource::<TaxRate>("tax_rate")
+                    .migrate_component("population", 1, |old: PopulationV1| PopulationV2 {
+                        adults: u32::from(old.people),
+                        children: 0,
+                    })
+                    .migrate_component("population", 2, |old: PopulationV2| Population {
+                        adults: old.adults,
+                        children: old.children,
+                        capacity: old.adults + old.children,
+                    })
+                    .migrate_resource("tax_rate", 1, |old: TaxRateV1| {
+                        TaxRate(f32::from(old.0) / 100.0)
+                    });
+            }
+        }
+
+        let GameWorld { world, .. } = builder.build().unwrap();
+        world
+    }
+
+    #[test]
+    fn old_types_are_migrated_step_by_step() {
+        for format in [SaveFormat::Binary, SaveFormat::Ron].iter() {
+            let mut old = build_world_at_version(1);
+            old.insert(TaxRateV1(15));
+            old.create_entity()
+                .with(PopulationV1 { people: 12 })
+                .build();
+            let bytes = save_world(&old, *format).unwrap();
+
+            let mut world = build_world_at_version(3);
+            load_world(&mut world, &bytes).unwrap();
+
+            let populations = world.read_storage::<Population>();
+            let populations: Vec<&Population> = populations.join().collect();
+            assert_eq!(
+                populations,
+                vec![&Population {
+                    adults: 12,
+                    children: 0,
+                    capacity: 12,
+                }]
+            );
+            assert_eq!(*world.fetch::<TaxRate>(), TaxRate(0.15));
+        }
+    }
+
+    #[test]
+    fn newer_types_are_rejected() {
+        let mut world = build_world_at_version(3);
+        world.insert(TaxRate(0.1));
+        let bytes = save_world(&world, SaveFormat::Binary).unwrap();
+
+        let err = load_world(&mut build_world_at_version(2), &bytes).unwrap_err();
+        assert!(err.contains("newer version"), "{}", err);
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert!(load_world(&mut build_world(), b"not a save").is_err());
+        assert!(load_world(&mut build_world(), b"CITY\x01").is_err());
+    }
+}
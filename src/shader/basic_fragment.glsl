@@ -6,8 +6,6 @@ in VS_OUT {
 
 out vec4 frag_color;
 
-uniform float red;
-
 void main() {
-    frag_color = vec4(red, IN.color.yz, 1.0);
+    frag_color = vec4(IN.color, 1.0);
 }
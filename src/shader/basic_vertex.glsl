@@ -1,17 +1,18 @@
 #version 330 core
 
+#include "camera.glsl"
+
 layout (location = 0) in vec3 vertex_position;
 layout (location = 1) in vec3 vertex_color;
 
+uniform mat4 object_matrix;
+
 out VS_OUT {
     vec3 color;
 } OUT;
 
-uniform mat4 projection_matrix;
-
 void main() {
     OUT.color = vertex_color;
 
-    gl_Position = projection_matrix
-                * vec4(vertex_position, 1.0);
+    gl_Position = project_position((object_matrix * vec4(vertex_position, 1.0)).xyz);
 }
@@ -0,0 +1,7 @@
+#version 330 core
+
+uniform mat4 projection_matrix;
+
+vec4 project_position(vec3 position) {
+    return projection_matrix * vec4(position, 1.0);
+}
@@ -0,0 +1,17 @@
+#version 330 core
+
+in VS_OUT {
+    vec2 uv;
+    vec4 color;
+} IN;
+
+out vec4 frag_color;
+
+uniform sampler2D sprite_texture;
+
+void main() {
+    frag_color = texture(sprite_texture, IN.uv) * IN.color;
+    if (frag_color.a <= 0.0) {
+        discard;
+    }
+}
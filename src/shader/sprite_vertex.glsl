@@ -0,0 +1,26 @@
+#version 330 core
+
+#include "camera.glsl"
+
+layout (location = 0) in vec2 vertex_position;
+layout (location = 1) in vec2 vertex_uv;
+
+// Per-instance attributes, the object matrix is split into its columns
+layout (location = 2) in vec4 object_matrix_0;
+layout (location = 3) in vec4 object_matrix_1;
+layout (location = 4) in vec4 object_matrix_2;
+layout (location = 5) in vec4 object_matrix_3;
+layout (location = 6) in vec4 sprite_color;
+
+out VS_OUT {
+    vec2 uv;
+    vec4 color;
+} OUT;
+
+void main() {
+    OUT.uv = vertex_uv;
+    OUT.color = sprite_color;
+
+    mat4 object_matrix = mat4(object_matrix_0, object_matrix_1, object_matrix_2, object_matrix_3);
+    gl_Position = project_position((object_matrix * vec4(vertex_position, 0.0, 1.0)).xyz);
+}
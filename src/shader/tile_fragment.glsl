@@ -0,0 +1,14 @@
+#version 330 core
+
+in VS_OUT {
+    vec2 uv;
+    vec4 color;
+} IN;
+
+out vec4 frag_color;
+
+uniform sampler2D tile_atlas;
+
+void main() {
+    frag_color = texture(tile_atlas, IN.uv) * IN.color;
+}
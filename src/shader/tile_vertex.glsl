@@ -0,0 +1,19 @@
+#version 330 core
+
+#include "camera.glsl"
+
+layout (location = 0) in vec3 vertex_position;
+layout (location = 1) in vec2 vertex_uv;
+layout (location = 2) in vec4 vertex_color;
+
+out VS_OUT {
+    vec2 uv;
+    vec4 color;
+} OUT;
+
+void main() {
+    OUT.uv = vertex_uv;
+    OUT.color = vertex_color;
+
+    gl_Position = project_position(vertex_position);
+}
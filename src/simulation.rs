@@ -0,0 +1,152 @@
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::world::{GlobalTransform, InterpolatedTransform, SimulationTime, Spin, Transform};
+use nalgebra::UnitQuaternion;
+use specs::{Join, Read, ReadStorage, System, WriteStorage};
+use std::time::Duration;
+
+// Decides how many fixed size simulation steps to run each frame so the
+// simulation runs at the same rate no matter how fast frames are drawn.
+// Time left over after the last step is used to interpolate between the
+// last two simulation states when drawing.
+pub struct FixedTimestep {
+    step: Duration,
+    // Running more steps than this in a single frame means the simulation
+    // can't keep up, so the remaining time is dropped instead of letting it
+    // pile up forever
+    max_steps: u32,
+    accumulator: Duration,
+}
+
+impl FixedTimestep {
+    pub fn new(ticks_per_second: u32, max_steps: u32) -> Self {
+        Self {
+            step: Duration::from_secs(1) / ticks_per_second.max(1),
+            max_steps: max_steps.max(1),
+            accumulator: Duration::default(),
+        }
+    }
+
+    // Add the time that passed since the last frame and get the number of
+    // steps to run now
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.accumulator += elapsed;
+
+        let mut steps = 0;
+        while self.accumulator >= self.step {
+            if steps == self.max_steps {
+                println!(
+                    "Simulation is falling behind, skipping {:.1}ms",
+                    self.accumulator.as_secs_f64() * 1000.0
+                );
+                self.accumulator = Duration::default();
+                break;
+            }
+
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+
+        steps
+    }
+
+    // How far the current time is between the last simulation step and the
+    // next one, from 0 to 1
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator.as_secs_f64() / self.step.as_secs_f64()) as f32
+    }
+
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+}
+
+// Remembers where interpolated entities were before the step so they can be
+// drawn between their old and new positions. Runs first in every step.
+pub struct StorePreviousTransforms;
+
+impl<'a> System<'a> for StorePreviousTransforms {
+    type SystemData = (
+        ReadStorage<'a, GlobalTransform>,
+        WriteStorage<'a, InterpolatedTransform>,
+    );
+
+    fn run(&mut self, (globals, mut interpolated): Self::SystemData) {
+        for (global, interpolated) in (&globals, &mut interpolated).join() {
+            interpolated.previous = Some(global.0);
+        }
+    }
+}
+
+// Rotates entities with a `Spin` around the Z axis
+pub struct SpinSystem;
+
+impl<'a> System<'a> for SpinSystem {
+    type SystemData = (
+        Read<'a, SimulationTime>,
+        ReadStorage<'a, Spin>,
+        WriteStorage<'a, Transform>,
+    );
+
+    fn run(&mut self, (time, spins, mut transforms): Self::SystemData) {
+        for (spin, transform) in (&spins, &mut transforms).join() {
+            transform.rotation *= UnitQuaternion::from_euler_angles(0.0, 0.0, spin.0 * time.step);
+        }
+    }
+}
+
+// Simple animations that run as part of the simulation
+pub struct AnimationPlugin;
+
+impl Plugin for AnimationPlugin {
+    fn name(&self) -> &'static str {
+        "animation"
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .save_component::<Spin>("spin")
+            .add_simulation_system(SpinSystem, "spin", &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn time_is_saved_up_for_whole_steps() {
+        let mut timestep = FixedTimestep::new(10, 5);
+        assert_eq!(timestep.step(), millis(100));
+
+        assert_eq!(timestep.advance(millis(50)), 0);
+        assert!((timestep.alpha() - 0.5).abs() < 1e-5);
+        assert_eq!(timestep.advance(millis(60)), 1);
+        assert!((timestep.alpha() - 0.1).abs() < 1e-5);
+        assert_eq!(timestep.advance(millis(250)), 2);
+        assert!((timestep.alpha() - 0.6).abs() < 1e-5);
+    }
+
+    #[test]
+    fn time_past_the_step_limit_is_dropped() {
+        let mut timestep = FixedTimestep::new(10, 5);
+        assert_eq!(timestep.advance(millis(1050)), 5);
+        assert_eq!(timestep.alpha(), 0.0);
+        assert_eq!(timestep.advance(millis(100)), 1);
+
+        // Exactly the limit isn't falling behind
+        assert_eq!(timestep.advance(millis(550)), 5);
+        assert!((timestep.alpha() - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn zero_rates_and_limits_still_step() {
+        let mut timestep = FixedTimestep::new(0, 0);
+        assert_eq!(timestep.step(), Duration::from_secs(1));
+        assert_eq!(timestep.advance(Duration::from_secs(3)), 1);
+        assert_eq!(timestep.alpha(), 0.0);
+    }
+}
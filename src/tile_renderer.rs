@@ -0,0 +1,742 @@
+use crate::renderer::{Bounds, Mat4, RenderStats};
+use crate::roads::{RoadNetwork, SegmentId};
+use crate::tilemap::{Terrain, Tile, TileMap, ZoneDensity, CHUNK_SIZE};
+use crate::tools::TileHighlights;
+use crate::world::Building;
+use crate::zoning;
+use gl::types::GLushort;
+use gl_bindings::{gl, Gl};
+use nalgebra::{Matrix4, Point3, Vector2, Vector3, Vector4};
+use render::assets::{Assets, Handle};
+use render::shader_source::{ShaderDefines, ShaderLoader};
+use render::{Mesh, ShaderProgram, ShaderStage, Texture, Vec2, Vec3, Vec4};
+use std::collections::HashMap;
+
+// The texture unit the atlas is bound to
+const ATLAS_TEXTURE_UNIT: u32 = 0;
+
+// Only this many highlighted tiles fit in a mesh with 16 bit indices
+const MAX_HIGHLIGHTS: usize = (GLushort::MAX as usize + 1) / 4;
+
+// And this many vehicles or buildings
+const MAX_VEHICLES: usize = (GLushort::MAX as usize + 1) / 4;
+const MAX_BUILDINGS: usize = (GLushort::MAX as usize + 1) / 4;
+
+// How far buildings are drawn in from the corners of their lots, so
+// neighboring buildings don't run together
+const BUILDING_INSET: f32 = 0.1;
+
+// Road meshes are started over before they get too big for 16 bit indices
+const MAX_MESH_VERTICES: usize = GLushort::MAX as usize;
+
+// Junctions are drawn as octagons
+const JUNCTION_SIDES: usize = 8;
+
+// The atlas is a grid of square cells, one for each kind of tile image
+const ATLAS_CELL_SIZE: u32 = 16;
+const ATLAS_CELLS: u32 = 4;
+
+#[derive(render_derive::VertexAttribPointers, Copy, Clone, Debug)]
+#[repr(C, packed)]
+struct TileVertex {
+    #[location = 0]
+    pos: Vec3,
+
+    #[location = 1]
+    uv: Vec2,
+
+    #[location = 2]
+    color: Vec4,
+}
+
+type ChunkMesh = Mesh<TileVertex, GLushort>;
+
+// The layers of the map's chunks, drawn on top of each other in this order.
+// Roads are drawn over them from the road network.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TileLayer {
+    Ground,
+    Zoning,
+}
+
+impl TileLayer {
+    pub const ALL: [TileLayer; 2] = [TileLayer::Ground, TileLayer::Zoning];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// The images in the atlas
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum AtlasCell {
+    Terrain(Terrain),
+    ZoneOverlay,
+    Road,
+    Vehicle,
+    Building,
+}
+
+impl AtlasCell {
+    fn index(self) -> u32 {
+        match self {
+            AtlasCell::Terrain(terrain) => terrain as u32,
+            AtlasCell::ZoneOverlay => 5,
+            AtlasCell::Road => 6,
+            AtlasCell::Vehicle => 7,
+            AtlasCell::Building => 8,
+        }
+    }
+
+    // The corners of the cell in the atlas, moved in by half a texel so
+    // neighboring cells don't bleed in
+    fn uvs(self) -> (Vec2, Vec2) {
+        let size = (ATLAS_CELL_SIZE * ATLAS_CELLS) as f32;
+        let inset = 0.5 / size;
+        let cell = 1.0 / ATLAS_CELLS as f32;
+        let x = (self.index() % ATLAS_CELLS) as f32 * cell;
+        let y = (self.index() / ATLAS_CELLS) as f32 * cell;
+
+        (
+            Vec2::new(x + inset, y + inset),
+            Vec2::new(x + cell - inset, y + cell - inset),
+        )
+    }
+
+    // The middle of one of the cell's texels
+    fn texel_uv(self, x: u32, y: u32) -> Vec2 {
+        let size = (ATLAS_CELL_SIZE * ATLAS_CELLS) as f32;
+        let cell_x = self.index() % ATLAS_CELLS * ATLAS_CELL_SIZE + x;
+        let cell_y = self.index() / ATLAS_CELLS * ATLAS_CELL_SIZE + y;
+        Vec2::new((cell_x as f32 + 0.5) / size, (cell_y as f32 + 0.5) / size)
+    }
+
+    // The color of a pixel of the cell. The images are generated so the game
+    // doesn't need any art files yet.
+    fn pixel(self, x: u32, y: u32) -> [u8; 4] {
+        let edge = x == 0 || y == 0 || x == ATLAS_CELL_SIZE - 1 || y == ATLAS_CELL_SIZE - 1;
+        let speckle = (noise(x, y, self.index()) % 24) as u8;
+
+        match self {
+            AtlasCell::Terrain(Terrain::Grass) => [70 + speckle, 140 + speckle, 60, 255],
+            AtlasCell::Terrain(Terrain::Dirt) => [120 + speckle, 90 + speckle, 60, 255],
+            AtlasCell::Terrain(Terrain::Sand) => [210 + speckle, 195 + speckle, 140, 255],
+            AtlasCell::Terrain(Terrain::Rock) => [110 + speckle, 110 + speckle, 115 + speckle, 255],
+            AtlasCell::Terrain(Terrain::Water) => [40, 90 + speckle / 2, 170 + speckle, 255],
+            // White so the zone's color can be applied with the vertex color
+            AtlasCell::ZoneOverlay if edge => [255, 255, 255, 220],
+            AtlasCell::ZoneOverlay => [255, 255, 255, 90],
+            AtlasCell::Road => {
+                let middle = ATLAS_CELL_SIZE / 2;
+                let dash = (x == middle - 1 || x == middle) && y % 4 < 2;
+                if dash {
+                    [230, 220, 120, 255]
+                } else {
+                    [70 + speckle / 2, 70 + speckle / 2, 75 + speckle / 2, 255]
+                }
+            }
+            // White so each vehicle can have its own color, with windows
+            // across the front and back
+            AtlasCell::Vehicle => {
+                let window = (x == 3 || x == ATLAS_CELL_SIZE - 5) && !edge;
+                if edge {
+                    [40, 40, 40, 255]
+                } else if window {
+                    [120, 150, 180, 255]
+                } else {
+                    [255, 255, 255, 255]
+                }
+            }
+            // A roof tinted with the zone's color, with a ridge down the
+            // middle
+            AtlasCell::Building => {
+                let middle = ATLAS_CELL_SIZE / 2;
+                if edge {
+                    [60, 60, 60, 255]
+                } else if x == middle - 1 || x == middle {
+                    [170, 170, 170, 255]
+                } else {
+                    [220 + speckle, 220 + speckle, 220 + speckle, 255]
+                }
+            }
+        }
+    }
+}
+
+// Cheap hash used to speckle the generated images
+fn noise(x: u32, y: u32, seed: u32) -> u32 {
+    let mut n = x
+        .wrapping_mul(374_761_393)
+        .wrapping_add(y.wrapping_mul(668_265_263))
+        .wrapping_add(seed.wrapping_mul(2_147_483_647));
+    n = (n ^ (n >> 13)).wrapping_mul(1_274_126_177);
+    n ^ (n >> 16)
+}
+
+fn atlas_pixels() -> Vec<u8> {
+    let size = ATLAS_CELL_SIZE * ATLAS_CELLS;
+    let cells = [
+        AtlasCell::Terrain(Terrain::Grass),
+        AtlasCell::Terrain(Terrain::Dirt),
+        AtlasCell::Terrain(Terrain::Sand),
+        AtlasCell::Terrain(Terrain::Rock),
+        AtlasCell::Terrain(Terrain::Water),
+        AtlasCell::ZoneOverlay,
+        AtlasCell::Road,
+        AtlasCell::Vehicle,
+        AtlasCell::Building,
+    ];
+
+    let mut pixels = vec![0; (size * size * 4) as usize];
+    for cell in cells.iter() {
+        let cell_x = cell.index() % ATLAS_CELLS * ATLAS_CELL_SIZE;
+        let cell_y = cell.index() / ATLAS_CELLS * ATLAS_CELL_SIZE;
+        for y in 0..ATLAS_CELL_SIZE {
+            for x in 0..ATLAS_CELL_SIZE {
+                let i = (((cell_y + y) * size + cell_x + x) * 4) as usize;
+                pixels[i..i + 4].copy_from_slice(&cell.pixel(x, y));
+            }
+        }
+    }
+
+    pixels
+}
+
+fn to_vec4(color: Vector4<f32>) -> Vec4 {
+    Vec4::new(color.x, color.y, color.z, color.w)
+}
+
+// The image and color a tile is drawn with on the layer, if it's drawn
+fn tile_appearance(tile: &Tile, layer: TileLayer) -> Option<(AtlasCell, Vec4)> {
+    match layer {
+        TileLayer::Ground => {
+            // Higher ground is drawn lighter and forests are drawn darker
+            let shade = 0.7 + 0.3 * f32::from(tile.height) / 255.0;
+            let forest = 1.0 - 0.5 * f32::from(tile.forest) / 255.0;
+            Some((
+                AtlasCell::Terrain(tile.terrain),
+                Vec4::new(
+                    shade * forest,
+                    shade * (0.4 + 0.6 * forest),
+                    shade * forest,
+                    1.0,
+                ),
+            ))
+        }
+        TileLayer::Zoning => {
+            // Denser zones are drawn more solid
+            let opacity = match tile.density {
+                ZoneDensity::Low => 0.6,
+                ZoneDensity::Medium => 0.8,
+                ZoneDensity::High => 1.0,
+            };
+            zoning::zone_color(tile.zone).map(|color| {
+                (
+                    AtlasCell::ZoneOverlay,
+                    to_vec4(Vector4::new(color.x, color.y, color.z, color.w * opacity)),
+                )
+            })
+        }
+    }
+}
+
+// Add a quad covering the tile. Tile (x, y) covers the square from (x, y) to
+// (x + 1, y + 1).
+fn push_quad(
+    vertices: &mut Vec<TileVertex>,
+    indices: &mut Vec<GLushort>,
+    (x, y): (i32, i32),
+    cell: AtlasCell,
+    color: Vec4,
+) {
+    let (uv_min, uv_max) = cell.uvs();
+    let (x, y) = (x as f32, y as f32);
+    let first = vertices.len() as GLushort;
+    vertices.extend_from_slice(&[
+        TileVertex {
+            pos: Vec3::new(x, y, 0.0),
+            uv: Vec2::new(uv_min.x, uv_max.y),
+            color,
+        },
+        TileVertex {
+            pos: Vec3::new(x, y + 1.0, 0.0),
+            uv: Vec2::new(uv_min.x, uv_min.y),
+            color,
+        },
+        TileVertex {
+            pos: Vec3::new(x + 1.0, y + 1.0, 0.0),
+            uv: Vec2::new(uv_max.x, uv_min.y),
+            color,
+        },
+        TileVertex {
+            pos: Vec3::new(x + 1.0, y, 0.0),
+            uv: Vec2::new(uv_max.x, uv_max.y),
+            color,
+        },
+    ]);
+    indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+}
+
+// Build the mesh of one layer of a chunk, with a quad for every tile drawn
+// on the layer
+fn build_layer(gl: &Gl, map: &TileMap, chunk: (i32, i32), layer: TileLayer) -> Option<ChunkMesh> {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+
+    for (x, y) in map.chunk_tiles(chunk) {
+        let (cell, color) = match map
+            .get((x, y))
+            .and_then(|tile| tile_appearance(tile, layer))
+        {
+            Some(appearance) => appearance,
+            None => continue,
+        };
+
+        push_quad(&mut vertices, &mut indices, (x, y), cell, color);
+    }
+
+    if indices.is_empty() {
+        None
+    } else {
+        Some(Mesh::create(gl, vertices, indices))
+    }
+}
+
+// Collects road geometry into as many meshes as it takes to keep each one
+// small enough for 16 bit indices
+#[derive(Default)]
+struct RoadMeshBuilder {
+    vertices: Vec<TileVertex>,
+    indices: Vec<GLushort>,
+    finished: Vec<(Vec<TileVertex>, Vec<GLushort>)>,
+}
+
+impl RoadMeshBuilder {
+    // Add the vertices with indices relative to the first of them
+    fn push(&mut self, vertices: &[TileVertex], indices: &[GLushort]) {
+        if self.vertices.len() + vertices.len() > MAX_MESH_VERTICES {
+            self.finish();
+        }
+
+        let first = self.vertices.len() as GLushort;
+        self.vertices.extend_from_slice(vertices);
+        self.indices
+            .extend(indices.iter().map(|index| first + *index));
+    }
+
+    fn finish(&mut self) {
+        if !self.indices.is_empty() {
+            let vertices = std::mem::take(&mut self.vertices);
+            let indices = std::mem::take(&mut self.indices);
+            self.finished.push((vertices, indices));
+        }
+    }
+
+    fn build(mut self, gl: &Gl) -> Vec<ChunkMesh> {
+        self.finish();
+        self.finished
+            .into_iter()
+            .map(|(vertices, indices)| Mesh::create(gl, vertices, indices))
+            .collect()
+    }
+}
+
+fn road_vertex(pos: Vector2<f32>, uv: Vec2) -> TileVertex {
+    TileVertex {
+        pos: Vec3::new(pos.x, pos.y, 0.0),
+        uv,
+        color: Vec4::new(1.0, 1.0, 1.0, 1.0),
+    }
+}
+
+// Build the meshes of the road surfaces. Segments are split into pieces about
+// a tile long so the road's markings repeat along them, and the ends of
+// segments are covered by plain junctions.
+fn build_roads(gl: &Gl, network: &RoadNetwork) -> Vec<ChunkMesh> {
+    let mut builder = RoadMeshBuilder::default();
+    let (uv_min, uv_max) = AtlasCell::Road.uvs();
+
+    for (id, segment) in network.segments() {
+        let (from, to) = match network.segment_ends(id) {
+            Some(ends) => ends,
+            None => continue,
+        };
+        let length = (to - from).norm();
+        let direction = (to - from) / length;
+        let side = Vector2::new(-direction.y, direction.x) * segment.road_type.width() / 2.0;
+
+        let pieces = length.round().max(1.0) as usize;
+        for piece in 0..pieces {
+            let start = from + (to - from) * (piece as f32 / pieces as f32);
+            let end = from + (to - from) * ((piece + 1) as f32 / pieces as f32);
+            builder.push(
+                &[
+                    road_vertex(start - side, Vec2::new(uv_max.x, uv_max.y)),
+                    road_vertex(start + side, Vec2::new(uv_min.x, uv_max.y)),
+                    road_vertex(end + side, Vec2::new(uv_min.x, uv_min.y)),
+                    road_vertex(end - side, Vec2::new(uv_max.x, uv_min.y)),
+                ],
+                &[0, 1, 2, 0, 2, 3],
+            );
+        }
+    }
+
+    // Cover the ends of every segment meeting at a node so corners and
+    // crossings don't show gaps or markings running into each other
+    let asphalt = AtlasCell::Road.texel_uv(1, 1);
+    let corner_angle = std::f32::consts::PI * 2.0 / JUNCTION_SIDES as f32;
+    for (_, node) in network.nodes() {
+        let width = node
+            .segments
+            .iter()
+            .filter_map(|id| network.segment(*id))
+            .map(|segment| segment.road_type.width())
+            .fold(0.0, f32::max);
+        if node.segments.len() == 2 && is_straight(network, node.position, &node.segments) {
+            continue;
+        }
+
+        // Far enough out that the flat sides are as wide as the road
+        let radius = width / 2.0 / (corner_angle / 2.0).cos();
+        let mut vertices = vec![road_vertex(node.position, asphalt)];
+        let mut indices = Vec::new();
+        for corner in 0..JUNCTION_SIDES {
+            let angle = corner_angle * (corner as f32 + 0.5);
+            let offset = Vector2::new(angle.cos(), angle.sin()) * radius;
+            vertices.push(road_vertex(node.position + offset, asphalt));
+
+            let next = (corner + 1) % JUNCTION_SIDES;
+            indices.extend_from_slice(&[0, corner as GLushort + 1, next as GLushort + 1]);
+        }
+        builder.push(&vertices, &indices);
+    }
+
+    builder.build(gl)
+}
+
+// Whether the two segments continue straight through the point, in which
+// case the road's markings run through it too
+fn is_straight(network: &RoadNetwork, point: Vector2<f32>, segments: &[SegmentId]) -> bool {
+    let directions: Vec<Vector2<f32>> = segments
+        .iter()
+        .filter_map(|id| network.segment_ends(*id))
+        .map(|(from, to)| {
+            let away = if (from - point).norm() < (to - point).norm() {
+                to - point
+            } else {
+                from - point
+            };
+            away.normalize()
+        })
+        .collect();
+
+    directions.len() == 2 && directions[0].dot(&directions[1]) < -0.999
+}
+
+struct ChunkMeshes {
+    layers: [Option<ChunkMesh>; 2],
+    bounds: Bounds,
+}
+
+// Draws the tile map with one mesh per chunk and layer. Chunks are only
+// remeshed when their tiles change, and chunks outside of the view aren't
+// drawn. Roads and then highlighted tiles are drawn on top.
+pub struct TileMapRenderer {
+    gl: Gl,
+    shader: Handle<ShaderProgram>,
+    atlas: Texture,
+    chunks: HashMap<(i32, i32), ChunkMeshes>,
+    // The map's size and revision when the meshes were last updated
+    size: (u32, u32),
+    revision: Option<u64>,
+    // The road network's meshes, and its revision when they were built
+    road_meshes: Vec<ChunkMesh>,
+    road_revision: Option<u64>,
+    // Drawn over the map, and what it was built from
+    highlight_mesh: Option<ChunkMesh>,
+    highlights: TileHighlights,
+    vehicle_mesh: Option<ChunkMesh>,
+    // The building mesh, and the map revision it was colored with
+    building_mesh: Option<ChunkMesh>,
+    building_revision: Option<u64>,
+}
+
+impl TileMapRenderer {
+    pub fn new(
+        gl: &Gl,
+        shader_loader: &ShaderLoader,
+        shaders: &mut Assets<ShaderProgram>,
+    ) -> Result<Self, String> {
+        let shader = shader_loader.load_program(
+            gl,
+            shaders,
+            &[
+                (ShaderStage::Vertex, "tile_vertex.glsl"),
+                (ShaderStage::Fragment, "tile_fragment.glsl"),
+            ],
+            &ShaderDefines::new(),
+            &["projection_matrix", "tile_atlas"],
+        )?;
+
+        let atlas_size = ATLAS_CELL_SIZE * ATLAS_CELLS;
+        Ok(Self {
+            gl: gl.clone(),
+            shader,
+            atlas: Texture::new_rgba(gl, atlas_size, atlas_size, &atlas_pixels()),
+            chunks: HashMap::new(),
+            size: (0, 0),
+            revision: None,
+            road_meshes: Vec::new(),
+            road_revision: None,
+            highlight_mesh: None,
+            highlights: TileHighlights::default(),
+            vehicle_mesh: None,
+            building_mesh: None,
+            building_revision: None,
+        })
+    }
+
+    // Rebuild the meshes of the chunks that changed since the last update.
+    // Returns the number of chunks that were rebuilt.
+    pub fn update(&mut self, map: &TileMap) -> usize {
+        // A different map was loaded, so every chunk has to be rebuilt
+        let size = (map.width(), map.height());
+        if size != self.size
+            || self
+                .revision
+                .is_some_and(|revision| map.revision() < revision)
+        {
+            self.chunks.clear();
+            self.revision = None;
+            self.size = size;
+        }
+
+        let changed: Vec<(i32, i32)> = match self.revision {
+            Some(revision) => map.changed_chunks_since(revision).collect(),
+            None => {
+                let (chunks_x, chunks_y) = map.chunk_count();
+                (0..chunks_y)
+                    .flat_map(|cy| (0..chunks_x).map(move |cx| (cx, cy)))
+                    .collect()
+            }
+        };
+
+        for &chunk in changed.iter() {
+            let min = Vector3::new(
+                (chunk.0 * CHUNK_SIZE) as f32,
+                (chunk.1 * CHUNK_SIZE) as f32,
+                0.0,
+            );
+            let max = Vector3::new(
+                ((chunk.0 + 1) * CHUNK_SIZE).min(map.width() as i32) as f32,
+                ((chunk.1 + 1) * CHUNK_SIZE).min(map.height() as i32) as f32,
+                0.0,
+            );
+            let build = |layer| build_layer(&self.gl, map, chunk, layer);
+
+            self.chunks.insert(
+                chunk,
+                ChunkMeshes {
+                    layers: [build(TileLayer::Ground), build(TileLayer::Zoning)],
+                    bounds: Bounds::new(min, max),
+                },
+            );
+        }
+        self.revision = Some(map.revision());
+
+        changed.len()
+    }
+
+    // Rebuild the road meshes if the road network changed. Returns whether
+    // they were rebuilt.
+    pub fn update_roads(&mut self, network: &RoadNetwork) -> bool {
+        if self.road_revision == Some(network.revision()) {
+            return false;
+        }
+
+        self.road_meshes = build_roads(&self.gl, network);
+        self.road_revision = Some(network.revision());
+        true
+    }
+
+    // Rebuild the highlight mesh if the highlighted tiles changed
+    pub fn update_highlights(&mut self, highlights: &TileHighlights) {
+        if *highlights == self.highlights {
+            return;
+        }
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (pos, color) in highlights.0.iter().take(MAX_HIGHLIGHTS) {
+            push_quad(
+                &mut vertices,
+                &mut indices,
+                *pos,
+                AtlasCell::ZoneOverlay,
+                to_vec4(*color),
+            );
+        }
+
+        self.highlight_mesh = if indices.is_empty() {
+            None
+        } else {
+            Some(Mesh::create(&self.gl, vertices, indices))
+        };
+        self.highlights = highlights.clone();
+    }
+
+    // Rebuild the building mesh if the buildings or the zones they're on
+    // changed. Buildings are drawn in the color of the zone they're on,
+    // darker the higher their level, and grey once they're abandoned.
+    pub fn update_buildings<'b, I>(&mut self, map: &TileMap, changed: bool, buildings: I)
+    where
+        I: IntoIterator<Item = &'b Building>,
+    {
+        if !changed && self.building_revision == Some(map.revision()) {
+            return;
+        }
+
+        let (uv_min, uv_max) = AtlasCell::Building.uvs();
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for building in buildings.into_iter().take(MAX_BUILDINGS) {
+            let zone_color = map
+                .get(building.lot)
+                .and_then(|tile| zoning::zone_color(tile.zone))
+                .filter(|_| building.abandoned.is_none());
+            let color = match zone_color {
+                Some(color) => {
+                    let shade = 1.0 - 0.2 * f32::from(building.level.saturating_sub(1));
+                    Vector4::new(color.x * shade, color.y * shade, color.z * shade, 1.0)
+                }
+                None => Vector4::new(0.5, 0.5, 0.5, 1.0),
+            };
+            let color = to_vec4(color);
+            let (x, y) = (building.lot.0 as f32, building.lot.1 as f32);
+            let (width, height) = (f32::from(building.size.0), f32::from(building.size.1));
+            let first = vertices.len() as GLushort;
+            let corners = [
+                (Vector2::new(x, y), Vec2::new(uv_min.x, uv_max.y)),
+                (Vector2::new(x, y + height), Vec2::new(uv_min.x, uv_min.y)),
+                (
+                    Vector2::new(x + width, y + height),
+                    Vec2::new(uv_max.x, uv_min.y),
+                ),
+                (Vector2::new(x + width, y), Vec2::new(uv_max.x, uv_max.y)),
+            ];
+            let center = Vector2::new(x + width / 2.0, y + height / 2.0);
+            for (corner, uv) in corners.iter() {
+                let pos = corner + (center - corner).normalize() * BUILDING_INSET;
+                vertices.push(TileVertex {
+                    pos: Vec3::new(pos.x, pos.y, 0.0),
+                    uv: *uv,
+                    color,
+                });
+            }
+            indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+
+        self.building_mesh = if indices.is_empty() {
+            None
+        } else {
+            Some(Mesh::create(&self.gl, vertices, indices))
+        };
+        self.building_revision = Some(map.revision());
+    }
+
+    // Refill the vehicle mesh, which changes every frame, so its buffers are
+    // kept and updated in place. Each vehicle is a unit square moved by its
+    // object matrix and tinted with its color.
+    pub fn update_vehicles(&mut self, vehicles: &[(Matrix4<f32>, Vector4<f32>)]) {
+        let (uv_min, uv_max) = AtlasCell::Vehicle.uvs();
+        let corners = [
+            (Vector2::new(-0.5, -0.5), Vec2::new(uv_min.x, uv_max.y)),
+            (Vector2::new(-0.5, 0.5), Vec2::new(uv_min.x, uv_min.y)),
+            (Vector2::new(0.5, 0.5), Vec2::new(uv_max.x, uv_min.y)),
+            (Vector2::new(0.5, -0.5), Vec2::new(uv_max.x, uv_max.y)),
+        ];
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (matrix, color) in vehicles.iter().take(MAX_VEHICLES) {
+            let first = vertices.len() as GLushort;
+            for (corner, uv) in corners.iter() {
+                let pos = matrix.transform_point(&Point3::new(corner.x, corner.y, 0.0));
+                vertices.push(TileVertex {
+                    pos: Vec3::new(pos.x, pos.y, pos.z),
+                    uv: *uv,
+                    color: to_vec4(*color),
+                });
+            }
+            indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+
+        match &mut self.vehicle_mesh {
+            Some(mesh) => mesh.update(vertices, indices),
+            None if indices.is_empty() => {}
+            None => self.vehicle_mesh = Some(Mesh::create(&self.gl, vertices, indices)),
+        }
+    }
+
+    pub fn render(
+        &self,
+        view_projection: &Matrix4<f32>,
+        shaders: &Assets<ShaderProgram>,
+        stats: &mut RenderStats,
+    ) {
+        let shader = match shaders.get(&self.shader) {
+            Some(shader) => shader,
+            None => return,
+        };
+
+        let visible: Vec<&ChunkMeshes> = self
+            .chunks
+            .values()
+            .filter(|chunk| chunk.bounds.is_visible(view_projection))
+            .collect();
+        stats.chunks += visible.len();
+        stats.culled += self.chunks.len() - visible.len();
+
+        // The map is below everything else, so the layers are drawn over each
+        // other without touching the depth buffer
+        unsafe {
+            self.gl.Enable(gl::BLEND);
+            self.gl.BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+        }
+        shader.bind();
+        shader.set_uniform("projection_matrix", &Mat4(*view_projection));
+        shader.set_uniform("tile_atlas", &(ATLAS_TEXTURE_UNIT as i32));
+        self.atlas.bind(ATLAS_TEXTURE_UNIT);
+
+        for layer in TileLayer::ALL.iter() {
+            for chunk in visible.iter() {
+                if let Some(mesh) = &chunk.layers[layer.index()] {
+                    mesh.render();
+                    stats.draw_calls += 1;
+                }
+            }
+        }
+        for mesh in self.road_meshes.iter() {
+            mesh.render();
+            stats.draw_calls += 1;
+        }
+        if let Some(mesh) = &self.building_mesh {
+            mesh.render();
+            stats.draw_calls += 1;
+        }
+        if let Some(mesh) = self.vehicle_mesh.as_ref().filter(|mesh| !mesh.is_empty()) {
+            mesh.render();
+            stats.draw_calls += 1;
+        }
+        if let Some(mesh) = &self.highlight_mesh {
+            mesh.render();
+            stats.draw_calls += 1;
+        }
+
+        self.atlas.unbind(ATLAS_TEXTURE_UNIT);
+        shader.unbind();
+        unsafe {
+            self.gl.Disable(gl::BLEND);
+        }
+    }
+}
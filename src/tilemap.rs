@@ -0,0 +1,860 @@
+use crate::mapgen::{self, MapGenParams};
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::save::{EntityIds, MapEntities};
+use serde::{Deserialize, Serialize};
+use specs::Entity;
+use std::collections::VecDeque;
+
+// Width and height of a chunk in tiles. Chunks are the unit of change
+// tracking, so everything about a chunk is rebuilt when any of its tiles
+// change.
+pub const CHUNK_SIZE: i32 = 32;
+
+// The widest and tallest map in tiles. Saved maps bigger than this are
+// rejected instead of trying to allocate them.
+pub const MAX_MAP_SIZE: u32 = 4096;
+
+const NEIGHBORS4: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+const NEIGHBORS8: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Terrain {
+    #[default]
+    Grass,
+    Dirt,
+    Sand,
+    Rock,
+    Water,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Zone {
+    #[default]
+    Unzoned,
+    Residential,
+    Commercial,
+    Industrial,
+}
+
+// How tightly buildings are packed in a zone
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ZoneDensity {
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+// Small facts about a tile, packed into a byte
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TileFlags(u8);
+
+impl TileFlags {
+    pub const NONE: Self = Self(0);
+    // Has a road on it
+    pub const ROAD: Self = Self(1);
+    // Has power lines or is next to a powered building
+    pub const POWERED: Self = Self(1 << 1);
+    // Has water pipes under it
+    pub const WATER_PIPE: Self = Self(1 << 2);
+    // Can't be built on, like the edge of the map
+    pub const LOCKED: Self = Self(1 << 3);
+
+    pub fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn insert(&mut self, flags: Self) {
+        self.0 |= flags.0;
+    }
+
+    pub fn remove(&mut self, flags: Self) {
+        self.0 &= !flags.0;
+    }
+
+    pub fn set(&mut self, flags: Self, value: bool) {
+        if value {
+            self.insert(flags);
+        } else {
+            self.remove(flags);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Tile {
+    pub terrain: Terrain,
+    // Height of the ground in levels above the lowest point of the map
+    pub height: u8,
+    pub zone: Zone,
+    pub density: ZoneDensity,
+    // The building or road that's on the tile
+    pub owner: Option<Entity>,
+    pub flags: TileFlags,
+    // How thickly the tile is covered in trees, 0 for none
+    pub forest: u8,
+    // How much ore can be mined from the tile
+    pub ore: u8,
+    // How well crops grow on the tile
+    pub fertility: u8,
+}
+
+// The tiles on the line between the two tiles (inclusive), from `from` to
+// `to`
+pub fn line_tiles(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    // Bresenham's line algorithm
+    let dx = (to.0 - from.0).abs();
+    let dy = -(to.1 - from.1).abs();
+    let step_x = if from.0 < to.0 { 1 } else { -1 };
+    let step_y = if from.1 < to.1 { 1 } else { -1 };
+
+    let mut tiles = Vec::new();
+    let (mut x, mut y) = from;
+    let mut error = dx + dy;
+    loop {
+        tiles.push((x, y));
+        if (x, y) == to {
+            break;
+        }
+
+        let double_error = 2 * error;
+        if double_error >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if double_error <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+
+    tiles
+}
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    tiles: Vec<Tile>,
+    // The map's revision when the chunk was last changed
+    revision: u64,
+}
+
+// The land the city is built on, split into chunks of `CHUNK_SIZE` tiles.
+// Every chunk remembers when it was last changed so things built from the
+// tiles, like meshes, only have to be rebuilt for the chunks that changed.
+#[derive(Debug, Clone)]
+pub struct TileMap {
+    width: i32,
+    height: i32,
+    chunks_x: i32,
+    chunks_y: i32,
+    chunks: Vec<Chunk>,
+    // Increased every time a chunk changes
+    revision: u64,
+}
+
+impl TileMap {
+    pub fn new(width: u32, height: u32) -> Self {
+        let width = width as i32;
+        let height = height as i32;
+        let chunks_x = (width + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let chunks_y = (height + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let chunk = Chunk {
+            tiles: vec![Tile::default(); (CHUNK_SIZE * CHUNK_SIZE) as usize],
+            revision: 0,
+        };
+
+        Self {
+            width,
+            height,
+            chunks_x,
+            chunks_y,
+            chunks: vec![chunk; (chunks_x * chunks_y) as usize],
+            revision: 0,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height as u32
+    }
+
+    pub fn contains(&self, (x, y): (i32, i32)) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    fn index(&self, (x, y): (i32, i32)) -> Option<(usize, usize)> {
+        if !self.contains((x, y)) {
+            return None;
+        }
+
+        let chunk = (y / CHUNK_SIZE) * self.chunks_x + x / CHUNK_SIZE;
+        let tile = (y % CHUNK_SIZE) * CHUNK_SIZE + x % CHUNK_SIZE;
+        Some((chunk as usize, tile as usize))
+    }
+
+    pub fn get(&self, pos: (i32, i32)) -> Option<&Tile> {
+        self.index(pos)
+            .map(|(chunk, tile)| &self.chunks[chunk].tiles[tile])
+    }
+
+    // The tile's chunk is marked as changed, even if the tile isn't
+    pub fn get_mut(&mut self, pos: (i32, i32)) -> Option<&mut Tile> {
+        let (chunk, tile) = self.index(pos)?;
+        self.revision += 1;
+        let chunk = &mut self.chunks[chunk];
+        chunk.revision = self.revision;
+        Some(&mut chunk.tiles[tile])
+    }
+
+    // Replace the tile, only marking its chunk as changed if it's different.
+    // Returns false if the position is outside of the map.
+    pub fn set(&mut self, pos: (i32, i32), tile: Tile) -> bool {
+        match self.get(pos) {
+            Some(old) if *old == tile => true,
+            Some(_) => {
+                *self.get_mut(pos).unwrap() = tile;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Change the tile with `change`, only marking its chunk as changed if
+    // the tile ends up different
+    pub fn update<F: FnOnce(&mut Tile)>(&mut self, pos: (i32, i32), change: F) -> bool {
+        match self.get(pos) {
+            Some(tile) => {
+                let mut tile = *tile;
+                change(&mut tile);
+                self.set(pos, tile)
+            }
+            None => false,
+        }
+    }
+
+    // Number of chunks along each axis
+    pub fn chunk_count(&self) -> (i32, i32) {
+        (self.chunks_x, self.chunks_y)
+    }
+
+    pub fn chunk_of((x, y): (i32, i32)) -> (i32, i32) {
+        (x.div_euclid(CHUNK_SIZE), y.div_euclid(CHUNK_SIZE))
+    }
+
+    // The map's revision, which increases whenever any chunk changes
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // The map's revision when the chunk last changed
+    pub fn chunk_revision(&self, (cx, cy): (i32, i32)) -> Option<u64> {
+        if cx < 0 || cy < 0 || cx >= self.chunks_x || cy >= self.chunks_y {
+            return None;
+        }
+
+        Some(self.chunks[(cy * self.chunks_x + cx) as usize].revision)
+    }
+
+    // The chunks changed after the given map revision. Keep the map's
+    // `revision` after handling them to only get new changes next time.
+    pub fn changed_chunks_since(&self, revision: u64) -> impl Iterator<Item = (i32, i32)> + '_ {
+        let chunks_x = self.chunks_x;
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(move |(_, chunk)| chunk.revision > revision)
+            .map(move |(i, _)| (i as i32 % chunks_x, i as i32 / chunks_x))
+    }
+
+    // The tiles of a chunk that are inside the map
+    pub fn chunk_tiles(&self, (cx, cy): (i32, i32)) -> impl Iterator<Item = (i32, i32)> {
+        self.rect(
+            (cx * CHUNK_SIZE, cy * CHUNK_SIZE),
+            ((cx + 1) * CHUNK_SIZE - 1, (cy + 1) * CHUNK_SIZE - 1),
+        )
+    }
+
+    // The 4 tiles sharing an edge with this one that are inside the map
+    pub fn neighbors4(&self, (x, y): (i32, i32)) -> impl Iterator<Item = (i32, i32)> + '_ {
+        NEIGHBORS4
+            .iter()
+            .map(move |(dx, dy)| (x + dx, y + dy))
+            .filter(move |pos| self.contains(*pos))
+    }
+
+    // The 8 tiles around this one that are inside the map
+    pub fn neighbors8(&self, (x, y): (i32, i32)) -> impl Iterator<Item = (i32, i32)> + '_ {
+        NEIGHBORS8
+            .iter()
+            .map(move |(dx, dy)| (x + dx, y + dy))
+            .filter(move |pos| self.contains(*pos))
+    }
+
+    // Every tile in the rectangle between the two corners (inclusive) that's
+    // inside the map, row by row
+    pub fn rect(&self, a: (i32, i32), b: (i32, i32)) -> impl Iterator<Item = (i32, i32)> {
+        let min_x = a.0.min(b.0).max(0);
+        let min_y = a.1.min(b.1).max(0);
+        let max_x = a.0.max(b.0).min(self.width - 1);
+        let max_y = a.1.max(b.1).min(self.height - 1);
+
+        (min_y..=max_y).flat_map(move |y| (min_x..=max_x).map(move |x| (x, y)))
+    }
+
+    // The tiles on the line between the two tiles (inclusive) that are
+    // inside the map, from `from` to `to`
+    pub fn line(&self, from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+        line_tiles(from, to)
+            .into_iter()
+            .filter(|pos| self.contains(*pos))
+            .collect()
+    }
+
+    // Every tile connected to `start` through tiles sharing an edge that
+    // `include` accepts, including `start` if it's accepted
+    pub fn flood_fill<F>(&self, start: (i32, i32), include: F) -> Vec<(i32, i32)>
+    where
+        F: Fn((i32, i32), &Tile) -> bool,
+    {
+        let mut visited = vec![false; (self.width.max(0) * self.height.max(0)) as usize];
+        let mut filled = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+
+        while let Some(pos) = queue.pop_front() {
+            let tile = match self.get(pos) {
+                Some(tile) => tile,
+                None => continue,
+            };
+            let index = (pos.1 * self.width + pos.0) as usize;
+            if visited[index] {
+                continue;
+            }
+            visited[index] = true;
+
+            if include(pos, tile) {
+                filled.push(pos);
+                queue.extend(self.neighbors4(pos));
+            }
+        }
+
+        filled
+    }
+}
+
+// The saved form of the map, with each field of the tiles stored together
+// so the binary form stays small
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedTileMap {
+    width: u32,
+    height: u32,
+    // Row by row across the whole map
+    terrain: Vec<Terrain>,
+    heights: Vec<u8>,
+    zones: Vec<Zone>,
+    densities: Vec<ZoneDensity>,
+    flags: Vec<TileFlags>,
+    forest: Vec<u8>,
+    ore: Vec<u8>,
+    fertility: Vec<u8>,
+    // Saved ids of the owners, by tile index. Most tiles don't have one.
+    owners: Vec<(u32, u32)>,
+}
+
+// Version 2 of the saved map, from before zones had densities
+#[derive(Serialize, Deserialize)]
+pub struct SavedTileMapV2 {
+    width: u32,
+    height: u32,
+    terrain: Vec<Terrain>,
+    heights: Vec<u8>,
+    zones: Vec<Zone>,
+    flags: Vec<TileFlags>,
+    forest: Vec<u8>,
+    ore: Vec<u8>,
+    fertility: Vec<u8>,
+    owners: Vec<(u32, u32)>,
+}
+
+impl From<SavedTileMapV2> for SavedTileMap {
+    fn from(old: SavedTileMapV2) -> Self {
+        let count = old.terrain.len();
+        Self {
+            width: old.width,
+            height: old.height,
+            terrain: old.terrain,
+            heights: old.heights,
+            zones: old.zones,
+            densities: vec![ZoneDensity::Low; count],
+            flags: old.flags,
+            forest: old.forest,
+            ore: old.ore,
+            fertility: old.fertility,
+            owners: old.owners,
+        }
+    }
+}
+
+// Version 1 of the saved map, from before tiles had resources
+#[derive(Serialize, Deserialize)]
+pub struct SavedTileMapV1 {
+    width: u32,
+    height: u32,
+    terrain: Vec<Terrain>,
+    heights: Vec<u8>,
+    zones: Vec<Zone>,
+    flags: Vec<TileFlags>,
+    owners: Vec<(u32, u32)>,
+}
+
+impl From<SavedTileMapV1> for SavedTileMapV2 {
+    fn from(old: SavedTileMapV1) -> Self {
+        let count = old.terrain.len();
+        Self {
+            width: old.width,
+            height: old.height,
+            terrain: old.terrain,
+            heights: old.heights,
+            zones: old.zones,
+            flags: old.flags,
+            forest: vec![0; count],
+            ore: vec![0; count],
+            fertility: vec![0; count],
+            owners: old.owners,
+        }
+    }
+}
+
+impl MapEntities for TileMap {
+    type Saved = SavedTileMap;
+
+    fn save(&self, ids: &EntityIds) -> Option<SavedTileMap> {
+        let tiles = || {
+            self.rect((0, 0), (self.width - 1, self.height - 1))
+                .map(move |pos| self.get(pos).unwrap())
+        };
+
+        Some(SavedTileMap {
+            width: self.width(),
+            height: self.height(),
+            terrain: tiles().map(|tile| tile.terrain).collect(),
+            heights: tiles().map(|tile| tile.height).collect(),
+            zones: tiles().map(|tile| tile.zone).collect(),
+            densities: tiles().map(|tile| tile.density).collect(),
+            flags: tiles().map(|tile| tile.flags).collect(),
+            forest: tiles().map(|tile| tile.forest).collect(),
+            ore: tiles().map(|tile| tile.ore).collect(),
+            fertility: tiles().map(|tile| tile.fertility).collect(),
+            owners: tiles()
+                .enumerate()
+                .filter_map(|(i, tile)| {
+                    tile.owner
+                        .and_then(|owner| ids.id(owner))
+                        .map(|id| (i as u32, id))
+                })
+                .collect(),
+        })
+    }
+
+    fn check(saved: &SavedTileMap) -> Result<(), String> {
+        if saved.width > MAX_MAP_SIZE || saved.height > MAX_MAP_SIZE {
+            return Err(format!(
+                "the map is too big ({}x{})",
+                saved.width, saved.height
+            ));
+        }
+        let count = (saved.width * saved.height) as usize;
+        let lengths = [
+            saved.terrain.len(),
+            saved.heights.len(),
+            saved.zones.len(),
+            saved.densities.len(),
+            saved.flags.len(),
+            saved.forest.len(),
+            saved.ore.len(),
+            saved.fertility.len(),
+        ];
+        if lengths.iter().any(|&length| length != count) {
+            return Err("the map has the wrong number of tiles".to_owned());
+        }
+        Ok(())
+    }
+
+    // The map was checked when it was decoded
+    fn load(saved: SavedTileMap, ids: &EntityIds) -> Option<Self> {
+        let count = (saved.width * saved.height) as usize;
+        let mut map = TileMap::new(saved.width, saved.height);
+        let width = saved.width as usize;
+        for i in 0..count {
+            let pos = ((i % width) as i32, (i / width) as i32);
+            *map.get_mut(pos).unwrap() = Tile {
+                terrain: saved.terrain[i],
+                height: saved.heights[i],
+                zone: saved.zones[i],
+                density: saved.densities[i],
+                owner: None,
+                flags: saved.flags[i],
+                forest: saved.forest[i],
+                ore: saved.ore[i],
+                fertility: saved.fertility[i],
+            };
+        }
+        for (i, id) in saved.owners {
+            let pos = ((i as usize % width) as i32, (i as usize / width) as i32);
+            if let (Some(tile), Some(owner)) = (map.get_mut(pos), ids.entity(id)) {
+                tile.owner = Some(owner);
+            }
+        }
+
+        Some(map)
+    }
+}
+
+// Adds the tile map, which is saved along with the world
+pub struct TileMapPlugin {
+    width: u32,
+    height: u32,
+    generate: Option<MapGenParams>,
+}
+
+impl TileMapPlugin {
+    // Start with a flat, empty map
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            generate: None,
+        }
+    }
+
+    // Start with a generated map
+    pub fn generated(params: MapGenParams) -> Self {
+        Self {
+            width: params.width,
+            height: params.height,
+            generate: Some(params),
+        }
+    }
+}
+
+impl Plugin for TileMapPlugin {
+    fn name(&self) -> &'static str {
+        "tile_map"
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        let map = match &self.generate {
+            Some(params) => mapgen::generate(params),
+            None => TileMap::new(self.width, self.height),
+        };
+
+        builder
+            .insert_resource(map)
+            .save_mapped_resource::<TileMap>("tile_map")
+            .migrate_resource::<SavedTileMapV1, SavedTileMapV2, _>("tile_map", 1, From::from)
+            .migrate_resource::<SavedTileMapV2, SavedTileMap, _>("tile_map", 2, From::from);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy::EconomyPlugin;
+    use crate::plugin::{GameWorld, WorldBuilder};
+    use crate::save::{load_world, save_world, SaveFormat};
+    use crate::world::Transform;
+    use nalgebra::{UnitQuaternion, Vector3};
+    use specs::{Builder, WorldExt};
+
+    fn sorted(mut tiles: Vec<(i32, i32)>) -> Vec<(i32, i32)> {
+        tiles.sort_unstable();
+        tiles
+    }
+
+    #[test]
+    fn rects_are_clipped_to_the_map() {
+        let map = TileMap::new(4, 3);
+        let tiles: Vec<_> = map.rect((2, 1), (0, 0)).collect();
+        assert_eq!(tiles, vec![(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (2, 1)]);
+
+        assert_eq!(map.rect((-5, -5), (10, 10)).count(), 12);
+        assert_eq!(map.rect((3, 2), (3, 2)).collect::<Vec<_>>(), vec![(3, 2)]);
+        assert_eq!(map.rect((5, 5), (8, 8)).count(), 0);
+    }
+
+    #[test]
+    fn lines_go_from_start_to_end() {
+        assert_eq!(line_tiles((2, 2), (2, 2)), vec![(2, 2)]);
+        assert_eq!(
+            line_tiles((0, 0), (3, 0)),
+            vec![(0, 0), (1, 0), (2, 0), (3, 0)]
+        );
+        assert_eq!(line_tiles((2, 2), (0, 0)), vec![(2, 2), (1, 1), (0, 0)]);
+
+        // Every step moves to a neighboring tile
+        let tiles = line_tiles((0, 0), (7, 3));
+        assert_eq!((tiles[0], tiles[tiles.len() - 1]), ((0, 0), (7, 3)));
+        for pair in tiles.windows(2) {
+            assert!((pair[1].0 - pair[0].0).abs() <= 1 && (pair[1].1 - pair[0].1).abs() <= 1);
+        }
+
+        // Only the part inside the map is kept
+        let map = TileMap::new(4, 4);
+        assert_eq!(
+            map.line((-2, 1), (5, 1)),
+            vec![(0, 1), (1, 1), (2, 1), (3, 1)]
+        );
+    }
+
+    #[test]
+    fn flood_fill_stays_inside_the_region() {
+        // A wall of water splits the map in two
+        let mut map = TileMap::new(6, 4);
+        for y in 0..4 {
+            map.get_mut((3, y)).unwrap().terrain = Terrain::Water;
+        }
+        let land = |_, tile: &Tile| tile.terrain != Terrain::Water;
+
+        assert_eq!(
+            sorted(map.flood_fill((0, 0), land)),
+            sorted(map.rect((0, 0), (2, 3)).collect())
+        );
+        assert_eq!(map.flood_fill((5, 3), land).len(), 8);
+        assert!(map.flood_fill((3, 0), land).is_empty());
+        assert!(map.flood_fill((-1, 0), land).is_empty());
+    }
+
+    #[test]
+    fn neighbors_stop_at_the_map_edges() {
+        let map = TileMap::new(3, 3);
+
+        assert_eq!(
+            sorted(map.neighbors4((0, 0)).collect()),
+            vec![(0, 1), (1, 0)]
+        );
+        assert_eq!(
+            sorted(map.neighbors8((0, 0)).collect()),
+            vec![(0, 1), (1, 0), (1, 1)]
+        );
+        assert_eq!(map.neighbors4((2, 1)).count(), 3);
+        assert_eq!(map.neighbors8((2, 1)).count(), 5);
+        assert_eq!(map.neighbors4((1, 1)).count(), 4);
+        assert_eq!(map.neighbors8((1, 1)).count(), 8);
+    }
+
+    #[test]
+    fn only_changed_chunks_are_reported() {
+        let mut map = TileMap::new(CHUNK_SIZE as u32 * 2, CHUNK_SIZE as u32 * 2);
+        assert_eq!(map.chunk_count(), (2, 2));
+        let start = map.revision();
+        assert_eq!(map.changed_chunks_since(start).count(), 0);
+
+        // Setting a tile to what it already is doesn't change anything
+        assert!(map.set((1, 1), Tile::default()));
+        assert_eq!(map.revision(), start);
+
+        map.update((CHUNK_SIZE + 1, 1), |tile| tile.zone = Zone::Residential);
+        let after_first = map.revision();
+        assert!(after_first > start);
+        assert_eq!(
+            map.changed_chunks_since(start).collect::<Vec<_>>(),
+            vec![(1, 0)]
+        );
+        assert_eq!(map.chunk_revision((1, 0)), Some(after_first));
+        assert_eq!(map.chunk_revision((0, 0)), Some(0));
+        assert_eq!(map.chunk_revision((2, 0)), None);
+
+        map.update((1, CHUNK_SIZE), |tile| tile.zone = Zone::Industrial);
+        assert_eq!(
+            map.changed_chunks_since(after_first).collect::<Vec<_>>(),
+            vec![(0, 1)]
+        );
+        assert_eq!(map.changed_chunks_since(start).count(), 2);
+        assert!(!map.set((-1, 0), Tile::default()));
+    }
+
+    // Saves with the map at older versions, which have to keep loading
+    const FIXTURES: &[(&str, &[u8])] = &[
+        ("v1.city", include_bytes!("../fixtures/tile_maps/v1.city")),
+        ("v1.ron", include_bytes!("../fixtures/tile_maps/v1.ron")),
+        ("v2.city", include_bytes!("../fixtures/tile_maps/v2.city")),
+        ("v2.ron", include_bytes!("../fixtures/tile_maps/v2.ron")),
+    ];
+
+    #[test]
+    fn old_maps_still_load() {
+        for (name, bytes) in FIXTURES.iter() {
+            // The fixtures were saved with the city's funds in them
+            let GameWorld { mut world, .. } = WorldBuilder::new()
+                .with_plugin(TileMapPlugin::new(1, 1))
+                .with_plugin(EconomyPlugin)
+                .build()
+                .unwrap();
+            load_world(&mut world, bytes)
+                .unwrap_or_else(|err| panic!("failed to load {}: {}", name, err));
+
+            let map = world.read_resource::<TileMap>();
+            assert_eq!((map.width(), map.height()), (3, 2), "{}", name);
+            let tiles: Vec<Tile> = map
+                .rect((0, 0), (2, 1))
+                .map(|pos| *map.get(pos).unwrap())
+                .collect();
+            let field = |get: fn(&Tile) -> u8| tiles.iter().map(get).collect::<Vec<u8>>();
+            assert_eq!(
+                field(|tile| tile.height),
+                vec![1, 2, 0, 3, 9, 4],
+                "{}",
+                name
+            );
+            assert_eq!(tiles[1].terrain, Terrain::Sand, "{}", name);
+            assert_eq!(tiles[5].zone, Zone::Industrial, "{}", name);
+            assert!(tiles[4].flags.contains(TileFlags::LOCKED), "{}", name);
+
+            // Resources didn't exist in version 1, and densities in version 2
+            if name.starts_with("v1") {
+                assert!(tiles.iter().all(|tile| tile.forest == 0), "{}", name);
+                assert!(tiles.iter().all(|tile| tile.ore == 0), "{}", name);
+                assert!(tiles.iter().all(|tile| tile.fertility == 0), "{}", name);
+            } else {
+                assert_eq!(
+                    field(|tile| tile.forest),
+                    vec![0, 200, 0, 50, 0, 255],
+                    "{}",
+                    name
+                );
+                assert_eq!(
+                    field(|tile| tile.ore),
+                    vec![0, 0, 0, 10, 180, 0],
+                    "{}",
+                    name
+                );
+                assert_eq!(
+                    field(|tile| tile.fertility),
+                    vec![90, 20, 0, 120, 0, 60],
+                    "{}",
+                    name
+                );
+            }
+            assert!(
+                tiles.iter().all(|tile| tile.density == ZoneDensity::Low),
+                "{}",
+                name
+            );
+
+            let owner = tiles[3].owner.expect("the owner is kept");
+            let transforms = world.read_storage::<Transform>();
+            assert_eq!(transforms.get(owner).unwrap().position.y, 1.0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn saved_owners_point_at_the_loaded_entities() {
+        let build = || {
+            let GameWorld { world, .. } = WorldBuilder::new()
+                .with_plugin(TileMapPlugin::new(8, 8))
+                .build()
+                .unwrap();
+            world
+        };
+        let transform = |x| {
+            Transform::new(
+                Vector3::new(x, 0.0, 0.0),
+                UnitQuaternion::identity(),
+                Vector3::new(1.0, 1.0, 1.0),
+            )
+        };
+
+        let mut world = build();
+        // Entities that aren't saved don't own tiles after loading
+        let unsaved = world.create_entity().build();
+        let first = world.create_entity().with(transform(1.0)).build();
+        let second = world.create_entity().with(transform(2.0)).build();
+        {
+            let mut map = world.write_resource::<TileMap>();
+            map.get_mut((1, 1)).unwrap().owner = Some(first);
+            map.get_mut((2, 1)).unwrap().owner = Some(second);
+            map.get_mut((7, 7)).unwrap().owner = Some(second);
+            map.get_mut((0, 0)).unwrap().owner = Some(unsaved);
+        }
+        let bytes = save_world(&world, SaveFormat::Binary).unwrap();
+
+        let mut loaded = build();
+        // Taking up the ids the saved entities had
+        for _ in 0..3 {
+            loaded.create_entity().build();
+        }
+        load_world(&mut loaded, &bytes).unwrap();
+        let map = loaded.read_resource::<TileMap>();
+        let transforms = loaded.read_storage::<Transform>();
+        let owner_x = |pos| {
+            let owner = map.get(pos).unwrap().owner.unwrap();
+            transforms.get(owner).unwrap().position.x
+        };
+        assert_eq!(owner_x((1, 1)), 1.0);
+        assert_eq!(owner_x((2, 1)), 2.0);
+        assert_eq!(owner_x((7, 7)), 2.0);
+        assert_eq!(map.get((0, 0)).unwrap().owner, None);
+        assert_eq!(map.get((3, 3)).unwrap().owner, None);
+    }
+
+    #[test]
+    fn broken_saved_maps_fail_to_load() {
+        let saved = |width, height, count| SavedTileMap {
+            width,
+            height,
+            terrain: vec![Terrain::Grass; count],
+            heights: vec![0; count],
+            zones: vec![Zone::Unzoned; count],
+            densities: vec![ZoneDensity::Low; count],
+            flags: vec![TileFlags::NONE; count],
+            forest: vec![0; count],
+            ore: vec![0; count],
+            fertility: vec![0; count],
+            owners: Vec::new(),
+        };
+        // Saved as it is, at the version the map is at now
+        let save = |map: SavedTileMap| {
+            let mut builder = WorldBuilder::new();
+            builder
+                .insert_resource(map)
+                .save_resource::<SavedTileMap>("tile_map")
+                .migrate_resource("tile_map", 1, |map: SavedTileMap| map)
+                .migrate_resource("tile_map", 2, |map: SavedTileMap| map);
+            save_world(&builder.build().unwrap().world, SaveFormat::Binary).unwrap()
+        };
+
+        for (map, error) in [
+            (saved(MAX_MAP_SIZE + 1, 1, 0), "too big"),
+            (saved(u32::MAX, u32::MAX, 0), "too big"),
+            (saved(3, 2, 5), "wrong number of tiles"),
+        ] {
+            let GameWorld { mut world, .. } = WorldBuilder::new()
+                .with_plugin(TileMapPlugin::new(4, 4))
+                .build()
+                .unwrap();
+            let err = load_world(&mut world, &save(map)).unwrap_err();
+            assert!(err.contains(error), "{}", err);
+            let map = world.read_resource::<TileMap>();
+            assert_eq!((map.width(), map.height()), (4, 4));
+        }
+
+        let GameWorld { mut world, .. } = WorldBuilder::new()
+            .with_plugin(TileMapPlugin::new(4, 4))
+            .build()
+            .unwrap();
+        load_world(&mut world, &save(saved(3, 2, 6))).unwrap();
+        assert_eq!(world.read_resource::<TileMap>().width(), 3);
+    }
+}
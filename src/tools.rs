@@ -0,0 +1,124 @@
+use crate::input::InputState;
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::roads::RoadType;
+use crate::tilemap::Zone;
+use glfw::Key;
+use nalgebra::Vector4;
+use specs::{Read, System, Write};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tool {
+    Zone(Zone),
+    Road(RoadType),
+    Bulldoze,
+}
+
+// The tool the player is using, if any. Only one tool is in use at a time.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ActiveTool(pub Option<Tool>);
+
+// Tiles tools want drawn over the map this frame, like the area about to be
+// zoned, each with its color. Cleared every frame.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TileHighlights(pub Vec<((i32, i32), Vector4<f32>)>);
+
+// What the tool in use is about to do, like what it will cost. Shown to the
+// player and cleared every frame.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolStatus(pub Option<String>);
+
+// Picks the tool in use:
+// - 1, 2 and 3 pick residential, commercial and industrial zoning and 4
+//   picks de-zoning
+// - 5, 6 and 7 pick streets, avenues and highways and 9 picks the bulldozer
+// - 0 puts the tool away
+#[derive(Default)]
+pub struct ToolSwitchSystem;
+
+impl<'a> System<'a> for ToolSwitchSystem {
+    type SystemData = (Read<'a, InputState>, Write<'a, ActiveTool>);
+
+    fn run(&mut self, (input, mut active): Self::SystemData) {
+        let keys = [
+            (Key::Num1, Some(Tool::Zone(Zone::Residential))),
+            (Key::Num2, Some(Tool::Zone(Zone::Commercial))),
+            (Key::Num3, Some(Tool::Zone(Zone::Industrial))),
+            (Key::Num4, Some(Tool::Zone(Zone::Unzoned))),
+            (Key::Num5, Some(Tool::Road(RoadType::Street))),
+            (Key::Num6, Some(Tool::Road(RoadType::Avenue))),
+            (Key::Num7, Some(Tool::Road(RoadType::Highway))),
+            (Key::Num9, Some(Tool::Bulldoze)),
+            (Key::Num0, None),
+        ];
+        for &(key, tool) in keys.iter() {
+            if input.was_key_pressed(key) {
+                active.0 = tool;
+            }
+        }
+    }
+}
+
+// Adds what the tools share: the tool in use, the highlighted tiles and the
+// status line. The tools themselves are added by their own plugins.
+pub struct ToolsPlugin;
+
+impl Plugin for ToolsPlugin {
+    fn name(&self) -> &'static str {
+        "tools"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["input"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .insert_resource(ActiveTool::default())
+            .insert_resource(TileHighlights::default())
+            .insert_resource(ToolStatus::default())
+            .add_frame_system(ToolSwitchSystem, "tool_switch", &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::InputPlugin;
+    use crate::plugin::GameWorld;
+    use glfw::{Action, Modifiers, WindowEvent};
+    use specs::WorldExt;
+
+    fn press(game: &mut GameWorld, key: Key) -> Option<Tool> {
+        {
+            let mut input = game.world.write_resource::<InputState>();
+            input.end_frame();
+            input.handle_event(&WindowEvent::Key(key, 0, Action::Press, Modifiers::empty()));
+        }
+        game.frame_systems.dispatch(&game.world);
+        game.world.read_resource::<ActiveTool>().0
+    }
+
+    #[test]
+    fn one_tool_is_in_use_at_a_time() {
+        let mut game = WorldBuilder::new()
+            .with_plugin(InputPlugin::new((800, 600)))
+            .with_plugin(ToolsPlugin)
+            .build()
+            .unwrap();
+        assert_eq!(game.world.read_resource::<ActiveTool>().0, None);
+
+        assert_eq!(
+            press(&mut game, Key::Num2),
+            Some(Tool::Zone(Zone::Commercial))
+        );
+        // Picking a road tool puts the zoning tool away
+        assert_eq!(
+            press(&mut game, Key::Num6),
+            Some(Tool::Road(RoadType::Avenue))
+        );
+        assert_eq!(press(&mut game, Key::Num9), Some(Tool::Bulldoze));
+        // Other keys leave the tool alone
+        assert_eq!(press(&mut game, Key::Z), Some(Tool::Bulldoze));
+        assert_eq!(press(&mut game, Key::Num0), None);
+    }
+}
@@ -0,0 +1,1048 @@
+use crate::pathfinding::{Path, PathFinder, TravelCosts};
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::roads::{self, NodeId, RoadNetwork, RoadType, SegmentId};
+use crate::tilemap::{TileMap, Zone};
+use crate::world::{
+    InterpolatedTransform, SimulationTime, Transform, Vehicle, VehicleV1, TICKS_PER_DAY,
+};
+use nalgebra::{UnitQuaternion, Vector2, Vector3};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use specs::{Entities, Entity, Join, Read, ReadStorage, System, Write, WriteStorage};
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::{FRAC_PI_4, PI};
+
+// Length of a vehicle in tiles
+pub const VEHICLE_LENGTH: f32 = 0.35;
+
+// The space drivers leave to the vehicle in front of them when stopped
+const MIN_GAP: f32 = 0.1;
+
+// How quickly vehicles speed up and slow down in tiles per second squared
+const ACCELERATION: f32 = 2.0;
+const BRAKING: f32 = 5.0;
+
+// Vehicles this close to the end of their segment are waiting at it
+const STOP_LINE: f32 = 0.05;
+
+// How long a vehicle has an intersection without lights to itself
+const CROSSING_TIME: f32 = 0.5;
+
+// How long each direction gets a green light, and how long every light is
+// red between them
+const GREEN_TIME: f32 = 8.0;
+const CLEARANCE_TIME: f32 = 1.0;
+
+// How often the traffic statistics are updated and fed into pathfinding
+const STATS_INTERVAL_TICKS: u64 = 30;
+
+// Trips waiting for room to start are dropped after this many
+const MAX_PENDING_TRIPS: usize = 256;
+
+// No more vehicles are added once there are this many
+pub const MAX_VEHICLES: usize = 2000;
+
+// How often someone living on a residential tile drives to work
+const TRIPS_PER_HOME_PER_DAY: f32 = 0.02;
+
+// The lanes of the road going one way
+type LaneKey = (SegmentId, bool, u8);
+
+// The segment a vehicle is on in the direction it's going
+#[derive(Debug, Copy, Clone)]
+struct Leg {
+    segment: SegmentId,
+    // Whether it's going from the segment's `from` node to its `to` node
+    forward: bool,
+    start: Vector2<f32>,
+    direction: Vector2<f32>,
+    length: f32,
+    road_type: RoadType,
+    // The node at the end of the segment
+    end: NodeId,
+    last: bool,
+}
+
+impl Leg {
+    // The leg of the path at the index, if its road still exists
+    fn new(network: &RoadNetwork, path: &Path, index: usize) -> Option<Self> {
+        let segment = *path.segments.get(index)?;
+        let road = network.segment(segment)?;
+        let from = path.nodes[index];
+        let end = path.nodes[index + 1];
+        if (road.from, road.to) != (from, end) && (road.to, road.from) != (from, end) {
+            return None;
+        }
+
+        let start = network.node(from)?.position;
+        let offset = network.node(end)?.position - start;
+        Some(Self {
+            segment,
+            forward: road.from == from,
+            start,
+            direction: offset.normalize(),
+            length: offset.norm(),
+            road_type: road.road_type,
+            end,
+            last: index + 1 == path.segments.len(),
+        })
+    }
+
+    fn lane_key(&self, lane: u8) -> LaneKey {
+        (self.segment, self.forward, lane)
+    }
+
+    fn lanes(&self) -> u8 {
+        self.road_type.lanes()
+    }
+
+    // Where a vehicle in the lane is. Vehicles keep to the right, with the
+    // lanes counted from the middle of the road.
+    fn position(&self, distance: f32, lane: u8) -> Vector2<f32> {
+        let lane_width = self.road_type.width() / 2.0 / f32::from(self.lanes());
+        let right = Vector2::new(self.direction.y, -self.direction.x);
+        self.start + self.direction * distance + right * lane_width * (f32::from(lane) + 0.5)
+    }
+
+    fn angle(&self) -> f32 {
+        self.direction.y.atan2(self.direction.x)
+    }
+}
+
+// Where the vehicles are on each lane, sorted from the start of the lane
+#[derive(Default)]
+struct Lanes(HashMap<LaneKey, Vec<(f32, Entity)>>);
+
+impl Lanes {
+    // How far along the lane the last vehicle on it is
+    fn tail(&self, key: LaneKey) -> f32 {
+        self.0
+            .get(&key)
+            .and_then(|vehicles| vehicles.first())
+            .map(|(distance, _)| *distance)
+            .unwrap_or(f32::INFINITY)
+    }
+
+    // The lane of the leg with the most room at its start, if any of them
+    // have room for another vehicle
+    fn lane_with_room(&self, leg: &Leg) -> Option<u8> {
+        (0..leg.lanes())
+            .map(|lane| (lane, self.tail(leg.lane_key(lane))))
+            .filter(|(_, tail)| *tail >= VEHICLE_LENGTH + MIN_GAP)
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(lane, _)| lane)
+    }
+
+    // How far along the lane the vehicle in front of the given one is
+    fn leader(&self, key: LaneKey, entity: Entity) -> Option<f32> {
+        let vehicles = self.0.get(&key)?;
+        let i = vehicles.iter().position(|(_, other)| *other == entity)?;
+        vehicles.get(i + 1).map(|(distance, _)| *distance)
+    }
+
+    fn insert(&mut self, key: LaneKey, distance: f32, entity: Entity) {
+        let vehicles = self.0.entry(key).or_default();
+        let i = vehicles
+            .iter()
+            .position(|(other, _)| *other > distance)
+            .unwrap_or(vehicles.len());
+        vehicles.insert(i, (distance, entity));
+    }
+
+    fn remove(&mut self, key: LaneKey, entity: Entity) {
+        if let Some(vehicles) = self.0.get_mut(&key) {
+            vehicles.retain(|(_, other)| *other != entity);
+        }
+    }
+
+    fn set(&mut self, key: LaneKey, entity: Entity, distance: f32) {
+        if let Some(found) = self
+            .0
+            .get_mut(&key)
+            .and_then(|vehicles| vehicles.iter_mut().find(|(_, other)| *other == entity))
+        {
+            found.0 = distance;
+        }
+    }
+}
+
+// Lights that let the roads into an intersection go in turns
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrafficLight {
+    // The roads that get a green light together
+    phases: Vec<Vec<SegmentId>>,
+    phase: usize,
+    // How long the current phase has been going
+    timer: f32,
+}
+
+impl TrafficLight {
+    // Roads coming in along the same line get a green light together
+    fn new(network: &RoadNetwork, node: NodeId) -> Self {
+        let mut phases: Vec<(f32, Vec<SegmentId>)> = Vec::new();
+        let position = network.node(node).map(|node| node.position).unwrap();
+        for &segment in network.node(node).unwrap().segments.iter() {
+            let other = network.segment(segment).unwrap().other_end(node);
+            let away = network.node(other).unwrap().position - position;
+            let axis = away.y.atan2(away.x).rem_euclid(PI);
+
+            let same_axis = |other_axis: f32| {
+                let difference = (axis - other_axis).abs();
+                difference.min(PI - difference) < FRAC_PI_4
+            };
+            match phases.iter_mut().find(|(other, _)| same_axis(*other)) {
+                Some((_, segments)) => segments.push(segment),
+                None => phases.push((axis, vec![segment])),
+            }
+        }
+
+        Self {
+            phases: phases.into_iter().map(|(_, segments)| segments).collect(),
+            phase: 0,
+            timer: 0.0,
+        }
+    }
+
+    fn advance(&mut self, step: f32) {
+        self.timer += step;
+        if self.timer >= CLEARANCE_TIME + GREEN_TIME {
+            self.timer = 0.0;
+            self.phase = (self.phase + 1) % self.phases.len();
+        }
+    }
+
+    pub fn is_green(&self, segment: SegmentId) -> bool {
+        self.timer >= CLEARANCE_TIME && self.phases[self.phase].contains(&segment)
+    }
+}
+
+// Who goes first where roads meet
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    // Busy intersections, with an avenue or highway, have lights
+    Lights(TrafficLight),
+    // The rest work like all-way stops: vehicles stop and go one at a time,
+    // whoever waited longest first
+    Stop { reserved_until: f32 },
+}
+
+// The intersections of the road network and how they're controlled. Nodes
+// where fewer than three roads meet don't need any control.
+#[derive(Debug, Clone, Default)]
+pub struct Intersections {
+    controls: HashMap<NodeId, Control>,
+    // Seconds of simulated traffic
+    time: f32,
+    // The road network's revision the intersections were found in
+    revision: Option<u64>,
+}
+
+impl Intersections {
+    pub fn control(&self, node: NodeId) -> Option<&Control> {
+        self.controls.get(&node)
+    }
+
+    // Find the intersections again if the roads changed, and move the lights
+    // on
+    fn update(&mut self, network: &RoadNetwork, step: f32) {
+        if self.revision != Some(network.revision()) {
+            let mut controls = HashMap::new();
+            for (id, node) in network.nodes() {
+                if node.segments.len() < 3 {
+                    continue;
+                }
+
+                let busy = node.segments.iter().any(|segment| {
+                    network
+                        .segment(*segment)
+                        .is_some_and(|segment| segment.road_type != RoadType::Street)
+                });
+                let control = if busy {
+                    let light = TrafficLight::new(network, id);
+                    // Lights that didn't change keep going where they were
+                    match self.controls.remove(&id) {
+                        Some(Control::Lights(old)) if old.phases == light.phases => {
+                            Control::Lights(old)
+                        }
+                        _ => Control::Lights(light),
+                    }
+                } else {
+                    Control::Stop {
+                        reserved_until: 0.0,
+                    }
+                };
+                controls.insert(id, control);
+            }
+
+            self.controls = controls;
+            self.revision = Some(network.revision());
+        }
+
+        self.time += step;
+        for control in self.controls.values_mut() {
+            if let Control::Lights(light) = control {
+                light.advance(step);
+            }
+        }
+    }
+
+    // Whether a vehicle coming from the segment may go into the intersection
+    // now, not counting whose turn it is at stops
+    fn is_open(&self, node: NodeId, from: SegmentId) -> bool {
+        match self.controls.get(&node) {
+            Some(Control::Lights(light)) => light.is_green(from),
+            Some(Control::Stop { reserved_until }) => *reserved_until <= self.time,
+            None => true,
+        }
+    }
+
+    fn is_stop(&self, node: NodeId) -> bool {
+        matches!(self.controls.get(&node), Some(Control::Stop { .. }))
+    }
+
+    fn reserve(&mut self, node: NodeId) {
+        let until = self.time + CROSSING_TIME;
+        if let Some(Control::Stop { reserved_until }) = self.controls.get_mut(&node) {
+            *reserved_until = until;
+        }
+    }
+}
+
+// Trips waiting to be driven, from one node of the road network to another
+#[derive(Debug, Clone, Default)]
+pub struct TripRequests(pub VecDeque<(NodeId, NodeId)>);
+
+// The traffic on a segment over the last few seconds
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct SegmentTraffic {
+    // Vehicles on the segment on average
+    pub vehicles: f32,
+    // The vehicles over how many fit, where 1 is jammed
+    pub load: f32,
+    // In tiles per second
+    pub average_speed: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrafficStats {
+    pub segments: HashMap<SegmentId, SegmentTraffic>,
+    pub vehicles: usize,
+    // Vehicles that made it to where they were going
+    pub arrived: u64,
+    // Vehicles that lost their road with no other way to go
+    pub stranded: u64,
+    // Vehicles counted and their speeds added up on each segment since the
+    // statistics were last updated
+    counted: HashMap<SegmentId, (u32, f32)>,
+    ticks: u32,
+}
+
+// Add vehicles for the requested trips that have a path and room to start
+#[derive(Default)]
+pub struct TripSpawnSystem;
+
+impl<'a> System<'a> for TripSpawnSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, RoadNetwork>,
+        Read<'a, TravelCosts>,
+        Write<'a, PathFinder>,
+        Write<'a, TripRequests>,
+        WriteStorage<'a, Vehicle>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, InterpolatedTransform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            network,
+            costs,
+            mut finder,
+            mut requests,
+            mut vehicles,
+            mut transforms,
+            mut interpolated,
+        ): Self::SystemData,
+    ) {
+        let mut lanes = Lanes::default();
+        for (entity, vehicle) in (&entities, &vehicles).join() {
+            if let Some(leg) = Leg::new(&network, &vehicle.path, vehicle.leg) {
+                lanes.insert(leg.lane_key(vehicle.lane), vehicle.distance, entity);
+            }
+        }
+
+        let excess = requests.0.len().saturating_sub(MAX_PENDING_TRIPS);
+        requests.0.drain(..excess);
+
+        let mut count = vehicles.count();
+        let mut pending = VecDeque::new();
+        while let Some((from, to)) = requests.0.pop_front() {
+            if count >= MAX_VEHICLES {
+                pending.push_back((from, to));
+                continue;
+            }
+
+            let path = match finder.find_path(&network, &costs, from, to) {
+                Some(path) if !path.segments.is_empty() => path,
+                _ => continue,
+            };
+            let leg = Leg::new(&network, &path, 0).unwrap();
+            let lane = match lanes.lane_with_room(&leg) {
+                Some(lane) => lane,
+                None => {
+                    pending.push_back((from, to));
+                    continue;
+                }
+            };
+
+            let position = leg.position(0.0, lane);
+            let entity = entities
+                .build_entity()
+                .with(Vehicle::new(path, lane), &mut vehicles)
+                .with(
+                    Transform::new(
+                        Vector3::new(position.x, position.y, 0.0),
+                        UnitQuaternion::from_euler_angles(0.0, 0.0, leg.angle()),
+                        Vector3::new(1.0, 1.0, 1.0),
+                    ),
+                    &mut transforms,
+                )
+                .with(InterpolatedTransform::default(), &mut interpolated)
+                .build();
+            lanes.insert(leg.lane_key(lane), 0.0, entity);
+            count += 1;
+        }
+        requests.0 = pending;
+    }
+}
+
+// Drives the vehicles along their paths. Vehicles keep their distance to the
+// one in front, wait for their turn at intersections and for room on the
+// next road, and are removed when they get where they're going.
+#[derive(Default)]
+pub struct VehicleSystem;
+
+impl<'a> System<'a> for VehicleSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, SimulationTime>,
+        Read<'a, RoadNetwork>,
+        Read<'a, TravelCosts>,
+        Write<'a, PathFinder>,
+        Write<'a, Intersections>,
+        Write<'a, TrafficStats>,
+        WriteStorage<'a, Vehicle>,
+        WriteStorage<'a, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            network,
+            costs,
+            mut finder,
+            mut intersections,
+            mut stats,
+            mut vehicles,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let step = time.step;
+        intersections.update(&network, step);
+
+        // Vehicles on a road that was split or joined carry on along the
+        // segment they're now on, and those on a road that was removed are taken off it. Vehicles
+        // whose next road is gone find another way from the end of the road
+        // they're on.
+        for (entity, vehicle) in (&entities, &mut vehicles).join() {
+            if Leg::new(&network, &vehicle.path, vehicle.leg).is_none()
+                && !rejoin(&network, &costs, &mut finder, vehicle)
+            {
+                stats.stranded += 1;
+                entities.delete(entity).unwrap();
+            } else if vehicle.leg + 1 < vehicle.path.segments.len()
+                && Leg::new(&network, &vehicle.path, vehicle.leg + 1).is_none()
+            {
+                reroute(&network, &costs, &mut finder, vehicle);
+            }
+        }
+
+        let mut lanes = Lanes::default();
+        for (entity, vehicle) in (&entities, &vehicles).join() {
+            if let Some(leg) = Leg::new(&network, &vehicle.path, vehicle.leg) {
+                lanes.insert(leg.lane_key(vehicle.lane), vehicle.distance, entity);
+            }
+        }
+
+        // At each stop, the vehicle that has waited longest and has somewhere
+        // to go gets to go next
+        let mut next_up: HashMap<NodeId, (f32, Entity)> = HashMap::new();
+        for (entity, vehicle) in (&entities, &vehicles).join() {
+            let leg = match Leg::new(&network, &vehicle.path, vehicle.leg) {
+                Some(leg) if !leg.last && vehicle.distance >= leg.length - STOP_LINE => leg,
+                _ => continue,
+            };
+            let has_room = Leg::new(&network, &vehicle.path, vehicle.leg + 1)
+                .and_then(|next| lanes.lane_with_room(&next))
+                .is_some();
+            if intersections.is_stop(leg.end) && has_room {
+                let best = next_up.entry(leg.end).or_insert((vehicle.waiting, entity));
+                if vehicle.waiting > best.0 {
+                    *best = (vehicle.waiting, entity);
+                }
+            }
+        }
+
+        for (entity, vehicle, transform) in (&entities, &mut vehicles, &mut transforms).join() {
+            let mut leg = match Leg::new(&network, &vehicle.path, vehicle.leg) {
+                Some(leg) => leg,
+                None => continue,
+            };
+            vehicle.lane = vehicle.lane.min(leg.lanes() - 1);
+            let key = leg.lane_key(vehicle.lane);
+
+            // Keep back from the vehicle in front
+            let mut gap = lanes
+                .leader(key, entity)
+                .map(|leader| leader - vehicle.distance - VEHICLE_LENGTH)
+                .unwrap_or(f32::INFINITY);
+
+            // And stop at the end of the road unless the vehicle can go on
+            let next = if leg.last {
+                None
+            } else {
+                Leg::new(&network, &vehicle.path, vehicle.leg + 1)
+                    .and_then(|next| lanes.lane_with_room(&next).map(|lane| (next, lane)))
+                    .filter(|_| intersections.is_open(leg.end, leg.segment))
+                    .filter(|_| {
+                        !intersections.is_stop(leg.end)
+                            || next_up
+                                .get(&leg.end)
+                                .is_some_and(|(_, next)| *next == entity)
+                    })
+            };
+            if !leg.last && next.is_none() {
+                gap = gap.min(leg.length - vehicle.distance + MIN_GAP);
+            }
+
+            let room = (gap - MIN_GAP).max(0.0);
+            let safe_speed = (2.0 * BRAKING * room).sqrt();
+            vehicle.speed = (vehicle.speed + ACCELERATION * step)
+                .min(leg.road_type.speed())
+                .min(safe_speed);
+            let moved = (vehicle.speed * step).min(room);
+            vehicle.distance += moved;
+            vehicle.waiting = if moved > 1e-4 {
+                0.0
+            } else {
+                vehicle.waiting + step
+            };
+
+            // Vehicles held at the end of the road stop right on it
+            if vehicle.distance >= leg.length && (leg.last || next.is_some()) {
+                match next {
+                    None => {
+                        stats.arrived += 1;
+                        lanes.remove(key, entity);
+                        entities.delete(entity).unwrap();
+                        continue;
+                    }
+                    Some((next, lane)) => {
+                        if intersections.is_stop(leg.end) {
+                            intersections.reserve(leg.end);
+                            next_up.remove(&leg.end);
+                        }
+                        lanes.remove(key, entity);
+                        vehicle.distance -= leg.length;
+                        vehicle.leg += 1;
+                        vehicle.lane = lane;
+                        leg = next;
+                        lanes.insert(leg.lane_key(lane), vehicle.distance, entity);
+                    }
+                }
+            } else {
+                lanes.set(key, entity, vehicle.distance);
+            }
+
+            vehicle.leg_ends = Some((leg.start, leg.start + leg.direction * leg.length));
+            let position = leg.position(vehicle.distance, vehicle.lane);
+            transform.position = Vector3::new(position.x, position.y, 0.0);
+            transform.rotation = UnitQuaternion::from_euler_angles(0.0, 0.0, leg.angle());
+        }
+    }
+}
+
+// Move a vehicle whose road was split or joined onto the segment the
+// vehicle is now on, and find the rest of the way from the end of that
+// segment. Returns false if the vehicle's road is gone.
+fn rejoin(
+    network: &RoadNetwork,
+    costs: &TravelCosts,
+    finder: &mut PathFinder,
+    vehicle: &mut Vehicle,
+) -> bool {
+    let (start, end) = match vehicle.leg_ends {
+        Some(ends) => ends,
+        None => return false,
+    };
+    let direction = (end - start).normalize();
+    let point = start + direction * vehicle.distance;
+    let segment = match network.segment_along(start, end, point) {
+        Some(segment) => segment,
+        None => return false,
+    };
+
+    let road = network.segment(segment).unwrap();
+    let from = network.node(road.from).unwrap().position;
+    let to = network.node(road.to).unwrap().position;
+    let (entry, exit, entry_position) = if (to - from).dot(&direction) > 0.0 {
+        (road.from, road.to, from)
+    } else {
+        (road.to, road.from, to)
+    };
+
+    let path = &mut vehicle.path;
+    path.nodes.truncate(vehicle.leg);
+    path.nodes.extend_from_slice(&[entry, exit]);
+    path.segments.truncate(vehicle.leg);
+    path.segments.push(segment);
+    vehicle.distance = (point - entry_position).norm();
+    reroute(network, costs, finder, vehicle);
+    true
+}
+
+// Replace the rest of the vehicle's path after the segment it's on with a
+// new path to where it's going, or end it there if there's no way
+fn reroute(
+    network: &RoadNetwork,
+    costs: &TravelCosts,
+    finder: &mut PathFinder,
+    vehicle: &mut Vehicle,
+) {
+    let destination = *vehicle.path.nodes.last().unwrap();
+    let path = &mut vehicle.path;
+    path.nodes.truncate(vehicle.leg + 2);
+    path.segments.truncate(vehicle.leg + 1);
+
+    let node = *path.nodes.last().unwrap();
+    if let Some(rest) = finder.find_path(network, costs, node, destination) {
+        path.nodes.extend_from_slice(&rest.nodes[1..]);
+        path.segments.extend_from_slice(&rest.segments);
+    }
+}
+
+// Counts the vehicles on each segment and every few seconds updates the
+// statistics and how congested pathfinding thinks each road is
+#[derive(Default)]
+pub struct TrafficStatsSystem;
+
+impl<'a> System<'a> for TrafficStatsSystem {
+    type SystemData = (
+        Read<'a, SimulationTime>,
+        Read<'a, RoadNetwork>,
+        Write<'a, TravelCosts>,
+        Write<'a, TrafficStats>,
+        ReadStorage<'a, Vehicle>,
+    );
+
+    fn run(&mut self, (time, network, mut costs, mut stats, vehicles): Self::SystemData) {
+        let mut count = 0;
+        for vehicle in vehicles.join() {
+            if let Some(segment) = vehicle.path.segments.get(vehicle.leg) {
+                let counted = stats.counted.entry(*segment).or_default();
+                counted.0 += 1;
+                counted.1 += vehicle.speed;
+            }
+            count += 1;
+        }
+        stats.vehicles = count;
+        stats.ticks += 1;
+
+        if time.tick % STATS_INTERVAL_TICKS != 0 {
+            return;
+        }
+
+        let ticks = stats.ticks.max(1) as f32;
+        let counted = std::mem::take(&mut stats.counted);
+        stats.ticks = 0;
+        stats.segments = counted
+            .into_iter()
+            .filter_map(|(segment, (vehicles, speeds))| {
+                let road = network.segment(segment)?;
+                let length = network.segment_length(segment)?.max(VEHICLE_LENGTH);
+                let average = vehicles as f32 / ticks;
+                Some((
+                    segment,
+                    SegmentTraffic {
+                        vehicles: average,
+                        load: average / (road.road_type.capacity() as f32 * length),
+                        average_speed: speeds / vehicles as f32,
+                    },
+                ))
+            })
+            .collect();
+
+        let congestion = stats
+            .segments
+            .iter()
+            .map(|(segment, traffic)| (*segment, traffic.load))
+            .collect();
+        costs.set_congestion(congestion);
+    }
+}
+
+// Sends people from homes to jobs in the zones once a day
+pub struct CommuteSystem {
+    rng: Pcg32,
+    // Trips owed from earlier days that didn't add up to a whole trip
+    owed: f32,
+}
+
+impl Default for CommuteSystem {
+    fn default() -> Self {
+        Self {
+            rng: Pcg32::seed_from_u64(0),
+            owed: 0.0,
+        }
+    }
+}
+
+impl<'a> System<'a> for CommuteSystem {
+    type SystemData = (
+        Read<'a, SimulationTime>,
+        Option<Read<'a, TileMap>>,
+        Read<'a, RoadNetwork>,
+        Write<'a, TripRequests>,
+    );
+
+    fn run(&mut self, (time, map, network, mut requests): Self::SystemData) {
+        let map = match map {
+            Some(map) if time.tick % TICKS_PER_DAY == 0 => map,
+            _ => return,
+        };
+
+        let mut homes = Vec::new();
+        let mut jobs = Vec::new();
+        for pos in map.rect((0, 0), (map.width() as i32 - 1, map.height() as i32 - 1)) {
+            match map.get(pos).map(|tile| tile.zone) {
+                Some(Zone::Residential) => homes.push(pos),
+                Some(Zone::Commercial) | Some(Zone::Industrial) => jobs.push(pos),
+                _ => {}
+            }
+        }
+        if homes.is_empty() || jobs.is_empty() {
+            return;
+        }
+
+        self.owed += homes.len() as f32 * TRIPS_PER_HOME_PER_DAY;
+        while self.owed >= 1.0 {
+            self.owed -= 1.0;
+            let home = homes[self.rng.gen_range(0, homes.len())];
+            let job = jobs[self.rng.gen_range(0, jobs.len())];
+            let from = network.nearest_node(roads::tile_center(home));
+            let to = network.nearest_node(roads::tile_center(job));
+            if let (Some(from), Some(to)) = (from, to) {
+                if from != to {
+                    requests.0.push_back((from, to));
+                }
+            }
+        }
+    }
+}
+
+// The transform of a vehicle's body, which is longer than it is wide
+pub fn vehicle_scale() -> Vector3<f32> {
+    Vector3::new(VEHICLE_LENGTH, VEHICLE_LENGTH / 2.0, 1.0)
+}
+
+pub struct TrafficPlugin;
+
+impl Plugin for TrafficPlugin {
+    fn name(&self) -> &'static str {
+        "traffic"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["pathfinding", "tile_map"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .save_component::<Vehicle>("vehicle")
+            .migrate_component::<VehicleV1, Vehicle, _>("vehicle", 1, From::from)
+            .insert_resource(TripRequests::default())
+            .insert_resource(Intersections::default())
+            .insert_resource(TrafficStats::default())
+            .add_simulation_system(CommuteSystem::default(), "commute", &[])
+            .add_simulation_system(TripSpawnSystem, "trip_spawn", &["commute"])
+            .add_simulation_system(VehicleSystem, "vehicles", &["trip_spawn"])
+            .add_simulation_system(TrafficStatsSystem, "traffic_stats", &["vehicles"]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::economy::{EconomyPlugin, Treasury};
+    use crate::pathfinding::PathfindingPlugin;
+    use crate::plugin::GameWorld;
+    use crate::roads::{self, place_road, tile_center, RoadPlugin};
+    use crate::tilemap::TileMapPlugin;
+    use specs::WorldExt;
+
+    const STEP: f32 = 1.0 / 30.0;
+
+    fn build_world() -> GameWorld {
+        let mut game = WorldBuilder::new()
+            .with_plugin(TileMapPlugin::new(32, 32))
+            .with_plugin(EconomyPlugin)
+            .with_plugin(RoadPlugin)
+            .with_plugin(PathfindingPlugin)
+            .with_plugin(TrafficPlugin)
+            .build()
+            .unwrap();
+        game.world.insert(SimulationTime {
+            tick: 0,
+            step: STEP,
+        });
+        game
+    }
+
+    fn road(game: &mut GameWorld, from: (i32, i32), to: (i32, i32), road_type: RoadType) {
+        let mut map = game.world.write_resource::<TileMap>();
+        let mut network = game.world.write_resource::<RoadNetwork>();
+        place_road(&mut map, &mut network, from, to, road_type);
+    }
+
+    fn trip(game: &mut GameWorld, from: (i32, i32), to: (i32, i32)) {
+        let network = game.world.read_resource::<RoadNetwork>();
+        let from = network.node_at(tile_center(from)).unwrap();
+        let to = network.node_at(tile_center(to)).unwrap();
+        game.world
+            .write_resource::<TripRequests>()
+            .0
+            .push_back((from, to));
+    }
+
+    fn tick(game: &mut GameWorld) {
+        game.world.write_resource::<SimulationTime>().tick += 1;
+        game.simulation.dispatch(&game.world);
+        game.world.maintain();
+    }
+
+    fn vehicles(game: &GameWorld) -> Vec<(Entity, Vehicle)> {
+        let entities = game.world.entities();
+        let vehicles = game.world.read_storage::<Vehicle>();
+        (&entities, &vehicles)
+            .join()
+            .map(|(entity, vehicle)| (entity, vehicle.clone()))
+            .collect()
+    }
+
+    // The node at the end of the road the vehicle is on
+    fn next_node(vehicle: &Vehicle) -> NodeId {
+        vehicle.path.nodes[vehicle.leg + 1]
+    }
+
+    // Vehicles in the same lane must never overlap
+    fn assert_no_overlap(game: &GameWorld) {
+        let network = game.world.read_resource::<RoadNetwork>();
+        let mut lanes = Lanes::default();
+        for (entity, vehicle) in vehicles(game) {
+            let leg = Leg::new(&network, &vehicle.path, vehicle.leg).unwrap();
+            lanes.insert(leg.lane_key(vehicle.lane), vehicle.distance, entity);
+        }
+        for vehicles in lanes.0.values() {
+            for pair in vehicles.windows(2) {
+                assert!(pair[1].0 - pair[0].0 >= VEHICLE_LENGTH - 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn vehicles_drive_to_their_destination_and_leave() {
+        let mut game = build_world();
+        road(&mut game, (0, 5), (10, 5), RoadType::Street);
+        trip(&mut game, (0, 5), (10, 5));
+
+        tick(&mut game);
+        assert_eq!(vehicles(&game).len(), 1);
+
+        // Ten tiles at two tiles per second, plus time to speed up
+        for _ in 0..180 {
+            tick(&mut game);
+        }
+        assert!(vehicles(&game).is_empty());
+        assert_eq!(game.world.read_resource::<TrafficStats>().arrived, 1);
+    }
+
+    #[test]
+    fn vehicles_queue_at_red_lights() {
+        let mut game = build_world();
+        road(&mut game, (0, 8), (16, 8), RoadType::Avenue);
+        road(&mut game, (8, 0), (8, 16), RoadType::Street);
+        for _ in 0..3 {
+            trip(&mut game, (0, 8), (16, 8));
+            trip(&mut game, (8, 0), (8, 16));
+        }
+
+        let middle = {
+            let network = game.world.read_resource::<RoadNetwork>();
+            network.node_at(tile_center((8, 8))).unwrap()
+        };
+        let mut longest_wait: f32 = 0.0;
+        for _ in 0..900 {
+            let before: HashMap<Entity, Vehicle> = vehicles(&game).into_iter().collect();
+            tick(&mut game);
+            assert_no_overlap(&game);
+
+            // Anyone who went through the middle had a green light
+            let intersections = game.world.read_resource::<Intersections>();
+            let light = match intersections.control(middle) {
+                Some(Control::Lights(light)) => light,
+                control => panic!("expected lights, found {:?}", control),
+            };
+            for (entity, vehicle) in vehicles(&game) {
+                longest_wait = longest_wait.max(vehicle.waiting);
+                if before.get(&entity).is_some_and(|old| old.leg == 0) && vehicle.leg == 1 {
+                    assert!(light.is_green(vehicle.path.segments[0]));
+                }
+            }
+        }
+
+        assert!(longest_wait > 1.0);
+        assert!(vehicles(&game).is_empty());
+        assert_eq!(game.world.read_resource::<TrafficStats>().arrived, 6);
+    }
+
+    #[test]
+    fn stops_let_one_vehicle_through_at_a_time() {
+        let mut game = build_world();
+        road(&mut game, (0, 8), (16, 8), RoadType::Street);
+        road(&mut game, (8, 0), (8, 8), RoadType::Street);
+        trip(&mut game, (0, 8), (16, 8));
+        trip(&mut game, (16, 8), (0, 8));
+        trip(&mut game, (8, 0), (0, 8));
+
+        let middle = {
+            let network = game.world.read_resource::<RoadNetwork>();
+            network.node_at(tile_center((8, 8))).unwrap()
+        };
+        let mut last_crossing: Option<u64> = None;
+        for tick_count in 0..900 {
+            let before: HashMap<Entity, Vehicle> = vehicles(&game).into_iter().collect();
+            tick(&mut game);
+
+            let crossed = vehicles(&game)
+                .into_iter()
+                .filter(|(entity, vehicle)| {
+                    before.get(entity).is_some_and(|old| old.leg < vehicle.leg)
+                        && next_node(&before[entity]) == middle
+                })
+                .count();
+            assert!(crossed <= 1);
+            if crossed == 1 {
+                if let Some(last) = last_crossing {
+                    assert!((tick_count - last) as f32 * STEP >= CROSSING_TIME - STEP);
+                }
+                last_crossing = Some(tick_count);
+            }
+        }
+        assert_eq!(game.world.read_resource::<TrafficStats>().arrived, 3);
+    }
+
+    #[test]
+    fn vehicles_keep_driving_when_their_road_is_split() {
+        let mut game = build_world();
+        road(&mut game, (0, 8), (20, 8), RoadType::Street);
+        for _ in 0..4 {
+            trip(&mut game, (0, 8), (20, 8));
+            trip(&mut game, (20, 8), (0, 8));
+        }
+        for _ in 0..60 {
+            tick(&mut game);
+        }
+        assert!(!vehicles(&game).is_empty());
+
+        // Build crossing roads through the one everyone is driving on
+        road(&mut game, (4, 0), (4, 16), RoadType::Street);
+        road(&mut game, (10, 0), (10, 16), RoadType::Street);
+        tick(&mut game);
+        {
+            let network = game.world.read_resource::<RoadNetwork>();
+            for (_, vehicle) in vehicles(&game) {
+                assert!(Leg::new(&network, &vehicle.path, vehicle.leg).is_some());
+            }
+        }
+        assert_no_overlap(&game);
+
+        for _ in 0..900 {
+            tick(&mut game);
+        }
+        let stats = game.world.read_resource::<TrafficStats>();
+        assert_eq!(stats.stranded, 0);
+        assert_eq!(stats.arrived, 8);
+    }
+
+    #[test]
+    fn vehicles_keep_driving_when_their_road_is_joined() {
+        let mut game = build_world();
+        road(&mut game, (0, 8), (20, 8), RoadType::Street);
+        road(&mut game, (10, 0), (10, 8), RoadType::Street);
+        for _ in 0..2 {
+            trip(&mut game, (0, 8), (20, 8));
+            trip(&mut game, (20, 8), (0, 8));
+        }
+        for _ in 0..30 {
+            tick(&mut game);
+        }
+        assert!(!vehicles(&game).is_empty());
+
+        // Without the side road the main road is one segment again
+        {
+            let mut map = game.world.write_resource::<TileMap>();
+            let mut network = game.world.write_resource::<RoadNetwork>();
+            let mut treasury = game.world.write_resource::<Treasury>();
+            let side: Vec<(i32, i32)> = (0..8).map(|y| (10, y)).collect();
+            roads::bulldoze(&mut map, &mut network, &mut treasury, &side).unwrap();
+            assert_eq!(network.segment_count(), 1);
+        }
+        tick(&mut game);
+        {
+            let network = game.world.read_resource::<RoadNetwork>();
+            for (_, vehicle) in vehicles(&game) {
+                assert!(Leg::new(&network, &vehicle.path, vehicle.leg).is_some());
+            }
+        }
+
+        for _ in 0..900 {
+            tick(&mut game);
+        }
+        let stats = game.world.read_resource::<TrafficStats>();
+        assert_eq!(stats.stranded, 0);
+        assert_eq!(stats.arrived, 4);
+    }
+
+    #[test]
+    fn congestion_is_fed_into_travel_costs() {
+        let mut game = build_world();
+        road(&mut game, (0, 5), (20, 5), RoadType::Street);
+        for _ in 0..10 {
+            trip(&mut game, (0, 5), (20, 5));
+        }
+        for _ in 0..STATS_INTERVAL_TICKS * 3 {
+            tick(&mut game);
+        }
+
+        let segment = vehicles(&game)[0].1.path.segments[0];
+        let stats = game.world.read_resource::<TrafficStats>();
+        let costs = game.world.read_resource::<TravelCosts>();
+        assert!(stats.segments[&segment].vehicles > 1.0);
+        assert!(stats.segments[&segment].load > 0.0);
+        assert_eq!(costs.congestion(segment), stats.segments[&segment].load);
+    }
+}
@@ -1,9 +1,32 @@
-use nalgebra::{Matrix4, Translation3, UnitQuaternion, Vector3};
-use specs::Component;
+use crate::pathfinding::Path;
+use crate::renderer::{Bounds, WorldMesh};
+use crate::save::{EntityIds, MapEntities};
+use nalgebra::{
+    Matrix4, Orthographic3, Point3, Rotation3, Translation3, UnitQuaternion, Vector2, Vector3,
+    Vector4,
+};
+use render::assets::Handle;
+use render::{ShaderProgram, Texture};
+use serde::{Deserialize, Serialize};
+use specs::{Component, DenseVecStorage, Entity, FlaggedStorage, VecStorage};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_3, FRAC_PI_4};
 use std::ops::Mul;
 
-#[derive(Component, Debug, Clone, PartialEq)]
-#[storage(specs::VecStorage)]
+// How far the camera sits from the point it looks at. Orthographic
+// projections don't change with distance, this just has to keep everything
+// on the map between the near and far planes.
+const CAMERA_DISTANCE: f32 = 100.0;
+
+// Half of the height of the view in world units at a zoom of 1
+const CAMERA_BASE_HALF_HEIGHT: f32 = 10.0;
+
+// How far the isometric view is tilted away from looking straight down
+const CAMERA_ISOMETRIC_TILT: f32 = FRAC_PI_3;
+
+// A transform relative to the entity's parent, or to the world if it
+// doesn't have one. Changes are tracked so only moved subtrees have their
+// global transforms recomputed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transform {
     pub position: Vector3<f32>,
     pub rotation: UnitQuaternion<f32>,
@@ -45,12 +68,422 @@ impl Default for Transform {
     }
 }
 
-macro_rules! create_world {
-    ($($component:ty),*) => {{
-        use specs::{World, WorldExt};
-        let mut world = World::new();
-        world.register::<Transform>();
-        $( world.register::<$component>(); )*
-        world
-    }};
+impl Component for Transform {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+// The entity this one is attached to, so it moves along with it
+#[derive(Component, Debug, Copy, Clone, PartialEq, Eq)]
+#[storage(specs::FlaggedStorage)]
+pub struct Parent(pub Entity);
+
+impl MapEntities for Parent {
+    type Saved = u32;
+
+    // Parents that aren't saved are dropped, leaving the child as a root
+    fn save(&self, ids: &EntityIds) -> Option<u32> {
+        ids.id(self.0)
+    }
+
+    fn load(saved: u32, ids: &EntityIds) -> Option<Self> {
+        ids.entity(saved).map(Parent)
+    }
+}
+
+// The entities attached to this one. This is kept up to date from the
+// `Parent` components by the transform hierarchy, don't change it directly.
+#[derive(Component, Debug, Clone, Default, PartialEq, Eq)]
+#[storage(specs::DenseVecStorage)]
+pub struct Children(pub Vec<Entity>);
+
+// The entity's transform relative to the world, with all of its parents'
+// transforms applied
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+#[storage(specs::VecStorage)]
+pub struct GlobalTransform(pub Matrix4<f32>);
+
+impl Default for GlobalTransform {
+    fn default() -> Self {
+        Self(Matrix4::identity())
+    }
+}
+
+// Draws a mesh with the given material (shader program) at the entity's
+// global transform
+#[derive(Component, Debug, Clone)]
+#[storage(specs::VecStorage)]
+pub struct MeshRenderer {
+    pub mesh: Handle<WorldMesh>,
+    pub material: Handle<ShaderProgram>,
+    // The mesh's bounds in local space, used to skip it when it's out of view
+    pub bounds: Bounds,
+}
+
+impl MeshRenderer {
+    pub fn new(mesh: Handle<WorldMesh>, material: Handle<ShaderProgram>, bounds: Bounds) -> Self {
+        Self {
+            mesh,
+            material,
+            bounds,
+        }
+    }
+}
+
+// A textured rectangle in the entity's XY plane, centered on the entity
+#[derive(Component, Debug, Clone)]
+#[storage(specs::VecStorage)]
+pub struct Sprite {
+    pub texture: Handle<Texture>,
+    pub size: Vector2<f32>,
+    // Multiplied with the texture's color
+    pub color: Vector4<f32>,
+}
+
+impl Sprite {
+    pub fn new(texture: Handle<Texture>, size: Vector2<f32>) -> Self {
+        Self {
+            texture,
+            size,
+            color: Vector4::new(1.0, 1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn with_color(mut self, color: Vector4<f32>) -> Self {
+        self.color = color;
+        self
+    }
+}
+
+// Draws the entity between where it was before and after the last
+// simulation step, so it moves smoothly when frames are drawn faster than
+// the simulation runs
+#[derive(Component, Debug, Copy, Clone, Default, PartialEq)]
+#[storage(specs::VecStorage)]
+pub struct InterpolatedTransform {
+    pub previous: Option<Matrix4<f32>>,
+}
+
+impl InterpolatedTransform {
+    // The object transform to draw the entity with. The matrices are blended
+    // component-wise, which is close enough for the small changes made in a
+    // single step.
+    pub fn interpolate(&self, current: &Matrix4<f32>, alpha: f32) -> Matrix4<f32> {
+        match self.previous {
+            Some(previous) => previous * (1.0 - alpha) + current * alpha,
+            None => *current,
+        }
+    }
+}
+
+// Rotates the entity around the Z axis, in radians per second
+#[derive(Component, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[storage(specs::HashMapStorage)]
+pub struct Spin(pub f32);
+
+// A car driving along a path over the roads. It's removed once it reaches
+// the end of the path.
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[storage(specs::DenseVecStorage)]
+pub struct Vehicle {
+    pub path: Path,
+    // The index of the segment in the path the vehicle is on
+    pub leg: usize,
+    // How far the vehicle is along the segment in the direction it's going
+    pub distance: f32,
+    // In tiles per second
+    pub speed: f32,
+    // Counted from the middle of the road outwards
+    pub lane: u8,
+    // How long the vehicle has been stopped, so those waiting longest at an
+    // intersection go first
+    pub waiting: f32,
+    // Where the segment the vehicle is on started and ended when it last
+    // moved, so it can find its road again after it's split or joined
+    pub leg_ends: Option<(Vector2<f32>, Vector2<f32>)>,
+}
+
+impl Vehicle {
+    pub fn new(path: Path, lane: u8) -> Self {
+        Self {
+            path,
+            leg: 0,
+            distance: 0.0,
+            speed: 0.0,
+            lane,
+            waiting: 0.0,
+            leg_ends: None,
+        }
+    }
+}
+
+// Version 1 of the saved vehicle, from before vehicles kept the ends of
+// their segment
+#[derive(Serialize, Deserialize)]
+pub struct VehicleV1 {
+    path: Path,
+    leg: usize,
+    distance: f32,
+    speed: f32,
+    lane: u8,
+    waiting: f32,
+}
+
+impl From<VehicleV1> for Vehicle {
+    fn from(old: VehicleV1) -> Self {
+        Self {
+            path: old.path,
+            leg: old.leg,
+            distance: old.distance,
+            speed: old.speed,
+            lane: old.lane,
+            waiting: old.waiting,
+            leg_ends: None,
+        }
+    }
+}
+
+// A building standing on a lot of zoned tiles, which own it. Changes are
+// flagged so the building mesh is only rebuilt when they change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Building {
+    // The name of the building's type
+    pub kind: String,
+    // The lowest corner of the lot and its size in tiles
+    pub lot: (i32, i32),
+    pub size: (u8, u8),
+    pub level: u8,
+    // How well the building is doing, from 0 where it's abandoned to 1
+    pub condition: f32,
+    // The day the building was abandoned, if it's empty
+    pub abandoned: Option<u64>,
+}
+
+impl Component for Building {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl Building {
+    // The tiles of the lot
+    pub fn tiles(&self) -> impl Iterator<Item = (i32, i32)> {
+        let (x, y) = self.lot;
+        let (width, height) = (i32::from(self.size.0), i32::from(self.size.1));
+        (y..y + height).flat_map(move |y| (x..x + width).map(move |x| (x, y)))
+    }
+}
+
+// Lets the entity be picked with the mouse. The bounds are in the entity's
+// local space, so they're moved, rotated and scaled by its transform.
+#[derive(Component, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[storage(specs::VecStorage)]
+pub struct Pickable {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Pickable {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Self { min, max }
+    }
+}
+
+impl Default for Pickable {
+    // A unit cube centered on the entity
+    fn default() -> Self {
+        Self::new(Vector3::new(-0.5, -0.5, -0.5), Vector3::new(0.5, 0.5, 0.5))
+    }
+}
+
+// An orthographic camera looking down at the ground plane (z = 0). The
+// controller moves the camera towards its targets so movement is smoothed.
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[storage(specs::HashMapStorage)]
+pub struct Camera {
+    // The point on the ground in the center of the view
+    pub position: Vector2<f32>,
+    pub zoom: f32,
+    // Number of quarter turns the view is rotated counterclockwise
+    pub rotation_steps: u8,
+    // Tilt the view and turn it an extra 45 degrees for an isometric look
+    pub isometric: bool,
+
+    pub target_position: Vector2<f32>,
+    pub target_zoom: f32,
+}
+
+impl Camera {
+    pub fn new(position: Vector2<f32>, zoom: f32) -> Self {
+        Self {
+            position,
+            zoom,
+            rotation_steps: 0,
+            isometric: false,
+            target_position: position,
+            target_zoom: zoom,
+        }
+    }
+
+    // Rotation of the view around the vertical axis
+    pub fn yaw(&self) -> f32 {
+        let yaw = f32::from(self.rotation_steps % 4) * FRAC_PI_2;
+        if self.isometric {
+            yaw + FRAC_PI_4
+        } else {
+            yaw
+        }
+    }
+
+    pub fn tilt(&self) -> f32 {
+        if self.isometric {
+            CAMERA_ISOMETRIC_TILT
+        } else {
+            0.0
+        }
+    }
+
+    // Half of the width and height of the view in world units
+    pub fn half_extents(&self, aspect: f32) -> Vector2<f32> {
+        let half_height = CAMERA_BASE_HALF_HEIGHT / self.zoom;
+        Vector2::new(half_height * aspect, half_height)
+    }
+
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        let target: Matrix4<f32> =
+            Translation3::new(-self.position.x, -self.position.y, 0.0).into();
+        let yaw = Rotation3::from_axis_angle(&Vector3::z_axis(), -self.yaw()).to_homogeneous();
+        let tilt = Rotation3::from_axis_angle(&Vector3::x_axis(), -self.tilt()).to_homogeneous();
+        let back: Matrix4<f32> = Translation3::new(0.0, 0.0, -CAMERA_DISTANCE).into();
+
+        back.mul(&tilt).mul(&yaw).mul(&target)
+    }
+
+    pub fn projection_matrix(&self, aspect: f32) -> Matrix4<f32> {
+        let half = self.half_extents(aspect);
+        Orthographic3::new(-half.x, half.x, -half.y, half.y, 0.1, CAMERA_DISTANCE * 2.0)
+            .to_homogeneous()
+    }
+
+    pub fn view_projection(&self, aspect: f32) -> Matrix4<f32> {
+        self.projection_matrix(aspect).mul(&self.view_matrix())
+    }
+
+    // The ray through the given cursor position (in window coordinates)
+    // from the near plane to the far plane
+    pub fn cursor_ray(
+        &self,
+        cursor: (f64, f64),
+        window_size: (i32, i32),
+    ) -> Option<(Point3<f32>, Vector3<f32>)> {
+        if window_size.0 <= 0 || window_size.1 <= 0 {
+            return None;
+        }
+
+        let aspect = window_size.0 as f32 / window_size.1 as f32;
+        let inverse = self.view_projection(aspect).try_inverse()?;
+
+        // Convert to normalized device coordinates (y points up)
+        let x = (2.0 * cursor.0 / f64::from(window_size.0) - 1.0) as f32;
+        let y = (1.0 - 2.0 * cursor.1 / f64::from(window_size.1)) as f32;
+
+        let near = inverse.transform_point(&Point3::new(x, y, -1.0));
+        let far = inverse.transform_point(&Point3::new(x, y, 1.0));
+        Some((near, far - near))
+    }
+
+    // The point on the ground under the cursor
+    pub fn screen_to_ground(
+        &self,
+        cursor: (f64, f64),
+        window_size: (i32, i32),
+    ) -> Option<Vector2<f32>> {
+        let (origin, direction) = self.cursor_ray(cursor, window_size)?;
+        if direction.z.abs() < f32::EPSILON {
+            return None;
+        }
+
+        let t = -origin.z / direction.z;
+        let point = origin + direction * t;
+        Some(Vector2::new(point.x, point.y))
+    }
+
+    // Converts a direction on the screen (x right, y up) to the direction
+    // on the ground it moves the view in
+    pub fn screen_to_ground_direction(&self, direction: Vector2<f32>) -> Vector2<f32> {
+        let (sin, cos) = self.yaw().sin_cos();
+        Vector2::new(
+            direction.x * cos - direction.y * sin,
+            direction.x * sin + direction.y * cos,
+        )
+    }
+}
+
+// Seconds passed since the last frame
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct DeltaTime(pub f32);
+
+// Length of the in-game calendar in simulation ticks
+pub const TICKS_PER_DAY: u64 = 30;
+pub const DAYS_PER_MONTH: u64 = 30;
+
+// The simulation's progress. Simulation systems should move things by
+// `step` seconds each time they run instead of using `DeltaTime`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SimulationTime {
+    pub tick: u64,
+    pub step: f32,
+}
+
+impl SimulationTime {
+    // Number of in-game days since the city was founded
+    pub fn day(&self) -> u64 {
+        self.tick / TICKS_PER_DAY
+    }
+
+    pub fn month(&self) -> u64 {
+        self.day() / DAYS_PER_MONTH
+    }
+}
+
+// The area of the world the camera can look at
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapBounds {
+    pub min: Vector2<f32>,
+    pub max: Vector2<f32>,
+}
+
+impl MapBounds {
+    pub fn new(min: Vector2<f32>, max: Vector2<f32>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn clamp(&self, point: Vector2<f32>) -> Vector2<f32> {
+        Vector2::new(
+            point.x.max(self.min.x).min(self.max.x),
+            point.y.max(self.min.y).min(self.max.y),
+        )
+    }
+}
+
+// Describes the map being played, saved in the header of save files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MapInfo {
+    pub name: String,
+    // Size of the map in tiles
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MapInfo {
+    pub fn new(name: &str, width: u32, height: u32) -> Self {
+        Self {
+            name: name.to_owned(),
+            width,
+            height,
+        }
+    }
+}
+
+impl Default for MapInfo {
+    fn default() -> Self {
+        Self::new("Unnamed", 0, 0)
+    }
 }
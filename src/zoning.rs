@@ -0,0 +1,430 @@
+use crate::economy::Treasury;
+use crate::input::InputState;
+use crate::picking::Selection;
+use crate::plugin::{Plugin, WorldBuilder};
+use crate::tilemap::{Terrain, TileFlags, TileMap, Zone, ZoneDensity};
+use crate::tools::{ActiveTool, TileHighlights, Tool, ToolStatus};
+use glfw::{Key, MouseButton};
+use nalgebra::Vector4;
+use specs::{Read, System, Write};
+use std::collections::HashSet;
+
+// Largest height difference to a neighboring tile that can still be zoned
+pub const MAX_SLOPE: u8 = 8;
+pub const MAX_BRUSH_RADIUS: i32 = 8;
+
+// The color zones are drawn with
+pub fn zone_color(zone: Zone) -> Option<Vector4<f32>> {
+    match zone {
+        Zone::Unzoned => None,
+        Zone::Residential => Some(Vector4::new(0.3, 0.9, 0.3, 1.0)),
+        Zone::Commercial => Some(Vector4::new(0.3, 0.5, 1.0, 1.0)),
+        Zone::Industrial => Some(Vector4::new(1.0, 0.8, 0.2, 1.0)),
+    }
+}
+
+// What zoning a tile costs. De-zoning is free.
+pub fn zoning_cost(zone: Zone, density: ZoneDensity) -> i64 {
+    match (zone, density) {
+        (Zone::Unzoned, _) => 0,
+        (_, ZoneDensity::Low) => 10,
+        (_, ZoneDensity::Medium) => 20,
+        (_, ZoneDensity::High) => 40,
+    }
+}
+
+// Whether the tile can be zoned, and why not if it can't
+pub fn check_zoning(map: &TileMap, pos: (i32, i32)) -> Result<(), String> {
+    let tile = map
+        .get(pos)
+        .ok_or_else(|| "outside of the map".to_owned())?;
+    if tile.terrain == Terrain::Water {
+        return Err("can't zone water".to_owned());
+    }
+    if tile.flags.contains(TileFlags::ROAD) {
+        return Err("can't zone roads".to_owned());
+    }
+
+    let steep = map.neighbors4(pos).any(|next| {
+        let next = map.get(next).unwrap();
+        (i32::from(next.height) - i32::from(tile.height)).abs() > i32::from(MAX_SLOPE)
+    });
+    if steep {
+        return Err("too steep".to_owned());
+    }
+
+    let touches_road = map
+        .neighbors4(pos)
+        .any(|next| map.get(next).unwrap().flags.contains(TileFlags::ROAD));
+    if !touches_road {
+        return Err("not next to a road".to_owned());
+    }
+
+    Ok(())
+}
+
+// The tiles that zoning would change. Tiles that can't be zoned or are
+// already zoned this way are left out.
+pub fn plan_zoning(
+    map: &TileMap,
+    tiles: &[(i32, i32)],
+    zone: Zone,
+    density: ZoneDensity,
+) -> Vec<(i32, i32)> {
+    tiles
+        .iter()
+        .cloned()
+        .filter(|&pos| match map.get(pos) {
+            Some(tile) if zone == Zone::Unzoned => tile.zone != Zone::Unzoned,
+            Some(tile) => {
+                (tile.zone, tile.density) != (zone, density) && check_zoning(map, pos).is_ok()
+            }
+            None => false,
+        })
+        .collect()
+}
+
+// Zone the tiles that can be zoned, charging the treasury for them. Nothing
+// changes if the city can't afford all of them. Returns the number of tiles
+// that were zoned.
+pub fn zone_tiles(
+    map: &mut TileMap,
+    treasury: &mut Treasury,
+    tiles: &[(i32, i32)],
+    zone: Zone,
+    density: ZoneDensity,
+) -> Result<usize, String> {
+    let planned = plan_zoning(map, tiles, zone, density);
+    let cost = planned.len() as i64 * zoning_cost(zone, density);
+    if !treasury.spend(cost) {
+        return Err(format!(
+            "zoning {} tiles costs ${}, but the city only has ${}",
+            planned.len(),
+            cost,
+            treasury.funds
+        ));
+    }
+
+    // De-zoned tiles go back to the default density
+    let density = if zone == Zone::Unzoned {
+        ZoneDensity::default()
+    } else {
+        density
+    };
+    for &pos in planned.iter() {
+        map.update(pos, |tile| {
+            tile.zone = zone;
+            tile.density = density;
+        });
+    }
+
+    Ok(planned.len())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZoneShape {
+    // Zones around the cursor while the button is held
+    Brush,
+    // Zones the rectangle dragged out with the cursor
+    Rectangle,
+    // Zones the line dragged out with the cursor
+    Line,
+}
+
+// The zoning tool the player is using
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoningTool {
+    // The zone being painted, or `None` when another tool is in use.
+    // De-zoning paints `Zone::Unzoned`.
+    zone: Option<Zone>,
+    pub density: ZoneDensity,
+    pub shape: ZoneShape,
+    pub brush_radius: i32,
+    // Where the rectangle or line being dragged started
+    drag_start: Option<(i32, i32)>,
+    // The last problem shown to the player, so holding the brush down
+    // doesn't repeat it every frame
+    last_error: Option<String>,
+}
+
+impl Default for ZoningTool {
+    fn default() -> Self {
+        Self {
+            zone: None,
+            density: ZoneDensity::Low,
+            shape: ZoneShape::Rectangle,
+            brush_radius: 1,
+            drag_start: None,
+            last_error: None,
+        }
+    }
+}
+
+impl ZoningTool {
+    // The tiles the tool covers with the cursor over `hovered`
+    pub fn tiles(&self, map: &TileMap, hovered: (i32, i32)) -> Vec<(i32, i32)> {
+        let start = self.drag_start.unwrap_or(hovered);
+        match self.shape {
+            ZoneShape::Brush => {
+                let radius = self.brush_radius;
+                map.rect(
+                    (hovered.0 - radius, hovered.1 - radius),
+                    (hovered.0 + radius, hovered.1 + radius),
+                )
+                .filter(|(x, y)| {
+                    let (dx, dy) = (x - hovered.0, y - hovered.1);
+                    dx * dx + dy * dy <= radius * radius
+                })
+                .collect()
+            }
+            ZoneShape::Rectangle => map.rect(start, hovered).collect(),
+            ZoneShape::Line => map.line(start, hovered),
+        }
+    }
+
+    fn switch_to(&mut self, zone: Option<Zone>) {
+        if self.zone != zone {
+            self.zone = zone;
+            self.drag_start = None;
+        }
+    }
+
+    fn handle_keys(&mut self, input: &InputState) {
+        if input.was_key_pressed(Key::Z) {
+            self.density = match self.density {
+                ZoneDensity::Low => ZoneDensity::Medium,
+                ZoneDensity::Medium => ZoneDensity::High,
+                ZoneDensity::High => ZoneDensity::Low,
+            };
+        }
+
+        let shapes = [
+            (Key::B, ZoneShape::Brush),
+            (Key::R, ZoneShape::Rectangle),
+            (Key::L, ZoneShape::Line),
+        ];
+        for &(key, shape) in shapes.iter() {
+            if input.was_key_pressed(key) {
+                self.shape = shape;
+                self.drag_start = None;
+            }
+        }
+
+        if input.was_key_pressed(Key::LeftBracket) {
+            self.brush_radius = (self.brush_radius - 1).max(0);
+        }
+        if input.was_key_pressed(Key::RightBracket) {
+            self.brush_radius = (self.brush_radius + 1).min(MAX_BRUSH_RADIUS);
+        }
+    }
+
+    fn report(&mut self, result: Result<usize, String>) {
+        match result {
+            Ok(_) => self.last_error = None,
+            Err(err) => {
+                if self.last_error.as_ref() != Some(&err) {
+                    println!("Can't zone: {}", err);
+                }
+                self.last_error = Some(err);
+            }
+        }
+    }
+}
+
+// Zones tiles with the zoning tool:
+// - the zoning and de-zoning tools use it, see `ToolSwitchSystem`
+// - Z changes the density
+// - B, R and L pick the brush, rectangle and line shapes
+// - [ and ] change the size of the brush
+// - the left mouse button paints with the brush, or drags out rectangles
+//   and lines
+// The tiles that would be zoned are highlighted and the cost is shown.
+#[derive(Default)]
+pub struct ZoningSystem;
+
+impl<'a> System<'a> for ZoningSystem {
+    type SystemData = (
+        Read<'a, InputState>,
+        Read<'a, Selection>,
+        Read<'a, ActiveTool>,
+        Write<'a, ZoningTool>,
+        Option<Write<'a, TileMap>>,
+        Write<'a, Treasury>,
+        Write<'a, TileHighlights>,
+        Write<'a, ToolStatus>,
+    );
+
+    fn run(
+        &mut self,
+        (input, selection, active, mut tool, map, mut treasury, mut highlights, mut status): Self::SystemData,
+    ) {
+        tool.switch_to(match active.0 {
+            Some(Tool::Zone(zone)) => Some(zone),
+            _ => None,
+        });
+        tool.handle_keys(&input);
+
+        let (mut map, zone) = match (map, tool.zone) {
+            (Some(map), Some(zone)) => (map, zone),
+            _ => return,
+        };
+        let hovered = match selection.hovered_tile {
+            Some(hovered) if map.contains(hovered) => hovered,
+            _ => return,
+        };
+
+        let button = MouseButton::Button1;
+        if input.was_button_pressed(button) && tool.shape != ZoneShape::Brush {
+            tool.drag_start = Some(hovered);
+        }
+
+        let tiles = tool.tiles(&map, hovered);
+        let density = tool.density;
+        let apply = match tool.shape {
+            ZoneShape::Brush => input.is_button_down(button),
+            _ => tool.drag_start.is_some() && input.was_button_released(button),
+        };
+        if apply {
+            let result = zone_tiles(&mut map, &mut treasury, &tiles, zone, density);
+            tool.report(result);
+            tool.drag_start = None;
+        }
+
+        // Show which tiles will change and which can't
+        let planned: HashSet<(i32, i32)> = plan_zoning(&map, &tiles, zone, density)
+            .into_iter()
+            .collect();
+        let color = zone_color(zone).unwrap_or_else(|| Vector4::new(1.0, 1.0, 1.0, 1.0));
+        let blocked = Vector4::new(1.0, 0.2, 0.2, 1.0);
+        highlights.0.extend(tiles.iter().map(|pos| {
+            if planned.contains(pos) {
+                (*pos, color)
+            } else {
+                (*pos, blocked)
+            }
+        }));
+        status.0 = Some(format!(
+            "{:?} ({:?}): {} tiles, ${}",
+            zone,
+            density,
+            planned.len(),
+            planned.len() as i64 * zoning_cost(zone, density)
+        ));
+    }
+}
+
+pub struct ZoningPlugin;
+
+impl Plugin for ZoningPlugin {
+    fn name(&self) -> &'static str {
+        "zoning"
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["picking", "tile_map", "economy", "tools"]
+    }
+
+    fn build(&self, builder: &mut WorldBuilder) {
+        builder
+            .insert_resource(ZoningTool::default())
+            .add_frame_system(ZoningSystem, "zoning", &["picking", "tool_switch"]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flat grass with a road along the bottom row
+    fn map() -> TileMap {
+        let mut map = TileMap::new(12, 12);
+        for pos in map.line((0, 0), (11, 0)) {
+            map.update(pos, |tile| tile.flags.insert(TileFlags::ROAD));
+        }
+        map
+    }
+
+    #[test]
+    fn zones_need_to_touch_a_road_on_flat_dry_land() {
+        let mut map = map();
+        map.update((2, 1), |tile| tile.terrain = Terrain::Water);
+        map.update((4, 1), |tile| tile.height = MAX_SLOPE + 1);
+
+        assert_eq!(check_zoning(&map, (1, 1)), Ok(()));
+        assert_eq!(
+            check_zoning(&map, (1, 2)),
+            Err("not next to a road".to_owned())
+        );
+        assert_eq!(check_zoning(&map, (7, 1)), Ok(()));
+        assert_eq!(
+            check_zoning(&map, (1, 0)),
+            Err("can't zone roads".to_owned())
+        );
+        assert_eq!(
+            check_zoning(&map, (2, 1)),
+            Err("can't zone water".to_owned())
+        );
+        assert_eq!(check_zoning(&map, (5, 1)), Err("too steep".to_owned()));
+    }
+
+    #[test]
+    fn zoning_is_charged_and_skips_invalid_tiles() {
+        let mut map = map();
+        let mut treasury = Treasury::new(1000);
+        let tiles: Vec<_> = map.rect((0, 0), (3, 5)).collect();
+
+        let zoned = zone_tiles(
+            &mut map,
+            &mut treasury,
+            &tiles,
+            Zone::Residential,
+            ZoneDensity::Medium,
+        );
+        assert_eq!(zoned, Ok(4));
+        assert_eq!(treasury.funds, 1000 - 4 * 20);
+        assert_eq!(map.get((0, 0)).unwrap().zone, Zone::Unzoned);
+        assert_eq!(map.get((3, 2)).unwrap().zone, Zone::Unzoned);
+        assert_eq!(map.get((3, 1)).unwrap().density, ZoneDensity::Medium);
+
+        // Zoning the same way again is free
+        let funds = treasury.funds;
+        let zoned = zone_tiles(
+            &mut map,
+            &mut treasury,
+            &tiles,
+            Zone::Residential,
+            ZoneDensity::Medium,
+        );
+        assert_eq!(zoned, Ok(0));
+        assert_eq!(treasury.funds, funds);
+
+        let dezoned = zone_tiles(
+            &mut map,
+            &mut treasury,
+            &tiles,
+            Zone::Unzoned,
+            ZoneDensity::High,
+        );
+        assert_eq!(dezoned, Ok(4));
+        assert_eq!(map.get((3, 1)).unwrap().zone, Zone::Unzoned);
+        assert_eq!(map.get((3, 1)).unwrap().density, ZoneDensity::Low);
+    }
+
+    #[test]
+    fn nothing_is_zoned_without_the_money() {
+        let mut map = map();
+        let mut treasury = Treasury::new(30);
+        let tiles: Vec<_> = map.rect((0, 1), (3, 1)).collect();
+
+        let zoned = zone_tiles(
+            &mut map,
+            &mut treasury,
+            &tiles,
+            Zone::Industrial,
+            ZoneDensity::Low,
+        );
+        assert!(zoned.is_err());
+        assert_eq!(treasury.funds, 30);
+        assert_eq!(map.get((0, 1)).unwrap().zone, Zone::Unzoned);
+    }
+}